@@ -0,0 +1,237 @@
+//! Aggregate properties
+//!
+//! An aggregate property rolls up a property of a twin's children, declared
+//! per class like computed properties:
+//!
+//! ```
+//! use twintalk_core::aggregate::AggregateProperty;
+//!
+//! let hottest = AggregateProperty::max("maxTemperature", "temperature");
+//! let alerting = AggregateProperty::alerting("alertingUnits");
+//! ```
+//!
+//! The `Runtime` keeps each child's contribution and updates the aggregate
+//! as child telemetry arrives, without reading the other children. Changed
+//! aggregates are recorded as telemetry of the parent, so they persist, can
+//! trigger its alert rules, and roll up further to its own parents.
+
+use crate::relationship::RelationshipKind;
+use crate::twin::{Twin, TwinId};
+use crate::value::Value;
+use ordered_float::OrderedFloat;
+use std::collections::{BTreeMap, HashMap};
+
+/// How child values are combined
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Aggregation {
+    /// Number of children with a value
+    Count,
+    Sum,
+    Mean,
+    Min,
+    Max,
+}
+
+/// What each child contributes
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AggregateInput {
+    /// A numeric property
+    Property(String),
+    /// 1 while the child has an active alert, 0 otherwise
+    Alerting,
+}
+
+/// A property rolled up from a twin's children
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AggregateProperty {
+    name: String,
+    input: AggregateInput,
+    aggregation: Aggregation,
+    over: RelationshipKind,
+}
+
+impl AggregateProperty {
+    /// Aggregate `property` of the twins this one contains into `name`
+    pub fn new(
+        name: impl Into<String>,
+        property: impl Into<String>,
+        aggregation: Aggregation,
+    ) -> Self {
+        Self {
+            name: name.into(),
+            input: AggregateInput::Property(property.into()),
+            aggregation,
+            over: RelationshipKind::Contains,
+        }
+    }
+
+    /// Number of children reporting `property`
+    pub fn count(name: impl Into<String>, property: impl Into<String>) -> Self {
+        Self::new(name, property, Aggregation::Count)
+    }
+
+    pub fn sum(name: impl Into<String>, property: impl Into<String>) -> Self {
+        Self::new(name, property, Aggregation::Sum)
+    }
+
+    pub fn mean(name: impl Into<String>, property: impl Into<String>) -> Self {
+        Self::new(name, property, Aggregation::Mean)
+    }
+
+    pub fn min(name: impl Into<String>, property: impl Into<String>) -> Self {
+        Self::new(name, property, Aggregation::Min)
+    }
+
+    pub fn max(name: impl Into<String>, property: impl Into<String>) -> Self {
+        Self::new(name, property, Aggregation::Max)
+    }
+
+    /// Number of children with an active alert
+    pub fn alerting(name: impl Into<String>) -> Self {
+        Self {
+            name: name.into(),
+            input: AggregateInput::Alerting,
+            aggregation: Aggregation::Sum,
+            over: RelationshipKind::Contains,
+        }
+    }
+
+    /// Aggregate the targets of `kind` relationships instead of children
+    #[must_use]
+    pub fn over(mut self, kind: impl Into<RelationshipKind>) -> Self {
+        self.over = kind.into();
+        self
+    }
+
+    /// The property name
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn input(&self) -> &AggregateInput {
+        &self.input
+    }
+
+    pub fn aggregation(&self) -> Aggregation {
+        self.aggregation
+    }
+
+    /// The relationships followed to the twins aggregated
+    pub fn relationship(&self) -> &RelationshipKind {
+        &self.over
+    }
+
+    /// What `twin` contributes
+    pub(crate) fn contribution(&self, twin: &Twin) -> Option<f64> {
+        match &self.input {
+            AggregateInput::Property(property) => twin
+                .quantity(property)
+                .map(|q| q.value())
+                .or_else(|| twin.state().properties.get(property)?.as_f64()),
+            AggregateInput::Alerting => {
+                Some(f64::from(u8::from(twin.alerts().active().next().is_some())))
+            }
+        }
+    }
+}
+
+/// The contributions to one aggregate, kept so it updates incrementally
+#[derive(Debug, Clone)]
+pub(crate) struct AggregateState {
+    property: AggregateProperty,
+    values: HashMap<TwinId, f64>,
+    sum: f64,
+    ordered: BTreeMap<OrderedFloat<f64>, usize>,
+    /// The value last recorded on the parent
+    published: Option<f64>,
+}
+
+impl AggregateState {
+    pub(crate) fn new(property: AggregateProperty, published: Option<f64>) -> Self {
+        Self {
+            property,
+            values: HashMap::new(),
+            sum: 0.0,
+            ordered: BTreeMap::new(),
+            published,
+        }
+    }
+
+    pub(crate) fn property(&self) -> &AggregateProperty {
+        &self.property
+    }
+
+    /// Set or, with `None`, remove the contribution of `child`
+    pub(crate) fn set(&mut self, child: TwinId, value: Option<f64>) {
+        if let Some(old) = self.values.remove(&child) {
+            self.sum -= old;
+            if let Some(count) = self.ordered.get_mut(&OrderedFloat(old)) {
+                *count -= 1;
+                if *count == 0 {
+                    self.ordered.remove(&OrderedFloat(old));
+                }
+            }
+        }
+        if let Some(value) = value {
+            self.values.insert(child, value);
+            self.sum += value;
+            *self.ordered.entry(OrderedFloat(value)).or_default() += 1;
+        }
+    }
+
+    /// The aggregate over the current contributions
+    pub(crate) fn value(&self) -> Option<f64> {
+        #[allow(clippy::cast_precision_loss)]
+        let count = self.values.len() as f64;
+        match self.property.aggregation {
+            Aggregation::Count => Some(count),
+            Aggregation::Sum => Some(self.sum),
+            Aggregation::Mean if self.values.is_empty() => None,
+            Aggregation::Mean => Some(self.sum / count),
+            Aggregation::Min => self.ordered.keys().next().map(|v| v.0),
+            Aggregation::Max => self.ordered.keys().next_back().map(|v| v.0),
+        }
+    }
+
+    /// The value to record on the parent, if it changed since last time:
+    /// nil once nothing contributes
+    pub(crate) fn publish(&mut self) -> Option<Value> {
+        let value = self.value();
+        if self.published == value {
+            return None;
+        }
+        self.published = value;
+        Some(value.map_or(Value::Nil, Value::from))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_incremental_updates() {
+        let [a, b] = [(); 2].map(|()| TwinId::new());
+        let mut max = AggregateState::new(AggregateProperty::max("max", "t"), None);
+        let mut mean = AggregateState::new(AggregateProperty::mean("mean", "t"), None);
+        assert_eq!(max.value(), None);
+
+        for state in [&mut max, &mut mean] {
+            state.set(a, Some(20.0));
+            state.set(b, Some(30.0));
+        }
+        assert_eq!(max.publish(), Some(Value::from(30.0)));
+        assert_eq!(max.publish(), None);
+        assert_eq!(mean.value(), Some(25.0));
+
+        max.set(b, Some(10.0));
+        mean.set(b, None);
+        assert_eq!(max.publish(), Some(Value::from(20.0)));
+        assert_eq!(mean.value(), Some(20.0));
+
+        assert_eq!(mean.publish(), Some(Value::from(20.0)));
+        mean.set(a, None);
+        assert_eq!(mean.publish(), Some(Value::Nil));
+        assert_eq!(mean.publish(), None);
+    }
+}
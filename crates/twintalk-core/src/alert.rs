@@ -0,0 +1,377 @@
+//! Declarative alert rules
+//!
+//! Rules compare a property with a threshold every time telemetry updates
+//! it. A rule raises its alert once the condition has held for its duration,
+//! and clears it once the value is back past the threshold by more than the
+//! hysteresis, so a value hovering around the threshold does not flap:
+//!
+//! ```
+//! use std::time::Duration;
+//! use twintalk_core::alert::{AlertRule, Severity};
+//!
+//! // Above 80 for 2 minutes, cleared below 75
+//! let rule = AlertRule::above("overheat", "temperature", 80.0)
+//!     .with_hysteresis(5.0)
+//!     .held_for(Duration::from_secs(120))
+//!     .with_severity(Severity::Critical);
+//! ```
+//!
+//! Comparisons are made in the property's unit, from its metadata or its
+//! quantity value. Thresholds given as quantities are converted to it, and
+//! a rule whose threshold cannot be converted is not evaluated.
+
+use crate::event::TwinEvent;
+use crate::property::PropertyMetadata;
+use crate::quantity::{self, Quantity};
+use crate::twin::TwinId;
+use crate::value::Value;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::time::Duration;
+
+/// How urgent an alert is
+#[derive(
+    Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize,
+)]
+pub enum Severity {
+    Info,
+    #[default]
+    Warning,
+    Critical,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Info => write!(f, "info"),
+            Self::Warning => write!(f, "warning"),
+            Self::Critical => write!(f, "critical"),
+        }
+    }
+}
+
+/// The value a rule compares against
+#[derive(Debug, Clone, PartialEq)]
+pub enum Threshold {
+    /// A fixed value, in the unit of the property
+    Fixed(f64),
+    /// A fixed value with its own unit
+    Quantity(Quantity),
+    /// The value of another property of the twin, or `default` if unset
+    Property { name: String, default: f64 },
+}
+
+impl Threshold {
+    /// Read the threshold from a property, falling back to `default`
+    pub fn property(name: impl Into<String>, default: f64) -> Self {
+        Self::Property {
+            name: name.into(),
+            default,
+        }
+    }
+
+    /// The threshold in `unit`, or `None` if it cannot be converted
+    fn resolve(&self, properties: &BTreeMap<String, Value>, unit: Option<&str>) -> Option<f64> {
+        match self {
+            Self::Fixed(value) => Some(*value),
+            Self::Quantity(q) => quantity::magnitude(&Value::Quantity(q.clone()), unit),
+            Self::Property { name, default } => match properties.get(name) {
+                Some(value @ Value::Quantity(_)) => quantity::magnitude(value, unit),
+                value => Some(value.and_then(Value::as_f64).unwrap_or(*default)),
+            },
+        }
+    }
+}
+
+impl From<f64> for Threshold {
+    fn from(value: f64) -> Self {
+        Self::Fixed(value)
+    }
+}
+
+impl From<Quantity> for Threshold {
+    fn from(value: Quantity) -> Self {
+        Self::Quantity(value)
+    }
+}
+
+/// Which side of the threshold raises the alert
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Above,
+    Below,
+}
+
+/// A named condition on one property
+#[derive(Debug, Clone, PartialEq)]
+pub struct AlertRule {
+    /// Unique per twin; rules of a twin override class rules of the same name
+    pub name: String,
+    pub property: String,
+    pub direction: Direction,
+    pub threshold: Threshold,
+    /// How far back past the threshold the value must go to clear
+    pub hysteresis: f64,
+    /// How long the condition must hold before the alert is raised
+    pub duration: Duration,
+    pub severity: Severity,
+}
+
+impl AlertRule {
+    /// Alert while `property` is above `threshold`
+    pub fn above(
+        name: impl Into<String>,
+        property: impl Into<String>,
+        threshold: impl Into<Threshold>,
+    ) -> Self {
+        Self::new(name, property, Direction::Above, threshold.into())
+    }
+
+    /// Alert while `property` is below `threshold`
+    pub fn below(
+        name: impl Into<String>,
+        property: impl Into<String>,
+        threshold: impl Into<Threshold>,
+    ) -> Self {
+        Self::new(name, property, Direction::Below, threshold.into())
+    }
+
+    fn new(
+        name: impl Into<String>,
+        property: impl Into<String>,
+        direction: Direction,
+        threshold: Threshold,
+    ) -> Self {
+        Self {
+            name: name.into(),
+            property: property.into(),
+            direction,
+            threshold,
+            hysteresis: 0.0,
+            duration: Duration::ZERO,
+            severity: Severity::default(),
+        }
+    }
+
+    /// The check `checkAlert` made before rules existed, used for twins
+    /// without rules: `temperature` above the `threshold` property, 30 if unset
+    pub fn legacy() -> Self {
+        Self::above(
+            "alert",
+            "temperature",
+            Threshold::property("threshold", 30.0),
+        )
+    }
+
+    /// Clear only once the value is back past the threshold by `hysteresis`
+    #[must_use]
+    pub fn with_hysteresis(mut self, hysteresis: f64) -> Self {
+        self.hysteresis = hysteresis.abs();
+        self
+    }
+
+    /// Raise only once the condition has held for `duration`
+    #[must_use]
+    pub fn held_for(mut self, duration: Duration) -> Self {
+        self.duration = duration;
+        self
+    }
+
+    /// Raise with this severity instead of `Warning`
+    #[must_use]
+    pub fn with_severity(mut self, severity: Severity) -> Self {
+        self.severity = severity;
+        self
+    }
+
+    /// Whether the condition holds for the twin's current properties, with
+    /// plain numbers in `unit`, or `None` if the property has no numeric
+    /// value or the threshold is in an incompatible unit
+    pub fn holds(&self, properties: &BTreeMap<String, Value>, unit: Option<&str>) -> Option<bool> {
+        let value = properties.get(&self.property)?;
+        let unit = unit.or_else(|| value.as_quantity().map(Quantity::unit));
+        let value = quantity::magnitude(value, unit)?;
+        Some(self.breached(value, self.threshold.resolve(properties, unit)?))
+    }
+
+    /// Whether `value` is past the threshold
+    fn breached(&self, value: f64, threshold: f64) -> bool {
+        match self.direction {
+            Direction::Above => value > threshold,
+            Direction::Below => value < threshold,
+        }
+    }
+
+    /// Whether `value` is far enough back past the threshold to clear
+    fn clears(&self, value: f64, threshold: f64) -> bool {
+        match self.direction {
+            Direction::Above => value < threshold - self.hysteresis,
+            Direction::Below => value > threshold + self.hysteresis,
+        }
+    }
+}
+
+/// Where a twin stands on one rule
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct AlertState {
+    /// Whether the alert is raised
+    pub active: bool,
+    /// When the condition started holding, while waiting out the duration
+    pub pending_since: Option<DateTime<Utc>>,
+}
+
+/// The rules of a twin and its state on each
+#[derive(Debug, Clone, Default)]
+pub struct TwinAlerts {
+    rules: Vec<AlertRule>,
+    states: BTreeMap<String, AlertState>,
+}
+
+impl TwinAlerts {
+    /// The configured rules
+    pub fn rules(&self) -> &[AlertRule] {
+        &self.rules
+    }
+
+    /// Replace the rules, keeping the state of those still present
+    pub fn set_rules(&mut self, rules: Vec<AlertRule>) {
+        self.states
+            .retain(|name, _| rules.iter().any(|rule| &rule.name == name));
+        self.rules = rules;
+    }
+
+    /// State on the named rule
+    pub fn state(&self, rule: &str) -> AlertState {
+        self.states.get(rule).copied().unwrap_or_default()
+    }
+
+    /// Names of the raised alerts
+    pub fn active(&self) -> impl Iterator<Item = &str> {
+        self.states
+            .iter()
+            .filter(|(_, state)| state.active)
+            .map(|(name, _)| name.as_str())
+    }
+
+    /// Mark an alert raised or cleared, as when replaying its events.
+    /// Alerts of rules no longer configured are ignored.
+    pub(crate) fn set_active(&mut self, rule: &str, active: bool) {
+        if self.rules.iter().any(|r| r.name == rule) {
+            self.states.insert(
+                rule.to_string(),
+                AlertState {
+                    active,
+                    pending_since: None,
+                },
+            );
+        }
+    }
+
+    /// Evaluate the rules on the properties telemetry just updated, which
+    /// are in the units of their metadata, returning the alerts raised and
+    /// cleared as events
+    pub(crate) fn evaluate(
+        &mut self,
+        twin_id: TwinId,
+        properties: &BTreeMap<String, Value>,
+        metadata: &BTreeMap<String, PropertyMetadata>,
+        updated: &[(String, f64)],
+        timestamp: DateTime<Utc>,
+    ) -> Vec<TwinEvent> {
+        let mut events = Vec::new();
+        for rule in &self.rules {
+            let Some(value) = updated
+                .iter()
+                .rev()
+                .find(|(name, _)| *name == rule.property)
+                .map(|(_, value)| *value)
+            else {
+                continue;
+            };
+            let unit = metadata.get(&rule.property).and_then(|m| m.unit.as_deref());
+            let Some(threshold) = rule.threshold.resolve(properties, unit) else {
+                continue;
+            };
+            let state = self.states.entry(rule.name.clone()).or_default();
+
+            if state.active {
+                if rule.clears(value, threshold) {
+                    *state = AlertState::default();
+                    events.push(TwinEvent::AlertCleared {
+                        twin_id,
+                        rule: rule.name.clone(),
+                        property: rule.property.clone(),
+                        value,
+                        timestamp,
+                    });
+                }
+            } else if rule.breached(value, threshold) {
+                let since = *state.pending_since.get_or_insert(timestamp);
+                let held = (timestamp - since).to_std().unwrap_or_default();
+                if held >= rule.duration {
+                    *state = AlertState {
+                        active: true,
+                        pending_since: None,
+                    };
+                    events.push(TwinEvent::AlertRaised {
+                        twin_id,
+                        rule: rule.name.clone(),
+                        severity: rule.severity,
+                        property: rule.property.clone(),
+                        value,
+                        threshold,
+                        timestamp,
+                    });
+                }
+            } else {
+                state.pending_since = None;
+            }
+        }
+        events
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample(alerts: &mut TwinAlerts, value: f64, seconds: i64) -> Vec<&'static str> {
+        let start = DateTime::<Utc>::UNIX_EPOCH;
+        let data = [("temperature".to_string(), value)];
+        let properties = BTreeMap::from([("temperature".to_string(), Value::from(value))]);
+        alerts
+            .evaluate(
+                TwinId::new(),
+                &properties,
+                &BTreeMap::new(),
+                &data,
+                start + chrono::Duration::seconds(seconds),
+            )
+            .iter()
+            .map(TwinEvent::kind)
+            .collect()
+    }
+
+    #[test]
+    fn test_duration_and_hysteresis() {
+        let mut alerts = TwinAlerts::default();
+        alerts.set_rules(vec![AlertRule::above("hot", "temperature", 80.0)
+            .with_hysteresis(5.0)
+            .held_for(Duration::from_secs(120))]);
+
+        assert!(sample(&mut alerts, 85.0, 0).is_empty());
+        // Dipping below the threshold restarts the duration
+        assert!(sample(&mut alerts, 79.0, 60).is_empty());
+        assert!(sample(&mut alerts, 85.0, 90).is_empty());
+        assert!(sample(&mut alerts, 85.0, 180).is_empty());
+        assert_eq!(sample(&mut alerts, 86.0, 210), ["AlertRaised"]);
+        assert_eq!(alerts.active().collect::<Vec<_>>(), ["hot"]);
+
+        // Within the hysteresis band the alert stays raised
+        assert!(sample(&mut alerts, 77.0, 240).is_empty());
+        assert_eq!(sample(&mut alerts, 74.0, 270), ["AlertCleared"]);
+        assert_eq!(alerts.active().count(), 0);
+    }
+}
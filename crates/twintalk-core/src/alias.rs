@@ -0,0 +1,83 @@
+//! External names for twins
+//!
+//! Devices identify themselves by serial numbers or client ids rather than
+//! `TwinId`s. An alias maps such a name, unique within its namespace, to a
+//! twin. Aliases are recorded as `AliasAssigned` and `AliasRemoved` events
+//! on the twin, kept in its state so snapshots carry them, and indexed by
+//! the `Runtime` for lookup.
+
+use crate::twin::TwinId;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+
+/// A name of a twin within a namespace, such as `serial/SN-1042`
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct Alias {
+    pub namespace: String,
+    pub name: String,
+}
+
+impl Alias {
+    pub fn new(namespace: impl Into<String>, name: impl Into<String>) -> Self {
+        Self {
+            namespace: namespace.into(),
+            name: name.into(),
+        }
+    }
+}
+
+impl fmt::Display for Alias {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.namespace, self.name)
+    }
+}
+
+/// The aliases of all twins
+#[derive(Debug, Clone, Default)]
+pub struct AliasRegistry {
+    twins: HashMap<Alias, TwinId>,
+}
+
+impl AliasRegistry {
+    /// The twin named `alias`
+    pub fn lookup(&self, alias: &Alias) -> Option<TwinId> {
+        self.twins.get(alias).copied()
+    }
+
+    /// Name `twin` by `alias`, replacing the twin it named
+    pub fn assign(&mut self, alias: Alias, twin: TwinId) {
+        self.twins.insert(alias, twin);
+    }
+
+    /// Remove `alias`, returning the twin it named
+    pub fn remove(&mut self, alias: &Alias) -> Option<TwinId> {
+        self.twins.remove(alias)
+    }
+
+    /// Remove the aliases of `twin`
+    pub fn remove_twin(&mut self, twin: TwinId) {
+        self.twins.retain(|_, named| *named != twin);
+    }
+
+    /// The aliases of `twin`, in order
+    pub fn aliases_of(&self, twin: TwinId) -> Vec<Alias> {
+        let mut aliases: Vec<Alias> = self
+            .twins
+            .iter()
+            .filter(|(_, named)| **named == twin)
+            .map(|(alias, _)| alias.clone())
+            .collect();
+        aliases.sort();
+        aliases
+    }
+
+    /// Number of aliases
+    pub fn len(&self) -> usize {
+        self.twins.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.twins.is_empty()
+    }
+}
@@ -0,0 +1,159 @@
+//! Computed properties
+//!
+//! A computed property is a formula over other numeric properties, declared
+//! per class and recalculated whenever one of its inputs changes:
+//!
+//! ```
+//! use twintalk_core::computed::ComputedProperty;
+//!
+//! let power = ComputedProperty::new("power", ["voltage", "current"], |v| v[0] * v[1]);
+//! ```
+//!
+//! Computed values read like any other property but are not part of the
+//! twin's persisted state, since they can be recalculated from it. A property
+//! marked `persisted` is stored like a regular one instead, so snapshots and
+//! change events carry it.
+
+use crate::value::Value;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::Arc;
+
+type Formula = Arc<dyn Fn(&[f64]) -> f64 + Send + Sync>;
+
+/// A property calculated from others
+#[derive(Clone)]
+pub struct ComputedProperty {
+    name: String,
+    inputs: Vec<String>,
+    formula: Formula,
+    persisted: bool,
+}
+
+impl ComputedProperty {
+    /// Compute `name` by passing the values of `inputs`, in order, to
+    /// `formula`. The property is unset while any input is not numeric.
+    pub fn new<I, S>(
+        name: impl Into<String>,
+        inputs: I,
+        formula: impl Fn(&[f64]) -> f64 + Send + Sync + 'static,
+    ) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        Self {
+            name: name.into(),
+            inputs: inputs.into_iter().map(Into::into).collect(),
+            formula: Arc::new(formula),
+            persisted: false,
+        }
+    }
+
+    /// Store the value with the twin's properties, so it is persisted
+    #[must_use]
+    pub fn persisted(mut self) -> Self {
+        self.persisted = true;
+        self
+    }
+
+    /// The property name
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The properties the value is computed from
+    pub fn inputs(&self) -> &[String] {
+        &self.inputs
+    }
+
+    /// Whether the value is stored with the twin's properties
+    pub fn is_persisted(&self) -> bool {
+        self.persisted
+    }
+
+    /// Compute the value, reading inputs with `lookup`
+    pub fn compute<'a>(&self, lookup: impl Fn(&str) -> Option<&'a Value>) -> Option<f64> {
+        let values: Option<Vec<f64>> = self
+            .inputs
+            .iter()
+            .map(|input| lookup(input).and_then(Value::as_f64))
+            .collect();
+        values.map(|values| (self.formula)(&values))
+    }
+}
+
+impl fmt::Debug for ComputedProperty {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ComputedProperty")
+            .field("name", &self.name)
+            .field("inputs", &self.inputs)
+            .field("persisted", &self.persisted)
+            .finish_non_exhaustive()
+    }
+}
+
+/// The computed properties of a twin and their current values
+#[derive(Debug, Clone, Default)]
+pub(crate) struct ComputedValues {
+    properties: Vec<ComputedProperty>,
+    values: BTreeMap<String, Value>,
+}
+
+impl ComputedValues {
+    pub(crate) fn properties(&self) -> &[ComputedProperty] {
+        &self.properties
+    }
+
+    /// Values of the computed properties that are not persisted
+    pub(crate) fn values(&self) -> &BTreeMap<String, Value> {
+        &self.values
+    }
+
+    pub(crate) fn is_computed(&self, name: &str) -> bool {
+        self.properties.iter().any(|p| p.name == name)
+    }
+
+    /// Replace the declarations and recompute every value
+    pub(crate) fn set_properties(
+        &mut self,
+        properties: Vec<ComputedProperty>,
+        stored: &mut BTreeMap<String, Value>,
+    ) {
+        self.properties = properties;
+        self.values.clear();
+        self.recompute(stored, None);
+    }
+
+    /// Recompute the properties depending on `changed`, or all of them.
+    /// Properties are computed in declaration order, so one may use
+    /// another declared before it.
+    pub(crate) fn recompute(
+        &mut self,
+        stored: &mut BTreeMap<String, Value>,
+        changed: Option<&[&str]>,
+    ) {
+        let mut changed: Option<Vec<String>> =
+            changed.map(|names| names.iter().map(ToString::to_string).collect());
+        for property in &self.properties {
+            if let Some(changed) = &changed {
+                if !property.inputs.iter().any(|input| changed.contains(input)) {
+                    continue;
+                }
+            }
+            let value = property.compute(|name| stored.get(name).or_else(|| self.values.get(name)));
+            let target = if property.persisted {
+                &mut *stored
+            } else {
+                &mut self.values
+            };
+            match value {
+                Some(value) => target.insert(property.name.clone(), Value::from(value)),
+                None => target.remove(&property.name),
+            };
+            if let Some(changed) = &mut changed {
+                changed.push(property.name.clone());
+            }
+        }
+    }
+}
@@ -2,12 +2,19 @@
 //!
 //! All twin state changes are recorded as events for replay and audit.
 
+use crate::alert::Severity;
+use crate::alias::Alias;
+use crate::property::PropertyMetadata;
+use crate::relationship::{Relationship, RelationshipKind};
 use crate::twin::TwinId;
 use crate::value::Value;
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
 use std::fmt;
+use std::future::Future;
+use uuid::Uuid;
 
 /// Events that can happen to a twin
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -57,6 +64,83 @@ pub enum TwinEvent {
         twin_id: TwinId,
         timestamp: DateTime<Utc>,
     },
+
+    /// An alert rule's condition was met
+    AlertRaised {
+        twin_id: TwinId,
+        rule: String,
+        severity: Severity,
+        property: String,
+        value: f64,
+        threshold: f64,
+        timestamp: DateTime<Utc>,
+    },
+
+    /// A raised alert's property went back past its threshold
+    AlertCleared {
+        twin_id: TwinId,
+        rule: String,
+        property: String,
+        value: f64,
+        timestamp: DateTime<Utc>,
+    },
+
+    /// The twin was related to another
+    RelationshipAdded {
+        twin_id: TwinId,
+        kind: RelationshipKind,
+        target: TwinId,
+        timestamp: DateTime<Utc>,
+    },
+
+    /// A relationship of the twin was removed
+    RelationshipRemoved {
+        twin_id: TwinId,
+        kind: RelationshipKind,
+        target: TwinId,
+        timestamp: DateTime<Utc>,
+    },
+
+    /// The twin was given an external name
+    AliasAssigned {
+        twin_id: TwinId,
+        alias: Alias,
+        timestamp: DateTime<Utc>,
+    },
+
+    /// An external name of the twin was removed
+    AliasRemoved {
+        twin_id: TwinId,
+        alias: Alias,
+        timestamp: DateTime<Utc>,
+    },
+
+    /// A label of the twin was set
+    LabelSet {
+        twin_id: TwinId,
+        key: String,
+        value: String,
+        timestamp: DateTime<Utc>,
+    },
+
+    /// A label of the twin was removed
+    LabelRemoved {
+        twin_id: TwinId,
+        key: String,
+        timestamp: DateTime<Utc>,
+    },
+
+    /// The state machine of the twin took a transition
+    StateChanged {
+        twin_id: TwinId,
+        /// The property holding the state
+        property: String,
+        from: String,
+        to: String,
+        /// The selector of the message that fired it, or `telemetry`
+        trigger: String,
+        timestamp: DateTime<Utc>,
+    },
 }
 
 impl TwinEvent {
@@ -68,7 +152,16 @@ impl TwinEvent {
             | Self::TelemetryReceived { twin_id, .. }
             | Self::MessageSent { twin_id, .. }
             | Self::Cloned { twin_id, .. }
-            | Self::Destroyed { twin_id, .. } => *twin_id,
+            | Self::Destroyed { twin_id, .. }
+            | Self::AlertRaised { twin_id, .. }
+            | Self::AlertCleared { twin_id, .. }
+            | Self::RelationshipAdded { twin_id, .. }
+            | Self::RelationshipRemoved { twin_id, .. }
+            | Self::AliasAssigned { twin_id, .. }
+            | Self::AliasRemoved { twin_id, .. }
+            | Self::LabelSet { twin_id, .. }
+            | Self::LabelRemoved { twin_id, .. }
+            | Self::StateChanged { twin_id, .. } => *twin_id,
         }
     }
 
@@ -80,33 +173,56 @@ impl TwinEvent {
             | Self::TelemetryReceived { timestamp, .. }
             | Self::MessageSent { timestamp, .. }
             | Self::Cloned { timestamp, .. }
-            | Self::Destroyed { timestamp, .. } => *timestamp,
+            | Self::Destroyed { timestamp, .. }
+            | Self::AlertRaised { timestamp, .. }
+            | Self::AlertCleared { timestamp, .. }
+            | Self::RelationshipAdded { timestamp, .. }
+            | Self::RelationshipRemoved { timestamp, .. }
+            | Self::AliasAssigned { timestamp, .. }
+            | Self::AliasRemoved { timestamp, .. }
+            | Self::LabelSet { timestamp, .. }
+            | Self::LabelRemoved { timestamp, .. }
+            | Self::StateChanged { timestamp, .. } => *timestamp,
+        }
+    }
+
+    /// Get the event kind, matching the serialized `type` tag
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Self::Created { .. } => "Created",
+            Self::PropertyChanged { .. } => "PropertyChanged",
+            Self::TelemetryReceived { .. } => "TelemetryReceived",
+            Self::MessageSent { .. } => "MessageSent",
+            Self::Cloned { .. } => "Cloned",
+            Self::Destroyed { .. } => "Destroyed",
+            Self::AlertRaised { .. } => "AlertRaised",
+            Self::AlertCleared { .. } => "AlertCleared",
+            Self::RelationshipAdded { .. } => "RelationshipAdded",
+            Self::RelationshipRemoved { .. } => "RelationshipRemoved",
+            Self::AliasAssigned { .. } => "AliasAssigned",
+            Self::AliasRemoved { .. } => "AliasRemoved",
+            Self::LabelSet { .. } => "LabelSet",
+            Self::LabelRemoved { .. } => "LabelRemoved",
+            Self::StateChanged { .. } => "StateChanged",
         }
     }
 }
 
 impl fmt::Display for TwinEvent {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (twin_id, timestamp) = (self.twin_id(), self.timestamp());
         match self {
-            Self::Created {
-                twin_id,
-                class_name,
-                timestamp,
-            } => {
+            Self::Created { class_name, .. } => {
                 write!(f, "[{timestamp}] Created {twin_id} ({class_name})")
             }
             Self::PropertyChanged {
-                twin_id,
                 property,
                 new_value,
-                timestamp,
                 ..
-            } => {
-                write!(
-                    f,
-                    "[{timestamp}] {twin_id} property '{property}' = {new_value}"
-                )
-            }
+            } => write!(
+                f,
+                "[{timestamp}] {twin_id} property '{property}' = {new_value}"
+            ),
             Self::TelemetryReceived {
                 twin_id,
                 data,
@@ -136,43 +252,404 @@ impl fmt::Display for TwinEvent {
             Self::Destroyed { twin_id, timestamp } => {
                 write!(f, "[{timestamp}] {twin_id} destroyed")
             }
+            Self::AlertRaised {
+                twin_id,
+                rule,
+                severity,
+                property,
+                value,
+                timestamp,
+                ..
+            } => {
+                write!(
+                    f,
+                    "[{timestamp}] {twin_id} raised {severity} alert '{rule}' ({property} = {value})"
+                )
+            }
+            Self::AlertCleared {
+                twin_id,
+                rule,
+                property,
+                value,
+                timestamp,
+            } => {
+                write!(
+                    f,
+                    "[{timestamp}] {twin_id} cleared alert '{rule}' ({property} = {value})"
+                )
+            }
+            Self::RelationshipAdded { kind, target, .. } => {
+                write!(f, "[{timestamp}] {twin_id} {kind} {target}")
+            }
+            Self::RelationshipRemoved { kind, target, .. } => {
+                write!(f, "[{timestamp}] {twin_id} no longer {kind} {target}")
+            }
+            Self::AliasAssigned { alias, .. } => write!(f, "[{timestamp}] {twin_id} named {alias}"),
+            Self::AliasRemoved { alias, .. } => {
+                write!(f, "[{timestamp}] {twin_id} no longer named {alias}")
+            }
+            Self::LabelSet { key, value, .. } => {
+                write!(f, "[{timestamp}] {twin_id} labelled {key}={value}")
+            }
+            Self::LabelRemoved { key, .. } => {
+                write!(f, "[{timestamp}] {twin_id} no longer labelled {key}")
+            }
+            Self::StateChanged {
+                property,
+                from,
+                to,
+                trigger,
+                ..
+            } => write!(
+                f,
+                "[{timestamp}] {twin_id} {property}: {from} -> {to} on {trigger}"
+            ),
+        }
+    }
+}
+
+/// Who and what caused an event
+///
+/// Events recorded before metadata existed decode with nil ids.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct EventMetadata {
+    /// Unique id of this event
+    pub event_id: Uuid,
+
+    /// Shared by every event resulting from the same original request
+    pub correlation_id: Uuid,
+
+    /// Id of the event that directly caused this one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub causation_id: Option<Uuid>,
+
+    /// Caller on whose behalf the event was recorded
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub principal: Option<String>,
+
+    /// Free-form headers, e.g. an upstream message id
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub headers: BTreeMap<String, String>,
+}
+
+impl EventMetadata {
+    /// Metadata for an event that starts a new correlation
+    pub fn new() -> Self {
+        let event_id = Uuid::new_v4();
+        Self {
+            event_id,
+            correlation_id: event_id,
+            ..Self::default()
+        }
+    }
+}
+
+/// An event together with its metadata, as stored in the log
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EventEnvelope {
+    pub metadata: EventMetadata,
+    pub event: TwinEvent,
+}
+
+impl EventEnvelope {
+    /// Wrap an event with fresh metadata
+    pub fn new(event: TwinEvent) -> Self {
+        Self {
+            metadata: EventMetadata::new(),
+            event,
+        }
+    }
+}
+
+tokio::task_local! {
+    static CURRENT_CONTEXT: EventContext;
+}
+
+/// Metadata propagated to every event recorded within a scope
+///
+/// `Runtime` stamps events with the context of the task recording them, and
+/// runs the effects of a message inside a child context caused by that
+/// message, so nested sends share one correlation id. The context is
+/// task-local and does not follow `tokio::spawn`.
+#[derive(Debug, Clone, Default)]
+pub struct EventContext {
+    pub correlation_id: Option<Uuid>,
+    pub causation_id: Option<Uuid>,
+    pub principal: Option<String>,
+    pub headers: BTreeMap<String, String>,
+}
+
+impl EventContext {
+    /// Create a context with a new correlation id
+    pub fn new() -> Self {
+        Self {
+            correlation_id: Some(Uuid::new_v4()),
+            ..Self::default()
+        }
+    }
+
+    /// Set the principal
+    #[must_use]
+    pub fn with_principal(mut self, principal: impl Into<String>) -> Self {
+        self.principal = Some(principal.into());
+        self
+    }
+
+    /// Join an existing correlation, e.g. one received from an upstream system
+    #[must_use]
+    pub fn with_correlation_id(mut self, correlation_id: Uuid) -> Self {
+        self.correlation_id = Some(correlation_id);
+        self
+    }
+
+    /// Add a header
+    #[must_use]
+    pub fn with_header(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.headers.insert(name.into(), value.into());
+        self
+    }
+
+    /// The context of the current task, if any
+    pub fn current() -> Option<Self> {
+        CURRENT_CONTEXT.try_with(Clone::clone).ok()
+    }
+
+    /// Run `f` with this context as the current one
+    pub async fn scope<F: Future>(self, f: F) -> F::Output {
+        CURRENT_CONTEXT.scope(self, f).await
+    }
+
+    /// Child context for events caused by the event with `metadata`
+    #[must_use]
+    pub fn caused_by(&self, metadata: &EventMetadata) -> Self {
+        Self {
+            correlation_id: Some(metadata.correlation_id),
+            causation_id: Some(metadata.event_id),
+            ..self.clone()
+        }
+    }
+
+    /// Metadata for a new event recorded in this context
+    pub fn metadata(&self) -> EventMetadata {
+        let event_id = Uuid::new_v4();
+        EventMetadata {
+            event_id,
+            correlation_id: self.correlation_id.unwrap_or(event_id),
+            causation_id: self.causation_id,
+            principal: self.principal.clone(),
+            headers: self.headers.clone(),
         }
     }
 }
 
 /// Event store trait for different storage backends
+///
+/// Backends store `EventEnvelope`s; the plain event methods are provided in
+/// terms of them.
 #[async_trait::async_trait]
 pub trait EventStore: Send + Sync {
-    /// Append an event to the store
-    async fn append(&self, event: TwinEvent) -> Result<u64>;
+    /// Append an event to the store with fresh metadata
+    async fn append(&self, event: TwinEvent) -> Result<u64> {
+        self.append_envelope(EventEnvelope::new(event)).await
+    }
+
+    /// Append several events with fresh metadata, returning their versions
+    /// in input order
+    async fn append_batch(&self, events: Vec<TwinEvent>) -> Result<Vec<u64>> {
+        self.append_envelopes(events.into_iter().map(EventEnvelope::new).collect())
+            .await
+    }
+
+    /// Append an event with its metadata
+    async fn append_envelope(&self, envelope: EventEnvelope) -> Result<u64>;
+
+    /// Append several events with their metadata, returning their versions
+    /// in input order
+    ///
+    /// Backends override this to write the batch with a single flush. The
+    /// default appends one event at a time.
+    async fn append_envelopes(&self, envelopes: Vec<EventEnvelope>) -> Result<Vec<u64>> {
+        let mut versions = Vec::with_capacity(envelopes.len());
+        for envelope in envelopes {
+            versions.push(self.append_envelope(envelope).await?);
+        }
+        Ok(versions)
+    }
+
+    /// Append events at the versions they had in another store, e.g. when
+    /// restoring a backup. Versions must ascend and exceed the latest
+    /// version; gaps are kept.
+    ///
+    /// The default appends normally, so it only succeeds when the versions
+    /// continue the store's own sequence without gaps.
+    async fn import_envelopes(&self, envelopes: Vec<(u64, EventEnvelope)>) -> Result<()> {
+        check_import_versions(self.get_latest_version().await?, &envelopes)?;
+        for (version, envelope) in envelopes {
+            let assigned = self.append_envelope(envelope).await?;
+            if assigned != version {
+                return Err(anyhow!(
+                    "Imported event {version} was stored as version {assigned}"
+                ));
+            }
+        }
+        Ok(())
+    }
 
     /// Get all events for a twin after a certain version
     async fn get_events(
         &self,
         twin_id: TwinId,
         after_version: u64,
-    ) -> Result<Vec<(u64, TwinEvent)>>;
+    ) -> Result<Vec<(u64, TwinEvent)>> {
+        Ok(strip_metadata(
+            self.get_envelopes(twin_id, after_version).await?,
+        ))
+    }
+
+    /// Get all events for a twin after a certain version, with metadata
+    async fn get_envelopes(
+        &self,
+        twin_id: TwinId,
+        after_version: u64,
+    ) -> Result<Vec<(u64, EventEnvelope)>>;
 
     /// Get all events in a time range
     async fn get_events_in_range(
         &self,
         start: DateTime<Utc>,
         end: DateTime<Utc>,
-    ) -> Result<Vec<(u64, TwinEvent)>>;
+    ) -> Result<Vec<(u64, TwinEvent)>> {
+        Ok(strip_metadata(
+            self.get_envelopes_in_range(start, end).await?,
+        ))
+    }
+
+    /// Get all events in a time range, with metadata
+    async fn get_envelopes_in_range(
+        &self,
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+    ) -> Result<Vec<(u64, EventEnvelope)>>;
+
+    /// Get up to `limit` events of any twin after `after_version`, in
+    /// version order, with metadata. The default reads the whole log;
+    /// backends override it to read only the page.
+    async fn get_envelopes_after(
+        &self,
+        after_version: u64,
+        limit: usize,
+    ) -> Result<Vec<(u64, EventEnvelope)>> {
+        let (start, end) = all_time();
+        let mut envelopes = self.get_envelopes_in_range(start, end).await?;
+        envelopes.retain(|(version, _)| *version > after_version);
+        envelopes.sort_by_key(|(version, _)| *version);
+        envelopes.truncate(limit);
+        Ok(envelopes)
+    }
+
+    /// Get the last `limit` events of a twin before `before_version`, in
+    /// version order, with metadata. The default reads all of the twin's
+    /// events; backends override it to read only the page.
+    async fn get_envelopes_before(
+        &self,
+        twin_id: TwinId,
+        before_version: u64,
+        limit: usize,
+    ) -> Result<Vec<(u64, EventEnvelope)>> {
+        let mut envelopes = self.get_envelopes(twin_id, 0).await?;
+        envelopes.retain(|(version, _)| *version < before_version);
+        let skip = envelopes.len().saturating_sub(limit);
+        Ok(envelopes.split_off(skip))
+    }
 
     /// Get the latest version number
     async fn get_latest_version(&self) -> Result<u64>;
 }
 
+/// Range bounds covering any realistic event. Four-digit years keep them
+/// ordered in backends that compare timestamps as text.
+pub(crate) fn all_time() -> (DateTime<Utc>, DateTime<Utc>) {
+    let end = "9999-12-31T23:59:59Z"
+        .parse()
+        .unwrap_or(DateTime::<Utc>::MAX_UTC);
+    (DateTime::UNIX_EPOCH, end)
+}
+
+/// Check that imported versions ascend and follow `latest`
+pub(crate) fn check_import_versions(latest: u64, envelopes: &[(u64, EventEnvelope)]) -> Result<()> {
+    let mut previous = latest;
+    for (version, _) in envelopes {
+        if *version <= previous {
+            return Err(anyhow!(
+                "Imported version {version} does not follow version {previous}"
+            ));
+        }
+        previous = *version;
+    }
+    Ok(())
+}
+
+fn strip_metadata(envelopes: Vec<(u64, EventEnvelope)>) -> Vec<(u64, TwinEvent)> {
+    envelopes
+        .into_iter()
+        .map(|(version, envelope)| (version, envelope.event))
+        .collect()
+}
+
 /// Snapshot for faster twin reconstruction
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct TwinSnapshot {
     pub twin_id: TwinId,
     pub class_name: String,
-    pub properties: std::collections::BTreeMap<String, Value>,
+    pub properties: BTreeMap<String, Value>,
     pub parent_id: Option<TwinId>,
     pub event_version: u64,
     pub timestamp: DateTime<Utc>,
+    /// Unit, quality and timestamps per property
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub metadata: BTreeMap<String, PropertyMetadata>,
+    /// Outgoing relationships
+    #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+    pub relationships: BTreeSet<Relationship>,
+    /// External names
+    #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+    pub aliases: BTreeSet<Alias>,
+    /// Labels for finding the twin
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub labels: BTreeMap<String, String>,
+    /// Rules whose alerts are raised
+    #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+    pub alerts: BTreeSet<String>,
+}
+
+impl TwinSnapshot {
+    /// A snapshot of a twin of `class_name` at `event_version`, taken now,
+    /// with no properties or other state
+    pub fn new(twin_id: TwinId, class_name: impl Into<String>, event_version: u64) -> Self {
+        Self {
+            twin_id,
+            class_name: class_name.into(),
+            properties: BTreeMap::new(),
+            parent_id: None,
+            event_version,
+            timestamp: Utc::now(),
+            metadata: BTreeMap::new(),
+            relationships: BTreeSet::new(),
+            aliases: BTreeSet::new(),
+            labels: BTreeMap::new(),
+            alerts: BTreeSet::new(),
+        }
+    }
+
+    /// With these properties
+    #[must_use]
+    pub fn with_properties(
+        mut self,
+        properties: impl IntoIterator<Item = (String, Value)>,
+    ) -> Self {
+        self.properties.extend(properties);
+        self
+    }
 }
 
 /// Snapshot store trait
@@ -184,6 +661,30 @@ pub trait SnapshotStore: Send + Sync {
     /// Get the latest snapshot for a twin
     async fn get_snapshot(&self, twin_id: TwinId) -> Result<Option<TwinSnapshot>>;
 
+    /// Get the latest snapshot of every twin; the runtime builds its
+    /// indexes from these and the events after them
+    async fn list_snapshots(&self) -> Result<Vec<TwinSnapshot>>;
+
+    /// Get up to `limit` snapshots in twin id order, starting after the
+    /// twin `after`, to page through the snapshots of a large store
+    ///
+    /// The default lists every snapshot and keeps the page.
+    async fn list_snapshots_after(
+        &self,
+        after: Option<TwinId>,
+        limit: usize,
+    ) -> Result<Vec<TwinSnapshot>> {
+        let mut snapshots: Vec<_> = self
+            .list_snapshots()
+            .await?
+            .into_iter()
+            .filter(|s| after.is_none_or(|after| s.twin_id > after))
+            .collect();
+        snapshots.sort_by_key(|s| s.twin_id);
+        snapshots.truncate(limit);
+        Ok(snapshots)
+    }
+
     /// Delete old snapshots
     async fn cleanup_old_snapshots(&self, before: DateTime<Utc>) -> Result<u64>;
 }
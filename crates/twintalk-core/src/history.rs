@@ -0,0 +1,255 @@
+//! Bounded per-property telemetry history
+//!
+//! Twins keep recent telemetry samples of each property so messages can
+//! aggregate over time windows, e.g. `avg: #temperature over: 300`. History
+//! lives in memory only; the runtime rebuilds it from the event log when a
+//! twin is loaded.
+
+use crate::value::Value;
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Utc};
+use std::collections::{BTreeMap, VecDeque};
+use std::time::Duration;
+
+/// Selectors answered from history
+pub(crate) const SELECTORS: &[&str] = &[
+    "avg:",
+    "avg:over:",
+    "min:",
+    "min:over:",
+    "max:",
+    "max:over:",
+    "rate:",
+    "rate:over:",
+    "last:count:",
+];
+
+/// How much history each twin keeps per property
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HistoryConfig {
+    /// Maximum samples kept per property (0 disables history)
+    pub max_samples: usize,
+
+    /// Samples older than this, relative to the newest one, are dropped
+    pub window: Option<Duration>,
+}
+
+impl Default for HistoryConfig {
+    fn default() -> Self {
+        Self {
+            max_samples: 1000,
+            window: Some(Duration::from_secs(3600)), // 1 hour
+        }
+    }
+}
+
+impl HistoryConfig {
+    /// Keep no history
+    pub fn disabled() -> Self {
+        Self {
+            max_samples: 0,
+            window: None,
+        }
+    }
+
+    /// Whether any samples are kept
+    pub fn is_enabled(&self) -> bool {
+        self.max_samples > 0
+    }
+}
+
+/// Recent samples of one property, oldest first
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct PropertyHistory {
+    samples: VecDeque<(DateTime<Utc>, f64)>,
+}
+
+impl PropertyHistory {
+    /// All samples, oldest first
+    pub fn samples(&self) -> impl Iterator<Item = (DateTime<Utc>, f64)> + '_ {
+        self.samples.iter().copied()
+    }
+
+    /// Number of samples kept
+    pub fn len(&self) -> usize {
+        self.samples.len()
+    }
+
+    /// Whether no samples are kept
+    pub fn is_empty(&self) -> bool {
+        self.samples.is_empty()
+    }
+
+    /// Samples taken at or after `since`
+    pub fn since(&self, since: DateTime<Utc>) -> impl Iterator<Item = (DateTime<Utc>, f64)> + '_ {
+        // Samples arrive in time order, apart from clock skew between
+        // producers, so filter rather than binary search
+        self.samples().filter(move |(time, _)| *time >= since)
+    }
+
+    /// Mean of the samples since `since`
+    #[allow(clippy::cast_precision_loss)]
+    pub fn average(&self, since: DateTime<Utc>) -> Option<f64> {
+        let (sum, count) = self
+            .since(since)
+            .fold((0.0, 0usize), |(sum, count), (_, v)| (sum + v, count + 1));
+        (count > 0).then(|| sum / count as f64)
+    }
+
+    /// Smallest sample since `since`
+    pub fn min(&self, since: DateTime<Utc>) -> Option<f64> {
+        self.since(since).map(|(_, v)| v).reduce(f64::min)
+    }
+
+    /// Largest sample since `since`
+    pub fn max(&self, since: DateTime<Utc>) -> Option<f64> {
+        self.since(since).map(|(_, v)| v).reduce(f64::max)
+    }
+
+    /// Change per second between the first and last sample since `since`
+    #[allow(clippy::cast_precision_loss)]
+    pub fn rate(&self, since: DateTime<Utc>) -> Option<f64> {
+        let mut samples = self.since(since);
+        let (first_time, first) = samples.next()?;
+        let (last_time, last) = samples.last()?;
+        let elapsed = (last_time - first_time).num_microseconds()? as f64 / 1e6;
+        (elapsed > 0.0).then(|| (last - first) / elapsed)
+    }
+
+    /// The newest `count` samples, oldest first
+    pub fn last(&self, count: usize) -> Vec<f64> {
+        let skip = self.samples.len().saturating_sub(count);
+        self.samples.iter().skip(skip).map(|(_, v)| *v).collect()
+    }
+
+    fn push(&mut self, time: DateTime<Utc>, value: f64, config: &HistoryConfig) {
+        self.samples.push_back((time, value));
+        while self.samples.len() > config.max_samples {
+            self.samples.pop_front();
+        }
+        if let Some(cutoff) = config
+            .window
+            .and_then(|window| chrono::Duration::from_std(window).ok())
+            .and_then(|window| time.checked_sub_signed(window))
+        {
+            while self.samples.front().is_some_and(|(t, _)| *t < cutoff) {
+                self.samples.pop_front();
+            }
+        }
+    }
+}
+
+/// History of all properties of a twin
+#[derive(Debug, Clone, Default)]
+pub struct TwinHistory {
+    config: HistoryConfig,
+    properties: BTreeMap<String, PropertyHistory>,
+}
+
+impl TwinHistory {
+    /// Empty history kept according to `config`
+    pub fn new(config: HistoryConfig) -> Self {
+        Self {
+            config,
+            properties: BTreeMap::new(),
+        }
+    }
+
+    /// How much history is kept
+    pub fn config(&self) -> HistoryConfig {
+        self.config
+    }
+
+    /// History of one property
+    pub fn get(&self, property: &str) -> Option<&PropertyHistory> {
+        self.properties.get(property)
+    }
+
+    /// Record telemetry samples taken at `time`
+    pub fn record(&mut self, data: &[(String, f64)], time: DateTime<Utc>) {
+        if !self.config.is_enabled() {
+            return;
+        }
+        for (property, value) in data {
+            self.properties
+                .entry(property.clone())
+                .or_default()
+                .push(time, *value, &self.config);
+        }
+    }
+
+    /// Answer an aggregation message
+    ///
+    /// `avg:`, `min:`, `max:` and `rate:` take a property name, optionally
+    /// followed by `over:` a window in seconds back from now; `last:count:`
+    /// takes a property name and a count. Aggregates of no samples are nil.
+    pub(crate) fn aggregate(&self, selector: &str, args: &[Value]) -> Result<Value> {
+        let property = args
+            .first()
+            .and_then(Value::as_str)
+            .ok_or_else(|| anyhow!("{selector} expects a property name"))?;
+        let history = self.get(property);
+
+        if selector == "last:count:" {
+            let count = args
+                .get(1)
+                .and_then(Value::as_i64)
+                .and_then(|n| usize::try_from(n).ok())
+                .ok_or_else(|| anyhow!("{selector} expects a non-negative count"))?;
+            let values = history.map(|h| h.last(count)).unwrap_or_default();
+            return Ok(Value::Array(values.into_iter().map(Value::from).collect()));
+        }
+
+        let since = match args.get(1) {
+            None => DateTime::<Utc>::MIN_UTC,
+            Some(seconds) => {
+                let window = seconds
+                    .as_f64()
+                    .and_then(|s| Duration::try_from_secs_f64(s).ok())
+                    .ok_or_else(|| anyhow!("{selector} expects a window in seconds"))?;
+                // Windows longer than chrono can represent cover all history
+                chrono::Duration::from_std(window)
+                    .ok()
+                    .and_then(|window| Utc::now().checked_sub_signed(window))
+                    .unwrap_or(DateTime::<Utc>::MIN_UTC)
+            }
+        };
+
+        let Some(history) = history else {
+            return Ok(Value::Nil);
+        };
+        let result = match selector.split(':').next() {
+            Some("avg") => history.average(since),
+            Some("min") => history.min(since),
+            Some("max") => history.max(since),
+            Some("rate") => history.rate(since),
+            _ => return Err(anyhow!("Twin does not understand: {selector}")),
+        };
+        Ok(result.map_or(Value::Nil, Value::from))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bounds() {
+        let config = HistoryConfig {
+            max_samples: 3,
+            window: Some(Duration::from_secs(60)),
+        };
+        let mut history = TwinHistory::new(config);
+        let start = Utc::now();
+        for i in 0..5 {
+            let data = [("t".to_string(), f64::from(i))];
+            history.record(&data, start + chrono::Duration::seconds(i64::from(i)));
+        }
+        assert_eq!(history.get("t").unwrap().last(10), vec![2.0, 3.0, 4.0]);
+
+        // A sample past the window drops the older ones
+        let data = [("t".to_string(), 9.0)];
+        history.record(&data, start + chrono::Duration::seconds(100));
+        assert_eq!(history.get("t").unwrap().last(10), vec![9.0]);
+    }
+}
@@ -0,0 +1,521 @@
+//! Secondary indexes for finding twins
+//!
+//! The `Runtime` indexes every twin, loaded or not, by class, by label and
+//! by the values of the properties chosen with `Runtime::index_property`,
+//! so fleets can be queried without loading them:
+//!
+//! ```
+//! use twintalk_core::index::{Condition, TwinQuery};
+//!
+//! // TemperatureSensors in Berlin with firmware below 2.3
+//! let query = TwinQuery::new()
+//!     .class("TemperatureSensor")
+//!     .label("site", "berlin")
+//!     .property("firmware", Condition::lt(2.3))
+//!     .limit(100);
+//! ```
+//!
+//! Results are ordered by `TwinId`; each page gives the cursor to pass to
+//! `TwinQuery::after` for the next one.
+
+use crate::event::{TwinEvent, TwinSnapshot};
+use crate::quantity;
+use crate::twin::TwinId;
+use crate::value::Value;
+use anyhow::{anyhow, Result};
+use ordered_float::OrderedFloat;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::mem;
+use std::ops::{Bound, RangeBounds};
+
+/// A property value as indexed. Numbers, quantities (by magnitude) and
+/// booleans compare as numbers, strings and symbols as text.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum IndexKey {
+    Number(OrderedFloat<f64>),
+    Text(String),
+}
+
+impl IndexKey {
+    /// The key of `value`, if it can be indexed
+    pub fn of(value: &Value) -> Option<Self> {
+        match value {
+            Value::Boolean(b) => Some(Self::Number(OrderedFloat(f64::from(u8::from(*b))))),
+            Value::String(s) | Value::Symbol(s) => Some(Self::Text(s.clone())),
+            other => quantity::magnitude(other, None).map(|n| Self::Number(OrderedFloat(n))),
+        }
+    }
+}
+
+impl From<f64> for IndexKey {
+    fn from(n: f64) -> Self {
+        Self::Number(OrderedFloat(n))
+    }
+}
+
+impl From<i64> for IndexKey {
+    #[allow(clippy::cast_precision_loss)]
+    fn from(n: i64) -> Self {
+        Self::Number(OrderedFloat(n as f64))
+    }
+}
+
+impl From<bool> for IndexKey {
+    fn from(b: bool) -> Self {
+        Self::Number(OrderedFloat(f64::from(u8::from(b))))
+    }
+}
+
+impl From<&str> for IndexKey {
+    fn from(s: &str) -> Self {
+        Self::Text(s.to_string())
+    }
+}
+
+impl From<String> for IndexKey {
+    fn from(s: String) -> Self {
+        Self::Text(s)
+    }
+}
+
+/// What an indexed property must satisfy. Numbers only match numbers and
+/// text only text.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Condition {
+    Equals(IndexKey),
+    Range(Bound<IndexKey>, Bound<IndexKey>),
+}
+
+impl Condition {
+    pub fn eq(key: impl Into<IndexKey>) -> Self {
+        Self::Equals(key.into())
+    }
+
+    pub fn lt(key: impl Into<IndexKey>) -> Self {
+        Self::Range(Bound::Unbounded, Bound::Excluded(key.into()))
+    }
+
+    pub fn le(key: impl Into<IndexKey>) -> Self {
+        Self::Range(Bound::Unbounded, Bound::Included(key.into()))
+    }
+
+    pub fn gt(key: impl Into<IndexKey>) -> Self {
+        Self::Range(Bound::Excluded(key.into()), Bound::Unbounded)
+    }
+
+    pub fn ge(key: impl Into<IndexKey>) -> Self {
+        Self::Range(Bound::Included(key.into()), Bound::Unbounded)
+    }
+
+    /// From `low` to `high`, inclusive
+    pub fn between(low: impl Into<IndexKey>, high: impl Into<IndexKey>) -> Self {
+        Self::Range(Bound::Included(low.into()), Bound::Included(high.into()))
+    }
+
+    /// Whether `key` satisfies the condition
+    pub fn matches(&self, key: &IndexKey) -> bool {
+        match self {
+            Self::Equals(expected) => key == expected,
+            Self::Range(low, high) => {
+                self.kind()
+                    .is_none_or(|kind| kind == mem::discriminant(key))
+                    && (low.as_ref(), high.as_ref()).contains(key)
+            }
+        }
+    }
+
+    /// The kind of key the condition applies to
+    fn kind(&self) -> Option<mem::Discriminant<IndexKey>> {
+        match self {
+            Self::Equals(key)
+            | Self::Range(Bound::Included(key) | Bound::Excluded(key), _)
+            | Self::Range(_, Bound::Included(key) | Bound::Excluded(key)) => {
+                Some(mem::discriminant(key))
+            }
+            Self::Range(Bound::Unbounded, Bound::Unbounded) => None,
+        }
+    }
+}
+
+/// A search for twins; every filter given must match
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TwinQuery {
+    class: Option<String>,
+    labels: BTreeMap<String, String>,
+    properties: Vec<(String, Condition)>,
+    after: Option<TwinId>,
+    limit: Option<usize>,
+}
+
+impl TwinQuery {
+    /// A query matching every twin
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Only twins of `class_name`
+    #[must_use]
+    pub fn class(mut self, class_name: impl Into<String>) -> Self {
+        self.class = Some(class_name.into());
+        self
+    }
+
+    /// Only twins labelled `key=value`
+    #[must_use]
+    pub fn label(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        self.labels.insert(key.into(), value.into());
+        self
+    }
+
+    /// Only twins whose indexed `property` satisfies `condition`
+    #[must_use]
+    pub fn property(mut self, property: impl Into<String>, condition: Condition) -> Self {
+        self.properties.push((property.into(), condition));
+        self
+    }
+
+    /// Only twins after `cursor`, the `next` of the previous page
+    #[must_use]
+    pub fn after(mut self, cursor: TwinId) -> Self {
+        self.after = Some(cursor);
+        self
+    }
+
+    /// At most `limit` twins per page
+    #[must_use]
+    pub fn limit(mut self, limit: usize) -> Self {
+        self.limit = Some(limit);
+        self
+    }
+}
+
+/// One page of query results
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct QueryPage {
+    pub twins: Vec<TwinId>,
+    /// The cursor for the next page, if there is one
+    pub next: Option<TwinId>,
+}
+
+/// Twins by class, label and indexed property values
+#[derive(Debug, Clone, Default)]
+pub struct SecondaryIndex {
+    indexed: BTreeSet<String>,
+    twins: BTreeSet<TwinId>,
+    classes: HashMap<String, BTreeSet<TwinId>>,
+    class_of: HashMap<TwinId, String>,
+    labels: HashMap<(String, String), BTreeSet<TwinId>>,
+    labels_of: HashMap<TwinId, BTreeMap<String, String>>,
+    values: HashMap<String, BTreeMap<IndexKey, BTreeSet<TwinId>>>,
+    values_of: HashMap<TwinId, BTreeMap<String, Value>>,
+}
+
+impl SecondaryIndex {
+    /// An empty index over the values of `properties`
+    pub fn new(properties: impl IntoIterator<Item = String>) -> Self {
+        Self {
+            indexed: properties.into_iter().collect(),
+            ..Self::default()
+        }
+    }
+
+    /// The class of `twin`, if it is indexed
+    pub fn class_of(&self, twin: TwinId) -> Option<&str> {
+        self.class_of.get(&twin).map(String::as_str)
+    }
+
+    /// The value of the label `key` of `twin`
+    pub fn label_of(&self, twin: TwinId, key: &str) -> Option<&str> {
+        self.labels_of.get(&twin)?.get(key).map(String::as_str)
+    }
+
+    /// The value of the indexed `property` of `twin`
+    pub fn value_of(&self, twin: TwinId, property: &str) -> Option<&Value> {
+        self.values_of.get(&twin)?.get(property)
+    }
+
+    /// Whether the values of `property` are indexed
+    pub fn is_indexed(&self, property: &str) -> bool {
+        self.indexed.contains(property)
+    }
+
+    /// Index the state a snapshot holds
+    pub fn apply_snapshot(&mut self, snapshot: &TwinSnapshot) {
+        let twin = snapshot.twin_id;
+        self.add(twin, &snapshot.class_name);
+        for (key, value) in &snapshot.labels {
+            self.set_label(twin, key, Some(value));
+        }
+        for (property, value) in &snapshot.properties {
+            self.set_value(twin, property, value.clone());
+        }
+    }
+
+    /// Index the change an event makes
+    pub fn apply(&mut self, event: &TwinEvent) {
+        match event {
+            TwinEvent::Created {
+                twin_id,
+                class_name,
+                ..
+            } => self.add(*twin_id, class_name),
+            TwinEvent::PropertyChanged {
+                twin_id,
+                property,
+                new_value,
+                ..
+            } => self.set_value(*twin_id, property, new_value.clone()),
+            TwinEvent::TelemetryReceived { twin_id, data, .. } => {
+                for (property, value) in data {
+                    self.set_value(*twin_id, property, Value::Float((*value).into()));
+                }
+            }
+            TwinEvent::LabelSet {
+                twin_id,
+                key,
+                value,
+                ..
+            } => self.set_label(*twin_id, key, Some(value)),
+            TwinEvent::LabelRemoved { twin_id, key, .. } => self.set_label(*twin_id, key, None),
+            TwinEvent::StateChanged {
+                twin_id,
+                property,
+                to,
+                ..
+            } => self.set_value(*twin_id, property, Value::Symbol(to.clone())),
+            TwinEvent::Destroyed { twin_id, .. } => self.remove(*twin_id),
+            _ => {}
+        }
+    }
+
+    fn add(&mut self, twin: TwinId, class_name: &str) {
+        self.twins.insert(twin);
+        if let Some(old) = self.class_of.insert(twin, class_name.to_string()) {
+            self.classes.remove_twin(&old, twin);
+        }
+        self.classes
+            .entry(class_name.to_string())
+            .or_default()
+            .insert(twin);
+    }
+
+    fn remove(&mut self, twin: TwinId) {
+        self.twins.remove(&twin);
+        if let Some(class_name) = self.class_of.remove(&twin) {
+            self.classes.remove_twin(&class_name, twin);
+        }
+        for (key, value) in self.labels_of.remove(&twin).unwrap_or_default() {
+            self.labels.remove_twin(&(key, value), twin);
+        }
+        for (property, value) in self.values_of.remove(&twin).unwrap_or_default() {
+            if let (Some(values), Some(key)) =
+                (self.values.get_mut(&property), IndexKey::of(&value))
+            {
+                values.remove_twin(&key, twin);
+            }
+        }
+    }
+
+    fn set_label(&mut self, twin: TwinId, key: &str, value: Option<&String>) {
+        let labels = self.labels_of.entry(twin).or_default();
+        let old = match value {
+            Some(value) => labels.insert(key.to_string(), value.clone()),
+            None => labels.remove(key),
+        };
+        if let Some(old) = old {
+            self.labels.remove_twin(&(key.to_string(), old), twin);
+        }
+        if let Some(value) = value {
+            self.labels
+                .entry((key.to_string(), value.clone()))
+                .or_default()
+                .insert(twin);
+        }
+    }
+
+    fn set_value(&mut self, twin: TwinId, property: &str, value: Value) {
+        if !self.indexed.contains(property) {
+            return;
+        }
+        let key = IndexKey::of(&value);
+        let old = self
+            .values_of
+            .entry(twin)
+            .or_default()
+            .insert(property.to_string(), value);
+        let values = self.values.entry(property.to_string()).or_default();
+        if let Some(old) = old.as_ref().and_then(IndexKey::of) {
+            values.remove_twin(&old, twin);
+        }
+        if let Some(key) = key {
+            values.entry(key).or_default().insert(twin);
+        }
+    }
+
+    /// The twins whose `property` satisfies `condition`
+    fn matching(&self, property: &str, condition: &Condition) -> Result<BTreeSet<TwinId>> {
+        if !self.indexed.contains(property) {
+            return Err(anyhow!("Property {property} is not indexed"));
+        }
+        let Some(values) = self.values.get(property) else {
+            return Ok(BTreeSet::new());
+        };
+        let range = match condition {
+            Condition::Equals(key) => (Bound::Included(key), Bound::Included(key)),
+            Condition::Range(low, high) => (low.as_ref(), high.as_ref()),
+        };
+        if is_empty(range) {
+            return Ok(BTreeSet::new());
+        }
+        Ok(values
+            .range::<IndexKey, _>(range)
+            .filter(|(key, _)| condition.matches(key))
+            .flat_map(|(_, twins)| twins.iter().copied())
+            .collect())
+    }
+
+    /// Run `query`, returning the first page of twins after its cursor
+    pub fn query(&self, query: &TwinQuery) -> Result<QueryPage> {
+        let empty = BTreeSet::new();
+        let mut filters: Vec<&BTreeSet<TwinId>> = Vec::new();
+        if let Some(class_name) = &query.class {
+            filters.push(self.classes.get(class_name).unwrap_or(&empty));
+        }
+        for (key, value) in &query.labels {
+            filters.push(
+                self.labels
+                    .get(&(key.clone(), value.clone()))
+                    .unwrap_or(&empty),
+            );
+        }
+        let matching = query
+            .properties
+            .iter()
+            .map(|(property, condition)| self.matching(property, condition))
+            .collect::<Result<Vec<_>>>()?;
+        filters.extend(&matching);
+
+        // Walk the smallest set, checking the others
+        filters.sort_by_key(|twins| twins.len());
+        let (walked, checked) = filters
+            .split_first()
+            .map_or((&self.twins, &[][..]), |(first, rest)| (*first, rest));
+        let start = query.after.map_or(Bound::Unbounded, Bound::Excluded);
+        let mut found = walked
+            .range((start, Bound::Unbounded))
+            .filter(|twin| checked.iter().all(|twins| twins.contains(twin)))
+            .copied();
+
+        let limit = query.limit.unwrap_or(usize::MAX);
+        let twins: Vec<TwinId> = found.by_ref().take(limit).collect();
+        let next = match found.next() {
+            Some(_) => twins.last().copied(),
+            None => None,
+        };
+        Ok(QueryPage { twins, next })
+    }
+
+    /// Number of twins indexed
+    pub fn len(&self) -> usize {
+        self.twins.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.twins.is_empty()
+    }
+}
+
+/// Whether no key lies in `range`, which `BTreeMap::range` panics on
+fn is_empty((low, high): (Bound<&IndexKey>, Bound<&IndexKey>)) -> bool {
+    match (low, high) {
+        (Bound::Included(low), Bound::Included(high)) => low > high,
+        (
+            Bound::Included(low) | Bound::Excluded(low),
+            Bound::Included(high) | Bound::Excluded(high),
+        ) => low >= high,
+        _ => false,
+    }
+}
+
+/// Maps of keys to sets of twins
+trait TwinSets<K> {
+    /// Remove `twin` from the set under `key`, dropping the set once empty
+    fn remove_twin(&mut self, key: &K, twin: TwinId);
+}
+
+impl<K: std::hash::Hash + Eq> TwinSets<K> for HashMap<K, BTreeSet<TwinId>> {
+    fn remove_twin(&mut self, key: &K, twin: TwinId) {
+        if let Some(twins) = self.get_mut(key) {
+            twins.remove(&twin);
+            if twins.is_empty() {
+                self.remove(key);
+            }
+        }
+    }
+}
+
+impl<K: Ord> TwinSets<K> for BTreeMap<K, BTreeSet<TwinId>> {
+    fn remove_twin(&mut self, key: &K, twin: TwinId) {
+        if let Some(twins) = self.get_mut(key) {
+            twins.remove(&twin);
+            if twins.is_empty() {
+                self.remove(key);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Utc;
+
+    #[test]
+    fn test_conditions() {
+        assert!(Condition::lt(2.3).matches(&IndexKey::from(2.2)));
+        assert!(!Condition::lt(2.3).matches(&IndexKey::from("2.2")));
+        assert!(Condition::between("a", "c").matches(&IndexKey::from("b")));
+        assert_eq!(
+            IndexKey::of(&Value::Symbol("on".to_string())),
+            Some(IndexKey::from("on"))
+        );
+        assert_eq!(IndexKey::of(&Value::Nil), None);
+    }
+
+    #[test]
+    fn test_index_follows_events() {
+        let [a, b] = [(); 2].map(|()| TwinId::new());
+        let timestamp = Utc::now();
+        let mut index = SecondaryIndex::new(["firmware".to_string()]);
+        for twin_id in [a, b] {
+            index.apply(&TwinEvent::Created {
+                twin_id,
+                class_name: "Sensor".to_string(),
+                timestamp,
+            });
+        }
+        index.apply(&TwinEvent::TelemetryReceived {
+            twin_id: a,
+            data: vec![("firmware".to_string(), 2.1)],
+            timestamp,
+        });
+        index.apply(&TwinEvent::TelemetryReceived {
+            twin_id: b,
+            data: vec![("firmware".to_string(), 2.4)],
+            timestamp,
+        });
+
+        let old = TwinQuery::new()
+            .class("Sensor")
+            .property("firmware", Condition::lt(2.3));
+        assert_eq!(index.query(&old).unwrap().twins, [a]);
+        index.apply(&TwinEvent::Destroyed {
+            twin_id: a,
+            timestamp,
+        });
+        assert!(index.query(&old).unwrap().twins.is_empty());
+        assert!(index
+            .query(&TwinQuery::new().property("other", Condition::eq(1.0)))
+            .is_err());
+    }
+}
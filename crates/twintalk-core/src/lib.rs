@@ -2,22 +2,56 @@
 //!
 //! This crate provides the core digital twin execution engine with:
 //! - Twin instance management and prototype-based cloning
+//! - External names for twins, such as device serial numbers
 //! - `Smalltalk`-inspired message passing
 //! - Telemetry ingestion and state updates
+//! - Windowed aggregations over recent telemetry
+//! - Unit-aware quantities
+//! - Per-class property schemas
+//! - Typed relationships between twins
+//! - Labels and secondary indexes for fleet queries
+//! - A query language for selecting and acting on fleets
+//! - Aggregates rolled up from child twins
+//! - Declarative state machines for twin classes
 //! - Event sourcing for persistence
 
 #![allow(clippy::multiple_crate_versions)]
 
+pub mod aggregate;
+pub mod alert;
+pub mod alias;
+pub mod computed;
 pub mod event;
+pub mod history;
+pub mod index;
 pub mod message;
+pub mod property;
+pub mod quantity;
+pub mod query;
+pub mod relationship;
 pub mod runtime;
+pub mod state_machine;
 pub mod storage;
 pub mod twin;
+pub mod validation;
 pub mod value;
 
+pub use aggregate::AggregateProperty;
+pub use alert::{AlertRule, Severity};
+pub use alias::Alias;
+pub use computed::ComputedProperty;
+pub use event::{EventContext, EventMetadata};
+pub use history::{HistoryConfig, PropertyHistory};
+pub use index::{Condition, TwinQuery};
 pub use message::Message;
+pub use property::{PropertyMetadata, Quality};
+pub use quantity::Quantity;
+pub use query::Query;
+pub use relationship::{Relationship, RelationshipKind};
 pub use runtime::{Runtime, RuntimeConfig};
+pub use state_machine::{StateMachine, Transition};
 pub use twin::{Twin, TwinId};
+pub use validation::{ClassSchema, PropertySchema, ValidationError};
 pub use value::Value;
 
 // Re-export the message macro
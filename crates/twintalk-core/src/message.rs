@@ -2,6 +2,7 @@
 //!
 //! Messages are pre-compiled for performance while maintaining flexibility.
 
+use crate::quantity::Quantity;
 use crate::value::Value;
 use anyhow::{anyhow, Result};
 use serde::{Deserialize, Serialize};
@@ -90,6 +91,14 @@ impl Message {
         }
     }
 
+    /// A custom message: `Message::send("setpoint:", vec![21.5.into()])`
+    pub fn send(selector: impl Into<String>, args: Vec<Value>) -> Self {
+        Self::Send {
+            selector: selector.into(),
+            args,
+        }
+    }
+
     /// Get the selector (method name) for this message
     pub fn selector(&self) -> &str {
         match self {
@@ -168,6 +177,10 @@ fn parse_value(s: &str) -> Value {
     if let Ok(f) = s.parse::<f64>() {
         return Value::Float(f.into());
     }
+    // Quantity: 77°F
+    if let Some(q) = Quantity::parse(s) {
+        return Value::Quantity(q);
+    }
 
     // Boolean
     match s {
@@ -243,6 +256,14 @@ mod tests {
         );
 
         assert_eq!(Message::parse("clone").unwrap(), Message::Clone);
+
+        assert_eq!(
+            Message::parse("temperature: 77°F").unwrap(),
+            Message::SetProperty(
+                "temperature".to_string(),
+                Value::Quantity(Quantity::new(77.0, "°F"))
+            )
+        );
     }
 
     #[test]
@@ -0,0 +1,193 @@
+//! Property metadata
+//!
+//! Each property can carry its unit, an OPC-style quality, the time the
+//! source sampled it and the time its value last changed. Metadata is part
+//! of the twin state, so snapshots persist it, and is read and written with
+//! messages:
+//!
+//! | selector                 | args                     | answers                |
+//! |--------------------------|--------------------------|------------------------|
+//! | `metadataOf:`            | property                 | map of all fields      |
+//! | `unitOf:`                | property                 | unit or nil            |
+//! | `qualityOf:`             | property                 | `#good`, `#uncertain` or `#bad` |
+//! | `unitOf:put:`            | property, unit or nil    | nil                    |
+//! | `qualityOf:put:`         | property, quality        | nil                    |
+//! | `sourceTimestampOf:put:` | property, RFC 3339 or nil | nil                   |
+//!
+//! The setters are recorded as `MessageSent` events like any custom message,
+//! and replayed from them.
+
+use crate::value::Value;
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+
+/// Selectors answered from metadata
+pub(crate) const SELECTORS: &[&str] = &[
+    "metadataOf:",
+    "unitOf:",
+    "qualityOf:",
+    "unitOf:put:",
+    "qualityOf:put:",
+    "sourceTimestampOf:put:",
+];
+
+/// Whether `selector` changes metadata, and so is replayed from the log
+pub(crate) fn is_setter(selector: &str) -> bool {
+    SELECTORS.contains(&selector) && selector.ends_with("put:")
+}
+
+/// How far a value can be trusted, as in OPC UA
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Quality {
+    #[default]
+    Good,
+    Uncertain,
+    Bad,
+}
+
+impl Quality {
+    /// The quality named `good`, `uncertain` or `bad`
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "good" => Some(Self::Good),
+            "uncertain" => Some(Self::Uncertain),
+            "bad" => Some(Self::Bad),
+            _ => None,
+        }
+    }
+
+    /// The name as used in messages
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Good => "good",
+            Self::Uncertain => "uncertain",
+            Self::Bad => "bad",
+        }
+    }
+}
+
+impl fmt::Display for Quality {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// What is known about a property besides its value
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PropertyMetadata {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub unit: Option<String>,
+    #[serde(default)]
+    pub quality: Quality,
+    /// When the source sampled the value
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source_timestamp: Option<DateTime<Utc>>,
+    /// When the value last changed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_changed: Option<DateTime<Utc>>,
+}
+
+impl PropertyMetadata {
+    /// The metadata as a map, as answered by `metadataOf:`
+    pub fn to_value(&self) -> Value {
+        let timestamp =
+            |t: Option<DateTime<Utc>>| t.map_or(Value::Nil, |t| Value::from(t.to_rfc3339()));
+        Value::Map(BTreeMap::from([
+            (
+                "unit".to_string(),
+                self.unit.clone().map_or(Value::Nil, Value::String),
+            ),
+            (
+                "quality".to_string(),
+                Value::Symbol(self.quality.as_str().to_string()),
+            ),
+            (
+                "sourceTimestamp".to_string(),
+                timestamp(self.source_timestamp),
+            ),
+            ("lastChanged".to_string(), timestamp(self.last_changed)),
+        ]))
+    }
+}
+
+/// Answer a metadata message on the metadata of all properties
+pub(crate) fn handle(
+    metadata: &mut BTreeMap<String, PropertyMetadata>,
+    selector: &str,
+    args: &[Value],
+) -> Result<Value> {
+    let property = args
+        .first()
+        .and_then(Value::as_str)
+        .ok_or_else(|| anyhow!("{selector} expects a property name"))?;
+    let arg = || {
+        args.get(1)
+            .ok_or_else(|| anyhow!("{selector} expects a value"))
+    };
+
+    match selector {
+        "metadataOf:" => Ok(metadata
+            .get(property)
+            .cloned()
+            .unwrap_or_default()
+            .to_value()),
+        "unitOf:" => Ok(metadata
+            .get(property)
+            .and_then(|m| m.unit.clone())
+            .map_or(Value::Nil, Value::String)),
+        "qualityOf:" => Ok(Value::Symbol(
+            metadata
+                .get(property)
+                .map(|m| m.quality)
+                .unwrap_or_default()
+                .as_str()
+                .to_string(),
+        )),
+        "unitOf:put:" => {
+            let unit = match arg()? {
+                Value::Nil => None,
+                value => Some(
+                    value
+                        .as_str()
+                        .ok_or_else(|| anyhow!("{selector} expects a unit name"))?
+                        .to_string(),
+                ),
+            };
+            metadata.entry(property.to_string()).or_default().unit = unit;
+            Ok(Value::Nil)
+        }
+        "qualityOf:put:" => {
+            let quality = arg()?
+                .as_str()
+                .and_then(Quality::parse)
+                .ok_or_else(|| anyhow!("{selector} expects #good, #uncertain or #bad"))?;
+            metadata.entry(property.to_string()).or_default().quality = quality;
+            Ok(Value::Nil)
+        }
+        "sourceTimestampOf:put:" => {
+            let timestamp = match arg()? {
+                Value::Nil => None,
+                value => {
+                    let text = value
+                        .as_str()
+                        .ok_or_else(|| anyhow!("{selector} expects an RFC 3339 timestamp"))?;
+                    Some(
+                        DateTime::parse_from_rfc3339(text)
+                            .map_err(|e| anyhow!(e))?
+                            .with_timezone(&Utc),
+                    )
+                }
+            };
+            metadata
+                .entry(property.to_string())
+                .or_default()
+                .source_timestamp = timestamp;
+            Ok(Value::Nil)
+        }
+        _ => Err(anyhow!("Twin does not understand: {selector}")),
+    }
+}
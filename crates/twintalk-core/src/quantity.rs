@@ -0,0 +1,359 @@
+//! Unit-aware quantities
+//!
+//! A `Quantity` is a number with a unit. Quantities of the same dimension
+//! convert into each other, so adding °F to °C converts the °F first, while
+//! adding kPa to °C is an error:
+//!
+//! ```
+//! use twintalk_core::quantity::Quantity;
+//!
+//! let indoor = Quantity::new(20.0, "°C");
+//! let warmer = indoor.try_add(&Quantity::new(9.0, "°F")).unwrap();
+//! assert!((warmer.value() - 25.0).abs() < 1e-9);
+//! assert!(indoor.try_add(&Quantity::new(101.3, "kPa")).is_err());
+//! ```
+//!
+//! Temperatures convert as absolute values, except the amounts added and
+//! subtracted, which are differences: 9 °F warmer is 5 °C warmer. Units
+//! outside the built-in table are allowed, but only combine with the same
+//! unit.
+//!
+//! Twins answer these messages about their properties, taking the unit of
+//! plain numbers from the property metadata:
+//!
+//! | selector         | args                | answers                      |
+//! |------------------|---------------------|------------------------------|
+//! | `quantityOf:`    | property            | the value as a quantity      |
+//! | `convert:to:`    | property, unit      | the value in `unit`          |
+//! | `compare:with:`  | property, quantity  | -1, 0 or 1                   |
+//! | `increase:by:`   | property, quantity  | the increased value          |
+
+use crate::value::Value;
+use anyhow::{anyhow, Result};
+use ordered_float::OrderedFloat;
+use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+use std::fmt;
+
+/// Selectors answered with quantities
+pub(crate) const SELECTORS: &[&str] = &[
+    "quantityOf:",
+    "convert:to:",
+    "compare:with:",
+    "increase:by:",
+];
+
+/// What a unit measures
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Dimension {
+    Temperature,
+    Pressure,
+    Length,
+    Mass,
+    Time,
+    Speed,
+    Volume,
+    Energy,
+    Power,
+    Voltage,
+    Current,
+    Frequency,
+    Ratio,
+}
+
+/// A known unit: `value * scale + offset` is the value in the base unit
+/// of its dimension
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Unit {
+    pub symbol: &'static str,
+    pub dimension: Dimension,
+    pub scale: f64,
+    pub offset: f64,
+}
+
+const fn unit(symbol: &'static str, dimension: Dimension, scale: f64) -> Unit {
+    Unit {
+        symbol,
+        dimension,
+        scale,
+        offset: 0.0,
+    }
+}
+
+const CELSIUS: Unit = Unit {
+    symbol: "°C",
+    dimension: Dimension::Temperature,
+    scale: 1.0,
+    offset: 273.15,
+};
+
+const FAHRENHEIT: Unit = Unit {
+    symbol: "°F",
+    dimension: Dimension::Temperature,
+    scale: 5.0 / 9.0,
+    offset: 273.15 - 32.0 * 5.0 / 9.0,
+};
+
+/// The built-in units
+const UNITS: &[Unit] = &[
+    unit("K", Dimension::Temperature, 1.0),
+    CELSIUS,
+    FAHRENHEIT,
+    unit("Pa", Dimension::Pressure, 1.0),
+    unit("hPa", Dimension::Pressure, 1e2),
+    unit("kPa", Dimension::Pressure, 1e3),
+    unit("MPa", Dimension::Pressure, 1e6),
+    unit("mbar", Dimension::Pressure, 1e2),
+    unit("bar", Dimension::Pressure, 1e5),
+    unit("psi", Dimension::Pressure, 6_894.757_293_168),
+    unit("atm", Dimension::Pressure, 101_325.0),
+    unit("mm", Dimension::Length, 1e-3),
+    unit("cm", Dimension::Length, 1e-2),
+    unit("m", Dimension::Length, 1.0),
+    unit("km", Dimension::Length, 1e3),
+    unit("in", Dimension::Length, 0.0254),
+    unit("ft", Dimension::Length, 0.3048),
+    unit("mi", Dimension::Length, 1_609.344),
+    unit("g", Dimension::Mass, 1e-3),
+    unit("kg", Dimension::Mass, 1.0),
+    unit("t", Dimension::Mass, 1e3),
+    unit("oz", Dimension::Mass, 0.028_349_523_125),
+    unit("lb", Dimension::Mass, 0.453_592_37),
+    unit("ms", Dimension::Time, 1e-3),
+    unit("s", Dimension::Time, 1.0),
+    unit("min", Dimension::Time, 60.0),
+    unit("h", Dimension::Time, 3_600.0),
+    unit("d", Dimension::Time, 86_400.0),
+    unit("m/s", Dimension::Speed, 1.0),
+    unit("km/h", Dimension::Speed, 1.0 / 3.6),
+    unit("mph", Dimension::Speed, 0.447_04),
+    unit("kn", Dimension::Speed, 1_852.0 / 3_600.0),
+    unit("mL", Dimension::Volume, 1e-6),
+    unit("L", Dimension::Volume, 1e-3),
+    unit("m³", Dimension::Volume, 1.0),
+    unit("gal", Dimension::Volume, 0.003_785_411_784),
+    unit("J", Dimension::Energy, 1.0),
+    unit("kJ", Dimension::Energy, 1e3),
+    unit("MJ", Dimension::Energy, 1e6),
+    unit("Wh", Dimension::Energy, 3_600.0),
+    unit("kWh", Dimension::Energy, 3.6e6),
+    unit("W", Dimension::Power, 1.0),
+    unit("kW", Dimension::Power, 1e3),
+    unit("MW", Dimension::Power, 1e6),
+    unit("hp", Dimension::Power, 745.699_871_582_270_2),
+    unit("mV", Dimension::Voltage, 1e-3),
+    unit("V", Dimension::Voltage, 1.0),
+    unit("kV", Dimension::Voltage, 1e3),
+    unit("mA", Dimension::Current, 1e-3),
+    unit("A", Dimension::Current, 1.0),
+    unit("Hz", Dimension::Frequency, 1.0),
+    unit("kHz", Dimension::Frequency, 1e3),
+    unit("rpm", Dimension::Frequency, 1.0 / 60.0),
+    unit("%", Dimension::Ratio, 1e-2),
+    unit("ppm", Dimension::Ratio, 1e-6),
+];
+
+/// ASCII spellings of symbols, for typing at a REPL
+const ALIASES: &[(&str, &str)] = &[("degC", "°C"), ("degF", "°F"), ("m3", "m³")];
+
+/// Look up a built-in unit by symbol or alias
+pub fn lookup(symbol: &str) -> Option<&'static Unit> {
+    let symbol = ALIASES
+        .iter()
+        .find(|(alias, _)| *alias == symbol)
+        .map_or(symbol, |(_, symbol)| symbol);
+    UNITS.iter().find(|unit| unit.symbol == symbol)
+}
+
+/// Convert `value` from one unit to another of the same dimension
+pub fn convert(value: f64, from: &str, to: &str) -> Result<f64> {
+    Ok(match units(from, to)? {
+        Some((source, target)) => {
+            (value.mul_add(source.scale, source.offset) - target.offset) / target.scale
+        }
+        None => value,
+    })
+}
+
+/// Convert a difference of `value` from one unit to another of the same
+/// dimension, scaling without the offset between them
+pub fn convert_difference(value: f64, from: &str, to: &str) -> Result<f64> {
+    Ok(match units(from, to)? {
+        Some((source, target)) => value * source.scale / target.scale,
+        None => value,
+    })
+}
+
+/// The units to convert between, or `None` if they are the same
+fn units(from: &str, to: &str) -> Result<Option<(&'static Unit, &'static Unit)>> {
+    if from == to {
+        return Ok(None);
+    }
+    let source = lookup(from).ok_or_else(|| anyhow!("Unknown unit: {from}"))?;
+    let target = lookup(to).ok_or_else(|| anyhow!("Unknown unit: {to}"))?;
+    if source.symbol == target.symbol {
+        return Ok(None);
+    }
+    if source.dimension != target.dimension {
+        return Err(anyhow!(
+            "Cannot convert {from} ({:?}) to {to} ({:?})",
+            source.dimension,
+            target.dimension
+        ));
+    }
+    Ok(Some((source, target)))
+}
+
+/// A number with a unit
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Quantity {
+    value: OrderedFloat<f64>,
+    unit: String,
+}
+
+impl Quantity {
+    pub fn new(value: f64, unit: impl Into<String>) -> Self {
+        Self {
+            value: OrderedFloat(value),
+            unit: unit.into(),
+        }
+    }
+
+    /// Parse a number directly followed by a built-in unit, like `77°F`
+    /// or `101.3 kPa`
+    pub fn parse(text: &str) -> Option<Self> {
+        let split = text
+            .find(|c: char| !(c.is_ascii_digit() || matches!(c, '.' | '-' | '+')))
+            .filter(|&i| i > 0)?;
+        let (number, symbol) = text.split_at(split);
+        let unit = lookup(symbol.trim())?;
+        Some(Self::new(number.parse().ok()?, unit.symbol))
+    }
+
+    pub fn value(&self) -> f64 {
+        self.value.into_inner()
+    }
+
+    pub fn unit(&self) -> &str {
+        &self.unit
+    }
+
+    /// What the unit measures, if it is a built-in one
+    pub fn dimension(&self) -> Option<Dimension> {
+        lookup(&self.unit).map(|unit| unit.dimension)
+    }
+
+    /// The same quantity in `unit`
+    pub fn to(&self, unit: &str) -> Result<Self> {
+        let value = convert(self.value(), &self.unit, unit)?;
+        Ok(Self::new(value, lookup(unit).map_or(unit, |u| u.symbol)))
+    }
+
+    /// `self` increased by the difference `other`, in the unit of `self`
+    pub fn try_add(&self, other: &Self) -> Result<Self> {
+        let delta = convert_difference(other.value(), &other.unit, &self.unit)?;
+        Ok(Self::new(self.value() + delta, self.unit.clone()))
+    }
+
+    /// `self` decreased by the difference `other`, in the unit of `self`
+    pub fn try_sub(&self, other: &Self) -> Result<Self> {
+        let delta = convert_difference(other.value(), &other.unit, &self.unit)?;
+        Ok(Self::new(self.value() - delta, self.unit.clone()))
+    }
+
+    /// The quantity multiplied by a plain number
+    #[must_use]
+    pub fn scale(&self, factor: f64) -> Self {
+        Self::new(self.value() * factor, self.unit.clone())
+    }
+
+    /// Compare with a quantity of the same dimension
+    pub fn try_cmp(&self, other: &Self) -> Result<Ordering> {
+        Ok(self.value.cmp(&other.to(&self.unit)?.value))
+    }
+}
+
+impl fmt::Display for Quantity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", self.value, self.unit)
+    }
+}
+
+/// The numeric value of `value` in `unit`: quantities are converted, plain
+/// numbers are taken to be in `unit` already. `None` if not numeric or not
+/// convertible.
+pub(crate) fn magnitude(value: &Value, unit: Option<&str>) -> Option<f64> {
+    match (value, unit) {
+        (Value::Quantity(q), Some(unit)) => q.to(unit).ok().map(|q| q.value()),
+        (Value::Quantity(q), None) => Some(q.value()),
+        (other, _) => other.as_f64(),
+    }
+}
+
+/// `value` as a quantity, taking plain numbers to be in `unit`
+pub(crate) fn as_quantity(value: &Value, unit: Option<&str>) -> Option<Quantity> {
+    match value {
+        Value::Quantity(q) => Some(q.clone()),
+        other => Some(Quantity::new(other.as_f64()?, unit?)),
+    }
+}
+
+/// Check a value about to be stored in a property. Quantities are
+/// converted to the property's declared unit, or to the unit of its
+/// current value; incompatible units are an error.
+pub(crate) fn conform(
+    name: &str,
+    value: &Value,
+    declared: Option<&str>,
+    current: Option<&Value>,
+) -> Result<Value> {
+    let Value::Quantity(q) = value else {
+        return Ok(value.clone());
+    };
+    let target = declared.or_else(|| match current {
+        Some(Value::Quantity(current)) => Some(current.unit()),
+        _ => None,
+    });
+    match target {
+        Some(unit) => q
+            .to(unit)
+            .map(Value::Quantity)
+            .map_err(|e| anyhow!("{name}: {e}")),
+        None => Ok(value.clone()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn close(a: f64, b: f64) -> bool {
+        (a - b).abs() < 1e-9
+    }
+
+    #[test]
+    fn test_conversions() {
+        assert!(close(convert(100.0, "°C", "°F").unwrap(), 212.0));
+        assert!(close(convert(32.0, "degF", "K").unwrap(), 273.15));
+        assert!(close(convert(1.0, "bar", "kPa").unwrap(), 100.0));
+        assert!(close(convert(1.0, "kWh", "J").unwrap(), 3.6e6));
+        assert!(convert(1.0, "bar", "°C").is_err());
+        assert!(convert(1.0, "furlong", "m").is_err());
+        assert!(close(convert(1.0, "furlong", "furlong").unwrap(), 1.0));
+        assert!(close(convert_difference(9.0, "°F", "°C").unwrap(), 5.0));
+        assert!(close(convert_difference(5.0, "°C", "K").unwrap(), 5.0));
+        assert!(convert_difference(1.0, "bar", "°C").is_err());
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(Quantity::parse("77°F"), Some(Quantity::new(77.0, "°F")));
+        assert_eq!(
+            Quantity::parse("-3.5 degC"),
+            Some(Quantity::new(-3.5, "°C"))
+        );
+        assert_eq!(Quantity::parse("12"), None);
+        assert_eq!(Quantity::parse("12 parsecs"), None);
+        assert_eq!(Quantity::parse("kPa"), None);
+    }
+}
@@ -0,0 +1,997 @@
+//! Twin query language
+//!
+//! Fleets are selected and summarised with queries such as
+//!
+//! ```
+//! use twintalk_core::query::Query;
+//!
+//! let hottest = Query::parse(
+//!     "select id, temperature where class = TemperatureSensor \
+//!      and label.site = berlin and (temperature > 22.5 or not online = true) \
+//!      order by temperature desc limit 10",
+//! )
+//! .unwrap();
+//! let per_site = Query::parse(
+//!     "select label.site, count(*), avg(temperature) group by label.site",
+//! )
+//! .unwrap();
+//! ```
+//!
+//! A query selects columns, or `*` for the class and every property, then
+//! optionally filters `where` a predicate, groups `group by` a field, sorts
+//! `order by` a column `asc` or `desc`, and keeps a `limit` of rows.
+//!
+//! - Fields are `id`, `class`, `label.<key>` or a property name.
+//! - Predicates compare fields with `=`, `!=`, `<`, `<=`, `>` and `>=`, and
+//!   combine with `and`, `or`, `not` and parentheses. Values are numbers,
+//!   booleans, or text, quoted or bare. As in the secondary index, numbers
+//!   only compare with numbers and text with text, and a twin without the
+//!   field matches no comparison.
+//! - Columns aggregate with `count`, `sum`, `avg`, `min` and `max`, over
+//!   the whole selection or each group.
+//!
+//! `Runtime::select` streams the rows, reading only the twins the secondary
+//! index cannot rule out. A query reading nothing but ids, classes, labels
+//! and indexed properties is answered from the index alone, without loading
+//! twins. `Runtime::broadcast` sends a message to every twin a query
+//! selects.
+
+use crate::aggregate::Aggregation;
+use crate::index::{Condition, IndexKey, SecondaryIndex, TwinQuery};
+use crate::runtime::Runtime;
+use crate::twin::{Twin, TwinId};
+use crate::value::Value;
+use anyhow::{anyhow, Result};
+use std::cmp::Ordering;
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+
+/// Twins read from the secondary index at a time
+const PAGE: usize = 256;
+
+/// Deepest nesting of `not` and parentheses a query may use
+const MAX_DEPTH: usize = 64;
+
+/// What a query reads from a twin
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Field {
+    Id,
+    Class,
+    Label(String),
+    Property(String),
+}
+
+impl Field {
+    fn parse(name: &str) -> Self {
+        match name {
+            "id" => Self::Id,
+            "class" => Self::Class,
+            _ => name.strip_prefix("label.").map_or_else(
+                || Self::Property(name.to_string()),
+                |key| Self::Label(key.to_string()),
+            ),
+        }
+    }
+
+    /// The value of the field on `twin`
+    pub fn of(&self, twin: &Twin) -> Option<Value> {
+        match self {
+            Self::Id => Some(Value::String(twin.id().to_string())),
+            Self::Class => Some(Value::String(twin.class_name().to_string())),
+            Self::Label(key) => twin.labels().get(key).cloned().map(Value::String),
+            Self::Property(name) => twin.property(name).cloned(),
+        }
+    }
+}
+
+/// A twin as a query reads it
+trait Fields {
+    fn id(&self) -> TwinId;
+
+    /// The value of `field`
+    fn get(&self, field: &Field) -> Option<Value>;
+
+    /// The class and every property, for `select *`
+    fn all(&self) -> Vec<(String, Value)>;
+}
+
+impl Fields for Twin {
+    fn id(&self) -> TwinId {
+        self.state().id
+    }
+
+    fn get(&self, field: &Field) -> Option<Value> {
+        field.of(self)
+    }
+
+    fn all(&self) -> Vec<(String, Value)> {
+        let class = (
+            "class".to_string(),
+            Value::String(self.class_name().to_string()),
+        );
+        let properties = self.state().properties.clone().into_iter();
+        std::iter::once(class).chain(properties).collect()
+    }
+}
+
+/// A twin as the secondary index holds it, without its unindexed
+/// properties
+struct Indexed<'a> {
+    twin: TwinId,
+    index: &'a SecondaryIndex,
+}
+
+impl Fields for Indexed<'_> {
+    fn id(&self) -> TwinId {
+        self.twin
+    }
+
+    fn get(&self, field: &Field) -> Option<Value> {
+        let text = |s: &str| Value::String(s.to_string());
+        match field {
+            Field::Id => Some(text(&self.twin.to_string())),
+            Field::Class => self.index.class_of(self.twin).map(text),
+            Field::Label(key) => self.index.label_of(self.twin, key).map(text),
+            Field::Property(name) => self.index.value_of(self.twin, name).cloned(),
+        }
+    }
+
+    fn all(&self) -> Vec<(String, Value)> {
+        self.get(&Field::Class)
+            .map(|class| ("class".to_string(), class))
+            .into_iter()
+            .collect()
+    }
+}
+
+impl fmt::Display for Field {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Id => write!(f, "id"),
+            Self::Class => write!(f, "class"),
+            Self::Label(key) => write!(f, "label.{key}"),
+            Self::Property(name) => write!(f, "{name}"),
+        }
+    }
+}
+
+/// Which twins a query selects
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Predicate {
+    Compare(Field, Condition),
+    Not(Box<Self>),
+    And(Vec<Self>),
+    Or(Vec<Self>),
+}
+
+impl Predicate {
+    /// Whether `twin` satisfies the predicate
+    pub fn matches(&self, twin: &Twin) -> bool {
+        self.holds(twin)
+    }
+
+    fn holds(&self, twin: &dyn Fields) -> bool {
+        match self {
+            Self::Compare(field, condition) => twin
+                .get(field)
+                .as_ref()
+                .and_then(IndexKey::of)
+                .is_some_and(|key| condition.matches(&key)),
+            Self::Not(predicate) => !predicate.holds(twin),
+            Self::And(predicates) => predicates.iter().all(|p| p.holds(twin)),
+            Self::Or(predicates) => predicates.iter().any(|p| p.holds(twin)),
+        }
+    }
+
+    /// Every field the predicate compares
+    fn fields(&self) -> Vec<&Field> {
+        match self {
+            Self::Compare(field, _) => vec![field],
+            Self::Not(predicate) => predicate.fields(),
+            Self::And(predicates) | Self::Or(predicates) => {
+                predicates.iter().flat_map(Self::fields).collect()
+            }
+        }
+    }
+
+    /// The predicates that must all hold
+    fn conjuncts(&self) -> &[Self] {
+        match self {
+            Self::And(predicates) => predicates,
+            other => std::slice::from_ref(other),
+        }
+    }
+}
+
+/// One column of the results
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Column {
+    Field(Field),
+    /// An aggregate of a field, or with `None` of the rows themselves
+    Aggregate(Aggregation, Option<Field>),
+}
+
+impl Column {
+    fn is_aggregate(&self) -> bool {
+        matches!(self, Self::Aggregate(..))
+    }
+}
+
+impl fmt::Display for Column {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = |aggregation: Aggregation| match aggregation {
+            Aggregation::Count => "count",
+            Aggregation::Sum => "sum",
+            Aggregation::Mean => "avg",
+            Aggregation::Min => "min",
+            Aggregation::Max => "max",
+        };
+        match self {
+            Self::Field(field) => write!(f, "{field}"),
+            Self::Aggregate(aggregation, Some(field)) => {
+                write!(f, "{}({field})", name(*aggregation))
+            }
+            Self::Aggregate(aggregation, None) => write!(f, "{}(*)", name(*aggregation)),
+        }
+    }
+}
+
+/// The direction rows are sorted in
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Order {
+    #[default]
+    Ascending,
+    Descending,
+}
+
+/// A parsed query
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Query {
+    /// The columns selected; empty for `*`
+    columns: Vec<Column>,
+    predicate: Option<Predicate>,
+    group_by: Option<Field>,
+    order_by: Option<(Column, Order)>,
+    limit: Option<usize>,
+}
+
+impl Query {
+    /// Parse a query such as `select id where temperature > 22.0`
+    pub fn parse(text: &str) -> Result<Self> {
+        let mut parser = Parser {
+            tokens: tokenize(text)?,
+            position: 0,
+            depth: 0,
+        };
+        let query = parser.query()?;
+        query.check()?;
+        Ok(query)
+    }
+
+    /// Reject queries that mix rows of twins with aggregated rows
+    fn check(&self) -> Result<()> {
+        if self.is_aggregated() {
+            if self.columns.is_empty() {
+                return Err(anyhow!("Cannot select * with aggregates"));
+            }
+            for column in &self.columns {
+                if let Column::Field(field) = column {
+                    if self.group_by.as_ref() != Some(field) {
+                        return Err(anyhow!("Column {field} is neither grouped nor aggregated"));
+                    }
+                }
+            }
+            if let Some((column, _)) = &self.order_by {
+                if !self.columns.contains(column) {
+                    return Err(anyhow!("Cannot order aggregated rows by {column}"));
+                }
+            }
+        } else if let Some((column @ Column::Aggregate(..), _)) = &self.order_by {
+            return Err(anyhow!("Cannot order by {column} without aggregating"));
+        }
+        Ok(())
+    }
+
+    /// Whether rows summarise groups of twins rather than one twin each
+    pub fn is_aggregated(&self) -> bool {
+        self.group_by.is_some() || self.columns.iter().any(Column::is_aggregate)
+    }
+
+    /// Whether `twin` satisfies the predicate
+    pub fn matches(&self, twin: &Twin) -> bool {
+        self.holds(twin)
+    }
+
+    fn holds(&self, twin: &dyn Fields) -> bool {
+        self.predicate
+            .as_ref()
+            .is_none_or(|predicate| predicate.holds(twin))
+    }
+
+    /// Whether the secondary index holds every field the query reads,
+    /// given which properties are indexed
+    pub(crate) fn reads_only_indexed(&self, indexed: impl Fn(&str) -> bool) -> bool {
+        if self.columns.is_empty() {
+            return false;
+        }
+        let columns = self
+            .columns
+            .iter()
+            .chain(self.order_by.iter().map(|(c, _)| c));
+        let mut fields = columns
+            .filter_map(|column| match column {
+                Column::Field(field) | Column::Aggregate(_, Some(field)) => Some(field),
+                Column::Aggregate(_, None) => None,
+            })
+            .chain(&self.group_by)
+            .chain(self.predicate.iter().flat_map(Predicate::fields));
+        fields.all(|field| match field {
+            Field::Property(name) => indexed(name),
+            Field::Id | Field::Class | Field::Label(_) => true,
+        })
+    }
+
+    /// The index query for the twins that may match, given which properties
+    /// are indexed
+    pub(crate) fn candidates(&self, indexed: impl Fn(&str) -> bool) -> TwinQuery {
+        let mut candidates = TwinQuery::new();
+        let conjuncts = self
+            .predicate
+            .as_ref()
+            .map_or(&[][..], Predicate::conjuncts);
+        for predicate in conjuncts {
+            candidates = match predicate {
+                Predicate::Compare(Field::Class, Condition::Equals(IndexKey::Text(class))) => {
+                    candidates.class(class)
+                }
+                Predicate::Compare(Field::Label(key), Condition::Equals(IndexKey::Text(value))) => {
+                    candidates.label(key, value)
+                }
+                Predicate::Compare(Field::Property(property), condition) if indexed(property) => {
+                    candidates.property(property, condition.clone())
+                }
+                _ => candidates,
+            };
+        }
+        candidates
+    }
+
+    /// The row of one twin
+    fn row(&self, twin: &dyn Fields) -> Row {
+        let columns = if self.columns.is_empty() {
+            twin.all()
+        } else {
+            self.columns
+                .iter()
+                .map(|column| {
+                    let value = match column {
+                        Column::Field(field) => twin.get(field),
+                        Column::Aggregate(..) => None,
+                    };
+                    (column.to_string(), value.unwrap_or_default())
+                })
+                .collect()
+        };
+        Row {
+            twin_id: Some(twin.id()),
+            columns,
+        }
+    }
+
+    /// The value `twin` is sorted by
+    fn sort_key(&self, twin: &dyn Fields) -> Option<IndexKey> {
+        match &self.order_by {
+            Some((Column::Field(field), _)) => twin.get(field).as_ref().and_then(IndexKey::of),
+            _ => None,
+        }
+    }
+
+    /// The group of `twin` and what it contributes to each column
+    fn contribution(&self, twin: &dyn Fields) -> (Value, Vec<Option<Value>>) {
+        let group = self
+            .group_by
+            .as_ref()
+            .and_then(|field| twin.get(field))
+            .unwrap_or_default();
+        let inputs = self
+            .columns
+            .iter()
+            .map(|column| match column {
+                Column::Aggregate(_, Some(field)) => twin.get(field),
+                // Every row counts
+                Column::Aggregate(_, None) => Some(Value::Boolean(true)),
+                Column::Field(_) => None,
+            })
+            .collect();
+        (group, inputs)
+    }
+
+    /// Sort `rows` by the `order by` column, given each row's key
+    fn sort<T>(&self, rows: &mut [(Option<IndexKey>, T)]) {
+        if let Some((_, order)) = &self.order_by {
+            rows.sort_by(|(a, _), (b, _)| compare(a.as_ref(), b.as_ref(), *order));
+        }
+    }
+}
+
+/// Compare sort keys, keeping rows without one last
+fn compare(a: Option<&IndexKey>, b: Option<&IndexKey>, order: Order) -> Ordering {
+    match (a, b, order) {
+        (Some(a), Some(b), Order::Ascending) => a.cmp(b),
+        (Some(a), Some(b), Order::Descending) => b.cmp(a),
+        (a, b, _) => a.is_none().cmp(&b.is_none()),
+    }
+}
+
+/// One result of a query
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Row {
+    /// The twin the row is of; `None` for aggregated rows
+    pub twin_id: Option<TwinId>,
+    /// Column names and values, `Nil` where a twin has no value
+    pub columns: Vec<(String, Value)>,
+}
+
+impl Row {
+    /// The value of the column `name`
+    pub fn get(&self, name: &str) -> Option<&Value> {
+        self.columns
+            .iter()
+            .find(|(column, _)| column == name)
+            .map(|(_, value)| value)
+    }
+}
+
+/// The values aggregated into one column of one group
+#[derive(Debug, Clone, Copy, Default)]
+struct Accumulator {
+    count: usize,
+    numbers: usize,
+    sum: f64,
+    min: Option<f64>,
+    max: Option<f64>,
+}
+
+impl Accumulator {
+    fn add(&mut self, value: Option<&Value>) {
+        let Some(value) = value.filter(|value| **value != Value::Nil) else {
+            return;
+        };
+        self.count += 1;
+        if let Some(IndexKey::Number(n)) = IndexKey::of(value) {
+            self.numbers += 1;
+            self.sum += n.0;
+            self.min = Some(self.min.map_or(n.0, |min| min.min(n.0)));
+            self.max = Some(self.max.map_or(n.0, |max| max.max(n.0)));
+        }
+    }
+
+    fn value(&self, aggregation: Aggregation) -> Value {
+        #[allow(clippy::cast_precision_loss)]
+        let number = match aggregation {
+            Aggregation::Count => return Value::Integer(self.count.try_into().unwrap_or(i64::MAX)),
+            Aggregation::Sum => Some(self.sum),
+            Aggregation::Mean => (self.numbers > 0).then(|| self.sum / self.numbers as f64),
+            Aggregation::Min => self.min,
+            Aggregation::Max => self.max,
+        };
+        number.map_or(Value::Nil, |n| Value::Float(n.into()))
+    }
+}
+
+/// The rows of a query, read from the `Runtime` as they are needed
+pub struct QueryStream<'a> {
+    runtime: &'a Runtime,
+    query: Query,
+    candidates: TwinQuery,
+    pending: VecDeque<TwinId>,
+    cursor: Option<TwinId>,
+    exhausted: bool,
+    /// Whether rows are read from the secondary index instead of the twins
+    from_index: bool,
+    /// Rows of queries that sort or aggregate, which need every twin first
+    buffered: Option<VecDeque<Row>>,
+    returned: usize,
+}
+
+impl<'a> QueryStream<'a> {
+    pub(crate) fn new(
+        runtime: &'a Runtime,
+        query: Query,
+        candidates: TwinQuery,
+        from_index: bool,
+    ) -> Self {
+        Self {
+            runtime,
+            query,
+            candidates,
+            pending: VecDeque::new(),
+            cursor: None,
+            exhausted: false,
+            from_index,
+            buffered: None,
+            returned: 0,
+        }
+    }
+
+    /// The next row, or `None` after the last
+    pub async fn next(&mut self) -> Result<Option<Row>> {
+        if self.query.limit.is_some_and(|limit| self.returned >= limit) {
+            return Ok(None);
+        }
+        let row = if self.query.is_aggregated() || self.query.order_by.is_some() {
+            if self.buffered.is_none() {
+                self.buffered = Some(self.evaluate().await?);
+            }
+            self.buffered.as_mut().and_then(VecDeque::pop_front)
+        } else {
+            self.next_match(Query::row).await?
+        };
+        self.returned += usize::from(row.is_some());
+        Ok(row)
+    }
+
+    /// The remaining rows
+    pub async fn collect(mut self) -> Result<Vec<Row>> {
+        let mut rows = Vec::new();
+        while let Some(row) = self.next().await? {
+            rows.push(row);
+        }
+        Ok(rows)
+    }
+
+    /// Every row, sorted
+    async fn evaluate(&mut self) -> Result<VecDeque<Row>> {
+        let mut rows = if self.query.is_aggregated() {
+            self.aggregate().await?
+        } else {
+            let mut rows = Vec::new();
+            while let Some(row) = self
+                .next_match(|query, twin| (query.sort_key(twin), query.row(twin)))
+                .await?
+            {
+                rows.push(row);
+            }
+            rows
+        };
+        self.query.sort(&mut rows);
+        Ok(rows.into_iter().map(|(_, row)| row).collect())
+    }
+
+    /// A row per group, with its sort key
+    async fn aggregate(&mut self) -> Result<Vec<(Option<IndexKey>, Row)>> {
+        let mut groups: HashMap<Value, Vec<Accumulator>> = HashMap::new();
+        if self.query.group_by.is_none() {
+            // One row, even of no twins
+            groups.insert(Value::Nil, Vec::new());
+        }
+        while let Some((group, inputs)) = self.next_match(Query::contribution).await? {
+            let accumulators = groups.entry(group).or_default();
+            accumulators.resize(inputs.len(), Accumulator::default());
+            for (accumulator, input) in accumulators.iter_mut().zip(&inputs) {
+                accumulator.add(input.as_ref());
+            }
+        }
+
+        let query = &self.query;
+        let sorted_by = query.order_by.as_ref().map(|(column, _)| column);
+        let mut groups: Vec<(Value, Vec<Accumulator>)> = groups.into_iter().collect();
+        groups.sort_by(|(a, _), (b, _)| {
+            compare(
+                IndexKey::of(a).as_ref(),
+                IndexKey::of(b).as_ref(),
+                Order::Ascending,
+            )
+        });
+        Ok(groups
+            .into_iter()
+            .map(|(group, mut accumulators)| {
+                accumulators.resize(query.columns.len(), Accumulator::default());
+                let columns: Vec<(String, Value)> = query
+                    .columns
+                    .iter()
+                    .zip(&accumulators)
+                    .map(|(column, accumulator)| {
+                        let value = match column {
+                            Column::Field(_) => group.clone(),
+                            Column::Aggregate(aggregation, _) => accumulator.value(*aggregation),
+                        };
+                        (column.to_string(), value)
+                    })
+                    .collect();
+                let key = sorted_by
+                    .and_then(|column| query.columns.iter().position(|c| c == column))
+                    .and_then(|i| IndexKey::of(&columns[i].1));
+                let row = Row {
+                    twin_id: None,
+                    columns,
+                };
+                (key, row)
+            })
+            .collect())
+    }
+
+    /// What `read` gives for the next twin the query matches
+    async fn next_match<T: Send>(
+        &mut self,
+        read: impl Fn(&Query, &dyn Fields) -> T + Sync,
+    ) -> Result<Option<T>> {
+        while let Some(twin_id) = self.next_candidate().await? {
+            let query = &self.query;
+            let found = if self.from_index {
+                let index = self.runtime.secondary().await?;
+                let twin = Indexed {
+                    twin: twin_id,
+                    index: &index,
+                };
+                let found = query.holds(&twin).then(|| read(query, &twin));
+                drop(index);
+                found
+            } else {
+                self.runtime
+                    .inspect(twin_id, |twin| query.holds(twin).then(|| read(query, twin)))
+                    .await?
+            };
+            if found.is_some() {
+                return Ok(found);
+            }
+        }
+        Ok(None)
+    }
+
+    /// The next twin the index does not rule out
+    async fn next_candidate(&mut self) -> Result<Option<TwinId>> {
+        if self.pending.is_empty() && !self.exhausted {
+            let mut page = self.candidates.clone().limit(PAGE);
+            if let Some(cursor) = self.cursor {
+                page = page.after(cursor);
+            }
+            let page = self.runtime.query(&page).await?;
+            self.cursor = page.next;
+            self.exhausted = page.next.is_none();
+            self.pending.extend(page.twins);
+        }
+        Ok(self.pending.pop_front())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Word(String),
+    Number(String),
+    Text(String),
+    Symbol(&'static str),
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Word(word) | Self::Number(word) => write!(f, "{word}"),
+            Self::Text(text) => write!(f, "'{text}'"),
+            Self::Symbol(symbol) => write!(f, "{symbol}"),
+        }
+    }
+}
+
+const SYMBOLS: [&str; 10] = ["<=", ">=", "!=", "<", ">", "=", "(", ")", ",", "*"];
+
+fn tokenize(text: &str) -> Result<Vec<Token>> {
+    let is_word = |c: char| c.is_alphanumeric() || matches!(c, '_' | '.' | '-' | '#');
+    let mut tokens = Vec::new();
+    let mut rest = text.trim_start();
+    while let Some(c) = rest.chars().next() {
+        let (token, len) = if let Some(symbol) = SYMBOLS.iter().find(|s| rest.starts_with(**s)) {
+            (Token::Symbol(symbol), symbol.len())
+        } else if c == '\'' || c == '"' {
+            let end = rest[1..]
+                .find(c)
+                .ok_or_else(|| anyhow!("Unterminated text in query: {rest}"))?;
+            (Token::Text(rest[1..=end].to_string()), end + 2)
+        } else if is_word(c) {
+            let len = rest.find(|c| !is_word(c)).unwrap_or(rest.len());
+            let word = &rest[..len];
+            let numeric = c.is_ascii_digit() || c == '-' || c == '.';
+            let token = if numeric && word.parse::<f64>().is_ok() {
+                Token::Number(word.to_string())
+            } else {
+                Token::Word(word.to_string())
+            };
+            (token, len)
+        } else {
+            return Err(anyhow!("Unexpected {c:?} in query"));
+        };
+        tokens.push(token);
+        rest = rest[len..].trim_start();
+    }
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    position: usize,
+    depth: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position)
+    }
+
+    fn advance(&mut self) -> Result<Token> {
+        let token = self
+            .tokens
+            .get(self.position)
+            .cloned()
+            .ok_or_else(|| anyhow!("Unexpected end of query"))?;
+        self.position += 1;
+        Ok(token)
+    }
+
+    /// Consume the keyword `word` if it is next
+    fn keyword(&mut self, word: &str) -> bool {
+        let found = matches!(self.peek(), Some(Token::Word(w)) if w.eq_ignore_ascii_case(word));
+        self.position += usize::from(found);
+        found
+    }
+
+    /// Consume the symbol `symbol` if it is next
+    fn symbol(&mut self, symbol: &str) -> bool {
+        let found = matches!(self.peek(), Some(Token::Symbol(s)) if *s == symbol);
+        self.position += usize::from(found);
+        found
+    }
+
+    fn expect_keyword(&mut self, word: &str) -> Result<()> {
+        if self.keyword(word) {
+            Ok(())
+        } else {
+            Err(self.unexpected(word))
+        }
+    }
+
+    fn expect_symbol(&mut self, symbol: &str) -> Result<()> {
+        if self.symbol(symbol) {
+            Ok(())
+        } else {
+            Err(self.unexpected(symbol))
+        }
+    }
+
+    fn unexpected(&self, expected: &str) -> anyhow::Error {
+        self.peek().map_or_else(
+            || anyhow!("Expected {expected} at end of query"),
+            |token| anyhow!("Expected {expected}, found {token}"),
+        )
+    }
+
+    fn query(&mut self) -> Result<Query> {
+        self.expect_keyword("select")?;
+        let mut columns = Vec::new();
+        if !self.symbol("*") {
+            columns.push(self.column()?);
+            while self.symbol(",") {
+                columns.push(self.column()?);
+            }
+        }
+        let predicate = if self.keyword("where") {
+            Some(self.or()?)
+        } else {
+            None
+        };
+        let group_by = if self.keyword("group") {
+            self.expect_keyword("by")?;
+            Some(self.field()?)
+        } else {
+            None
+        };
+        let order_by = if self.keyword("order") {
+            self.expect_keyword("by")?;
+            let column = self.column()?;
+            let order = if self.keyword("desc") {
+                Order::Descending
+            } else {
+                self.keyword("asc");
+                Order::Ascending
+            };
+            Some((column, order))
+        } else {
+            None
+        };
+        let limit = if self.keyword("limit") {
+            match self.advance()? {
+                Token::Number(n) => Some(n.parse().map_err(|_| anyhow!("Invalid limit {n}"))?),
+                other => return Err(anyhow!("Expected a limit, found {other}")),
+            }
+        } else {
+            None
+        };
+        if let Some(token) = self.peek() {
+            return Err(anyhow!("Unexpected {token} in query"));
+        }
+        Ok(Query {
+            columns,
+            predicate,
+            group_by,
+            order_by,
+            limit,
+        })
+    }
+
+    fn column(&mut self) -> Result<Column> {
+        let aggregation = match self.peek() {
+            Some(Token::Word(word))
+                if self.tokens.get(self.position + 1) == Some(&Token::Symbol("(")) =>
+            {
+                match word.to_ascii_lowercase().as_str() {
+                    "count" => Aggregation::Count,
+                    "sum" => Aggregation::Sum,
+                    "avg" | "mean" => Aggregation::Mean,
+                    "min" => Aggregation::Min,
+                    "max" => Aggregation::Max,
+                    _ => return Err(anyhow!("Unknown aggregate {word}")),
+                }
+            }
+            _ => return Ok(Column::Field(self.field()?)),
+        };
+        self.position += 2;
+        let field = if aggregation == Aggregation::Count && self.symbol("*") {
+            None
+        } else {
+            Some(self.field()?)
+        };
+        self.expect_symbol(")")?;
+        Ok(Column::Aggregate(aggregation, field))
+    }
+
+    fn field(&mut self) -> Result<Field> {
+        match self.advance()? {
+            Token::Word(name) => Ok(Field::parse(&name)),
+            other => Err(anyhow!("Expected a field, found {other}")),
+        }
+    }
+
+    fn or(&mut self) -> Result<Predicate> {
+        let mut predicates = vec![self.and()?];
+        while self.keyword("or") {
+            predicates.push(self.and()?);
+        }
+        Ok(if predicates.len() == 1 {
+            predicates.remove(0)
+        } else {
+            Predicate::Or(predicates)
+        })
+    }
+
+    fn and(&mut self) -> Result<Predicate> {
+        let mut predicates = vec![self.unary()?];
+        while self.keyword("and") {
+            predicates.push(self.unary()?);
+        }
+        Ok(if predicates.len() == 1 {
+            predicates.remove(0)
+        } else {
+            Predicate::And(predicates)
+        })
+    }
+
+    /// Parse a nested predicate with `parse`, one level deeper
+    fn nested(&mut self, parse: fn(&mut Self) -> Result<Predicate>) -> Result<Predicate> {
+        if self.depth == MAX_DEPTH {
+            return Err(anyhow!("Query nests deeper than {MAX_DEPTH} levels"));
+        }
+        self.depth += 1;
+        let predicate = parse(self);
+        self.depth -= 1;
+        predicate
+    }
+
+    fn unary(&mut self) -> Result<Predicate> {
+        if self.keyword("not") {
+            return Ok(Predicate::Not(Box::new(self.nested(Self::unary)?)));
+        }
+        if self.symbol("(") {
+            let predicate = self.nested(Self::or)?;
+            self.expect_symbol(")")?;
+            return Ok(predicate);
+        }
+        let field = self.field()?;
+        let op = match self.advance()? {
+            Token::Symbol(op @ ("=" | "!=" | "<" | "<=" | ">" | ">=")) => op,
+            other => return Err(anyhow!("Expected a comparison, found {other}")),
+        };
+        let key = value(&field, self.advance()?)?;
+        let condition = match op {
+            "<" => Condition::lt(key),
+            "<=" => Condition::le(key),
+            ">" => Condition::gt(key),
+            ">=" => Condition::ge(key),
+            _ => Condition::eq(key),
+        };
+        let compare = Predicate::Compare(field, condition);
+        Ok(if op == "!=" {
+            Predicate::Not(Box::new(compare))
+        } else {
+            compare
+        })
+    }
+}
+
+/// The key a field is compared with; ids, classes and labels are text
+fn value(field: &Field, token: Token) -> Result<IndexKey> {
+    let is_text = !matches!(field, Field::Property(_));
+    match token {
+        Token::Number(n) | Token::Word(n) | Token::Text(n) if is_text => Ok(IndexKey::Text(n)),
+        Token::Number(n) => Ok(IndexKey::from(n.parse::<f64>()?)),
+        Token::Word(word) => Ok(match word.as_str() {
+            "true" => IndexKey::from(true),
+            "false" => IndexKey::from(false),
+            _ => IndexKey::Text(word.strip_prefix('#').unwrap_or(&word).to_string()),
+        }),
+        Token::Text(text) => Ok(IndexKey::Text(text)),
+        Token::Symbol(symbol) => Err(anyhow!("Expected a value, found {symbol}")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let query = Query::parse(
+            "SELECT id, label.site WHERE class = Pump AND label.floor = 3 \
+             AND (flow >= 2.5 OR NOT mode != 'auto') ORDER BY flow DESC LIMIT 5",
+        )
+        .unwrap();
+        assert_eq!(
+            query.columns[1],
+            Column::Field(Field::Label("site".to_string()))
+        );
+        assert_eq!(
+            query.predicate.as_ref().unwrap().conjuncts()[1],
+            Predicate::Compare(Field::Label("floor".to_string()), Condition::eq("3"))
+        );
+        assert_eq!(query.limit, Some(5));
+        let candidates = query.candidates(|_| true);
+        assert_eq!(
+            candidates,
+            TwinQuery::new().class("Pump").label("floor", "3")
+        );
+
+        let query = Query::parse("select class, count(*), max(flow) group by class").unwrap();
+        assert!(query.is_aggregated());
+        assert_eq!(query.columns[2].to_string(), "max(flow)");
+
+        for invalid in [
+            "",
+            "select",
+            "select id where",
+            "select id where flow >",
+            "select id where (flow > 1",
+            "select id limit many",
+            "select id, count(*)",
+            "select * group by class",
+            "select id order by count(*)",
+            "select count(*) order by flow",
+            "select median(flow)",
+            "select id where flow ~ 1",
+            "select id where name = 'open",
+        ] {
+            assert!(Query::parse(invalid).is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn test_nesting_depth_is_capped() {
+        let nested = |depth: usize| {
+            format!(
+                "select id where {}flow > 1{}",
+                "not (".repeat(depth),
+                ")".repeat(depth)
+            )
+        };
+        assert!(Query::parse(&nested(MAX_DEPTH / 2)).is_ok());
+        let error = Query::parse(&nested(100_000)).unwrap_err();
+        assert!(error.to_string().contains("nests deeper"), "{error}");
+    }
+}
@@ -0,0 +1,258 @@
+//! Typed relationships between twins
+//!
+//! Relationships are directed edges from one twin to another, such as a
+//! building that `contains` its floors or a pump that `feeds` a tank. They
+//! are recorded as `RelationshipAdded` and `RelationshipRemoved` events on
+//! the source twin, kept in its state (so snapshots carry them), and
+//! indexed by the `Runtime` for traversal in both directions.
+//!
+//! `contains` forms a hierarchy: a twin has at most one container and
+//! containment never loops.
+
+use crate::twin::TwinId;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashSet, VecDeque};
+use std::fmt;
+
+/// What a relationship means
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(from = "String", into = "String")]
+pub enum RelationshipKind {
+    /// The target is part of the source: site → building → floor
+    Contains,
+    /// The source supplies the target: pump → tank
+    Feeds,
+    /// The twins are linked without a hierarchy
+    ConnectedTo,
+    /// Any other, by name
+    Custom(String),
+}
+
+impl RelationshipKind {
+    /// The name used in events and messages
+    pub fn as_str(&self) -> &str {
+        match self {
+            Self::Contains => "contains",
+            Self::Feeds => "feeds",
+            Self::ConnectedTo => "connectedTo",
+            Self::Custom(name) => name,
+        }
+    }
+}
+
+impl From<&str> for RelationshipKind {
+    fn from(name: &str) -> Self {
+        match name {
+            "contains" => Self::Contains,
+            "feeds" => Self::Feeds,
+            "connectedTo" => Self::ConnectedTo,
+            other => Self::Custom(other.to_string()),
+        }
+    }
+}
+
+impl From<String> for RelationshipKind {
+    fn from(name: String) -> Self {
+        Self::from(name.as_str())
+    }
+}
+
+impl From<RelationshipKind> for String {
+    fn from(kind: RelationshipKind) -> Self {
+        kind.as_str().to_string()
+    }
+}
+
+impl fmt::Display for RelationshipKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// An outgoing relationship of a twin
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct Relationship {
+    pub kind: RelationshipKind,
+    pub target: TwinId,
+}
+
+/// Which way to follow relationships
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    /// From source to target
+    Outgoing,
+    /// From target to source
+    Incoming,
+    Both,
+}
+
+/// Relationships of all twins, indexed both ways
+#[derive(Debug, Clone, Default)]
+pub struct RelationshipGraph {
+    outgoing: BTreeMap<TwinId, BTreeSet<Relationship>>,
+    incoming: BTreeMap<TwinId, BTreeSet<(RelationshipKind, TwinId)>>,
+}
+
+impl RelationshipGraph {
+    /// Add an edge, returning whether it is new
+    pub fn add(&mut self, source: TwinId, kind: RelationshipKind, target: TwinId) -> bool {
+        let added = self
+            .outgoing
+            .entry(source)
+            .or_default()
+            .insert(Relationship {
+                kind: kind.clone(),
+                target,
+            });
+        self.incoming
+            .entry(target)
+            .or_default()
+            .insert((kind, source));
+        added
+    }
+
+    /// Remove an edge, returning whether it existed
+    pub fn remove(&mut self, source: TwinId, kind: &RelationshipKind, target: TwinId) -> bool {
+        let removed = self.outgoing.get_mut(&source).is_some_and(|edges| {
+            edges.remove(&Relationship {
+                kind: kind.clone(),
+                target,
+            })
+        });
+        if let Some(edges) = self.incoming.get_mut(&target) {
+            edges.remove(&(kind.clone(), source));
+        }
+        removed
+    }
+
+    /// Replace the outgoing edges of `source`
+    pub fn set_outgoing(&mut self, source: TwinId, relationships: &BTreeSet<Relationship>) {
+        for old in self.outgoing.remove(&source).unwrap_or_default() {
+            if let Some(edges) = self.incoming.get_mut(&old.target) {
+                edges.remove(&(old.kind, source));
+            }
+        }
+        for relationship in relationships {
+            self.add(source, relationship.kind.clone(), relationship.target);
+        }
+    }
+
+    /// The twins directly related to `twin`, in `direction`, by
+    /// relationships of `kind` or of any kind
+    pub fn related(
+        &self,
+        twin: TwinId,
+        kind: Option<&RelationshipKind>,
+        direction: Direction,
+    ) -> Vec<TwinId> {
+        let matches = |k: &RelationshipKind| kind.is_none_or(|kind| kind == k);
+        let mut related = Vec::new();
+        if direction != Direction::Incoming {
+            related.extend(
+                self.outgoing
+                    .get(&twin)
+                    .into_iter()
+                    .flatten()
+                    .filter(|r| matches(&r.kind))
+                    .map(|r| r.target),
+            );
+        }
+        if direction != Direction::Outgoing {
+            related.extend(
+                self.incoming
+                    .get(&twin)
+                    .into_iter()
+                    .flatten()
+                    .filter(|(k, _)| matches(k))
+                    .map(|(_, source)| *source),
+            );
+        }
+        related
+    }
+
+    /// The twin containing `twin`
+    pub fn container(&self, twin: TwinId) -> Option<TwinId> {
+        self.related(twin, Some(&RelationshipKind::Contains), Direction::Incoming)
+            .first()
+            .copied()
+    }
+
+    /// The containers of `twin`, nearest first
+    pub fn ancestors(&self, twin: TwinId) -> Vec<TwinId> {
+        let mut ancestors = Vec::new();
+        let mut current = twin;
+        while let Some(container) = self.container(current) {
+            if container == twin || ancestors.contains(&container) {
+                break;
+            }
+            ancestors.push(container);
+            current = container;
+        }
+        ancestors
+    }
+
+    /// The twins reachable from `twin` within `hops` relationships,
+    /// followed in `direction`, nearest first
+    pub fn neighbors(
+        &self,
+        twin: TwinId,
+        hops: usize,
+        kind: Option<&RelationshipKind>,
+        direction: Direction,
+    ) -> Vec<TwinId> {
+        let mut seen = HashSet::from([twin]);
+        let mut found = Vec::new();
+        let mut queue = VecDeque::from([(twin, 0)]);
+        while let Some((current, distance)) = queue.pop_front() {
+            if distance == hops {
+                continue;
+            }
+            for next in self.related(current, kind, direction) {
+                if seen.insert(next) {
+                    found.push(next);
+                    queue.push_back((next, distance + 1));
+                }
+            }
+        }
+        found
+    }
+
+    /// Number of edges
+    pub fn len(&self) -> usize {
+        self.outgoing.values().map(BTreeSet::len).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_traversal() {
+        let [site, building, floor, room, pump] = [(); 5].map(|()| TwinId::new());
+        let mut graph = RelationshipGraph::default();
+        graph.add(site, RelationshipKind::Contains, building);
+        graph.add(building, RelationshipKind::Contains, floor);
+        graph.add(floor, RelationshipKind::Contains, room);
+        graph.add(pump, RelationshipKind::Feeds, room);
+
+        assert_eq!(graph.ancestors(room), [floor, building, site]);
+        assert_eq!(
+            graph.neighbors(site, 2, None, Direction::Outgoing),
+            [building, floor]
+        );
+        assert_eq!(graph.related(room, None, Direction::Incoming).len(), 2);
+
+        assert!(graph.remove(floor, &RelationshipKind::Contains, room));
+        assert!(!graph.remove(floor, &RelationshipKind::Contains, room));
+        assert!(graph.ancestors(room).is_empty());
+        assert_eq!(
+            RelationshipKind::from("connectedTo"),
+            RelationshipKind::ConnectedTo
+        );
+    }
+}
@@ -2,16 +2,35 @@
 //!
 //! Manages the lifecycle of twins with efficient memory usage.
 
-use crate::event::{EventStore, SnapshotStore, TwinEvent, TwinSnapshot};
+use crate::aggregate::{AggregateInput, AggregateProperty, AggregateState};
+use crate::alert::AlertRule;
+use crate::alias::{Alias, AliasRegistry};
+use crate::computed::ComputedProperty;
+use crate::event::{
+    EventContext, EventEnvelope, EventMetadata, EventStore, SnapshotStore, TwinEvent, TwinSnapshot,
+};
+use crate::history::HistoryConfig;
+use crate::index::{QueryPage, SecondaryIndex, TwinQuery};
+use crate::message::Message;
+use crate::property;
+use crate::quantity::Quantity;
+use crate::query::{Query, QueryStream};
+use crate::relationship::{Direction, RelationshipGraph, RelationshipKind};
+use crate::state_machine::{StateMachine, Trigger};
+use crate::storage::group_commit::GroupCommitter;
 use crate::storage::memory_store::MemoryEventStore;
 use crate::twin::{Twin, TwinId, TwinState};
+use crate::validation::ClassSchema;
 use crate::value::Value;
 use anyhow::{anyhow, Result};
 use chrono::Utc;
-use dashmap::DashMap;
+use dashmap::{DashMap, DashSet};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::hash::Hash;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
 use std::time::{Duration, Instant};
-use tokio::sync::RwLock;
+use tokio::sync::{broadcast, MappedMutexGuard, Mutex, MutexGuard, OwnedMutexGuard, RwLock};
 
 /// Configuration for the runtime
 #[derive(Debug, Clone)]
@@ -27,6 +46,17 @@ pub struct RuntimeConfig {
 
     /// Maximum number of active twins in memory
     pub max_active_twins: Option<usize>,
+
+    /// Group concurrent telemetry appends into batches of at most this
+    /// many events, sharing one store flush (`None` appends individually)
+    pub group_commit: Option<usize>,
+
+    /// Telemetry history kept per twin property
+    pub history: HistoryConfig,
+
+    /// Recorded events a slow change feed subscriber may fall behind by
+    /// before it misses some
+    pub change_feed_capacity: usize,
 }
 
 impl Default for RuntimeConfig {
@@ -36,24 +66,54 @@ impl Default for RuntimeConfig {
             eviction_interval: Duration::from_secs(60), // Check every minute
             snapshot_on_eviction: true,
             max_active_twins: None,
+            group_commit: None,
+            history: HistoryConfig::default(),
+            change_feed_capacity: 1024,
         }
     }
 }
 
+/// Transitions a single message or update may cascade through, by the
+/// actions of one transition firing the next
+const MAX_CASCADE: usize = 32;
+
+/// Events read per page when building indexes or recovering telemetry
+/// history from the log
+const HISTORY_PAGE: usize = 1000;
+
+/// An item of stored history, in the order indexes apply them
+enum Stored {
+    Snapshot(TwinSnapshot),
+    Event(TwinEvent),
+}
+
+tokio::task_local! {
+    /// Transitions taken above the current one in a cascade
+    static CASCADE_DEPTH: usize;
+}
+
 /// Active twin wrapper with last access tracking
 pub struct ActiveTwin {
     pub twin: RwLock<Twin>,
     last_accessed: RwLock<Instant>,
+    /// The version of the latest event of the twin its state reflects,
+    /// advanced while the twin is write locked
+    version: AtomicU64,
 }
 
 impl ActiveTwin {
-    fn new(twin: Twin) -> Self {
+    fn new(twin: Twin, version: u64) -> Self {
         Self {
             twin: RwLock::new(twin),
             last_accessed: RwLock::new(Instant::now()),
+            version: AtomicU64::new(version),
         }
     }
 
+    fn applied(&self, version: u64) {
+        self.version.fetch_max(version, Ordering::SeqCst);
+    }
+
     async fn touch(&self) {
         *self.last_accessed.write().await = Instant::now();
     }
@@ -65,18 +125,42 @@ pub struct Runtime {
     event_store: Arc<dyn EventStore>,
     snapshot_store: Arc<dyn SnapshotStore>,
     active_twins: Arc<DashMap<TwinId, Arc<ActiveTwin>>>,
+    telemetry_committer: Option<GroupCommitter>,
+    class_rules: DashMap<String, Vec<AlertRule>>,
+    twin_rules: DashMap<TwinId, Vec<AlertRule>>,
+    computed: DashMap<String, Vec<ComputedProperty>>,
+    schemas: DashMap<String, ClassSchema>,
+    aggregates: DashMap<String, Vec<AggregateProperty>>,
+    /// Contributions to the aggregates of each parent, built on first use
+    rollups: DashMap<TwinId, Vec<AggregateState>>,
+    /// Locks of the parents whose aggregates are being published, so they
+    /// are recorded in the order they were computed
+    rollup_locks: DashMap<TwinId, Arc<Mutex<()>>>,
+    /// Relationships and aliases of all twins, built from the stores on
+    /// first use
+    index: Mutex<Option<TwinIndex>>,
+    /// Locks of the twins whose relationships are being changed, so a
+    /// change is checked and recorded without holding the index
+    relation_locks: DashMap<TwinId, Arc<Mutex<()>>>,
+    /// Held while adding containment, as its checks span many twins
+    containment_lock: Mutex<()>,
+    /// The twins new devices are created from, per alias namespace
+    prototypes: DashMap<String, TwinId>,
+    /// Locks of the aliases being changed, so changes to one alias are
+    /// serialized without holding the index while twins are created
+    alias_locks: DashMap<Alias, Arc<Mutex<()>>>,
+    /// Twins by class, label and indexed property, built on first query
+    secondary: Mutex<Option<SecondaryIndex>>,
+    indexed_properties: DashSet<String>,
+    machines: DashMap<String, StateMachine>,
+    changes: broadcast::Sender<(u64, EventEnvelope)>,
 }
 
 impl Runtime {
     /// Create a new runtime with the given configuration
     pub fn new(config: RuntimeConfig) -> Self {
         let store = Arc::new(MemoryEventStore::new());
-        Self {
-            config,
-            event_store: store.clone(),
-            snapshot_store: store,
-            active_twins: Arc::new(DashMap::new()),
-        }
+        Self::with_stores(config, store.clone(), store)
     }
 
     /// Create a runtime with custom stores
@@ -85,18 +169,207 @@ impl Runtime {
         event_store: Arc<dyn EventStore>,
         snapshot_store: Arc<dyn SnapshotStore>,
     ) -> Self {
+        let telemetry_committer = config
+            .group_commit
+            .map(|max_batch| GroupCommitter::new(event_store.clone(), max_batch));
+        let (changes, _) = broadcast::channel(config.change_feed_capacity.max(1));
         Self {
             config,
             event_store,
             snapshot_store,
             active_twins: Arc::new(DashMap::new()),
+            telemetry_committer,
+            class_rules: DashMap::new(),
+            twin_rules: DashMap::new(),
+            computed: DashMap::new(),
+            schemas: DashMap::new(),
+            aggregates: DashMap::new(),
+            rollups: DashMap::new(),
+            rollup_locks: DashMap::new(),
+            index: Mutex::new(None),
+            prototypes: DashMap::new(),
+            alias_locks: DashMap::new(),
+            relation_locks: DashMap::new(),
+            containment_lock: Mutex::new(()),
+            secondary: Mutex::new(None),
+            indexed_properties: DashSet::new(),
+            machines: DashMap::new(),
+            changes,
+        }
+    }
+
+    /// Receive every event this runtime records from now on, with its version
+    pub fn subscribe(&self) -> broadcast::Receiver<(u64, EventEnvelope)> {
+        self.changes.subscribe()
+    }
+
+    /// Evaluate `rule` on telemetry of every twin of `class_name`,
+    /// replacing its rule of the same name
+    pub async fn add_class_rule(&self, class_name: impl Into<String>, rule: AlertRule) {
+        let class_name = class_name.into();
+        replace_rule(
+            &mut self.class_rules.entry(class_name.clone()).or_default(),
+            rule,
+        );
+        self.reconfigure(|twin| twin.class_name() == class_name)
+            .await;
+    }
+
+    /// Evaluate `rule` on telemetry of one twin, replacing its rule (or its
+    /// class's rule) of the same name
+    pub async fn add_twin_rule(&self, twin_id: TwinId, rule: AlertRule) {
+        replace_rule(&mut self.twin_rules.entry(twin_id).or_default(), rule);
+        self.reconfigure(|twin| twin.id() == twin_id).await;
+    }
+
+    /// Calculate `property` on every twin of `class_name`, replacing its
+    /// computed property of the same name
+    pub async fn define_computed(&self, class_name: impl Into<String>, property: ComputedProperty) {
+        let class_name = class_name.into();
+        {
+            let mut properties = self.computed.entry(class_name.clone()).or_default();
+            if let Some(existing) = properties.iter_mut().find(|p| p.name() == property.name()) {
+                *existing = property;
+            } else {
+                properties.push(property);
+            }
+        }
+        self.reconfigure(|twin| twin.class_name() == class_name)
+            .await;
+    }
+
+    /// Validate writes and telemetry of every twin of `class_name` against
+    /// `schema`, replacing its previous schema
+    pub async fn define_schema(&self, class_name: impl Into<String>, schema: ClassSchema) {
+        let class_name = class_name.into();
+        self.schemas.insert(class_name.clone(), schema);
+        self.reconfigure(|twin| twin.class_name() == class_name)
+            .await;
+    }
+
+    /// Roll up `property` from the children of every twin of `class_name`,
+    /// replacing its aggregate property of the same name. The aggregates of
+    /// active twins are updated right away, others on their next change.
+    pub async fn define_aggregate(
+        &self,
+        class_name: impl Into<String>,
+        property: AggregateProperty,
+    ) -> Result<()> {
+        let class_name = class_name.into();
+        {
+            let mut properties = self.aggregates.entry(class_name.clone()).or_default();
+            if let Some(existing) = properties.iter_mut().find(|p| p.name() == property.name()) {
+                *existing = property;
+            } else {
+                properties.push(property);
+            }
+        }
+        self.rollups.clear();
+
+        let active: Vec<_> = self
+            .active_twins
+            .iter()
+            .map(|entry| entry.value().clone())
+            .collect();
+        for active in active {
+            let parent = {
+                let twin = active.twin.read().await;
+                (twin.class_name() == class_name).then(|| twin.id())
+            };
+            if let Some(parent) = parent {
+                self.build_rollup(parent).await?;
+                self.publish_rollup(parent).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Drive every twin of `class_name` by `machine`, replacing its
+    /// previous state machine
+    pub fn define_state_machine(&self, class_name: impl Into<String>, machine: StateMachine) {
+        self.machines.insert(class_name.into(), machine);
+    }
+
+    /// The schema of a class, for export
+    pub fn schema(&self, class_name: &str) -> Option<ClassSchema> {
+        self.schemas.get(class_name).map(|schema| schema.clone())
+    }
+
+    /// The rules evaluated for a twin: its class's, then its own
+    fn rules_for(&self, class_name: &str, twin_id: TwinId) -> Vec<AlertRule> {
+        let mut rules = self
+            .class_rules
+            .get(class_name)
+            .map(|rules| rules.clone())
+            .unwrap_or_default();
+        if let Some(own) = self.twin_rules.get(&twin_id) {
+            for rule in own.iter() {
+                replace_rule(&mut rules, rule.clone());
+            }
+        }
+        rules
+    }
+
+    /// Whether telemetry for a twin that is not active must be checked by
+    /// loading it, for its rules or the schema, computed properties or
+    /// state machine of its class. Twins not stored yet are not checked.
+    async fn checks_telemetry(&self, twin_id: TwinId) -> Result<bool> {
+        if self.twin_rules.contains_key(&twin_id) {
+            return Ok(true);
+        }
+        if self.class_rules.is_empty()
+            && self.schemas.is_empty()
+            && self.machines.is_empty()
+            && self.computed.is_empty()
+        {
+            return Ok(false);
+        }
+        let Some(class_name) = self.secondary().await?.class_of(twin_id).map(String::from) else {
+            return Ok(false);
+        };
+        Ok(self.class_rules.contains_key(&class_name)
+            || self.schemas.contains_key(&class_name)
+            || self.machines.contains_key(&class_name)
+            || self.computed.contains_key(&class_name))
+    }
+
+    /// Give a twin the rules, computed properties and schema of its class
+    fn configure(&self, twin: &mut Twin) {
+        twin.set_alert_rules(self.rules_for(twin.class_name(), twin.id()));
+        twin.set_schema(
+            self.schemas
+                .get(twin.class_name())
+                .map(|schema| schema.clone())
+                .unwrap_or_default(),
+        );
+        let computed = self
+            .computed
+            .get(twin.class_name())
+            .map(|properties| properties.clone())
+            .unwrap_or_default();
+        twin.set_computed(computed);
+    }
+
+    /// Reconfigure the matching active twins
+    async fn reconfigure(&self, matches: impl Fn(&Twin) -> bool) {
+        let active: Vec<_> = self
+            .active_twins
+            .iter()
+            .map(|entry| entry.value().clone())
+            .collect();
+        for active in active {
+            let mut twin = active.twin.write().await;
+            if matches(&twin) {
+                self.configure(&mut twin);
+            }
         }
     }
 
     /// Create a new twin
     pub async fn create_twin(&self, class_name: impl Into<String>) -> Result<TwinId> {
-        let twin = Twin::new(class_name.into());
+        let mut twin = Twin::new(class_name.into()).with_history(self.config.history);
         let twin_id = twin.id();
+        self.configure(&mut twin);
 
         // Record creation event
         let event = TwinEvent::Created {
@@ -104,11 +377,11 @@ impl Runtime {
             class_name: twin.class_name().to_string(),
             timestamp: Utc::now(),
         };
-        self.event_store.append(event).await?;
+        let active = ActiveTwin::new(twin, 0);
+        self.record(&active, event).await?;
 
         // Add to active twins
-        self.active_twins
-            .insert(twin_id, Arc::new(ActiveTwin::new(twin)));
+        self.active_twins.insert(twin_id, Arc::new(active));
 
         Ok(twin_id)
     }
@@ -127,8 +400,20 @@ impl Runtime {
 
     /// Load a twin from events/snapshots
     async fn load_twin(&self, twin_id: TwinId) -> Result<Arc<ActiveTwin>> {
+        let (twin, version) = self.restore_twin(twin_id, true).await?;
+        let active = Arc::new(ActiveTwin::new(twin, version));
+        self.active_twins.insert(twin_id, active.clone());
+
+        Ok(active)
+    }
+
+    /// Rebuild a twin from events/snapshots, without activating it,
+    /// along with the version of the latest event it reflects. Without
+    /// `with_history` its telemetry history only holds the events after
+    /// its snapshot.
+    async fn restore_twin(&self, twin_id: TwinId, with_history: bool) -> Result<(Twin, u64)> {
         // Try to load from snapshot first
-        let (state, start_version) =
+        let (state, start_version, alerts) =
             if let Some(snapshot) = self.snapshot_store.get_snapshot(twin_id).await? {
                 let state = TwinState {
                     id: snapshot.twin_id,
@@ -137,10 +422,14 @@ impl Runtime {
                     parent_id: snapshot.parent_id,
                     created_at: snapshot.timestamp,
                     updated_at: snapshot.timestamp,
+                    metadata: snapshot.metadata,
+                    relationships: snapshot.relationships,
+                    aliases: snapshot.aliases,
+                    labels: snapshot.labels,
                 };
-                (Some(state), snapshot.event_version)
+                (Some(state), snapshot.event_version, snapshot.alerts)
             } else {
-                (None, 0)
+                (None, 0, BTreeSet::new())
             };
 
         // Replay events after snapshot
@@ -152,26 +441,120 @@ impl Runtime {
 
         // Create twin from first event if no snapshot
         let had_snapshot = state.is_some();
-        let mut twin = if let Some(s) = state {
+        let twin = if let Some(s) = state {
             Twin::from_state(s)
         } else if let Some((_, first_event)) = events.first() {
             match first_event {
-                TwinEvent::Created { class_name, .. } => Twin::new(class_name.clone()),
+                TwinEvent::Created {
+                    class_name,
+                    timestamp,
+                    ..
+                } => Twin::from_state(TwinState {
+                    id: twin_id,
+                    class_name: class_name.clone(),
+                    properties: BTreeMap::new(),
+                    parent_id: None,
+                    created_at: *timestamp,
+                    updated_at: *timestamp,
+                    metadata: BTreeMap::new(),
+                    relationships: BTreeSet::new(),
+                    aliases: BTreeSet::new(),
+                    labels: BTreeMap::new(),
+                }),
                 _ => return Err(anyhow!("First event must be Created")),
             }
         } else {
             return Err(anyhow!("No state or events found"));
         };
+        let mut twin = twin.with_history(self.config.history);
+        self.configure(&mut twin);
+
+        if had_snapshot {
+            twin.restore_alerts(&alerts);
+            if with_history {
+                self.recover_history(twin_id, &mut twin, start_version)
+                    .await?;
+            }
+        }
 
         // Replay remaining events
         for (_, event) in events.iter().skip(usize::from(!had_snapshot)) {
             Self::apply_event(&mut twin, event)?;
         }
+        let version = events
+            .iter()
+            .map(|(version, _)| *version)
+            .fold(start_version, u64::max);
 
-        let active = Arc::new(ActiveTwin::new(twin));
-        self.active_twins.insert(twin_id, active.clone());
+        Ok((twin, version))
+    }
 
-        Ok(active)
+    /// Read a twin, without loading it if it is not active. A twin that is
+    /// not active is read without the telemetry history before its
+    /// snapshot.
+    pub(crate) async fn inspect<T>(
+        &self,
+        twin_id: TwinId,
+        read: impl FnOnce(&Twin) -> T,
+    ) -> Result<T> {
+        let active = self.active_twins.get(&twin_id).map(|twin| twin.clone());
+        if let Some(active) = active {
+            return Ok(read(&*active.twin.read().await));
+        }
+        Ok(read(&self.restore_twin(twin_id, false).await?.0))
+    }
+
+    /// Recover the telemetry history a snapshot does not hold from the
+    /// events up to its `version`, reading back only until the window
+    /// ends or every property seen has `max_samples`
+    async fn recover_history(&self, twin_id: TwinId, twin: &mut Twin, version: u64) -> Result<()> {
+        let history = self.config.history;
+        if !history.is_enabled() {
+            return Ok(());
+        }
+
+        let now = Utc::now();
+        let start = history
+            .window
+            .and_then(|window| chrono::Duration::from_std(window).ok())
+            .and_then(|window| now.checked_sub_signed(window));
+        let mut samples: HashMap<String, usize> = HashMap::new();
+        let mut recovered = Vec::new();
+        let mut before = version.saturating_add(1);
+        'pages: loop {
+            let page = self
+                .event_store
+                .get_envelopes_before(twin_id, before, HISTORY_PAGE)
+                .await?;
+            let Some((first, _)) = page.first() else {
+                break;
+            };
+            before = *first;
+
+            for (_, envelope) in page.into_iter().rev() {
+                let TwinEvent::TelemetryReceived {
+                    data, timestamp, ..
+                } = envelope.event
+                else {
+                    continue;
+                };
+                if start.is_some_and(|start| timestamp < start) {
+                    break 'pages;
+                }
+                for (property, _) in &data {
+                    *samples.entry(property.clone()).or_default() += 1;
+                }
+                recovered.push((data, timestamp));
+                if !samples.is_empty() && samples.values().all(|n| *n >= history.max_samples) {
+                    break 'pages;
+                }
+            }
+        }
+
+        for (data, timestamp) in recovered.into_iter().rev() {
+            twin.record_history(&data, timestamp);
+        }
+        Ok(())
     }
 
     /// Apply an event to a twin
@@ -180,19 +563,53 @@ impl Runtime {
             TwinEvent::PropertyChanged {
                 property,
                 new_value,
+                timestamp,
+                ..
+            } => {
+                // Persisted computed properties are recalculated instead
+                if twin.computed().iter().any(|p| p.name() == property) {
+                    return Ok(());
+                }
+                twin.replay_property(property, new_value, *timestamp)?;
+            }
+            TwinEvent::MessageSent {
+                selector,
+                args,
+                result: Ok(_),
                 ..
+            } if property::is_setter(selector) => {
+                twin.send(&Message::Send {
+                    selector: selector.clone(),
+                    args: args.clone(),
+                })?;
+            }
+            TwinEvent::TelemetryReceived {
+                data, timestamp, ..
             } => {
-                twin.send(&crate::message::Message::SetProperty(
-                    property.clone(),
-                    new_value.clone(),
-                ))?;
+                // The alerts this raised or cleared follow in the log
+                twin.replay_telemetry(data, *timestamp)?;
             }
-            TwinEvent::TelemetryReceived { data, .. } => {
-                let updates: Vec<_> = data
-                    .iter()
-                    .map(|(k, v)| (k.clone(), Value::Float((*v).into())))
-                    .collect();
-                twin.send(&crate::message::Message::UpdateProperties(updates))?;
+            TwinEvent::AlertRaised { .. } | TwinEvent::AlertCleared { .. } => {
+                twin.replay_alert(event);
+            }
+            TwinEvent::RelationshipAdded { .. } | TwinEvent::RelationshipRemoved { .. } => {
+                twin.replay_relationship(event);
+            }
+            TwinEvent::AliasAssigned { .. }
+            | TwinEvent::AliasRemoved { .. }
+            | TwinEvent::Cloned { .. } => {
+                twin.replay_identity(event);
+            }
+            TwinEvent::LabelSet { .. } | TwinEvent::LabelRemoved { .. } => {
+                twin.replay_label(event);
+            }
+            TwinEvent::StateChanged {
+                property,
+                to,
+                timestamp,
+                ..
+            } => {
+                twin.replay_property(property, &Value::Symbol(to.clone()), *timestamp)?;
             }
             _ => {} // Other events don't modify state
         }
@@ -200,53 +617,1219 @@ impl Runtime {
     }
 
     /// Update twin with telemetry
+    ///
+    /// Inactive twins with alert rules, or of a class with a schema,
+    /// computed properties or a state machine, are loaded so their rules
+    /// see every update and invalid telemetry, such as values for computed
+    /// properties, is rejected before it is recorded; the alerts raised and
+    /// cleared are recorded as caused by the telemetry, as are the changes
+    /// it makes to the aggregates of the twin's parents. Failing to update
+    /// those aggregates is logged rather than returned, as the telemetry is
+    /// already recorded.
     pub async fn update_telemetry(&self, twin_id: TwinId, data: Vec<(String, f64)>) -> Result<()> {
-        // Record event first (for durability)
-        let event = TwinEvent::TelemetryReceived {
-            twin_id,
-            data: data.clone(),
-            timestamp: Utc::now(),
+        let (metadata, alerting) = self.record_telemetry(twin_id, &data).await?;
+        EventContext::current()
+            .unwrap_or_default()
+            .caused_by(&metadata)
+            .scope(self.roll_up(twin_id, &data, &[], alerting))
+            .await;
+        Ok(())
+    }
+
+    /// Record telemetry and what it causes on the twin itself, returning
+    /// its metadata and, if known, whether the twin has an active alert
+    async fn record_telemetry(
+        &self,
+        twin_id: TwinId,
+        data: &[(String, f64)],
+    ) -> Result<(EventMetadata, Option<bool>)> {
+        // Load before recording, or the replay would already include this
+        // update
+        let active = match self.active_twins.get(&twin_id).map(|twin| twin.clone()) {
+            Some(active) => Some(active),
+            None if self.checks_telemetry(twin_id).await? => Some(self.get_twin(twin_id).await?),
+            None => None,
         };
-        self.event_store.append(event).await?;
+        let timestamp = Utc::now();
+        let envelope = Self::envelope(TwinEvent::TelemetryReceived {
+            twin_id,
+            data: data.to_vec(),
+            timestamp,
+        });
+        let metadata = envelope.metadata.clone();
 
-        // Update in-memory twin if active
-        if let Some(active) = self.active_twins.get(&twin_id) {
-            active.touch().await;
-            let updates: Vec<_> = data
-                .into_iter()
-                .map(|(k, v)| (k, Value::Float(v.into())))
-                .collect();
+        // Update in-memory twin if active, holding its lock from validation
+        // until the update is applied, so a snapshot never sees it recorded
+        // but not applied
+        let mut alerting = None;
+        if let Some(active) = &active {
             let mut twin = active.twin.write().await;
-            twin.send(&crate::message::Message::UpdateProperties(updates))?;
+            twin.check_telemetry(data)?;
+            // Record event first (for durability)
+            let version = self.append_telemetry(envelope).await?;
+            active.touch().await;
+            let alerts = twin.apply_telemetry(data, timestamp)?;
+            active.applied(version);
+            alerting = Some(twin.alerts().active().next().is_some());
+            if !alerts.is_empty() {
+                let context = EventContext::current().unwrap_or_default();
+                context
+                    .caused_by(&metadata)
+                    .scope(async {
+                        for event in alerts {
+                            self.record(active, event).await?;
+                        }
+                        Ok::<_, anyhow::Error>(())
+                    })
+                    .await?;
+            }
+            drop(twin);
+            if let Some(machine) = self.machine_of(active).await {
+                EventContext::current()
+                    .unwrap_or_default()
+                    .caused_by(&metadata)
+                    .scope(self.change_state(active, &machine, &Trigger::Telemetry))
+                    .await?;
+            }
+        } else {
+            self.append_telemetry(envelope).await?;
         }
         // If not active, we don't load it - true lazy loading!
+        Ok((metadata, alerting))
+    }
+
+    /// Append telemetry, through the group committer if there is one
+    async fn append_telemetry(&self, envelope: EventEnvelope) -> Result<u64> {
+        let Some(committer) = &self.telemetry_committer else {
+            return self.append(envelope).await;
+        };
+        let version = committer.append_envelope(envelope.clone()).await?;
+        self.reindex(&envelope.event).await;
+        self.publish(version, envelope);
+        Ok(version)
+    }
+
+    /// Update twin with telemetry sent in any units
+    ///
+    /// Values are converted to the unit declared in each property's
+    /// metadata and recorded as plain telemetry. Properties without a unit
+    /// take the unit of their first reading. Values in an incompatible unit
+    /// reject the whole update.
+    pub async fn update_telemetry_in_units(
+        &self,
+        twin_id: TwinId,
+        data: Vec<(String, Quantity)>,
+    ) -> Result<()> {
+        let active = self.get_twin(twin_id).await?;
+        let undeclared: Vec<(String, String)> = {
+            let twin = active.twin.read().await;
+            data.iter()
+                .filter(|(name, _)| twin.metadata(name).and_then(|m| m.unit.as_ref()).is_none())
+                .map(|(name, q)| (name.clone(), q.unit().to_string()))
+                .collect()
+        };
+        for (name, unit) in undeclared {
+            let message = Message::Send {
+                selector: "unitOf:put:".to_string(),
+                args: vec![Value::from(name), Value::from(unit)],
+            };
+            self.send(twin_id, &message).await?;
+        }
+
+        let data = active.twin.read().await.to_declared_units(&data)?;
+        self.update_telemetry(twin_id, data).await
+    }
+
+    /// Send a message to a twin, recording the changes it causes
+    ///
+    /// Custom messages are recorded as `MessageSent`, and the property
+    /// changes they make as `PropertyChanged` events caused by it, with
+    /// removed properties changing to nil. Events carry the current
+    /// `EventContext`.
+    pub async fn send(&self, twin_id: TwinId, message: &Message) -> Result<Value> {
+        let active = self.get_twin(twin_id).await?;
+        if let Some(state) = self.trigger(&active, message).await? {
+            return Ok(Value::Symbol(state));
+        }
+
+        // Hold the twin lock until the changes are recorded, so the log
+        // order matches the order they were applied in
+        let mut twin = active.twin.write().await;
+        let before = twin.state().properties.clone();
+        let result = twin.send(message);
+        let changes = changed_properties(&before, &twin.state().properties);
+
+        let context = EventContext::current().unwrap_or_default();
+        let context = if let Message::Send { selector, args } = message {
+            let event = TwinEvent::MessageSent {
+                twin_id,
+                selector: selector.clone(),
+                args: args.clone(),
+                result: result
+                    .as_ref()
+                    .map(Clone::clone)
+                    .map_err(ToString::to_string),
+                timestamp: Utc::now(),
+            };
+            let metadata = self.record(&active, event).await?;
+            context.caused_by(&metadata)
+        } else {
+            context
+        };
+
+        context
+            .scope(async {
+                for (property, old_value, new_value) in changes {
+                    let event = TwinEvent::PropertyChanged {
+                        twin_id,
+                        property,
+                        old_value,
+                        new_value,
+                        timestamp: Utc::now(),
+                    };
+                    self.record(&active, event).await?;
+                }
+                Ok::<_, anyhow::Error>(())
+            })
+            .await?;
+        drop(twin);
+
+        result
+    }
+
+    /// Parse a message for a twin, as `Message::parse` does, except that a
+    /// unary selector triggering the twin's state machine is a send
+    pub async fn parse_message(&self, twin_id: TwinId, input: &str) -> Result<Message> {
+        let active = self.get_twin(twin_id).await?;
+        match self.machine_of(&active).await {
+            Some(machine) => machine.parse(input),
+            None => Message::parse(input),
+        }
+    }
+
+    /// The state machine of a twin's class
+    async fn machine_of(&self, active: &ActiveTwin) -> Option<StateMachine> {
+        let twin = active.twin.read().await;
+        self.machines
+            .get(twin.class_name())
+            .map(|machine| machine.clone())
+    }
+
+    /// Take the transition `message` fires, returning the new state, if it
+    /// is a send whose selector triggers the twin's state machine. The
+    /// state property cannot be written directly.
+    async fn trigger(&self, active: &ActiveTwin, message: &Message) -> Result<Option<String>> {
+        let Some(machine) = self.machine_of(active).await else {
+            return Ok(None);
+        };
+        let state = machine.property();
+        match message {
+            Message::SetProperty(property, _) if property == state => {
+                Err(anyhow!("Property {state} only changes by transitions"))
+            }
+            Message::UpdateProperties(updates) if updates.iter().any(|(p, _)| p == state) => {
+                Err(anyhow!("Property {state} only changes by transitions"))
+            }
+            Message::Send { selector, .. } if machine.handles(selector) => {
+                let trigger = Trigger::Message(selector.clone());
+                match self.change_state(active, &machine, &trigger).await? {
+                    Some(to) => Ok(Some(to)),
+                    None => {
+                        let twin = active.twin.read().await;
+                        let error = anyhow!(
+                            "{} cannot {selector} in state {}",
+                            twin.class_name(),
+                            machine.state(&twin)
+                        );
+                        drop(twin);
+                        Err(error)
+                    }
+                }
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// Take the transition `trigger` fires on a twin, if any, returning the
+    /// new state. The exit and entry actions are sent as caused by it; a
+    /// cascade of transitions fired by actions fails past `MAX_CASCADE`,
+    /// so actions that keep triggering each other cannot recurse forever.
+    async fn change_state(
+        &self,
+        active: &ActiveTwin,
+        machine: &StateMachine,
+        trigger: &Trigger,
+    ) -> Result<Option<String>> {
+        let mut twin = active.twin.write().await;
+        let Some(transition) = machine.fire(&twin, trigger) else {
+            return Ok(None);
+        };
+        let twin_id = twin.id();
+        let (from, to) = (machine.state(&twin), transition.to().to_string());
+        let depth = CASCADE_DEPTH.try_with(|depth| *depth).unwrap_or(0);
+        if depth >= MAX_CASCADE {
+            return Err(anyhow!(
+                "Transition from {from} to {to} of twin {twin_id} cascades through more than {MAX_CASCADE} transitions"
+            ));
+        }
+        let event = TwinEvent::StateChanged {
+            twin_id,
+            property: machine.property().to_string(),
+            from: from.clone(),
+            to: to.clone(),
+            trigger: trigger.to_string(),
+            timestamp: Utc::now(),
+        };
+        let metadata = self.record(active, event.clone()).await?;
+        Self::apply_event(&mut twin, &event)?;
+        drop(twin);
+
+        let actions = async {
+            for action in machine.actions(&from, &to) {
+                Box::pin(self.send(twin_id, &action)).await?;
+            }
+            Ok::<_, anyhow::Error>(())
+        };
+        EventContext::current()
+            .unwrap_or_default()
+            .caused_by(&metadata)
+            .scope(CASCADE_DEPTH.scope(depth + 1, actions))
+            .await?;
+        Ok(Some(to))
+    }
+
+    /// The transitions a twin took, oldest first, as far back as its log
+    /// goes
+    pub async fn state_changes(&self, twin_id: TwinId) -> Result<Vec<TwinEvent>> {
+        Ok(self
+            .event_store
+            .get_events(twin_id, 0)
+            .await?
+            .into_iter()
+            .map(|(_, event)| event)
+            .filter(|event| matches!(event, TwinEvent::StateChanged { .. }))
+            .collect())
+    }
+
+    /// Wrap an event with metadata from the current `EventContext`
+    fn envelope(event: TwinEvent) -> EventEnvelope {
+        EventEnvelope {
+            metadata: EventContext::current().unwrap_or_default().metadata(),
+            event,
+        }
+    }
+
+    /// Append an event of an active twin stamped with the current
+    /// `EventContext`. The caller holds the twin write locked until the
+    /// event is applied.
+    async fn record(&self, active: &ActiveTwin, event: TwinEvent) -> Result<EventMetadata> {
+        let envelope = Self::envelope(event);
+        let metadata = envelope.metadata.clone();
+        active.applied(self.append(envelope).await?);
+        Ok(metadata)
+    }
+
+    /// Append an envelope and publish it to the change feed
+    async fn append(&self, envelope: EventEnvelope) -> Result<u64> {
+        let version = self.event_store.append_envelope(envelope.clone()).await?;
+        self.reindex(&envelope.event).await;
+        self.publish(version, envelope);
+        Ok(version)
+    }
+
+    /// Append envelopes in one batch, so they are recorded all or none,
+    /// and publish them to the change feed
+    async fn append_batch(&self, envelopes: Vec<EventEnvelope>) -> Result<Vec<u64>> {
+        let versions = self.event_store.append_envelopes(envelopes.clone()).await?;
+        for (version, envelope) in versions.iter().zip(envelopes) {
+            self.reindex(&envelope.event).await;
+            self.publish(*version, envelope);
+        }
+        Ok(versions)
+    }
+
+    fn publish(&self, version: u64, envelope: EventEnvelope) {
+        // Sending only fails when nobody is subscribed
+        let _ = self.changes.send((version, envelope));
+    }
+
+    /// Relate `source` to `target`, returning whether the relationship is
+    /// new. A twin can only be contained once, and containment cannot loop.
+    pub async fn relate(
+        &self,
+        source: TwinId,
+        kind: impl Into<RelationshipKind>,
+        target: TwinId,
+    ) -> Result<bool> {
+        let kind = kind.into();
+        if source == target {
+            return Err(anyhow!("Twin {source} cannot be related to itself"));
+        }
+        let active = self.get_twin(source).await?;
+        self.get_twin(target).await?;
+
+        let lock = KeyLock::acquire(&self.relation_locks, source).await;
+        let containment = if kind == RelationshipKind::Contains {
+            Some(self.containment_lock.lock().await)
+        } else {
+            None
+        };
+        let index = self.graph().await?;
+        if index
+            .related(source, Some(&kind), Direction::Outgoing)
+            .contains(&target)
+        {
+            return Ok(false);
+        }
+        if containment.is_some() {
+            if let Some(container) = index.container(target) {
+                return Err(anyhow!("Twin {target} is already contained by {container}"));
+            }
+            if index.ancestors(source).contains(&target) {
+                return Err(anyhow!("Twin {target} contains {source}"));
+            }
+        }
+        drop(index);
+
+        let event = TwinEvent::RelationshipAdded {
+            twin_id: source,
+            kind: kind.clone(),
+            target,
+            timestamp: Utc::now(),
+        };
+        let mut twin = active.twin.write().await;
+        let metadata = self.record(&active, event.clone()).await?;
+        twin.replay_relationship(&event);
+        drop(twin);
+        self.graph().await?.add(source, kind.clone(), target);
+        drop(containment);
+        drop(lock);
+        let rollup = EventContext::current()
+            .unwrap_or_default()
+            .caused_by(&metadata)
+            .scope(self.rollup_relationship(source, &kind, target, true))
+            .await;
+        if let Err(e) = rollup {
+            // The relationship is recorded either way
+            tracing::warn!("Failed to roll up aggregates of twin {}: {}", source, e);
+        }
+        Ok(true)
+    }
+
+    /// Remove a relationship, returning whether it existed
+    pub async fn unrelate(
+        &self,
+        source: TwinId,
+        kind: impl Into<RelationshipKind>,
+        target: TwinId,
+    ) -> Result<bool> {
+        let kind = kind.into();
+        let active = self.get_twin(source).await?;
+        let lock = KeyLock::acquire(&self.relation_locks, source).await;
+        let related = self
+            .graph()
+            .await?
+            .related(source, Some(&kind), Direction::Outgoing)
+            .contains(&target);
+        if !related {
+            return Ok(false);
+        }
+
+        let event = TwinEvent::RelationshipRemoved {
+            twin_id: source,
+            kind: kind.clone(),
+            target,
+            timestamp: Utc::now(),
+        };
+        let mut twin = active.twin.write().await;
+        let metadata = self.record(&active, event.clone()).await?;
+        twin.replay_relationship(&event);
+        drop(twin);
+        self.graph().await?.remove(source, &kind, target);
+        drop(lock);
+        let rollup = EventContext::current()
+            .unwrap_or_default()
+            .caused_by(&metadata)
+            .scope(self.rollup_relationship(source, &kind, target, false))
+            .await;
+        if let Err(e) = rollup {
+            // The relationship is recorded either way
+            tracing::warn!("Failed to roll up aggregates of twin {}: {}", source, e);
+        }
+        Ok(true)
+    }
 
+    /// The twins directly related to `twin` in `direction`, by
+    /// relationships of `kind`, or of any kind if `None`
+    pub async fn related(
+        &self,
+        twin: TwinId,
+        kind: Option<RelationshipKind>,
+        direction: Direction,
+    ) -> Result<Vec<TwinId>> {
+        Ok(self.graph().await?.related(twin, kind.as_ref(), direction))
+    }
+
+    /// The twins `twin` contains
+    pub async fn children(&self, twin: TwinId) -> Result<Vec<TwinId>> {
+        self.related(twin, Some(RelationshipKind::Contains), Direction::Outgoing)
+            .await
+    }
+
+    /// The twins containing `twin`, nearest first
+    pub async fn ancestors(&self, twin: TwinId) -> Result<Vec<TwinId>> {
+        Ok(self.graph().await?.ancestors(twin))
+    }
+
+    /// The twins within `hops` relationships of `twin` in either direction,
+    /// by relationships of `kind`, or of any kind if `None`; nearest first
+    pub async fn neighbors(
+        &self,
+        twin: TwinId,
+        hops: usize,
+        kind: Option<RelationshipKind>,
+    ) -> Result<Vec<TwinId>> {
+        Ok(self
+            .graph()
+            .await?
+            .neighbors(twin, hops, kind.as_ref(), Direction::Both))
+    }
+
+    /// Send `message` to every twin `twin` has a `kind` relationship to,
+    /// answering each target's result
+    pub async fn send_related(
+        &self,
+        twin: TwinId,
+        kind: impl Into<RelationshipKind>,
+        message: &Message,
+    ) -> Result<Vec<(TwinId, Result<Value>)>> {
+        let targets = self
+            .related(twin, Some(kind.into()), Direction::Outgoing)
+            .await?;
+        let mut results = Vec::with_capacity(targets.len());
+        for target in targets {
+            results.push((target, self.send(target, message).await));
+        }
+        Ok(results)
+    }
+
+    /// Name `twin` by `name` within `namespace`, returning whether the
+    /// alias is new. An alias names one twin at a time.
+    pub async fn assign_alias(
+        &self,
+        twin: TwinId,
+        namespace: impl Into<String>,
+        name: impl Into<String>,
+    ) -> Result<bool> {
+        let alias = Alias::new(namespace, name);
+        self.get_twin(twin).await?;
+        let _lock = self.lock_alias(&alias).await;
+        let mut registry = self.alias_registry().await?;
+        match registry.lookup(&alias) {
+            Some(named) if named == twin => Ok(false),
+            Some(named) => Err(anyhow!("Alias {alias} already names twin {named}")),
+            None => {
+                self.record_alias(&mut registry, twin, alias).await?;
+                drop(registry);
+                Ok(true)
+            }
+        }
+    }
+
+    /// Remove an alias, returning whether it existed
+    pub async fn remove_alias(
+        &self,
+        namespace: impl Into<String>,
+        name: impl Into<String>,
+    ) -> Result<bool> {
+        let alias = Alias::new(namespace, name);
+        let _lock = self.lock_alias(&alias).await;
+        let mut registry = self.alias_registry().await?;
+        let Some(twin_id) = registry.lookup(&alias) else {
+            return Ok(false);
+        };
+        let active = self.get_twin(twin_id).await?;
+        let event = TwinEvent::AliasRemoved {
+            twin_id,
+            alias: alias.clone(),
+            timestamp: Utc::now(),
+        };
+        let mut twin = active.twin.write().await;
+        self.record(&active, event.clone()).await?;
+        twin.replay_identity(&event);
+        drop(twin);
+        registry.remove(&alias);
+        drop(registry);
+        Ok(true)
+    }
+
+    /// The twin named `name` within `namespace`
+    pub async fn lookup_alias(
+        &self,
+        namespace: impl Into<String>,
+        name: impl Into<String>,
+    ) -> Result<Option<TwinId>> {
+        let alias = Alias::new(namespace, name);
+        Ok(self.alias_registry().await?.lookup(&alias))
+    }
+
+    /// The aliases of `twin`
+    pub async fn aliases_of(&self, twin: TwinId) -> Result<Vec<Alias>> {
+        Ok(self.alias_registry().await?.aliases_of(twin))
+    }
+
+    /// Create the twins of unknown aliases in `namespace` from `prototype`,
+    /// when they first report telemetry
+    pub fn set_prototype(&self, namespace: impl Into<String>, prototype: TwinId) {
+        self.prototypes.insert(namespace.into(), prototype);
+    }
+
+    /// Create a twin with the class, properties, units and labels of
+    /// `prototype`
+    ///
+    /// The clone is recorded as its `Created` event, followed by `Cloned` and
+    /// the events setting what it copied, and is built by replaying them so
+    /// it matches the twin later loaded from the log.
+    pub async fn clone_twin(&self, prototype: TwinId) -> Result<TwinId> {
+        self.create_clone(prototype, None).await
+    }
+
+    /// Clone `prototype`, named by `alias` if given, recording all its
+    /// events in one batch so the clone is created whole or not at all
+    async fn create_clone(&self, prototype: TwinId, alias: Option<Alias>) -> Result<TwinId> {
+        let source = self.get_twin(prototype).await?;
+        let TwinState {
+            class_name,
+            properties,
+            metadata,
+            labels,
+            ..
+        } = source.twin.read().await.state().clone();
+        let units = metadata
+            .into_iter()
+            .filter_map(|(name, m)| Some((name, m.unit?)));
+
+        let twin_id = TwinId::new();
+        let timestamp = Utc::now();
+        let mut events = vec![TwinEvent::Cloned {
+            twin_id,
+            source_id: prototype,
+            timestamp,
+        }];
+        events.extend(properties.into_iter().map(|(property, new_value)| {
+            TwinEvent::PropertyChanged {
+                twin_id,
+                property,
+                old_value: None,
+                new_value,
+                timestamp,
+            }
+        }));
+        events.extend(units.map(|(name, unit)| TwinEvent::MessageSent {
+            twin_id,
+            selector: "unitOf:put:".to_string(),
+            args: vec![Value::from(name), Value::from(unit)],
+            result: Ok(Value::Nil),
+            timestamp,
+        }));
+        events.extend(labels.into_iter().map(|(key, value)| TwinEvent::LabelSet {
+            twin_id,
+            key,
+            value,
+            timestamp,
+        }));
+        events.extend(alias.iter().map(|alias| TwinEvent::AliasAssigned {
+            twin_id,
+            alias: alias.clone(),
+            timestamp,
+        }));
+
+        let mut twin = Twin::from_state(TwinState {
+            id: twin_id,
+            class_name: class_name.clone(),
+            properties: BTreeMap::new(),
+            parent_id: None,
+            created_at: timestamp,
+            updated_at: timestamp,
+            metadata: BTreeMap::new(),
+            relationships: BTreeSet::new(),
+            aliases: BTreeSet::new(),
+            labels: BTreeMap::new(),
+        })
+        .with_history(self.config.history);
+        self.configure(&mut twin);
+        for event in &events {
+            Self::apply_event(&mut twin, event)?;
+        }
+
+        let created = Self::envelope(TwinEvent::Created {
+            twin_id,
+            class_name,
+            timestamp,
+        });
+        let context = EventContext::current()
+            .unwrap_or_default()
+            .caused_by(&created.metadata);
+        let mut envelopes = vec![created];
+        envelopes.extend(events.into_iter().map(|event| EventEnvelope {
+            metadata: context.metadata(),
+            event,
+        }));
+        let versions = self.append_batch(envelopes).await?;
+
+        let active = ActiveTwin::new(twin, versions.last().copied().unwrap_or_default());
+        self.active_twins.insert(twin_id, Arc::new(active));
+        if let Some(alias) = alias {
+            self.alias_registry().await?.assign(alias, twin_id);
+        }
+
+        Ok(twin_id)
+    }
+
+    /// Update the twin named `name` within `namespace` with telemetry,
+    /// returning its id
+    ///
+    /// A device reporting under an unknown alias gets a clone of its
+    /// namespace's prototype, named by the alias; without a prototype the
+    /// update is rejected. The clone's events and its alias are recorded in
+    /// one batch, so a failure leaves no unnamed clone behind.
+    pub async fn update_telemetry_by_alias(
+        &self,
+        namespace: impl Into<String>,
+        name: impl Into<String>,
+        data: Vec<(String, f64)>,
+    ) -> Result<TwinId> {
+        let alias = Alias::new(namespace, name);
+        let known = self.alias_registry().await?.lookup(&alias);
+        let twin_id = if let Some(twin_id) = known {
+            twin_id
+        } else {
+            // Held while creating, so a device reporting twice at once
+            // still gets a single twin, while other devices carry on
+            let _lock = self.lock_alias(&alias).await;
+            let known = self.alias_registry().await?.lookup(&alias);
+            if let Some(twin_id) = known {
+                twin_id
+            } else {
+                let prototype = self
+                    .prototypes
+                    .get(&alias.namespace)
+                    .map(|prototype| *prototype)
+                    .ok_or_else(|| anyhow!("Unknown alias {alias}"))?;
+                self.create_clone(prototype, Some(alias)).await?
+            }
+        };
+        self.update_telemetry(twin_id, data).await?;
+        Ok(twin_id)
+    }
+
+    /// Record that `alias`, which names no twin yet, names `twin_id`
+    async fn record_alias(
+        &self,
+        registry: &mut AliasRegistry,
+        twin_id: TwinId,
+        alias: Alias,
+    ) -> Result<()> {
+        let active = self.get_twin(twin_id).await?;
+        let event = TwinEvent::AliasAssigned {
+            twin_id,
+            alias: alias.clone(),
+            timestamp: Utc::now(),
+        };
+        let mut twin = active.twin.write().await;
+        self.record(&active, event.clone()).await?;
+        twin.replay_identity(&event);
+        drop(twin);
+        registry.assign(alias, twin_id);
+        Ok(())
+    }
+
+    /// Lock `alias` until the returned guard drops
+    async fn lock_alias(&self, alias: &Alias) -> KeyLock<'_, Alias> {
+        KeyLock::acquire(&self.alias_locks, alias.clone()).await
+    }
+
+    /// The index of twins, loading it on first use
+    async fn index(&self) -> Result<MappedMutexGuard<'_, TwinIndex>> {
+        let mut index = self.index.lock().await;
+        if index.is_none() {
+            *index = Some(self.load_index().await?);
+        }
+        Ok(MutexGuard::map(index, |index| {
+            index.get_or_insert_with(TwinIndex::default)
+        }))
+    }
+
+    /// The relationship index, loading it on first use
+    async fn graph(&self) -> Result<MappedMutexGuard<'_, RelationshipGraph>> {
+        Ok(MappedMutexGuard::map(self.index().await?, |index| {
+            &mut index.graph
+        }))
+    }
+
+    /// The alias registry, loading it on first use
+    async fn alias_registry(&self) -> Result<MappedMutexGuard<'_, AliasRegistry>> {
+        Ok(MappedMutexGuard::map(self.index().await?, |index| {
+            &mut index.aliases
+        }))
+    }
+
+    /// Build the index from the snapshots and the events after them, as
+    /// compaction may have removed those covered by a snapshot
+    async fn load_index(&self) -> Result<TwinIndex> {
+        let mut index = TwinIndex::default();
+        self.stored_history(|stored| match stored {
+            Stored::Snapshot(snapshot) => {
+                index
+                    .graph
+                    .set_outgoing(snapshot.twin_id, &snapshot.relationships);
+                for alias in snapshot.aliases {
+                    index.aliases.assign(alias, snapshot.twin_id);
+                }
+            }
+            Stored::Event(event) => match event {
+                TwinEvent::RelationshipAdded {
+                    twin_id,
+                    kind,
+                    target,
+                    ..
+                } => {
+                    index.graph.add(twin_id, kind, target);
+                }
+                TwinEvent::RelationshipRemoved {
+                    twin_id,
+                    kind,
+                    target,
+                    ..
+                } => {
+                    index.graph.remove(twin_id, &kind, target);
+                }
+                TwinEvent::AliasAssigned { twin_id, alias, .. } => {
+                    index.aliases.assign(alias, twin_id);
+                }
+                TwinEvent::AliasRemoved { alias, .. } => {
+                    index.aliases.remove(&alias);
+                }
+                TwinEvent::Destroyed { twin_id, .. } => {
+                    index.aliases.remove_twin(twin_id);
+                }
+                _ => {}
+            },
+        })
+        .await?;
+        Ok(index)
+    }
+
+    /// Feed `apply` the snapshots of all twins, then the events after them
+    /// page by page, for building indexes; compaction may have removed
+    /// those covered by a snapshot
+    async fn stored_history(&self, mut apply: impl FnMut(Stored)) -> Result<()> {
+        let snapshots = self.snapshot_store.list_snapshots().await?;
+        let covered: HashMap<TwinId, u64> = snapshots
+            .iter()
+            .map(|snapshot| (snapshot.twin_id, snapshot.event_version))
+            .collect();
+        for snapshot in snapshots {
+            apply(Stored::Snapshot(snapshot));
+        }
+
+        let mut after = 0;
+        loop {
+            let page = self
+                .event_store
+                .get_envelopes_after(after, HISTORY_PAGE)
+                .await?;
+            let Some((last, _)) = page.last() else {
+                break;
+            };
+            after = *last;
+
+            for (version, EventEnvelope { event, .. }) in page {
+                if covered
+                    .get(&event.twin_id())
+                    .is_none_or(|covered| version > *covered)
+                {
+                    apply(Stored::Event(event));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Label `twin` with `key=value`, replacing its value for `key`
+    pub async fn set_label(
+        &self,
+        twin_id: TwinId,
+        key: impl Into<String>,
+        value: impl Into<String>,
+    ) -> Result<()> {
+        let (key, value) = (key.into(), value.into());
+        let active = self.get_twin(twin_id).await?;
+        let mut twin = active.twin.write().await;
+        if twin.labels().get(&key) == Some(&value) {
+            return Ok(());
+        }
+        let event = TwinEvent::LabelSet {
+            twin_id,
+            key,
+            value,
+            timestamp: Utc::now(),
+        };
+        self.record(&active, event.clone()).await?;
+        twin.replay_label(&event);
+        drop(twin);
         Ok(())
     }
 
+    /// Remove the label `key` of `twin`, returning whether it had one
+    pub async fn remove_label(&self, twin_id: TwinId, key: impl Into<String>) -> Result<bool> {
+        let key = key.into();
+        let active = self.get_twin(twin_id).await?;
+        let mut twin = active.twin.write().await;
+        if !twin.labels().contains_key(&key) {
+            return Ok(false);
+        }
+        let event = TwinEvent::LabelRemoved {
+            twin_id,
+            key,
+            timestamp: Utc::now(),
+        };
+        self.record(&active, event.clone()).await?;
+        twin.replay_label(&event);
+        drop(twin);
+        Ok(true)
+    }
+
+    /// Index the values of `property` for queries
+    pub async fn index_property(&self, property: impl Into<String>) {
+        if self.indexed_properties.insert(property.into()) {
+            // Rebuilt with the new property on the next query
+            *self.secondary.lock().await = None;
+        }
+    }
+
+    /// Find twins, loaded or not, by class, labels and indexed properties
+    pub async fn query(&self, query: &TwinQuery) -> Result<QueryPage> {
+        self.secondary().await?.query(query)
+    }
+
+    /// Stream the rows of `query` over every twin, active or persisted
+    pub fn select(&self, query: &Query) -> QueryStream<'_> {
+        let indexed = |property: &str| self.indexed_properties.contains(property);
+        let candidates = query.candidates(indexed);
+        let from_index = query.reads_only_indexed(indexed);
+        QueryStream::new(self, query.clone(), candidates, from_index)
+    }
+
+    /// Send `message` to every twin `query` selects, returning each result
+    pub async fn broadcast(
+        &self,
+        query: &Query,
+        message: &Message,
+    ) -> Result<Vec<(TwinId, Result<Value>)>> {
+        if query.is_aggregated() {
+            return Err(anyhow!("Cannot send to aggregated rows"));
+        }
+        let mut targets = Vec::new();
+        let mut rows = self.select(query);
+        while let Some(row) = rows.next().await? {
+            targets.extend(row.twin_id);
+        }
+        let mut results = Vec::with_capacity(targets.len());
+        for target in targets {
+            results.push((target, self.send(target, message).await));
+        }
+        Ok(results)
+    }
+
+    /// The secondary index, building it on first use
+    pub(crate) async fn secondary(&self) -> Result<MappedMutexGuard<'_, SecondaryIndex>> {
+        let mut secondary = self.secondary.lock().await;
+        if secondary.is_none() {
+            *secondary = Some(self.load_secondary().await?);
+        }
+        Ok(MutexGuard::map(secondary, |secondary| {
+            secondary.get_or_insert_with(SecondaryIndex::default)
+        }))
+    }
+
+    /// Build the secondary index from the stores
+    async fn load_secondary(&self) -> Result<SecondaryIndex> {
+        let properties = self.indexed_properties.iter().map(|p| p.key().clone());
+        let mut index = SecondaryIndex::new(properties);
+        self.stored_history(|stored| match stored {
+            Stored::Snapshot(snapshot) => index.apply_snapshot(&snapshot),
+            Stored::Event(event) => index.apply(&event),
+        })
+        .await?;
+        Ok(index)
+    }
+
+    /// Keep the secondary index, once built, current with a recorded event
+    async fn reindex(&self, event: &TwinEvent) {
+        if let Some(index) = self.secondary.lock().await.as_mut() {
+            index.apply(event);
+        }
+    }
+
+    /// Collect the contributions to the aggregates of `parent`, unless
+    /// they already are
+    async fn build_rollup(&self, parent: TwinId) -> Result<()> {
+        if self.rollups.contains_key(&parent) {
+            return Ok(());
+        }
+        let (class_name, properties) = self
+            .inspect(parent, |twin| {
+                (
+                    twin.class_name().to_string(),
+                    twin.state().properties.clone(),
+                )
+            })
+            .await?;
+        let aggregates = self
+            .aggregates
+            .get(&class_name)
+            .map(|aggregates| aggregates.clone())
+            .unwrap_or_default();
+
+        let mut states = Vec::with_capacity(aggregates.len());
+        for aggregate in aggregates {
+            let published = properties.get(aggregate.name()).and_then(Value::as_f64);
+            let children = self.graph().await?.related(
+                parent,
+                Some(aggregate.relationship()),
+                Direction::Outgoing,
+            );
+            let mut state = AggregateState::new(aggregate, published);
+            for child in children {
+                let value = self
+                    .inspect(child, |twin| state.property().contribution(twin))
+                    .await
+                    .ok()
+                    .flatten();
+                state.set(child, value);
+            }
+            states.push(state);
+        }
+        self.rollups.insert(parent, states);
+        Ok(())
+    }
+
+    /// Record the aggregates of `parent` that changed as its telemetry, and
+    /// set those left without contributions to nil. On failure the rollup
+    /// is dropped, to be rebuilt from what the parent recorded.
+    ///
+    /// Values are computed and recorded under the parent's lock, so a
+    /// concurrent publish cannot record an older value after a newer one.
+    /// They roll further up once it is released, so no task holds two.
+    async fn publish_rollup(&self, parent: TwinId) -> Result<()> {
+        let lock = KeyLock::acquire(&self.rollup_locks, parent).await;
+        let changes: Vec<(String, Value)> = self
+            .rollups
+            .get_mut(&parent)
+            .map(|mut states| {
+                states
+                    .iter_mut()
+                    .filter_map(|state| {
+                        Some((state.property().name().to_string(), state.publish()?))
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+        let mut data = Vec::new();
+        let mut cleared = Vec::new();
+        for (name, value) in changes {
+            match value.as_f64() {
+                Some(value) => data.push((name, value)),
+                None => cleared.push(name),
+            }
+        }
+
+        if data.is_empty() && cleared.is_empty() {
+            return Ok(());
+        }
+
+        let result = async {
+            for name in &cleared {
+                self.send(parent, &Message::SetProperty(name.clone(), Value::Nil))
+                    .await?;
+            }
+            if data.is_empty() {
+                return Ok(None);
+            }
+            self.record_telemetry(parent, &data).await.map(Some)
+        }
+        .await;
+        if result.is_err() {
+            self.rollups.remove(&parent);
+        }
+        drop(lock);
+
+        // Aggregates roll up again as the parent's own telemetry would, and
+        // so do clears, which telemetry cannot carry
+        let context = EventContext::current().unwrap_or_default();
+        let (context, alerting) = match result? {
+            Some((metadata, alerting)) => (context.caused_by(&metadata), alerting),
+            None => (context, None),
+        };
+        Box::pin(context.scope(self.roll_up(parent, &data, &cleared, alerting))).await;
+        Ok(())
+    }
+
+    /// Update the aggregates over `child` after its telemetry, from the
+    /// values it reported, the properties it `cleared` and, if known,
+    /// whether it has an active alert.
+    ///
+    /// Runs after the change is recorded, so failures are logged rather
+    /// than returned.
+    async fn roll_up(
+        &self,
+        child: TwinId,
+        data: &[(String, f64)],
+        cleared: &[String],
+        alerting: Option<bool>,
+    ) {
+        if self.aggregates.is_empty() {
+            return;
+        }
+        let kinds: BTreeSet<RelationshipKind> = self
+            .aggregates
+            .iter()
+            .flat_map(|entry| {
+                entry
+                    .value()
+                    .iter()
+                    .map(|aggregate| aggregate.relationship().clone())
+                    .collect::<Vec<_>>()
+            })
+            .collect();
+
+        for kind in kinds {
+            let parents = match self.graph().await {
+                Ok(graph) => graph.related(child, Some(&kind), Direction::Incoming),
+                Err(e) => {
+                    tracing::warn!("Failed to roll up aggregates over twin {}: {}", child, e);
+                    return;
+                }
+            };
+            for parent in parents {
+                if let Err(e) = self.build_rollup(parent).await {
+                    tracing::warn!("Failed to roll up aggregates of twin {}: {}", parent, e);
+                    continue;
+                }
+                if let Some(mut states) = self.rollups.get_mut(&parent) {
+                    for state in states
+                        .iter_mut()
+                        .filter(|state| *state.property().relationship() == kind)
+                    {
+                        match state.property().input() {
+                            AggregateInput::Property(property) => {
+                                if let Some((_, value)) =
+                                    data.iter().find(|(name, _)| name == property)
+                                {
+                                    state.set(child, Some(*value));
+                                } else if cleared.contains(property) {
+                                    state.set(child, None);
+                                }
+                            }
+                            AggregateInput::Alerting => {
+                                if let Some(alerting) = alerting {
+                                    state.set(child, Some(f64::from(u8::from(alerting))));
+                                }
+                            }
+                        }
+                    }
+                }
+                if let Err(e) = self.publish_rollup(parent).await {
+                    tracing::warn!("Failed to roll up aggregates of twin {}: {}", parent, e);
+                }
+            }
+        }
+    }
+
+    /// Add or remove `child` from the `kind` aggregates of `parent`
+    async fn rollup_relationship(
+        &self,
+        parent: TwinId,
+        kind: &RelationshipKind,
+        child: TwinId,
+        added: bool,
+    ) -> Result<()> {
+        if self.aggregates.is_empty() {
+            return Ok(());
+        }
+        if !self.rollups.contains_key(&parent) {
+            // Built from the current relationships, which include the change
+            self.build_rollup(parent).await?;
+            return self.publish_rollup(parent).await;
+        }
+
+        let properties: Vec<AggregateProperty> = self
+            .rollups
+            .get(&parent)
+            .map(|states| {
+                states
+                    .iter()
+                    .map(|state| state.property().clone())
+                    .filter(|property| property.relationship() == kind)
+                    .collect()
+            })
+            .unwrap_or_default();
+        let values = if added {
+            let twin_values = self
+                .inspect(child, |twin| {
+                    properties
+                        .iter()
+                        .map(|property| property.contribution(twin))
+                        .collect::<Vec<_>>()
+                })
+                .await?;
+            properties.iter().zip(twin_values).collect::<Vec<_>>()
+        } else {
+            properties.iter().map(|property| (property, None)).collect()
+        };
+        if let Some(mut states) = self.rollups.get_mut(&parent) {
+            for (property, value) in values {
+                if let Some(state) = states
+                    .iter_mut()
+                    .find(|state| state.property().name() == property.name())
+                {
+                    state.set(child, value);
+                }
+            }
+        }
+        self.publish_rollup(parent).await
+    }
+
     /// Create a snapshot for a twin
     pub async fn snapshot_twin(&self, twin_id: TwinId) -> Result<()> {
         let active = self.get_twin(twin_id).await?;
 
-        let (class_name, properties, parent_id) = {
+        // Read the version with the state, as events are applied under the
+        // write lock: compaction relies on the snapshot holding every event
+        // up to its version
+        let (state, alerts, version) = {
             let twin = active.twin.read().await;
-            let state = twin.state();
-            let class_name = state.class_name.clone();
-            let properties = state.properties.clone();
-            let parent_id = state.parent_id;
-            drop(twin); // Explicitly drop the lock before the tuple is created
-            (class_name, properties, parent_id)
+            let alerts = twin.alerts().active().map(String::from).collect();
+            (
+                twin.state().clone(),
+                alerts,
+                active.version.load(Ordering::SeqCst),
+            )
         };
 
-        let version = self.event_store.get_latest_version().await?;
-
         let snapshot = TwinSnapshot {
             twin_id,
-            class_name,
-            properties,
-            parent_id,
+            class_name: state.class_name,
+            properties: state.properties,
+            parent_id: state.parent_id,
             event_version: version,
             timestamp: Utc::now(),
+            metadata: state.metadata,
+            relationships: state.relationships,
+            aliases: state.aliases,
+            labels: state.labels,
+            alerts,
         };
 
         self.snapshot_store.save_snapshot(snapshot).await?;
@@ -301,6 +1884,67 @@ impl Runtime {
     }
 }
 
+/// Properties whose value differs between two states, as
+/// `(name, old value, new value)`; removed properties change to nil
+fn changed_properties(
+    before: &BTreeMap<String, Value>,
+    after: &BTreeMap<String, Value>,
+) -> Vec<(String, Option<Value>, Value)> {
+    let names: BTreeSet<&String> = before.keys().chain(after.keys()).collect();
+    names
+        .into_iter()
+        .filter(|name| before.get(*name) != after.get(*name))
+        .map(|name| {
+            let new_value = after.get(name).cloned().unwrap_or(Value::Nil);
+            (name.clone(), before.get(name).cloned(), new_value)
+        })
+        .collect()
+}
+
+/// Add `rule` to `rules`, replacing any rule of the same name
+fn replace_rule(rules: &mut Vec<AlertRule>, rule: AlertRule) {
+    if let Some(existing) = rules.iter_mut().find(|r| r.name == rule.name) {
+        *existing = rule;
+    } else {
+        rules.push(rule);
+    }
+}
+
+/// Indexes over all twins, kept current as their events are recorded
+#[derive(Debug, Default)]
+struct TwinIndex {
+    graph: RelationshipGraph,
+    aliases: AliasRegistry,
+}
+
+/// The lock of one key, such as an alias, forgotten once nobody waits
+/// for it
+struct KeyLock<'a, K: Eq + Hash> {
+    locks: &'a DashMap<K, Arc<Mutex<()>>>,
+    key: K,
+    guard: Option<OwnedMutexGuard<()>>,
+}
+
+impl<'a, K: Eq + Hash + Clone + Send + Sync> KeyLock<'a, K> {
+    /// Lock `key` until the returned guard drops
+    async fn acquire(locks: &'a DashMap<K, Arc<Mutex<()>>>, key: K) -> Self {
+        let lock = locks.entry(key.clone()).or_default().clone();
+        Self {
+            locks,
+            key,
+            guard: Some(lock.lock_owned().await),
+        }
+    }
+}
+
+impl<K: Eq + Hash> Drop for KeyLock<'_, K> {
+    fn drop(&mut self) {
+        drop(self.guard.take());
+        self.locks
+            .remove_if(&self.key, |_, lock| Arc::strong_count(lock) == 1);
+    }
+}
+
 /// Runtime statistics
 #[derive(Debug, Clone)]
 pub struct RuntimeStats {
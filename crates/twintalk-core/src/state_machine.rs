@@ -0,0 +1,291 @@
+//! State machines
+//!
+//! Equipment that moves between modes declares them per class, like
+//! computed properties and alert rules:
+//!
+//! ```
+//! use twintalk_core::index::Condition;
+//! use twintalk_core::message::Message;
+//! use twintalk_core::msg;
+//! use twintalk_core::state_machine::{StateMachine, Transition};
+//!
+//! let pump = StateMachine::new("state", "Idle")
+//!     .transition(Transition::on("start", "Idle", "Running"))
+//!     .transition(
+//!         Transition::on_telemetry("Running", "Fault")
+//!             .when("temperature", Condition::gt(90.0)),
+//!     )
+//!     .transition(Transition::on("repair", "Fault", "Maintenance"))
+//!     .transition(Transition::on("resume", "Maintenance", "Idle"))
+//!     .on_entry("Fault", msg!(alarm: true))
+//!     .on_exit("Fault", msg!(alarm: false));
+//!
+//! assert_eq!(pump.parse("start").unwrap(), Message::send("start", vec![]));
+//! assert_eq!(pump.parse("alarm").unwrap(), msg!(alarm));
+//! ```
+//!
+//! The state is held as a symbol in a property of the twin, so it can be
+//! read, indexed and queried like any other, and twins without it are in
+//! the initial state. It only changes by transitions: a message whose
+//! selector triggers one fires the first transition from the current state
+//! whose guards hold, and telemetry fires those triggered by telemetry.
+//! Only `Message::Send` fires transitions, so property reads never change
+//! state. Text such as `start` parses as a property read; parse it with
+//! `StateMachine::parse` or `Runtime::parse_message` to send it as a
+//! trigger.
+//! Each transition is recorded as a `StateChanged` event, and the exit
+//! actions of the old state and the entry actions of the new one are sent
+//! to the twin as caused by it. Actions that fire further transitions
+//! cascade, up to a limit past which the message fails.
+
+use crate::index::Condition;
+use crate::message::Message;
+use crate::query::{Field, Predicate};
+use crate::twin::Twin;
+use anyhow::Result;
+use std::collections::{BTreeSet, HashMap};
+use std::fmt;
+
+/// What fires a transition
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Trigger {
+    /// A message with this selector, such as `start`
+    Message(String),
+    /// Telemetry updating the twin
+    Telemetry,
+}
+
+impl fmt::Display for Trigger {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Message(selector) => write!(f, "{selector}"),
+            Self::Telemetry => write!(f, "telemetry"),
+        }
+    }
+}
+
+/// A move from one state to another
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Transition {
+    trigger: Trigger,
+    from: String,
+    to: String,
+    guards: Vec<Predicate>,
+}
+
+impl Transition {
+    /// Move from `from` to `to` on the message `selector`
+    pub fn on(selector: impl Into<String>, from: impl Into<String>, to: impl Into<String>) -> Self {
+        Self::new(Trigger::Message(selector.into()), from, to)
+    }
+
+    /// Move from `from` to `to` on telemetry, once the guards hold
+    pub fn on_telemetry(from: impl Into<String>, to: impl Into<String>) -> Self {
+        Self::new(Trigger::Telemetry, from, to)
+    }
+
+    fn new(trigger: Trigger, from: impl Into<String>, to: impl Into<String>) -> Self {
+        Self {
+            trigger,
+            from: from.into(),
+            to: to.into(),
+            guards: Vec::new(),
+        }
+    }
+
+    /// Only while `property` satisfies `condition`
+    #[must_use]
+    pub fn when(self, property: impl Into<String>, condition: Condition) -> Self {
+        self.guard(Predicate::Compare(
+            Field::Property(property.into()),
+            condition,
+        ))
+    }
+
+    /// Only while the twin satisfies `predicate`
+    #[must_use]
+    pub fn guard(mut self, predicate: Predicate) -> Self {
+        self.guards.push(predicate);
+        self
+    }
+
+    pub fn trigger(&self) -> &Trigger {
+        &self.trigger
+    }
+
+    pub fn from(&self) -> &str {
+        &self.from
+    }
+
+    pub fn to(&self) -> &str {
+        &self.to
+    }
+
+    /// Whether `trigger` fires the transition on `twin`, in `state`
+    fn fires(&self, twin: &Twin, state: &str, trigger: &Trigger) -> bool {
+        self.trigger == *trigger
+            && self.from == state
+            && self.guards.iter().all(|guard| guard.matches(twin))
+    }
+}
+
+/// The states of a twin class and the transitions between them
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StateMachine {
+    property: String,
+    initial: String,
+    transitions: Vec<Transition>,
+    entry: HashMap<String, Vec<Message>>,
+    exit: HashMap<String, Vec<Message>>,
+}
+
+impl StateMachine {
+    /// A machine holding its state in `property`, starting in `initial`
+    pub fn new(property: impl Into<String>, initial: impl Into<String>) -> Self {
+        Self {
+            property: property.into(),
+            initial: initial.into(),
+            transitions: Vec::new(),
+            entry: HashMap::new(),
+            exit: HashMap::new(),
+        }
+    }
+
+    /// Add a transition, tried after those already added
+    #[must_use]
+    pub fn transition(mut self, transition: Transition) -> Self {
+        self.transitions.push(transition);
+        self
+    }
+
+    /// Send `action` to the twin whenever it enters `state`
+    #[must_use]
+    pub fn on_entry(mut self, state: impl Into<String>, action: Message) -> Self {
+        self.entry.entry(state.into()).or_default().push(action);
+        self
+    }
+
+    /// Send `action` to the twin whenever it leaves `state`
+    #[must_use]
+    pub fn on_exit(mut self, state: impl Into<String>, action: Message) -> Self {
+        self.exit.entry(state.into()).or_default().push(action);
+        self
+    }
+
+    /// The property holding the state
+    pub fn property(&self) -> &str {
+        &self.property
+    }
+
+    pub fn initial(&self) -> &str {
+        &self.initial
+    }
+
+    pub fn transitions(&self) -> &[Transition] {
+        &self.transitions
+    }
+
+    /// Every state named by the machine
+    pub fn states(&self) -> BTreeSet<&str> {
+        self.transitions
+            .iter()
+            .flat_map(|t| [t.from.as_str(), t.to.as_str()])
+            .chain([self.initial.as_str()])
+            .collect()
+    }
+
+    /// The current state of `twin`
+    pub fn state(&self, twin: &Twin) -> String {
+        twin.property(&self.property)
+            .and_then(|value| value.as_str())
+            .unwrap_or(&self.initial)
+            .to_string()
+    }
+
+    /// Whether the message `selector` triggers any transition
+    pub fn handles(&self, selector: &str) -> bool {
+        self.transitions
+            .iter()
+            .any(|t| matches!(&t.trigger, Trigger::Message(s) if s == selector))
+    }
+
+    /// Parse `input` as `Message::parse` does, except that a unary
+    /// selector triggering a transition is a send rather than a read
+    pub fn parse(&self, input: &str) -> Result<Message> {
+        Ok(match Message::parse(input)? {
+            Message::GetProperty(selector) if self.handles(&selector) => {
+                Message::send(selector, Vec::new())
+            }
+            message => message,
+        })
+    }
+
+    /// The transition `trigger` fires on `twin`, if any
+    pub fn fire(&self, twin: &Twin, trigger: &Trigger) -> Option<&Transition> {
+        let state = self.state(twin);
+        self.transitions
+            .iter()
+            .find(|t| t.fires(twin, &state, trigger))
+    }
+
+    /// The actions sent on leaving `from` for `to`, in order
+    pub fn actions(&self, from: &str, to: &str) -> Vec<Message> {
+        let exit = self.exit.get(from).into_iter().flatten();
+        let entry = self.entry.get(to).into_iter().flatten();
+        exit.chain(entry).cloned().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::msg;
+    use crate::value::Value;
+    use std::collections::BTreeMap;
+
+    #[test]
+    fn test_fire() {
+        let machine = StateMachine::new("state", "Idle")
+            .transition(
+                Transition::on("start", "Idle", "Running").when("pressure", Condition::ge(1.0)),
+            )
+            .transition(
+                Transition::on_telemetry("Running", "Fault")
+                    .when("temperature", Condition::gt(90.0)),
+            )
+            .on_exit("Running", msg!(motor: false))
+            .on_entry("Fault", msg!(alarm: true));
+        let mut twin = Twin::new("Pump");
+        let start = Trigger::Message("start".to_string());
+        assert_eq!(machine.state(&twin), "Idle");
+        assert_eq!(machine.fire(&twin, &start), None);
+
+        twin.update_telemetry(BTreeMap::from([("pressure".to_string(), 1.5)]))
+            .unwrap();
+        assert_eq!(
+            machine.fire(&twin, &start).map(Transition::to),
+            Some("Running")
+        );
+        twin.send(&msg!(state: Value::Symbol("Running".to_string())))
+            .unwrap();
+        assert_eq!(machine.fire(&twin, &start), None);
+        assert_eq!(machine.fire(&twin, &Trigger::Telemetry), None);
+
+        twin.update_telemetry(BTreeMap::from([("temperature".to_string(), 95.0)]))
+            .unwrap();
+        assert_eq!(
+            machine.fire(&twin, &Trigger::Telemetry).map(Transition::to),
+            Some("Fault")
+        );
+        assert_eq!(
+            machine.actions("Running", "Fault"),
+            [msg!(motor: false), msg!(alarm: true)]
+        );
+        assert!(machine.handles("start"));
+        assert!(!machine.handles("stop"));
+        assert_eq!(
+            machine.states().into_iter().collect::<Vec<_>>(),
+            ["Fault", "Idle", "Running"]
+        );
+    }
+}
@@ -0,0 +1,372 @@
+//! Tamper-evident, hash-chained audit log
+//!
+//! `HashChainedStore` wraps any event store. Each appended event is stamped
+//! with the SHA-256 of its content chained with the hash of the previous
+//! event of the same twin, stored in the `audit.hash` and `audit.prev_hash`
+//! metadata headers:
+//!
+//! ```text
+//! hash = sha256(prev_hash "\n" event_json "\n" metadata_json)
+//! ```
+//!
+//! Editing, removing or reordering an event breaks the chain at that event,
+//! which `verify` reports. Rewriting the tail of a chain together with its
+//! hashes is only detectable against an earlier `AuditProof`, so proofs
+//! should be exported and kept outside the store.
+//!
+//! Hashes cover events as decoded, so events must not be upcast to a new
+//! schema between appending and verifying.
+//!
+//! Retention compaction (see `storage::retention`) leaves twins with
+//! chained events alone, as removing any of them would break the chain.
+
+use crate::event::{
+    all_time, EventEnvelope, EventMetadata, EventStore, SnapshotStore, TwinSnapshot,
+};
+use crate::twin::TwinId;
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use tokio::sync::Mutex;
+
+/// Header holding an event's chained hash
+pub const HASH_HEADER: &str = "audit.hash";
+
+/// Header holding the hash of the twin's previous event
+pub const PREV_HASH_HEADER: &str = "audit.prev_hash";
+
+/// Previous hash of the first event of a twin
+pub const GENESIS_HASH: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+/// Why a chain is broken
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ChainBreak {
+    /// The event carries no hash
+    Unchained,
+    /// The event's content no longer matches its hash
+    ContentMismatch,
+    /// The event does not link to the previous event's hash, so events were
+    /// removed or reordered
+    LinkMismatch,
+    /// An event covered by a proof is missing from the store
+    Missing,
+}
+
+/// The first event at which a chain fails verification
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BrokenLink {
+    pub twin_id: TwinId,
+    pub version: u64,
+    pub reason: ChainBreak,
+}
+
+impl fmt::Display for BrokenLink {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "audit chain of {} broken at version {}: {:?}",
+            self.twin_id, self.version, self.reason
+        )
+    }
+}
+
+/// Compute the chained hash of an event, ignoring its audit headers
+pub fn chain_hash(prev_hash: &str, envelope: &EventEnvelope) -> Result<String> {
+    let mut metadata = envelope.metadata.clone();
+    strip_audit_headers(&mut metadata);
+
+    let mut hasher = Sha256::new();
+    hasher.update(prev_hash.as_bytes());
+    hasher.update(b"\n");
+    hasher.update(serde_json::to_vec(&envelope.event).map_err(|e| anyhow!(e))?);
+    hasher.update(b"\n");
+    hasher.update(serde_json::to_vec(&metadata).map_err(|e| anyhow!(e))?);
+    Ok(hex::encode(hasher.finalize()))
+}
+
+/// Whether an event was stamped by a `HashChainedStore`
+pub(crate) fn is_chained(metadata: &EventMetadata) -> bool {
+    metadata.headers.contains_key(HASH_HEADER)
+}
+
+fn strip_audit_headers(metadata: &mut EventMetadata) {
+    metadata.headers.remove(HASH_HEADER);
+    metadata.headers.remove(PREV_HASH_HEADER);
+}
+
+/// Verify one twin's events (ascending by version), returning the hash at
+/// the head of the chain
+pub fn verify_chain(
+    twin_id: TwinId,
+    envelopes: &[(u64, EventEnvelope)],
+) -> Result<String, BrokenLink> {
+    let mut prev_hash = GENESIS_HASH.to_string();
+
+    for (version, envelope) in envelopes {
+        let broken = |reason| BrokenLink {
+            twin_id,
+            version: *version,
+            reason,
+        };
+        let headers = &envelope.metadata.headers;
+        let (Some(hash), Some(stored_prev)) =
+            (headers.get(HASH_HEADER), headers.get(PREV_HASH_HEADER))
+        else {
+            return Err(broken(ChainBreak::Unchained));
+        };
+        if *stored_prev != prev_hash {
+            return Err(broken(ChainBreak::LinkMismatch));
+        }
+        if chain_hash(&prev_hash, envelope).ok().as_ref() != Some(hash) {
+            return Err(broken(ChainBreak::ContentMismatch));
+        }
+        prev_hash.clone_from(hash);
+    }
+
+    Ok(prev_hash)
+}
+
+/// A twin's chained history, verifiable without access to the store
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuditProof {
+    pub twin_id: TwinId,
+    pub head_hash: String,
+    pub events: Vec<(u64, EventEnvelope)>,
+    pub exported_at: DateTime<Utc>,
+}
+
+impl AuditProof {
+    /// Check that the events form an intact chain ending at `head_hash`
+    pub fn verify(&self) -> Result<(), BrokenLink> {
+        let head = verify_chain(self.twin_id, &self.events)?;
+        if head == self.head_hash {
+            Ok(())
+        } else {
+            Err(BrokenLink {
+                twin_id: self.twin_id,
+                version: self.events.last().map_or(0, |(v, _)| *v),
+                reason: ChainBreak::ContentMismatch,
+            })
+        }
+    }
+}
+
+/// Event store wrapper that hash-chains every twin's events
+pub struct HashChainedStore<S> {
+    inner: S,
+    /// Last hash per twin, loaded from the store on first append. The lock
+    /// also serializes appends so chains follow version order.
+    heads: Mutex<HashMap<TwinId, String>>,
+}
+
+impl<S: EventStore + SnapshotStore> HashChainedStore<S> {
+    /// Chain events appended to `inner`
+    pub fn new(inner: S) -> Self {
+        Self {
+            inner,
+            heads: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// The wrapped store
+    pub fn inner(&self) -> &S {
+        &self.inner
+    }
+
+    /// Verify a twin's chain, returning the first broken link
+    pub async fn verify(&self, twin_id: TwinId) -> Result<Option<BrokenLink>> {
+        let envelopes = self.inner.get_envelopes(twin_id, 0).await?;
+        Ok(verify_chain(twin_id, &envelopes).err())
+    }
+
+    /// Verify every twin's chain, returning the first broken link of each
+    /// broken chain
+    pub async fn verify_all(&self) -> Result<Vec<BrokenLink>> {
+        let mut by_twin: BTreeMap<_, Vec<_>> = BTreeMap::new();
+        let (start, end) = all_time();
+        for (version, envelope) in self.inner.get_envelopes_in_range(start, end).await? {
+            by_twin
+                .entry(envelope.event.twin_id().0)
+                .or_default()
+                .push((version, envelope));
+        }
+
+        Ok(by_twin
+            .into_iter()
+            .filter_map(|(id, mut envelopes)| {
+                envelopes.sort_by_key(|(v, _)| *v);
+                verify_chain(TwinId(id), &envelopes).err()
+            })
+            .collect())
+    }
+
+    /// Export a twin's history with its head hash
+    pub async fn export_proof(&self, twin_id: TwinId) -> Result<AuditProof> {
+        let events = self.inner.get_envelopes(twin_id, 0).await?;
+        let head_hash = verify_chain(twin_id, &events).map_err(|e| anyhow!("{e}"))?;
+        Ok(AuditProof {
+            twin_id,
+            head_hash,
+            events,
+            exported_at: Utc::now(),
+        })
+    }
+
+    /// Check that every event covered by an earlier proof is still in the
+    /// store unchanged
+    pub async fn verify_against(&self, proof: &AuditProof) -> Result<Option<BrokenLink>> {
+        if let Err(broken) = proof.verify() {
+            return Err(anyhow!("Invalid proof: {broken}"));
+        }
+
+        let current: HashMap<_, _> = self
+            .inner
+            .get_envelopes(proof.twin_id, 0)
+            .await?
+            .into_iter()
+            .collect();
+        for (version, envelope) in &proof.events {
+            let broken = |reason| BrokenLink {
+                twin_id: proof.twin_id,
+                version: *version,
+                reason,
+            };
+            let Some(stored) = current.get(version) else {
+                return Ok(Some(broken(ChainBreak::Missing)));
+            };
+            if stored.metadata.headers.get(HASH_HEADER)
+                != envelope.metadata.headers.get(HASH_HEADER)
+            {
+                return Ok(Some(broken(ChainBreak::ContentMismatch)));
+            }
+        }
+
+        self.verify(proof.twin_id).await
+    }
+
+    async fn head(&self, heads: &HashMap<TwinId, String>, twin_id: TwinId) -> Result<String> {
+        if let Some(head) = heads.get(&twin_id) {
+            return Ok(head.clone());
+        }
+        let last = self.inner.get_envelopes(twin_id, 0).await?.pop();
+        Ok(last
+            .and_then(|(_, envelope)| envelope.metadata.headers.get(HASH_HEADER).cloned())
+            .unwrap_or_else(|| GENESIS_HASH.to_string()))
+    }
+}
+
+#[async_trait]
+impl<S: EventStore + SnapshotStore> EventStore for HashChainedStore<S> {
+    async fn append_envelope(&self, envelope: EventEnvelope) -> Result<u64> {
+        let versions = self.append_envelopes(vec![envelope]).await?;
+        versions
+            .first()
+            .copied()
+            .ok_or_else(|| anyhow!("Append produced no version"))
+    }
+
+    async fn append_envelopes(&self, mut envelopes: Vec<EventEnvelope>) -> Result<Vec<u64>> {
+        let mut heads = self.heads.lock().await;
+
+        // Chain the batch against a copy, so a failed append leaves the
+        // known heads untouched
+        let mut updated = HashMap::new();
+        for envelope in &mut envelopes {
+            let twin_id = envelope.event.twin_id();
+            let prev_hash = match updated.get(&twin_id) {
+                Some(head) => String::clone(head),
+                None => self.head(&heads, twin_id).await?,
+            };
+            strip_audit_headers(&mut envelope.metadata);
+            let hash = chain_hash(&prev_hash, envelope)?;
+            let headers = &mut envelope.metadata.headers;
+            headers.insert(PREV_HASH_HEADER.to_string(), prev_hash);
+            headers.insert(HASH_HEADER.to_string(), hash.clone());
+            updated.insert(twin_id, hash);
+        }
+
+        let versions = self.inner.append_envelopes(envelopes).await?;
+        heads.extend(updated);
+        drop(heads);
+        Ok(versions)
+    }
+
+    /// Imported events keep the hashes they were exported with
+    async fn import_envelopes(&self, envelopes: Vec<(u64, EventEnvelope)>) -> Result<()> {
+        let mut heads = self.heads.lock().await;
+        self.inner.import_envelopes(envelopes).await?;
+        heads.clear();
+        drop(heads);
+        Ok(())
+    }
+
+    async fn get_envelopes(
+        &self,
+        twin_id: TwinId,
+        after_version: u64,
+    ) -> Result<Vec<(u64, EventEnvelope)>> {
+        self.inner.get_envelopes(twin_id, after_version).await
+    }
+
+    async fn get_envelopes_before(
+        &self,
+        twin_id: TwinId,
+        before_version: u64,
+        limit: usize,
+    ) -> Result<Vec<(u64, EventEnvelope)>> {
+        self.inner
+            .get_envelopes_before(twin_id, before_version, limit)
+            .await
+    }
+
+    async fn get_envelopes_after(
+        &self,
+        after_version: u64,
+        limit: usize,
+    ) -> Result<Vec<(u64, EventEnvelope)>> {
+        self.inner.get_envelopes_after(after_version, limit).await
+    }
+
+    async fn get_envelopes_in_range(
+        &self,
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+    ) -> Result<Vec<(u64, EventEnvelope)>> {
+        self.inner.get_envelopes_in_range(start, end).await
+    }
+
+    async fn get_latest_version(&self) -> Result<u64> {
+        self.inner.get_latest_version().await
+    }
+}
+
+#[async_trait]
+impl<S: EventStore + SnapshotStore> SnapshotStore for HashChainedStore<S> {
+    async fn save_snapshot(&self, snapshot: TwinSnapshot) -> Result<()> {
+        self.inner.save_snapshot(snapshot).await
+    }
+
+    async fn get_snapshot(&self, twin_id: TwinId) -> Result<Option<TwinSnapshot>> {
+        self.inner.get_snapshot(twin_id).await
+    }
+
+    async fn list_snapshots(&self) -> Result<Vec<TwinSnapshot>> {
+        self.inner.list_snapshots().await
+    }
+
+    async fn list_snapshots_after(
+        &self,
+        after: Option<TwinId>,
+        limit: usize,
+    ) -> Result<Vec<TwinSnapshot>> {
+        self.inner.list_snapshots_after(after, limit).await
+    }
+
+    async fn cleanup_old_snapshots(&self, before: DateTime<Utc>) -> Result<u64> {
+        self.inner.cleanup_old_snapshots(before).await
+    }
+}
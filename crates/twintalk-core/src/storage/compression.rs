@@ -0,0 +1,290 @@
+//! Record compression for the sled, file and `SQLite` stores
+//!
+//! Telemetry records repeat the same property names and JSON structure
+//! thousands of times, which zstd compresses well. A dictionary trained on
+//! the store's own records (`SledEventStore::train_dictionary`) holds those
+//! shared strings once, so even small records shrink:
+//!
+//! ```no_run
+//! # fn main() -> anyhow::Result<()> {
+//! use twintalk_core::storage::{Compression, SledEventStore};
+//!
+//! let store = SledEventStore::new("/var/lib/twins")?;
+//! let dictionary = store.train_dictionary(16 * 1024)?;
+//! let store = store.with_compression(Compression::zstd(3).with_dictionary(dictionary))?;
+//! store.recompress()?;
+//! println!("{:.1}x", store.compression_report()?.ratio());
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! The file store takes a `Compression` in its `FileStoreConfig`, and the
+//! `SQLite` store through `SqliteEventStore::with_compression`; dictionary
+//! training, `recompress` and reports are specific to sled.
+//!
+//! Each record is framed with the codec that wrote it, so stores mix plain,
+//! compressed and dictionary-compressed records freely. Dictionaries are
+//! kept in the store for as long as it exists, so records written with an
+//! earlier dictionary remain readable.
+
+use anyhow::{anyhow, Result};
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::fmt;
+use std::io::Read;
+use std::sync::Arc;
+use zstd::dict::{DecoderDictionary, EncoderDictionary};
+
+/// Frame tag of a zstd record without dictionary. Plain JSON records start
+/// with `{`, so they never collide with a tag.
+const ZSTD_TAG: u8 = 1;
+
+/// Frame tag of a zstd record, followed by the 4-byte dictionary id
+const ZSTD_DICTIONARY_TAG: u8 = 2;
+
+/// A zstd dictionary trained on sample records
+#[derive(Clone, PartialEq, Eq)]
+pub struct Dictionary {
+    id: u32,
+    bytes: Arc<Vec<u8>>,
+}
+
+impl Dictionary {
+    /// Train a dictionary of at most `max_size` bytes. zstd needs many
+    /// samples (hundreds, ideally thousands) to find shared content.
+    pub fn train<S: AsRef<[u8]>>(samples: &[S], max_size: usize) -> Result<Self> {
+        let bytes = zstd::dict::from_samples(samples, max_size)
+            .map_err(|e| anyhow!("Dictionary training failed: {e}"))?;
+        Ok(Self::from_bytes(bytes))
+    }
+
+    /// Load a dictionary saved with `as_bytes`
+    pub fn from_bytes(bytes: Vec<u8>) -> Self {
+        Self {
+            id: crc32fast::hash(&bytes),
+            bytes: Arc::new(bytes),
+        }
+    }
+
+    /// Identifies the dictionary in compressed records
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    /// The raw dictionary
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+}
+
+impl fmt::Debug for Dictionary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Dictionary")
+            .field("id", &self.id)
+            .field("len", &self.bytes.len())
+            .finish()
+    }
+}
+
+/// How new records are compressed
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Compression {
+    level: Option<i32>,
+    dictionary: Option<Dictionary>,
+}
+
+impl Compression {
+    /// Store records as plain JSON
+    pub fn none() -> Self {
+        Self::default()
+    }
+
+    /// Compress records with zstd at `level` (1-22; 3 is zstd's default)
+    pub fn zstd(level: i32) -> Self {
+        Self {
+            level: Some(level),
+            dictionary: None,
+        }
+    }
+
+    /// Compress with a trained dictionary
+    #[must_use]
+    pub fn with_dictionary(mut self, dictionary: Dictionary) -> Self {
+        self.dictionary = Some(dictionary);
+        self
+    }
+
+    /// The dictionary new records are compressed with
+    pub fn dictionary(&self) -> Option<&Dictionary> {
+        self.dictionary.as_ref()
+    }
+}
+
+/// Compressed size of a store's records
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CompressionReport {
+    /// Records examined
+    pub records: u64,
+    /// Records stored compressed
+    pub compressed: u64,
+    /// Size of the records as uncompressed JSON
+    pub raw_bytes: u64,
+    /// Size of the records as stored
+    pub stored_bytes: u64,
+}
+
+impl CompressionReport {
+    /// Uncompressed size divided by stored size, 1.0 for an empty store
+    #[allow(clippy::cast_precision_loss)]
+    pub fn ratio(&self) -> f64 {
+        if self.stored_bytes == 0 {
+            1.0
+        } else {
+            self.raw_bytes as f64 / self.stored_bytes as f64
+        }
+    }
+
+    pub(crate) fn record(&mut self, raw: &[u8], stored: &[u8]) {
+        self.records += 1;
+        if is_compressed(stored) {
+            self.compressed += 1;
+        }
+        self.raw_bytes += raw.len() as u64;
+        self.stored_bytes += stored.len() as u64;
+    }
+}
+
+pub(crate) fn is_compressed(stored: &[u8]) -> bool {
+    matches!(stored.first(), Some(&(ZSTD_TAG | ZSTD_DICTIONARY_TAG)))
+}
+
+/// Frames and compresses records according to a `Compression`, and reads
+/// records written with any known dictionary
+#[derive(Default)]
+pub(crate) struct RecordCodec {
+    level: Option<i32>,
+    encoder: Option<(u32, EncoderDictionary<'static>)>,
+    decoders: HashMap<u32, DecoderDictionary<'static>>,
+}
+
+impl RecordCodec {
+    /// Write new records as configured by `compression`
+    pub(crate) fn configure(&mut self, compression: Compression) {
+        self.level = compression.level;
+        self.encoder = None;
+        if let (Some(level), Some(dictionary)) = (compression.level, compression.dictionary) {
+            self.add_dictionary(&dictionary);
+            self.encoder = Some((
+                dictionary.id,
+                EncoderDictionary::copy(&dictionary.bytes, level),
+            ));
+        }
+    }
+
+    /// Make records written with `dictionary` readable
+    pub(crate) fn add_dictionary(&mut self, dictionary: &Dictionary) {
+        self.decoders
+            .entry(dictionary.id)
+            .or_insert_with(|| DecoderDictionary::copy(&dictionary.bytes));
+    }
+
+    /// Frame an encoded record for storage
+    pub(crate) fn encode(&self, record: Vec<u8>) -> Result<Vec<u8>> {
+        let Some(level) = self.level else {
+            return Ok(record);
+        };
+
+        match &self.encoder {
+            Some((id, dictionary)) => {
+                let compressed = zstd::bulk::Compressor::with_prepared_dictionary(dictionary)
+                    .and_then(|mut c| c.compress(&record))
+                    .map_err(|e| anyhow!(e))?;
+                let mut framed = Vec::with_capacity(compressed.len() + 5);
+                framed.push(ZSTD_DICTIONARY_TAG);
+                framed.extend_from_slice(&id.to_be_bytes());
+                framed.extend_from_slice(&compressed);
+                Ok(framed)
+            }
+            None => {
+                let compressed = zstd::bulk::compress(&record, level).map_err(|e| anyhow!(e))?;
+                let mut framed = Vec::with_capacity(compressed.len() + 1);
+                framed.push(ZSTD_TAG);
+                framed.extend_from_slice(&compressed);
+                Ok(framed)
+            }
+        }
+    }
+
+    /// Unframe a stored record
+    pub(crate) fn decode<'a>(&self, stored: &'a [u8]) -> Result<Cow<'a, [u8]>> {
+        let mut record = Vec::new();
+        match stored.split_first() {
+            Some((&ZSTD_TAG, compressed)) => {
+                zstd::stream::read::Decoder::new(compressed)
+                    .and_then(|mut d| d.read_to_end(&mut record))
+                    .map_err(|e| anyhow!("Corrupt compressed record: {e}"))?;
+            }
+            Some((&ZSTD_DICTIONARY_TAG, framed)) => {
+                let (id, compressed) = framed
+                    .split_first_chunk::<4>()
+                    .ok_or_else(|| anyhow!("Truncated compressed record"))?;
+                let id = u32::from_be_bytes(*id);
+                let dictionary = self
+                    .decoders
+                    .get(&id)
+                    .ok_or_else(|| anyhow!("Unknown compression dictionary {id:08x}"))?;
+                zstd::stream::read::Decoder::with_prepared_dictionary(compressed, dictionary)
+                    .and_then(|mut d| d.read_to_end(&mut record))
+                    .map_err(|e| anyhow!("Corrupt compressed record: {e}"))?;
+            }
+            _ => return Ok(Cow::Borrowed(stored)),
+        }
+        Ok(Cow::Owned(record))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample(i: usize) -> Vec<u8> {
+        format!(
+            r#"{{"schema_version":1,"event":{{"type":"TelemetryReceived","data":[["temperature",{i}.5],["humidity",{}]]}}}}"#,
+            i % 7
+        )
+        .into_bytes()
+    }
+
+    #[test]
+    fn test_codecs_read_each_others_records() {
+        let samples: Vec<_> = (0..500).map(sample).collect();
+        let dictionary = Dictionary::train(&samples, 4096).unwrap();
+
+        let plain = RecordCodec::default();
+        let mut zstd = RecordCodec::default();
+        zstd.configure(Compression::zstd(3));
+        let mut trained = RecordCodec::default();
+        trained.configure(Compression::zstd(3).with_dictionary(dictionary.clone()));
+
+        let record = sample(1234);
+        let stored = [
+            plain.encode(record.clone()).unwrap(),
+            zstd.encode(record.clone()).unwrap(),
+            trained.encode(record.clone()).unwrap(),
+        ];
+        assert_eq!(stored[0], record);
+        assert!(stored[2].len() < record.len());
+
+        for framed in &stored {
+            assert_eq!(trained.decode(framed).unwrap().as_ref(), record.as_slice());
+        }
+        assert!(plain.decode(&stored[2]).is_err());
+
+        let mut reader = RecordCodec::default();
+        reader.add_dictionary(&Dictionary::from_bytes(dictionary.as_bytes().to_vec()));
+        assert_eq!(
+            reader.decode(&stored[2]).unwrap().as_ref(),
+            record.as_slice()
+        );
+    }
+}
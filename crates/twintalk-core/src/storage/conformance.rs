@@ -0,0 +1,788 @@
+//! Conformance suite for storage backends
+//!
+//! Every `EventStore + SnapshotStore` backend must behave the same way for
+//! the runtime to replay twins correctly. A new backend gets the whole suite
+//! by implementing `StoreFactory` and invoking the macro in a test file:
+//!
+//! ```ignore
+//! use twintalk_core::storage::conformance::StoreFactory;
+//!
+//! struct MyFactory { dir: tempfile::TempDir }
+//!
+//! impl StoreFactory for MyFactory {
+//!     type Store = MyStore;
+//!     fn create(&mut self) -> anyhow::Result<MyStore> { MyStore::open(self.dir.path()) }
+//!     fn reopen(&mut self) -> Option<anyhow::Result<MyStore>> {
+//!         Some(MyStore::open(self.dir.path()))
+//!     }
+//! }
+//!
+//! twintalk_core::event_store_conformance!(my_store, MyFactory {
+//!     dir: tempfile::tempdir().unwrap(),
+//! });
+//! ```
+//!
+//! Checks panic with a descriptive message on the first violation, so they
+//! are meant to run inside `#[tokio::test]` functions.
+
+use crate::event::{
+    EventEnvelope, EventMetadata, EventStore, SnapshotStore, TwinEvent, TwinSnapshot,
+};
+use crate::twin::TwinId;
+use crate::value::Value;
+use anyhow::Result;
+use chrono::{DateTime, Duration, Utc};
+use std::collections::{BTreeMap, HashSet};
+use std::sync::Arc;
+
+/// Opens the backend under test
+pub trait StoreFactory {
+    /// The backend under test
+    type Store: EventStore + SnapshotStore + 'static;
+
+    /// Open a store on fresh, empty storage
+    fn create(&mut self) -> Result<Self::Store>;
+
+    /// Open the storage of the last created store again, after that store
+    /// has been dropped. Non-persistent backends return `None`, which skips
+    /// the durability check.
+    fn reopen(&mut self) -> Option<Result<Self::Store>> {
+        None
+    }
+}
+
+/// Any store constructor is a factory for a non-persistent backend
+impl<S, F> StoreFactory for F
+where
+    S: EventStore + SnapshotStore + 'static,
+    F: FnMut() -> Result<S>,
+{
+    type Store = S;
+
+    fn create(&mut self) -> Result<S> {
+        self()
+    }
+}
+
+fn create<F: StoreFactory>(factory: &mut F) -> F::Store {
+    factory
+        .create()
+        .unwrap_or_else(|e| panic!("factory failed to create store: {e}"))
+}
+
+fn property_changed(twin_id: TwinId, value: i64, timestamp: DateTime<Utc>) -> TwinEvent {
+    TwinEvent::PropertyChanged {
+        twin_id,
+        property: "value".to_string(),
+        old_value: None,
+        new_value: Value::Integer(value),
+        timestamp,
+    }
+}
+
+fn snapshot(twin_id: TwinId, event_version: u64, timestamp: DateTime<Utc>) -> TwinSnapshot {
+    let properties = [(
+        "version".to_string(),
+        Value::from(event_version.to_string()),
+    )];
+    TwinSnapshot {
+        timestamp,
+        ..TwinSnapshot::new(twin_id, "Conformance", event_version).with_properties(properties)
+    }
+}
+
+fn versions(events: &[(u64, TwinEvent)]) -> Vec<u64> {
+    events.iter().map(|(v, _)| *v).collect()
+}
+
+/// Run every check against the backend
+pub async fn run_all<F: StoreFactory>(factory: &mut F) {
+    check_versions(factory).await;
+    check_ordering(factory).await;
+    check_after_version(factory).await;
+    check_paging(factory).await;
+    check_paging_back(factory).await;
+    check_time_range(factory).await;
+    check_append_batch(factory).await;
+    check_metadata(factory).await;
+    check_import(factory).await;
+    check_snapshots(factory).await;
+    check_snapshot_paging(factory).await;
+    check_snapshot_cleanup(factory).await;
+    check_concurrent_appends(factory).await;
+    check_reopen(factory).await;
+}
+
+/// Versions start at 1 and grow by one per append
+pub async fn check_versions<F: StoreFactory>(factory: &mut F) {
+    let store = create(factory);
+    let twin_id = TwinId::new();
+
+    assert_eq!(
+        store.get_latest_version().await.unwrap(),
+        0,
+        "empty store must report version 0"
+    );
+    for expected in 1..=5 {
+        let version = store
+            .append(property_changed(twin_id, 0, Utc::now()))
+            .await
+            .unwrap();
+        assert_eq!(version, expected, "append must return the next version");
+    }
+    assert_eq!(store.get_latest_version().await.unwrap(), 5);
+}
+
+/// Events for a twin come back in version order and only for that twin
+pub async fn check_ordering<F: StoreFactory>(factory: &mut F) {
+    let store = create(factory);
+    let a = TwinId::new();
+    let b = TwinId::new();
+
+    for i in 0..20 {
+        let twin_id = if i % 2 == 0 { a } else { b };
+        store
+            .append(property_changed(twin_id, i, Utc::now()))
+            .await
+            .unwrap();
+    }
+
+    let events = store.get_events(a, 0).await.unwrap();
+    assert_eq!(
+        versions(&events),
+        (1..=20).step_by(2).collect::<Vec<_>>(),
+        "events must be in version order"
+    );
+    assert!(
+        events.iter().all(|(_, e)| e.twin_id() == a),
+        "events of other twins must not be returned"
+    );
+    for (i, (_, event)) in (0..).step_by(2).zip(&events) {
+        match event {
+            TwinEvent::PropertyChanged { new_value, .. } => {
+                assert_eq!(*new_value, Value::Integer(i), "payload mismatch");
+            }
+            other => panic!("unexpected event {other:?}"),
+        }
+    }
+}
+
+/// `after_version` is exclusive and filters by global version
+pub async fn check_after_version<F: StoreFactory>(factory: &mut F) {
+    let store = create(factory);
+    let twin_id = TwinId::new();
+    let other = TwinId::new();
+
+    for i in 0..10 {
+        let target = if i == 4 { other } else { twin_id };
+        store
+            .append(property_changed(target, i, Utc::now()))
+            .await
+            .unwrap();
+    }
+
+    assert_eq!(store.get_events(twin_id, 0).await.unwrap().len(), 9);
+    assert_eq!(
+        versions(&store.get_events(twin_id, 3).await.unwrap()),
+        vec![4, 6, 7, 8, 9, 10],
+        "after_version must exclude the given version"
+    );
+    assert_eq!(
+        versions(&store.get_events(twin_id, 5).await.unwrap()),
+        vec![6, 7, 8, 9, 10],
+        "after_version may fall on another twin's event"
+    );
+    assert!(store.get_events(twin_id, 10).await.unwrap().is_empty());
+    assert!(store.get_events(twin_id, 100).await.unwrap().is_empty());
+    assert!(
+        store.get_events(TwinId::new(), 0).await.unwrap().is_empty(),
+        "unknown twins have no events"
+    );
+}
+
+/// Pages of the whole log start after the given version, hold at most
+/// `limit` events in version order and cover every twin
+pub async fn check_paging<F: StoreFactory>(factory: &mut F) {
+    let store = create(factory);
+    let twins = [TwinId::new(), TwinId::new(), TwinId::new()];
+
+    for i in 0..10 {
+        store
+            .append(property_changed(
+                twins[i % 3],
+                i64::try_from(i).unwrap(),
+                Utc::now(),
+            ))
+            .await
+            .unwrap();
+    }
+
+    let mut pages = Vec::new();
+    let mut after = 0;
+    loop {
+        let page = store.get_envelopes_after(after, 4).await.unwrap();
+        let Some((last, _)) = page.last() else {
+            break;
+        };
+        after = *last;
+        pages.push(page.iter().map(|(v, _)| *v).collect::<Vec<_>>());
+    }
+    assert_eq!(
+        pages,
+        vec![vec![1, 2, 3, 4], vec![5, 6, 7, 8], vec![9, 10]],
+        "pages must follow version order and respect the limit"
+    );
+    assert!(
+        store.get_envelopes_after(10, 4).await.unwrap().is_empty(),
+        "no page after the latest version"
+    );
+}
+
+/// Pages of one twin's events end before the given version and hold its
+/// last `limit` events before it in version order
+pub async fn check_paging_back<F: StoreFactory>(factory: &mut F) {
+    let store = create(factory);
+    let twins = [TwinId::new(), TwinId::new()];
+
+    for i in 0..10 {
+        store
+            .append(property_changed(
+                twins[i % 2],
+                i64::try_from(i).unwrap(),
+                Utc::now(),
+            ))
+            .await
+            .unwrap();
+    }
+
+    let mut pages = Vec::new();
+    let mut before = u64::MAX;
+    loop {
+        let page = store
+            .get_envelopes_before(twins[0], before, 2)
+            .await
+            .unwrap();
+        let Some((first, _)) = page.first() else {
+            break;
+        };
+        before = *first;
+        pages.push(page.iter().map(|(v, _)| *v).collect::<Vec<_>>());
+    }
+    assert_eq!(
+        pages,
+        vec![vec![7, 9], vec![3, 5], vec![1]],
+        "pages must go back from the latest event, in version order"
+    );
+    let page = store.get_envelopes_before(twins[1], 8, 10).await.unwrap();
+    assert_eq!(
+        page.iter().map(|(v, _)| *v).collect::<Vec<_>>(),
+        vec![2, 4, 6],
+        "before_version may fall on another twin's event"
+    );
+    assert!(
+        store
+            .get_envelopes_before(TwinId::new(), u64::MAX, 10)
+            .await
+            .unwrap()
+            .is_empty(),
+        "unknown twins have no events"
+    );
+}
+
+/// Time range queries are inclusive on both ends and ordered by version
+pub async fn check_time_range<F: StoreFactory>(factory: &mut F) {
+    let store = create(factory);
+    let start = Utc::now();
+
+    // Append out of time order to make sure results follow version order
+    for offset in [3, 0, 4, 1, 2] {
+        store
+            .append(property_changed(
+                TwinId::new(),
+                offset,
+                start + Duration::seconds(offset),
+            ))
+            .await
+            .unwrap();
+    }
+
+    let events = store
+        .get_events_in_range(start + Duration::seconds(1), start + Duration::seconds(3))
+        .await
+        .unwrap();
+    assert_eq!(
+        versions(&events),
+        vec![1, 4, 5],
+        "range must include both bounds and be in version order"
+    );
+
+    let empty = store
+        .get_events_in_range(start + Duration::seconds(10), start + Duration::seconds(20))
+        .await
+        .unwrap();
+    assert!(empty.is_empty(), "range without events must be empty");
+}
+
+/// Batches get contiguous versions and interleave with single appends
+pub async fn check_append_batch<F: StoreFactory>(factory: &mut F) {
+    let store = create(factory);
+    let a = TwinId::new();
+    let b = TwinId::new();
+
+    store
+        .append(property_changed(a, 0, Utc::now()))
+        .await
+        .unwrap();
+    let batch = vec![
+        property_changed(a, 1, Utc::now()),
+        property_changed(b, 2, Utc::now()),
+        property_changed(a, 3, Utc::now()),
+    ];
+    assert_eq!(store.append_batch(batch).await.unwrap(), vec![2, 3, 4]);
+    assert!(store.append_batch(Vec::new()).await.unwrap().is_empty());
+    assert_eq!(
+        store
+            .append(property_changed(b, 4, Utc::now()))
+            .await
+            .unwrap(),
+        5
+    );
+
+    assert_eq!(
+        versions(&store.get_events(a, 0).await.unwrap()),
+        vec![1, 2, 4]
+    );
+    assert_eq!(versions(&store.get_events(b, 0).await.unwrap()), vec![3, 5]);
+}
+
+/// Metadata is stored with its event and returned by every read path;
+/// wrapping stores may add headers of their own
+pub async fn check_metadata<F: StoreFactory>(factory: &mut F) {
+    let store = create(factory);
+    let twin_id = TwinId::new();
+
+    let root = EventMetadata::new();
+    let child = EventMetadata {
+        causation_id: Some(root.event_id),
+        correlation_id: root.correlation_id,
+        principal: Some("api:operator".to_string()),
+        headers: BTreeMap::from([("source".to_string(), "mqtt".to_string())]),
+        ..EventMetadata::new()
+    };
+    store
+        .append_envelope(EventEnvelope {
+            metadata: root.clone(),
+            event: property_changed(twin_id, 1, Utc::now()),
+        })
+        .await
+        .unwrap();
+    store
+        .append_envelopes(vec![EventEnvelope {
+            metadata: child.clone(),
+            event: property_changed(twin_id, 2, Utc::now()),
+        }])
+        .await
+        .unwrap();
+
+    let expected = vec![root, child];
+    // Wrapping stores may add headers of their own but keep the caller's
+    let metadata = |envelopes: Vec<(u64, EventEnvelope)>| -> Vec<EventMetadata> {
+        envelopes
+            .into_iter()
+            .zip(&expected)
+            .map(|((_, e), sent)| EventMetadata {
+                headers: e
+                    .metadata
+                    .headers
+                    .into_iter()
+                    .filter(|(key, _)| sent.headers.contains_key(key))
+                    .collect(),
+                ..e.metadata
+            })
+            .collect()
+    };
+    assert_eq!(
+        metadata(store.get_envelopes(twin_id, 0).await.unwrap()),
+        expected,
+        "metadata must round-trip through get_envelopes"
+    );
+    let in_range = store
+        .get_envelopes_in_range(Utc::now() - Duration::hours(1), Utc::now())
+        .await
+        .unwrap();
+    assert_eq!(
+        metadata(in_range),
+        expected,
+        "metadata must round-trip through get_envelopes_in_range"
+    );
+
+    // Plain appends get fresh, distinct ids
+    store
+        .append(property_changed(twin_id, 3, Utc::now()))
+        .await
+        .unwrap();
+    let envelopes = store.get_envelopes(twin_id, 2).await.unwrap();
+    let fresh = &envelopes[0].1.metadata;
+    assert!(!fresh.event_id.is_nil(), "append must assign an event id");
+    assert_eq!(fresh.correlation_id, fresh.event_id);
+}
+
+/// Imported events keep their versions, and appends continue after them
+pub async fn check_import<F: StoreFactory>(factory: &mut F) {
+    let store = create(factory);
+    let twin_id = TwinId::new();
+    let timestamp = Utc::now() - Duration::days(3);
+
+    let envelopes: Vec<(u64, EventEnvelope)> = [(2, 2), (5, 5), (6, 6)]
+        .into_iter()
+        .map(|(version, value)| {
+            let event = property_changed(twin_id, value, timestamp);
+            (version, EventEnvelope::new(event))
+        })
+        .collect();
+    store.import_envelopes(envelopes.clone()).await.unwrap();
+
+    let imported = store.get_envelopes(twin_id, 0).await.unwrap();
+    assert_eq!(
+        imported.iter().map(|(v, _)| *v).collect::<Vec<_>>(),
+        vec![2, 5, 6],
+        "import must keep the given versions"
+    );
+    for ((_, got), (_, want)) in imported.iter().zip(&envelopes) {
+        assert_eq!(got.metadata, want.metadata, "import must keep metadata");
+        assert_eq!(
+            got.event.timestamp(),
+            timestamp,
+            "import must keep timestamps"
+        );
+    }
+    assert_eq!(store.get_latest_version().await.unwrap(), 6);
+
+    let next = store
+        .append(property_changed(twin_id, 7, Utc::now()))
+        .await
+        .unwrap();
+    assert_eq!(next, 7, "appends must continue after imported versions");
+
+    let stale = vec![(
+        4,
+        EventEnvelope::new(property_changed(twin_id, 4, timestamp)),
+    )];
+    assert!(
+        store.import_envelopes(stale).await.is_err(),
+        "import must reject versions at or below the latest"
+    );
+    assert_eq!(store.get_events(twin_id, 0).await.unwrap().len(), 4);
+}
+
+/// The latest snapshot per twin wins
+pub async fn check_snapshots<F: StoreFactory>(factory: &mut F) {
+    let store = create(factory);
+    let twin_id = TwinId::new();
+
+    assert!(store.get_snapshot(twin_id).await.unwrap().is_none());
+
+    store
+        .save_snapshot(snapshot(twin_id, 3, Utc::now()))
+        .await
+        .unwrap();
+    store
+        .save_snapshot(snapshot(twin_id, 7, Utc::now()))
+        .await
+        .unwrap();
+    store
+        .save_snapshot(snapshot(TwinId::new(), 9, Utc::now()))
+        .await
+        .unwrap();
+
+    let saved = store.get_snapshot(twin_id).await.unwrap().unwrap();
+    assert_eq!(saved.twin_id, twin_id);
+    assert_eq!(
+        saved.event_version, 7,
+        "a newer snapshot must replace the old"
+    );
+    assert_eq!(saved.properties.get("version"), Some(&Value::from("7")));
+
+    let listed = store.list_snapshots().await.unwrap();
+    assert_eq!(listed.len(), 2, "list_snapshots must return one per twin");
+    assert!(listed
+        .iter()
+        .any(|s| s.twin_id == twin_id && s.event_version == 7));
+}
+
+/// Pages of snapshots follow twin id order and together hold every
+/// snapshot once
+pub async fn check_snapshot_paging<F: StoreFactory>(factory: &mut F) {
+    let store = create(factory);
+    let mut twins: Vec<TwinId> = (0..5).map(|_| TwinId::new()).collect();
+    for (version, twin_id) in (1..).zip(&twins) {
+        store
+            .save_snapshot(snapshot(*twin_id, version, Utc::now()))
+            .await
+            .unwrap();
+    }
+    twins.sort();
+
+    let mut pages = Vec::new();
+    let mut after = None;
+    loop {
+        let page = store.list_snapshots_after(after, 2).await.unwrap();
+        let Some(last) = page.last() else {
+            break;
+        };
+        after = Some(last.twin_id);
+        pages.push(page.iter().map(|s| s.twin_id).collect::<Vec<_>>());
+    }
+    assert_eq!(
+        pages,
+        vec![
+            twins[0..2].to_vec(),
+            twins[2..4].to_vec(),
+            twins[4..].to_vec()
+        ],
+        "pages must hold every snapshot once, in twin id order"
+    );
+}
+
+/// Cleanup removes exactly the snapshots older than the cutoff
+pub async fn check_snapshot_cleanup<F: StoreFactory>(factory: &mut F) {
+    let store = create(factory);
+    let now = Utc::now();
+    let old: Vec<TwinId> = (0..3).map(|_| TwinId::new()).collect();
+    let fresh: Vec<TwinId> = (0..2).map(|_| TwinId::new()).collect();
+
+    for twin_id in &old {
+        store
+            .save_snapshot(snapshot(*twin_id, 1, now - Duration::days(10)))
+            .await
+            .unwrap();
+    }
+    for twin_id in &fresh {
+        store
+            .save_snapshot(snapshot(*twin_id, 1, now))
+            .await
+            .unwrap();
+    }
+
+    let deleted = store
+        .cleanup_old_snapshots(now - Duration::days(5))
+        .await
+        .unwrap();
+    assert_eq!(deleted, 3, "cleanup must report the snapshots it removed");
+
+    for twin_id in &old {
+        assert!(store.get_snapshot(*twin_id).await.unwrap().is_none());
+    }
+    for twin_id in &fresh {
+        assert!(
+            store.get_snapshot(*twin_id).await.unwrap().is_some(),
+            "snapshots newer than the cutoff must be kept"
+        );
+    }
+}
+
+/// Concurrent appends never lose or duplicate versions
+pub async fn check_concurrent_appends<F: StoreFactory>(factory: &mut F) {
+    const TASKS: u64 = 8;
+    const PER_TASK: u64 = 25;
+
+    let store = Arc::new(create(factory));
+    let twins: Vec<TwinId> = (0..TASKS).map(|_| TwinId::new()).collect();
+
+    let handles: Vec<_> = twins
+        .iter()
+        .map(|twin_id| {
+            let store = store.clone();
+            let twin_id = *twin_id;
+            tokio::spawn(async move {
+                let mut versions = Vec::new();
+                for i in 0..PER_TASK {
+                    let event = property_changed(twin_id, i64::try_from(i).unwrap(), Utc::now());
+                    let version = if i % 5 == 0 {
+                        store.append_batch(vec![event]).await.unwrap()[0]
+                    } else {
+                        store.append(event).await.unwrap()
+                    };
+                    versions.push(version);
+                }
+                versions
+            })
+        })
+        .collect();
+
+    let mut all = HashSet::new();
+    for handle in handles {
+        for version in handle.await.unwrap() {
+            assert!(all.insert(version), "version {version} assigned twice");
+        }
+    }
+    assert_eq!(
+        all,
+        (1..=TASKS * PER_TASK).collect::<HashSet<_>>(),
+        "versions must be dense"
+    );
+    assert_eq!(store.get_latest_version().await.unwrap(), TASKS * PER_TASK);
+
+    for twin_id in twins {
+        let events = store.get_events(twin_id, 0).await.unwrap();
+        assert_eq!(
+            events.len() as u64,
+            PER_TASK,
+            "events lost under concurrency"
+        );
+        assert!(
+            events.windows(2).all(|w| w[0].0 < w[1].0),
+            "events must stay in version order"
+        );
+    }
+}
+
+/// Events and snapshots survive closing and reopening the store
+pub async fn check_reopen<F: StoreFactory>(factory: &mut F) {
+    let twin_id = TwinId::new();
+    let start = Utc::now();
+
+    {
+        let store = create(factory);
+        for i in 0..5 {
+            store
+                .append(property_changed(twin_id, i, start + Duration::seconds(i)))
+                .await
+                .unwrap();
+        }
+        store
+            .append_batch(vec![property_changed(
+                twin_id,
+                5,
+                start + Duration::seconds(5),
+            )])
+            .await
+            .unwrap();
+        store
+            .save_snapshot(snapshot(twin_id, 3, start))
+            .await
+            .unwrap();
+    }
+
+    let Some(reopened) = factory.reopen() else {
+        return;
+    };
+    let store = reopened.unwrap_or_else(|e| panic!("factory failed to reopen store: {e}"));
+
+    assert_eq!(
+        store.get_latest_version().await.unwrap(),
+        6,
+        "latest version must survive reopen"
+    );
+    assert_eq!(
+        versions(&store.get_events(twin_id, 3).await.unwrap()),
+        vec![4, 5, 6]
+    );
+    assert_eq!(
+        store
+            .get_events_in_range(start, start + Duration::seconds(1))
+            .await
+            .unwrap()
+            .len(),
+        2
+    );
+    let saved = store.get_snapshot(twin_id).await.unwrap();
+    assert_eq!(
+        saved.map(|s| s.event_version),
+        Some(3),
+        "snapshot lost on reopen"
+    );
+
+    assert_eq!(
+        store
+            .append(property_changed(twin_id, 6, Utc::now()))
+            .await
+            .unwrap(),
+        7,
+        "appends must continue after the last persisted version"
+    );
+}
+
+/// Generate one `#[tokio::test]` per conformance check.
+///
+/// `$factory` is evaluated once per test, so each test gets its own storage.
+#[macro_export]
+macro_rules! event_store_conformance {
+    ($name:ident, $factory:expr) => {
+        mod $name {
+            #[allow(unused_imports)]
+            use super::*;
+            use $crate::storage::conformance;
+
+            #[tokio::test]
+            async fn versions() {
+                conformance::check_versions(&mut $factory).await;
+            }
+
+            #[tokio::test]
+            async fn ordering() {
+                conformance::check_ordering(&mut $factory).await;
+            }
+
+            #[tokio::test]
+            async fn after_version() {
+                conformance::check_after_version(&mut $factory).await;
+            }
+
+            #[tokio::test]
+            async fn paging() {
+                conformance::check_paging(&mut $factory).await;
+            }
+
+            #[tokio::test]
+            async fn paging_back() {
+                conformance::check_paging_back(&mut $factory).await;
+            }
+
+            #[tokio::test]
+            async fn time_range() {
+                conformance::check_time_range(&mut $factory).await;
+            }
+
+            #[tokio::test]
+            async fn append_batch() {
+                conformance::check_append_batch(&mut $factory).await;
+            }
+
+            #[tokio::test]
+            async fn metadata() {
+                conformance::check_metadata(&mut $factory).await;
+            }
+
+            #[tokio::test]
+            async fn import() {
+                conformance::check_import(&mut $factory).await;
+            }
+
+            #[tokio::test]
+            async fn snapshots() {
+                conformance::check_snapshots(&mut $factory).await;
+            }
+
+            #[tokio::test]
+            async fn snapshot_paging() {
+                conformance::check_snapshot_paging(&mut $factory).await;
+            }
+
+            #[tokio::test]
+            async fn snapshot_cleanup() {
+                conformance::check_snapshot_cleanup(&mut $factory).await;
+            }
+
+            #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+            async fn concurrent_appends() {
+                conformance::check_concurrent_appends(&mut $factory).await;
+            }
+
+            #[tokio::test]
+            async fn reopen() {
+                conformance::check_reopen(&mut $factory).await;
+            }
+        }
+    };
+}
@@ -0,0 +1,107 @@
+//! Consistency reports for stores with secondary indexes
+//!
+//! `SledEventStore` writes an event and its twin index entry separately, so
+//! a crash in between leaves them disagreeing. `SledEventStore::check`
+//! reports such damage and `SledEventStore::repair` fixes it by rebuilding
+//! the index from the event tree.
+
+use crate::twin::TwinId;
+use std::fmt;
+use std::ops::RangeInclusive;
+
+/// One problem found by a consistency check
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Inconsistency {
+    /// An event missing from its twin's index, so replay skips it
+    OrphanedEvent { twin_id: TwinId, version: u64 },
+    /// An index entry whose event does not exist
+    MissingEvent { twin_id: TwinId, version: u64 },
+    /// An index entry pointing at another twin's event
+    MisindexedEvent {
+        twin_id: TwinId,
+        version: u64,
+        actual: TwinId,
+    },
+    /// A snapshot covering versions the log never assigned, so new events
+    /// could be skipped when replaying on top of it
+    SnapshotAhead {
+        twin_id: TwinId,
+        event_version: u64,
+        latest_version: u64,
+    },
+    /// An event record that cannot be decoded
+    UndecodableEvent { key: String, error: String },
+    /// A snapshot record that cannot be decoded
+    UndecodableSnapshot { key: String, error: String },
+    /// A twin index entry that cannot be decoded
+    UndecodableIndex { key: String, error: String },
+}
+
+impl fmt::Display for Inconsistency {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::OrphanedEvent { twin_id, version } => {
+                write!(f, "event {version} of {twin_id} is not indexed")
+            }
+            Self::MissingEvent { twin_id, version } => {
+                write!(f, "index of {twin_id} lists missing event {version}")
+            }
+            Self::MisindexedEvent {
+                twin_id,
+                version,
+                actual,
+            } => write!(
+                f,
+                "index of {twin_id} lists event {version}, which belongs to {actual}"
+            ),
+            Self::SnapshotAhead {
+                twin_id,
+                event_version,
+                latest_version,
+            } => write!(
+                f,
+                "snapshot of {twin_id} is at version {event_version}, after latest version {latest_version}"
+            ),
+            Self::UndecodableEvent { key, error } => {
+                write!(f, "event record {key} cannot be decoded: {error}")
+            }
+            Self::UndecodableSnapshot { key, error } => {
+                write!(f, "snapshot record {key} cannot be decoded: {error}")
+            }
+            Self::UndecodableIndex { key, error } => {
+                write!(f, "index record {key} cannot be decoded: {error}")
+            }
+        }
+    }
+}
+
+/// What a consistency check found
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ConsistencyReport {
+    /// Event records examined
+    pub events: u64,
+    /// Snapshot records examined
+    pub snapshots: u64,
+    /// Twin index entries examined
+    pub twins: u64,
+    /// Versions up to the latest with no event. Compaction and crashes
+    /// between assigning and writing a version leave these, so they are
+    /// reported but not counted as inconsistencies.
+    pub gaps: Vec<RangeInclusive<u64>>,
+    /// Problems found, in the order checked
+    pub issues: Vec<Inconsistency>,
+}
+
+impl ConsistencyReport {
+    /// Whether no problems were found
+    pub fn is_consistent(&self) -> bool {
+        self.issues.is_empty()
+    }
+
+    /// Record the gap before `version`, given the previous version seen
+    pub(crate) fn record_gap(&mut self, previous: u64, version: u64) {
+        if version > previous + 1 {
+            self.gaps.push(previous + 1..=version - 1);
+        }
+    }
+}
@@ -0,0 +1,42 @@
+//! Durability modes for persistent event stores
+//!
+//! Controls when appended events are forced to stable storage. Every mode
+//! trades ingest throughput against the window of acknowledged events that
+//! can be lost on a crash.
+
+use std::time::Duration;
+
+/// When a persistent store forces appended data to disk
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Durability {
+    /// Flush and fsync before `append`/`append_batch` returns.
+    ///
+    /// An acknowledged event survives a process crash and a power loss.
+    /// Each call costs one fsync, so batch appends (or group commit in the
+    /// runtime) are the way to raise throughput in this mode. An append
+    /// cancelled before it returns may or may not be durable; through group
+    /// commit it is committed regardless, once queued.
+    #[default]
+    EveryEvent,
+
+    /// Flush in the background at most every given interval.
+    ///
+    /// Appends return as soon as the event is in the store's write buffer.
+    /// A crash or power loss can drop events acknowledged during the last
+    /// interval; a clean shutdown loses nothing.
+    Interval(Duration),
+
+    /// Never flush explicitly; leave write-back to the store and the OS.
+    ///
+    /// Fastest mode. Data reaches disk when internal buffers fill, when the
+    /// store is dropped cleanly, or when `flush` is called. There is no
+    /// upper bound on how many acknowledged events a crash can drop.
+    OsManaged,
+}
+
+impl Durability {
+    /// Whether every append must flush before returning
+    pub fn flushes_on_append(&self) -> bool {
+        matches!(self, Self::EveryEvent)
+    }
+}
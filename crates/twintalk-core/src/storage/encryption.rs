@@ -0,0 +1,627 @@
+//! Encryption at rest for any event and snapshot store
+//!
+//! `EncryptedStore` wraps a backend and seals every record with AES-256-GCM
+//! before it reaches storage. Each record stores the id of the key that
+//! sealed it, so a `KeyRing` can hold retired keys for reading while new
+//! records use the active one.
+//!
+//! Events keep their kind, twin id and timestamp in the clear, with every
+//! other field blanked, so backends can still index them and retention
+//! policies still apply. The sealed event travels in the `crypto.*`
+//! metadata headers. Snapshots keep their header fields (twin id, class
+//! name, version and timestamp), which class-based retention relies on,
+//! and are otherwise sealed whole into one property. Event metadata
+//! (principal, headers) is not encrypted.
+//!
+//! The clear fields and the event version are authenticated with the
+//! ciphertext, so a sealed payload moved to another twin, or to another
+//! position in the log, fails to decrypt. Sealing an event therefore needs
+//! its version up front: appends through the wrapper are serialised, and
+//! the wrapper must be the only writer of its backend. To batch concurrent
+//! appends, put a `GroupCommitter` in front of it rather than behind it.
+//!
+//! Unencrypted records are rejected, unless the store is opened with
+//! `allow_plaintext` to read a store that predates encryption, e.g. to
+//! `reencrypt` it.
+//!
+//! Keys are rotated offline with `reencrypt`, which copies a store into a
+//! fresh one sealed with the new active key.
+
+use crate::alert::Severity;
+use crate::alias::Alias;
+use crate::event::{EventEnvelope, EventStore, SnapshotStore, TwinEvent, TwinSnapshot};
+use crate::relationship::RelationshipKind;
+use crate::storage::ndjson::TransferReport;
+use crate::twin::TwinId;
+use crate::value::Value;
+use aes_gcm::aead::{Aead, OsRng, Payload};
+use aes_gcm::{AeadCore, Aes256Gcm, KeyInit, Nonce};
+use anyhow::{anyhow, bail, Result};
+use async_trait::async_trait;
+use chrono::{DateTime, SecondsFormat, Utc};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fmt;
+use uuid::Uuid;
+
+/// Header holding the id of the key that sealed an event
+pub const KEY_ID_HEADER: &str = "crypto.key_id";
+
+/// Header holding a sealed event's nonce, hex encoded
+pub const NONCE_HEADER: &str = "crypto.nonce";
+
+/// Header holding a sealed event, hex encoded
+pub const CIPHERTEXT_HEADER: &str = "crypto.ciphertext";
+
+/// Property holding a sealed snapshot
+pub const SEALED_PROPERTY: &str = "crypto.sealed";
+
+/// Events read per page when scanning or re-encrypting a store
+const PAGE: usize = 1000;
+
+/// Encryption keys by id: one active key for writing, and retired keys
+/// that are still needed to read older records
+#[derive(Clone)]
+pub struct KeyRing {
+    active: String,
+    keys: HashMap<String, Aes256Gcm>,
+}
+
+impl KeyRing {
+    /// Seal new records with the 256-bit `key`, stored as `key_id`
+    pub fn new(key_id: impl Into<String>, key: &[u8; 32]) -> Self {
+        let active = key_id.into();
+        let mut keys = HashMap::new();
+        keys.insert(active.clone(), Aes256Gcm::new(key.into()));
+        Self { active, keys }
+    }
+
+    /// Also read records sealed with a retired key
+    #[must_use]
+    pub fn with_retired_key(mut self, key_id: impl Into<String>, key: &[u8; 32]) -> Self {
+        self.keys
+            .entry(key_id.into())
+            .or_insert_with(|| Aes256Gcm::new(key.into()));
+        self
+    }
+
+    /// Id of the key sealing new records
+    pub fn active_key_id(&self) -> &str {
+        &self.active
+    }
+
+    fn cipher(&self, key_id: &str) -> Result<&Aes256Gcm> {
+        self.keys
+            .get(key_id)
+            .ok_or_else(|| anyhow!("Unknown encryption key '{key_id}'"))
+    }
+
+    fn seal(&self, plaintext: &[u8], aad: &str) -> Result<Sealed> {
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let ciphertext = self
+            .cipher(&self.active)?
+            .encrypt(
+                &nonce,
+                Payload {
+                    msg: plaintext,
+                    aad: aad.as_bytes(),
+                },
+            )
+            .map_err(|e| anyhow!("Encryption failed: {e}"))?;
+        Ok(Sealed {
+            key_id: self.active.clone(),
+            nonce: hex::encode(nonce),
+            ciphertext: hex::encode(ciphertext),
+        })
+    }
+
+    fn open(&self, sealed: &Sealed, aad: &str) -> Result<Vec<u8>> {
+        let nonce = hex::decode(&sealed.nonce).map_err(|e| anyhow!(e))?;
+        if nonce.len() != 12 {
+            return Err(anyhow!("Invalid nonce length {}", nonce.len()));
+        }
+        let ciphertext = hex::decode(&sealed.ciphertext).map_err(|e| anyhow!(e))?;
+        self.cipher(&sealed.key_id)?
+            .decrypt(
+                Nonce::from_slice(&nonce),
+                Payload {
+                    msg: &ciphertext,
+                    aad: aad.as_bytes(),
+                },
+            )
+            .map_err(|_| anyhow!("Wrong key or tampered record"))
+    }
+}
+
+impl fmt::Debug for KeyRing {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut ids: Vec<_> = self.keys.keys().collect();
+        ids.sort();
+        f.debug_struct("KeyRing")
+            .field("active", &self.active)
+            .field("keys", &ids)
+            .finish()
+    }
+}
+
+/// A sealed record as stored
+struct Sealed {
+    key_id: String,
+    nonce: String,
+    ciphertext: String,
+}
+
+impl Sealed {
+    fn from_headers(headers: &BTreeMap<String, String>) -> Option<Self> {
+        Some(Self {
+            key_id: headers.get(KEY_ID_HEADER)?.clone(),
+            nonce: headers.get(NONCE_HEADER)?.clone(),
+            ciphertext: headers.get(CIPHERTEXT_HEADER)?.clone(),
+        })
+    }
+
+    fn into_headers(self, headers: &mut BTreeMap<String, String>) {
+        headers.insert(KEY_ID_HEADER.to_string(), self.key_id);
+        headers.insert(NONCE_HEADER.to_string(), self.nonce);
+        headers.insert(CIPHERTEXT_HEADER.to_string(), self.ciphertext);
+    }
+
+    fn from_properties(properties: &BTreeMap<String, Value>) -> Option<Self> {
+        let Some(Value::Map(fields)) = properties.get(SEALED_PROPERTY) else {
+            return None;
+        };
+        let field = |name: &str| match fields.get(name) {
+            Some(Value::String(s)) => Some(s.clone()),
+            _ => None,
+        };
+        Some(Self {
+            key_id: field("key_id")?,
+            nonce: field("nonce")?,
+            ciphertext: field("ciphertext")?,
+        })
+    }
+
+    fn into_properties(self) -> BTreeMap<String, Value> {
+        let fields = BTreeMap::from([
+            ("key_id".to_string(), Value::String(self.key_id)),
+            ("nonce".to_string(), Value::String(self.nonce)),
+            ("ciphertext".to_string(), Value::String(self.ciphertext)),
+        ]);
+        BTreeMap::from([(SEALED_PROPERTY.to_string(), Value::Map(fields))])
+    }
+}
+
+/// The version and clear fields of an event, authenticated with its
+/// ciphertext
+fn event_aad(version: u64, event: &TwinEvent) -> String {
+    format!(
+        "event\n{version}\n{}\n{}\n{}",
+        event.twin_id(),
+        event.kind(),
+        event
+            .timestamp()
+            .to_rfc3339_opts(SecondsFormat::Nanos, true)
+    )
+}
+
+/// The clear fields of a snapshot, authenticated with its ciphertext
+fn snapshot_aad(snapshot: &TwinSnapshot) -> String {
+    format!(
+        "snapshot\n{}\n{}\n{}",
+        snapshot.twin_id, snapshot.class_name, snapshot.event_version
+    )
+}
+
+/// The event as stored next to its ciphertext: its kind, twin id and
+/// timestamp, with every other field blanked
+fn redact(event: &TwinEvent) -> TwinEvent {
+    let (twin_id, timestamp) = (event.twin_id(), event.timestamp());
+    let nobody = TwinId(Uuid::nil());
+    match event {
+        TwinEvent::Created { .. } => TwinEvent::Created {
+            twin_id,
+            class_name: String::new(),
+            timestamp,
+        },
+        TwinEvent::PropertyChanged { .. } => TwinEvent::PropertyChanged {
+            twin_id,
+            property: String::new(),
+            old_value: None,
+            new_value: Value::Nil,
+            timestamp,
+        },
+        TwinEvent::TelemetryReceived { .. } => TwinEvent::TelemetryReceived {
+            twin_id,
+            data: Vec::new(),
+            timestamp,
+        },
+        TwinEvent::MessageSent { .. } => TwinEvent::MessageSent {
+            twin_id,
+            selector: String::new(),
+            args: Vec::new(),
+            result: Ok(Value::Nil),
+            timestamp,
+        },
+        TwinEvent::Cloned { .. } => TwinEvent::Cloned {
+            twin_id,
+            source_id: nobody,
+            timestamp,
+        },
+        TwinEvent::Destroyed { .. } => TwinEvent::Destroyed { twin_id, timestamp },
+        TwinEvent::AlertRaised { .. } => TwinEvent::AlertRaised {
+            twin_id,
+            rule: String::new(),
+            severity: Severity::default(),
+            property: String::new(),
+            value: 0.0,
+            threshold: 0.0,
+            timestamp,
+        },
+        TwinEvent::AlertCleared { .. } => TwinEvent::AlertCleared {
+            twin_id,
+            rule: String::new(),
+            property: String::new(),
+            value: 0.0,
+            timestamp,
+        },
+        TwinEvent::RelationshipAdded { .. } => TwinEvent::RelationshipAdded {
+            twin_id,
+            kind: RelationshipKind::from(""),
+            target: nobody,
+            timestamp,
+        },
+        TwinEvent::RelationshipRemoved { .. } => TwinEvent::RelationshipRemoved {
+            twin_id,
+            kind: RelationshipKind::from(""),
+            target: nobody,
+            timestamp,
+        },
+        TwinEvent::AliasAssigned { .. } => TwinEvent::AliasAssigned {
+            twin_id,
+            alias: Alias::new("", ""),
+            timestamp,
+        },
+        TwinEvent::AliasRemoved { .. } => TwinEvent::AliasRemoved {
+            twin_id,
+            alias: Alias::new("", ""),
+            timestamp,
+        },
+        TwinEvent::LabelSet { .. } => TwinEvent::LabelSet {
+            twin_id,
+            key: String::new(),
+            value: String::new(),
+            timestamp,
+        },
+        TwinEvent::LabelRemoved { .. } => TwinEvent::LabelRemoved {
+            twin_id,
+            key: String::new(),
+            timestamp,
+        },
+        TwinEvent::StateChanged { .. } => TwinEvent::StateChanged {
+            twin_id,
+            property: String::new(),
+            from: String::new(),
+            to: String::new(),
+            trigger: String::new(),
+            timestamp,
+        },
+    }
+}
+
+/// The snapshot as stored next to its ciphertext: its header fields, with
+/// one sealed entry for properties and nothing else
+fn redact_snapshot(snapshot: &TwinSnapshot, sealed: Sealed) -> TwinSnapshot {
+    TwinSnapshot {
+        twin_id: snapshot.twin_id,
+        class_name: snapshot.class_name.clone(),
+        properties: sealed.into_properties(),
+        parent_id: None,
+        event_version: snapshot.event_version,
+        timestamp: snapshot.timestamp,
+        metadata: BTreeMap::new(),
+        relationships: BTreeSet::new(),
+        aliases: BTreeSet::new(),
+        labels: BTreeMap::new(),
+        alerts: BTreeSet::new(),
+    }
+}
+
+/// Records per key id, as reported by `EncryptedStore::key_usage`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct KeyUsage {
+    /// Sealed events and snapshots per key id
+    pub by_key: BTreeMap<String, u64>,
+    /// Events and snapshots stored unencrypted
+    pub plaintext: u64,
+}
+
+/// Store wrapper that encrypts events and snapshots at rest
+pub struct EncryptedStore<S> {
+    inner: S,
+    keys: KeyRing,
+    allow_plaintext: bool,
+    append_lock: tokio::sync::Mutex<()>,
+}
+
+impl<S: EventStore + SnapshotStore> EncryptedStore<S> {
+    /// Encrypt records written to `inner` with the active key of `keys`
+    pub fn new(inner: S, keys: KeyRing) -> Self {
+        Self {
+            inner,
+            keys,
+            allow_plaintext: false,
+            append_lock: tokio::sync::Mutex::new(()),
+        }
+    }
+
+    /// Read unencrypted records as they are instead of rejecting them, to
+    /// migrate a store written before encryption was enabled
+    #[must_use]
+    pub fn allow_plaintext(mut self) -> Self {
+        self.allow_plaintext = true;
+        self
+    }
+
+    /// The wrapped store, which holds the sealed records
+    pub fn inner(&self) -> &S {
+        &self.inner
+    }
+
+    /// The keys used to seal and open records
+    pub fn keys(&self) -> &KeyRing {
+        &self.keys
+    }
+
+    /// Count stored records per sealing key, to tell when a retired key is
+    /// no longer needed
+    pub async fn key_usage(&self) -> Result<KeyUsage> {
+        let mut usage = KeyUsage::default();
+        let mut record = |sealed: Option<Sealed>| match sealed {
+            Some(sealed) => *usage.by_key.entry(sealed.key_id).or_default() += 1,
+            None => usage.plaintext += 1,
+        };
+
+        let mut after = 0;
+        loop {
+            let page = self.inner.get_envelopes_after(after, PAGE).await?;
+            let Some(&(last, _)) = page.last() else {
+                break;
+            };
+            for (_, envelope) in page {
+                record(Sealed::from_headers(&envelope.metadata.headers));
+            }
+            after = last;
+        }
+        for snapshot in self.inner.list_snapshots().await? {
+            record(Sealed::from_properties(&snapshot.properties));
+        }
+        Ok(usage)
+    }
+
+    fn seal_event(&self, version: u64, mut envelope: EventEnvelope) -> Result<EventEnvelope> {
+        let event = &envelope.event;
+        let aad = event_aad(version, event);
+        let plaintext = serde_json::to_vec(event).map_err(|e| anyhow!(e))?;
+        let sealed = self.keys.seal(&plaintext, &aad)?;
+
+        envelope.event = redact(event);
+        sealed.into_headers(&mut envelope.metadata.headers);
+        Ok(envelope)
+    }
+
+    fn open_event(&self, version: u64, mut envelope: EventEnvelope) -> Result<EventEnvelope> {
+        let twin_id = envelope.event.twin_id();
+        let headers = &mut envelope.metadata.headers;
+        let Some(sealed) = Sealed::from_headers(headers) else {
+            if self.allow_plaintext {
+                return Ok(envelope);
+            }
+            bail!("Event {version} of twin {twin_id} is not encrypted");
+        };
+        headers.remove(KEY_ID_HEADER);
+        headers.remove(NONCE_HEADER);
+        headers.remove(CIPHERTEXT_HEADER);
+
+        let aad = event_aad(version, &envelope.event);
+        let plaintext = self
+            .keys
+            .open(&sealed, &aad)
+            .map_err(|e| anyhow!("Cannot decrypt event {version} of twin {twin_id}: {e}"))?;
+        envelope.event = serde_json::from_slice(&plaintext).map_err(|e| anyhow!(e))?;
+        Ok(envelope)
+    }
+
+    fn open_events(
+        &self,
+        envelopes: Vec<(u64, EventEnvelope)>,
+    ) -> Result<Vec<(u64, EventEnvelope)>> {
+        envelopes
+            .into_iter()
+            .map(|(version, envelope)| Ok((version, self.open_event(version, envelope)?)))
+            .collect()
+    }
+
+    fn seal_snapshot(&self, snapshot: &TwinSnapshot) -> Result<TwinSnapshot> {
+        let plaintext = serde_json::to_vec(snapshot).map_err(|e| anyhow!(e))?;
+        let sealed = self.keys.seal(&plaintext, &snapshot_aad(snapshot))?;
+        Ok(redact_snapshot(snapshot, sealed))
+    }
+
+    fn open_snapshot(&self, snapshot: TwinSnapshot) -> Result<TwinSnapshot> {
+        let Some(sealed) = Sealed::from_properties(&snapshot.properties) else {
+            if self.allow_plaintext {
+                return Ok(snapshot);
+            }
+            bail!("Snapshot of twin {} is not encrypted", snapshot.twin_id);
+        };
+        let plaintext = self
+            .keys
+            .open(&sealed, &snapshot_aad(&snapshot))
+            .map_err(|e| anyhow!("Cannot decrypt snapshot of twin {}: {e}", snapshot.twin_id))?;
+        serde_json::from_slice(&plaintext).map_err(|e| anyhow!(e))
+    }
+}
+
+#[async_trait]
+impl<S: EventStore + SnapshotStore> EventStore for EncryptedStore<S> {
+    async fn append_envelope(&self, envelope: EventEnvelope) -> Result<u64> {
+        let versions = self.append_envelopes(vec![envelope]).await?;
+        versions
+            .first()
+            .copied()
+            .ok_or_else(|| anyhow!("Append produced no version"))
+    }
+
+    async fn append_envelopes(&self, envelopes: Vec<EventEnvelope>) -> Result<Vec<u64>> {
+        // Versions are sealed in, so they must be known before appending
+        let guard = self.append_lock.lock().await;
+        let latest = self.inner.get_latest_version().await?;
+        let expected: Vec<u64> = (latest + 1..).take(envelopes.len()).collect();
+        let sealed = envelopes
+            .into_iter()
+            .zip(&expected)
+            .map(|(envelope, version)| self.seal_event(*version, envelope))
+            .collect::<Result<_>>()?;
+        let versions = self.inner.append_envelopes(sealed).await?;
+        drop(guard);
+
+        if versions != expected {
+            bail!(
+                "Encrypted events were stored at versions {versions:?} instead of {expected:?}; \
+                 is something else writing to the store?"
+            );
+        }
+        Ok(versions)
+    }
+
+    async fn import_envelopes(&self, envelopes: Vec<(u64, EventEnvelope)>) -> Result<()> {
+        let guard = self.append_lock.lock().await;
+        let sealed = envelopes
+            .into_iter()
+            .map(|(version, envelope)| Ok((version, self.seal_event(version, envelope)?)))
+            .collect::<Result<_>>()?;
+        self.inner.import_envelopes(sealed).await?;
+        drop(guard);
+        Ok(())
+    }
+
+    async fn get_envelopes(
+        &self,
+        twin_id: TwinId,
+        after_version: u64,
+    ) -> Result<Vec<(u64, EventEnvelope)>> {
+        self.open_events(self.inner.get_envelopes(twin_id, after_version).await?)
+    }
+
+    async fn get_envelopes_before(
+        &self,
+        twin_id: TwinId,
+        before_version: u64,
+        limit: usize,
+    ) -> Result<Vec<(u64, EventEnvelope)>> {
+        self.open_events(
+            self.inner
+                .get_envelopes_before(twin_id, before_version, limit)
+                .await?,
+        )
+    }
+
+    async fn get_envelopes_after(
+        &self,
+        after_version: u64,
+        limit: usize,
+    ) -> Result<Vec<(u64, EventEnvelope)>> {
+        self.open_events(self.inner.get_envelopes_after(after_version, limit).await?)
+    }
+
+    async fn get_envelopes_in_range(
+        &self,
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+    ) -> Result<Vec<(u64, EventEnvelope)>> {
+        self.open_events(self.inner.get_envelopes_in_range(start, end).await?)
+    }
+
+    async fn get_latest_version(&self) -> Result<u64> {
+        self.inner.get_latest_version().await
+    }
+}
+
+#[async_trait]
+impl<S: EventStore + SnapshotStore> SnapshotStore for EncryptedStore<S> {
+    async fn save_snapshot(&self, snapshot: TwinSnapshot) -> Result<()> {
+        self.inner
+            .save_snapshot(self.seal_snapshot(&snapshot)?)
+            .await
+    }
+
+    async fn get_snapshot(&self, twin_id: TwinId) -> Result<Option<TwinSnapshot>> {
+        self.inner
+            .get_snapshot(twin_id)
+            .await?
+            .map(|snapshot| self.open_snapshot(snapshot))
+            .transpose()
+    }
+
+    async fn list_snapshots(&self) -> Result<Vec<TwinSnapshot>> {
+        self.inner
+            .list_snapshots()
+            .await?
+            .into_iter()
+            .map(|snapshot| self.open_snapshot(snapshot))
+            .collect()
+    }
+
+    async fn list_snapshots_after(
+        &self,
+        after: Option<TwinId>,
+        limit: usize,
+    ) -> Result<Vec<TwinSnapshot>> {
+        self.inner
+            .list_snapshots_after(after, limit)
+            .await?
+            .into_iter()
+            .map(|snapshot| self.open_snapshot(snapshot))
+            .collect()
+    }
+
+    async fn cleanup_old_snapshots(&self, before: DateTime<Utc>) -> Result<u64> {
+        self.inner.cleanup_old_snapshots(before).await
+    }
+}
+
+/// Copy every event and snapshot of `source` into the empty `target`,
+/// sealing them with the target's active key. Versions, timestamps and
+/// metadata are kept.
+///
+/// This rotates keys offline: open the old storage with a key ring that
+/// can read it, a fresh store with the new key, re-encrypt, then swap the
+/// storage. A source opened with `allow_plaintext` also has its
+/// unencrypted records encrypted on the way.
+pub async fn reencrypt<A, B>(
+    source: &EncryptedStore<A>,
+    target: &EncryptedStore<B>,
+) -> Result<TransferReport>
+where
+    A: EventStore + SnapshotStore,
+    B: EventStore + SnapshotStore,
+{
+    let mut report = TransferReport::default();
+
+    for snapshot in source.list_snapshots().await? {
+        target.save_snapshot(snapshot).await?;
+        report.snapshots += 1;
+    }
+
+    let mut after = 0;
+    loop {
+        let page = source.get_envelopes_after(after, PAGE).await?;
+        let Some(&(last, _)) = page.last() else {
+            break;
+        };
+        report.events += page.len() as u64;
+        target.import_envelopes(page).await?;
+        after = last;
+    }
+
+    Ok(report)
+}
@@ -0,0 +1,1105 @@
+//! Segmented append-only file log
+//!
+//! Events are appended to numbered segment files under `<dir>/segments`,
+//! named after the first version they hold. Each record is framed as
+//!
+//! ```text
+//! [len: u32 LE][crc32: u32 LE][version: u64 LE][event envelope JSON]
+//! ```
+//!
+//! where `len` and `crc32` cover everything after the header, and the
+//! envelope carries the event's schema version (see `storage::schema`).
+//! With `FileStoreConfig::compression` set, the envelope is stored as a
+//! compressed frame (see `storage::compression`) instead, and any
+//! dictionary it needs is kept under `<dir>/dictionaries`. Next to every
+//! `.log` segment sits a sparse `.idx` file of `(version, offset)` pairs,
+//! one entry per `index_interval_bytes` of log, used to seek close to a
+//! version instead of scanning the whole segment.
+//!
+//! On open, every segment is scanned to rebuild the twin index. A torn or
+//! corrupt record at the end of the newest segment (a crash mid-append) is
+//! truncated away; a corrupt record followed by more data, or corruption in
+//! an older, sealed segment, is an error.
+//!
+//! Snapshots are stored as one JSON file per twin under `<dir>/snapshots`,
+//! compressed like the log records, and replaced atomically on save.
+//!
+//! Log reads, appends and their syncs, and snapshot saves run on tokio's
+//! blocking pool so a slow disk never stalls the async workers.
+
+use crate::event::{check_import_versions, EventEnvelope, EventStore, SnapshotStore, TwinSnapshot};
+use crate::storage::compression::{Compression, Dictionary, RecordCodec};
+use crate::storage::durability::Durability;
+use crate::storage::schema::{self, UpcasterRegistry};
+use crate::twin::TwinId;
+use anyhow::{anyhow, bail, Result};
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufReader, BufWriter, ErrorKind, Read, Seek, SeekFrom, Take, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard, Weak};
+
+/// Length prefix plus CRC
+const RECORD_HEADER_LEN: u64 = 8;
+
+/// Sparse index entry: version plus byte offset
+const INDEX_ENTRY_LEN: usize = 16;
+
+/// Configuration for the file log
+#[derive(Debug, Clone)]
+pub struct FileStoreConfig {
+    /// Start a new segment once the active one reaches this size. Batches
+    /// are never split, so a segment may exceed it by one batch.
+    pub max_segment_bytes: u64,
+
+    /// Write one sparse index entry per this many bytes of log
+    pub index_interval_bytes: u64,
+
+    /// When appended records are synced to disk
+    pub durability: Durability,
+
+    /// Upcasters for events written with older schemas
+    pub upcasters: UpcasterRegistry,
+
+    /// How new events and snapshots are compressed. Records written with
+    /// other settings stay readable.
+    pub compression: Compression,
+}
+
+impl Default for FileStoreConfig {
+    fn default() -> Self {
+        Self {
+            max_segment_bytes: 64 * 1024 * 1024, // 64 MiB
+            index_interval_bytes: 4096,
+            durability: Durability::EveryEvent,
+            upcasters: UpcasterRegistry::new(),
+            compression: Compression::none(),
+        }
+    }
+}
+
+/// In-memory metadata for one segment file
+#[derive(Clone)]
+struct Segment {
+    first_version: u64,
+    last_version: Option<u64>,
+    min_timestamp: Option<DateTime<Utc>>,
+    max_timestamp: Option<DateTime<Utc>>,
+    size: u64,
+    index: Vec<(u64, u64)>,
+    last_indexed_offset: Option<u64>,
+}
+
+impl Segment {
+    fn new(first_version: u64) -> Self {
+        Self {
+            first_version,
+            last_version: None,
+            min_timestamp: None,
+            max_timestamp: None,
+            size: 0,
+            index: Vec::new(),
+            last_indexed_offset: None,
+        }
+    }
+
+    /// Record metadata for a record at `offset`, returning the sparse index
+    /// entry to write, if one is due
+    fn track(
+        &mut self,
+        version: u64,
+        offset: u64,
+        record_len: u64,
+        timestamp: DateTime<Utc>,
+        index_interval: u64,
+    ) -> Option<(u64, u64)> {
+        self.last_version = Some(version);
+        self.min_timestamp = Some(self.min_timestamp.map_or(timestamp, |t| t.min(timestamp)));
+        self.max_timestamp = Some(self.max_timestamp.map_or(timestamp, |t| t.max(timestamp)));
+        self.size = offset + record_len;
+
+        let due = self
+            .last_indexed_offset
+            .is_none_or(|last| offset - last >= index_interval);
+        if due {
+            self.last_indexed_offset = Some(offset);
+            self.index.push((version, offset));
+            Some((version, offset))
+        } else {
+            None
+        }
+    }
+
+    /// Offset to start scanning from to find `version`
+    fn seek_offset(&self, version: u64) -> u64 {
+        let pos = self.index.partition_point(|(v, _)| *v <= version);
+        pos.checked_sub(1).map_or(0, |i| self.index[i].1)
+    }
+
+    fn overlaps(&self, start: DateTime<Utc>, end: DateTime<Utc>) -> bool {
+        match (self.min_timestamp, self.max_timestamp) {
+            (Some(min), Some(max)) => min <= end && max >= start,
+            _ => false,
+        }
+    }
+}
+
+/// Outcome of reading one record
+enum ReadOutcome {
+    Record {
+        version: u64,
+        payload: Vec<u8>,
+    },
+    End,
+    /// A damaged last record, as left by a crash mid-append
+    Torn,
+    /// A damaged record followed by more data
+    Corrupt,
+}
+
+/// Read the record at the reader's position, whose limit is the rest of
+/// the segment
+fn read_record(reader: &mut Take<impl Read>) -> Result<ReadOutcome> {
+    let mut header = [0u8; 8];
+    match read_full(reader, &mut header)? {
+        0 => return Ok(ReadOutcome::End),
+        n if n < header.len() => return Ok(ReadOutcome::Torn),
+        _ => {}
+    }
+
+    // The length is untrusted: a record running past the end of the
+    // segment is torn, and one ending before it can only be corrupt
+    let len = u32::from_le_bytes([header[0], header[1], header[2], header[3]]);
+    let crc = u32::from_le_bytes([header[4], header[5], header[6], header[7]]);
+    let damaged = match u64::from(len).cmp(&reader.limit()) {
+        Ordering::Greater => return Ok(ReadOutcome::Torn),
+        Ordering::Equal => ReadOutcome::Torn,
+        Ordering::Less => ReadOutcome::Corrupt,
+    };
+    let len = len as usize;
+    if len < 8 {
+        return Ok(damaged);
+    }
+
+    let mut body = vec![0u8; len];
+    if read_full(reader, &mut body)? < len {
+        return Ok(ReadOutcome::Torn);
+    }
+    if crc32fast::hash(&body) != crc {
+        return Ok(damaged);
+    }
+
+    let payload = body.split_off(8);
+    let version = u64::from_le_bytes(body.try_into().map_err(|_| anyhow!("Bad record"))?);
+    Ok(ReadOutcome::Record { version, payload })
+}
+
+/// Read until `buf` is full or EOF, returning the bytes read
+fn read_full(reader: &mut impl Read, buf: &mut [u8]) -> Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == ErrorKind::Interrupted => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(filled)
+}
+
+/// Frame an event as a log record
+fn encode_record(codec: &RecordCodec, version: u64, envelope: &EventEnvelope) -> Result<Vec<u8>> {
+    let mut body = version.to_le_bytes().to_vec();
+    body.extend(codec.encode(schema::encode_envelope(envelope)?)?);
+
+    let len = u32::try_from(body.len()).map_err(|_| anyhow!("Event too large"))?;
+    let mut record = Vec::with_capacity(body.len() + 8);
+    record.extend_from_slice(&len.to_le_bytes());
+    record.extend_from_slice(&crc32fast::hash(&body).to_le_bytes());
+    record.extend_from_slice(&body);
+    Ok(record)
+}
+
+fn segment_path(dir: &Path, first_version: u64, extension: &str) -> PathBuf {
+    dir.join(format!("{first_version:020}.{extension}"))
+}
+
+/// Writers for the newest segment
+struct ActiveWriter {
+    log: BufWriter<File>,
+    index: BufWriter<File>,
+}
+
+impl ActiveWriter {
+    fn open(dir: &Path, first_version: u64) -> Result<Self> {
+        let open = |extension| {
+            OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(segment_path(dir, first_version, extension))
+        };
+        Ok(Self {
+            log: BufWriter::new(open("log")?),
+            index: BufWriter::new(open("idx")?),
+        })
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.log.flush()?;
+        self.index.flush()?;
+        Ok(())
+    }
+
+    fn sync(&mut self) -> Result<()> {
+        self.flush()?;
+        self.log.get_ref().sync_data()?;
+        self.index.get_ref().sync_data()?;
+        Ok(())
+    }
+}
+
+/// Mutable log state, guarded by one lock
+struct LogState {
+    segments_dir: PathBuf,
+    config: FileStoreConfig,
+    codec: Arc<RecordCodec>,
+    segments: BTreeMap<u64, Segment>,
+    writer: ActiveWriter,
+    twin_events: HashMap<TwinId, Vec<u64>>,
+    next_version: u64,
+    unsynced: bool,
+}
+
+impl LogState {
+    fn open(
+        segments_dir: PathBuf,
+        config: FileStoreConfig,
+        codec: Arc<RecordCodec>,
+    ) -> Result<Self> {
+        let mut first_versions = Vec::new();
+        for entry in fs::read_dir(&segments_dir)? {
+            let path = entry?.path();
+            if path.extension().is_some_and(|e| e == "log") {
+                let first = path
+                    .file_stem()
+                    .and_then(|s| s.to_str())
+                    .and_then(|s| s.parse::<u64>().ok())
+                    .ok_or_else(|| anyhow!("Unexpected segment file {}", path.display()))?;
+                first_versions.push(first);
+            }
+        }
+        first_versions.sort_unstable();
+
+        let mut segments = BTreeMap::new();
+        let mut twin_events: HashMap<TwinId, Vec<u64>> = HashMap::new();
+        let newest = first_versions.last().copied();
+
+        for first in first_versions {
+            let segment = Self::recover_segment(
+                &segments_dir,
+                first,
+                Some(first) == newest,
+                &config,
+                &codec,
+                &mut twin_events,
+            )?;
+            segments.insert(first, segment);
+        }
+
+        if segments.is_empty() {
+            segments.insert(1, Segment::new(1));
+        }
+
+        let (&active_first, active) = segments
+            .last_key_value()
+            .ok_or_else(|| anyhow!("No active segment"))?;
+        let next_version = active.last_version.map_or(active_first, |v| v + 1);
+        let writer = ActiveWriter::open(&segments_dir, active_first)?;
+
+        Ok(Self {
+            segments_dir,
+            config,
+            codec,
+            segments,
+            writer,
+            twin_events,
+            next_version,
+            unsynced: false,
+        })
+    }
+
+    /// Scan a segment, truncating a torn tail if it is the newest one, and
+    /// rewrite its sparse index if it is missing or stale
+    fn recover_segment(
+        dir: &Path,
+        first_version: u64,
+        is_newest: bool,
+        config: &FileStoreConfig,
+        codec: &RecordCodec,
+        twin_events: &mut HashMap<TwinId, Vec<u64>>,
+    ) -> Result<Segment> {
+        let log_path = segment_path(dir, first_version, "log");
+        let file = File::open(&log_path)?;
+        let len = file.metadata()?.len();
+        let mut reader = BufReader::new(file).take(len);
+        let mut segment = Segment::new(first_version);
+        let mut offset = 0;
+
+        loop {
+            match read_record(&mut reader)? {
+                ReadOutcome::Record { version, payload } => {
+                    let event = config.upcasters.decode_event(&codec.decode(&payload)?)?;
+                    let record_len = RECORD_HEADER_LEN + 8 + payload.len() as u64;
+                    segment.track(
+                        version,
+                        offset,
+                        record_len,
+                        event.timestamp(),
+                        config.index_interval_bytes,
+                    );
+                    twin_events
+                        .entry(event.twin_id())
+                        .or_default()
+                        .push(version);
+                    offset += record_len;
+                }
+                ReadOutcome::End => break,
+                ReadOutcome::Torn if is_newest => {
+                    tracing::warn!(
+                        "Truncating torn record at offset {} in {}",
+                        offset,
+                        log_path.display()
+                    );
+                    OpenOptions::new()
+                        .write(true)
+                        .open(&log_path)?
+                        .set_len(offset)?;
+                    break;
+                }
+                ReadOutcome::Torn => {
+                    bail!(
+                        "Corrupt record at offset {offset} in sealed segment {}",
+                        log_path.display()
+                    );
+                }
+                ReadOutcome::Corrupt => {
+                    bail!(
+                        "Corrupt record at offset {offset} in {}",
+                        log_path.display()
+                    );
+                }
+            }
+        }
+
+        let mut index_bytes = Vec::with_capacity(segment.index.len() * INDEX_ENTRY_LEN);
+        for (version, offset) in &segment.index {
+            index_bytes.extend_from_slice(&version.to_le_bytes());
+            index_bytes.extend_from_slice(&offset.to_le_bytes());
+        }
+        let index_path = segment_path(dir, first_version, "idx");
+        if fs::read(&index_path).ok().as_deref() != Some(index_bytes.as_slice()) {
+            fs::write(&index_path, &index_bytes)?;
+        }
+
+        Ok(segment)
+    }
+
+    fn active_mut(&mut self) -> Result<&mut Segment> {
+        self.segments
+            .values_mut()
+            .next_back()
+            .ok_or_else(|| anyhow!("No active segment"))
+    }
+
+    /// Seal the active segment and start a new one at `next_version`
+    fn roll(&mut self) -> Result<()> {
+        self.writer.sync()?;
+        self.unsynced = false;
+        let first = self.next_version;
+        self.writer = ActiveWriter::open(&self.segments_dir, first)?;
+        self.segments.insert(first, Segment::new(first));
+        Ok(())
+    }
+
+    fn append(&mut self, envelopes: &[EventEnvelope]) -> Result<Vec<u64>> {
+        let first = self.next_version;
+        self.write((first..).zip(envelopes))
+    }
+
+    /// Write records at the given ascending versions, which must not be
+    /// below `next_version`
+    ///
+    /// The batch goes to one segment and is only accounted for once it is
+    /// written; if writing fails, the segment is cut back to where it was.
+    fn write<'a>(
+        &mut self,
+        records: impl IntoIterator<Item = (u64, &'a EventEnvelope)>,
+    ) -> Result<Vec<u64>> {
+        // Encode first, so an unencodable event leaves the log untouched
+        let records = records
+            .into_iter()
+            .map(|(version, envelope)| {
+                Ok((
+                    version,
+                    envelope,
+                    encode_record(&self.codec, version, envelope)?,
+                ))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let (Some((first, _, _)), Some((last, _, _))) = (records.first(), records.last()) else {
+            return Ok(Vec::new());
+        };
+        let (first, last) = (*first, *last);
+
+        let max_segment_bytes = self.config.max_segment_bytes;
+        let active = self.active_mut()?;
+        if active.size >= max_segment_bytes && active.last_version.is_some() {
+            // Skipped versions (from imports) are never reused
+            self.next_version = first;
+            self.roll()?;
+        }
+
+        let mut segment = self.active_mut()?.clone();
+        let (log_len, index_len) = (segment.size, (segment.index.len() * INDEX_ENTRY_LEN) as u64);
+        let sync = self.config.durability.flushes_on_append();
+        let written = self.write_records(&mut segment, &records).and_then(|()| {
+            if sync {
+                self.writer.sync()?;
+            }
+            Ok(())
+        });
+        if let Err(e) = written {
+            self.discard_writes(log_len, index_len)?;
+            return Err(e);
+        }
+
+        *self.active_mut()? = segment;
+        for (version, envelope, _) in &records {
+            self.twin_events
+                .entry(envelope.event.twin_id())
+                .or_default()
+                .push(*version);
+        }
+        self.next_version = last + 1;
+        self.unsynced = !sync;
+        Ok(records.iter().map(|(version, _, _)| *version).collect())
+    }
+
+    /// Write encoded records to the active segment and flush them, tracking
+    /// them in `segment`, a copy of its metadata
+    fn write_records(
+        &mut self,
+        segment: &mut Segment,
+        records: &[(u64, &EventEnvelope, Vec<u8>)],
+    ) -> Result<()> {
+        let index_interval = self.config.index_interval_bytes;
+        for (version, envelope, record) in records {
+            let entry = segment.track(
+                *version,
+                segment.size,
+                record.len() as u64,
+                envelope.event.timestamp(),
+                index_interval,
+            );
+            self.writer.log.write_all(record)?;
+            if let Some((v, o)) = entry {
+                self.writer.index.write_all(&v.to_le_bytes())?;
+                self.writer.index.write_all(&o.to_le_bytes())?;
+            }
+        }
+        self.writer.flush()
+    }
+
+    /// Drop writes to the active segment that were not accounted for: what
+    /// is still buffered is discarded and its files are cut back to
+    /// `log_len` and `index_len` bytes
+    fn discard_writes(&mut self, log_len: u64, index_len: u64) -> Result<()> {
+        let first = self
+            .segments
+            .keys()
+            .next_back()
+            .copied()
+            .ok_or_else(|| anyhow!("No active segment"))?;
+        let writer = std::mem::replace(
+            &mut self.writer,
+            ActiveWriter::open(&self.segments_dir, first)?,
+        );
+        // Taken apart rather than dropped, which would flush the buffers
+        let (log, _) = writer.log.into_parts();
+        let (index, _) = writer.index.into_parts();
+        log.set_len(log_len)?;
+        index.set_len(index_len)?;
+        Ok(())
+    }
+
+    fn sync(&mut self) -> Result<()> {
+        if self.unsynced {
+            self.writer.sync()?;
+            self.unsynced = false;
+        }
+        Ok(())
+    }
+
+    fn decode(&self, payload: &[u8]) -> Result<EventEnvelope> {
+        self.config
+            .upcasters
+            .decode_envelope(&self.codec.decode(payload)?)
+    }
+
+    /// Read the wanted versions (ascending) from whichever segments hold
+    /// them
+    fn read_all_versions(&self, wanted: &[u64]) -> Result<Vec<(u64, EventEnvelope)>> {
+        let mut events = Vec::with_capacity(wanted.len());
+        let mut rest = wanted;
+        for segment in self.segments.values() {
+            let split =
+                rest.partition_point(|v| segment.last_version.is_some_and(|last| *v <= last));
+            let (in_segment, remaining) = rest.split_at(split);
+            events.extend(self.read_versions(segment, in_segment)?);
+            rest = remaining;
+            if rest.is_empty() {
+                break;
+            }
+        }
+        Ok(events)
+    }
+
+    /// Read the wanted versions (ascending) from one segment
+    fn read_versions(
+        &self,
+        segment: &Segment,
+        wanted: &[u64],
+    ) -> Result<Vec<(u64, EventEnvelope)>> {
+        let (Some(&first), Some(&last)) = (wanted.first(), wanted.last()) else {
+            return Ok(Vec::new());
+        };
+
+        let mut reader = self.segment_reader(segment, segment.seek_offset(first))?;
+        let mut events = Vec::with_capacity(wanted.len());
+        let mut next = 0;
+
+        while let Some((version, payload)) = self.next_record(segment, &mut reader)? {
+            if version > last {
+                break;
+            }
+            while next < wanted.len() && wanted[next] < version {
+                next += 1;
+            }
+            if wanted.get(next) == Some(&version) {
+                let envelope = self.decode(&payload)?;
+                events.push((version, envelope));
+            }
+        }
+
+        Ok(events)
+    }
+
+    /// Read up to `limit` records after `after_version`, across segments
+    fn read_after(&self, after_version: u64, limit: usize) -> Result<Vec<(u64, EventEnvelope)>> {
+        let mut events = Vec::new();
+        let segments = self
+            .segments
+            .values()
+            .filter(|s| s.last_version.is_some_and(|last| last > after_version));
+
+        for segment in segments {
+            let mut reader = self.segment_reader(segment, segment.seek_offset(after_version))?;
+            while events.len() < limit {
+                let Some((version, payload)) = self.next_record(segment, &mut reader)? else {
+                    break;
+                };
+                if version > after_version {
+                    let envelope = self.decode(&payload)?;
+                    events.push((version, envelope));
+                }
+            }
+            if events.len() >= limit {
+                break;
+            }
+        }
+
+        Ok(events)
+    }
+
+    /// Read every record of a segment that matches `keep`
+    fn scan_segment(
+        &self,
+        segment: &Segment,
+        keep: impl Fn(&EventEnvelope) -> bool,
+    ) -> Result<Vec<(u64, EventEnvelope)>> {
+        let mut reader = self.segment_reader(segment, 0)?;
+        let mut events = Vec::new();
+
+        while let Some((version, payload)) = self.next_record(segment, &mut reader)? {
+            let envelope = self.decode(&payload)?;
+            if keep(&envelope) {
+                events.push((version, envelope));
+            }
+        }
+
+        Ok(events)
+    }
+
+    /// Read the next record from a segment reader, or `None` at the end
+    /// of what has been accounted for. Everything before that end was
+    /// checked when it was written or opened, so damage there is an error.
+    fn next_record(
+        &self,
+        segment: &Segment,
+        reader: &mut Take<BufReader<File>>,
+    ) -> Result<Option<(u64, Vec<u8>)>> {
+        let offset = segment.size - reader.limit();
+        match read_record(reader)? {
+            ReadOutcome::Record { version, payload } => Ok(Some((version, payload))),
+            ReadOutcome::End => Ok(None),
+            ReadOutcome::Torn | ReadOutcome::Corrupt => bail!(
+                "Corrupt record at offset {offset} in {}",
+                segment_path(&self.segments_dir, segment.first_version, "log").display()
+            ),
+        }
+    }
+
+    fn segment_reader(&self, segment: &Segment, offset: u64) -> Result<Take<BufReader<File>>> {
+        let mut file = File::open(segment_path(
+            &self.segments_dir,
+            segment.first_version,
+            "log",
+        ))?;
+        file.seek(SeekFrom::Start(offset))?;
+        // Never read past what has been accounted for
+        Ok(BufReader::new(file).take(segment.size.saturating_sub(offset)))
+    }
+
+    /// Delete sealed segments matching `remove`; the active segment is kept
+    fn remove_segments(&mut self, remove: impl Fn(&Segment) -> bool) -> Result<usize> {
+        let active_first = self.segments.keys().next_back().copied();
+        let doomed: Vec<u64> = self
+            .segments
+            .iter()
+            .filter(|(first, segment)| Some(**first) != active_first && remove(segment))
+            .map(|(first, _)| *first)
+            .collect();
+
+        for first in &doomed {
+            if let Some(segment) = self.segments.remove(first) {
+                fs::remove_file(segment_path(&self.segments_dir, *first, "log"))?;
+                fs::remove_file(segment_path(&self.segments_dir, *first, "idx")).ok();
+
+                if let Some(last) = segment.last_version {
+                    for versions in self.twin_events.values_mut() {
+                        versions.retain(|v| *v < *first || *v > last);
+                    }
+                }
+            }
+        }
+        self.twin_events.retain(|_, versions| !versions.is_empty());
+
+        Ok(doomed.len())
+    }
+}
+
+impl Drop for LogState {
+    fn drop(&mut self) {
+        if let Err(e) = self.writer.sync() {
+            tracing::warn!("Failed to sync file log on close: {}", e);
+        }
+    }
+}
+
+/// Segmented append-only file event store
+pub struct FileEventStore {
+    state: Arc<Mutex<LogState>>,
+    snapshots_dir: PathBuf,
+    codec: Arc<RecordCodec>,
+}
+
+impl FileEventStore {
+    /// Open (or create) a file log in `dir` with default settings
+    pub fn new(dir: impl AsRef<Path>) -> Result<Self> {
+        Self::with_config(dir, FileStoreConfig::default())
+    }
+
+    /// Open (or create) a file log in `dir`
+    pub fn with_config(dir: impl AsRef<Path>, config: FileStoreConfig) -> Result<Self> {
+        let dir = dir.as_ref();
+        let segments_dir = dir.join("segments");
+        let snapshots_dir = dir.join("snapshots");
+        fs::create_dir_all(&segments_dir)?;
+        fs::create_dir_all(&snapshots_dir)?;
+
+        let codec = Arc::new(Self::open_codec(
+            &dir.join("dictionaries"),
+            &config.compression,
+        )?);
+        let durability = config.durability;
+        let state = Arc::new(Mutex::new(LogState::open(
+            segments_dir,
+            config,
+            codec.clone(),
+        )?));
+
+        if let Durability::Interval(interval) = durability {
+            Self::spawn_syncer(Arc::downgrade(&state), interval);
+        }
+
+        Ok(Self {
+            state,
+            snapshots_dir,
+            codec,
+        })
+    }
+
+    /// A codec for `compression` that reads every dictionary saved in
+    /// `dir`, saving the configured one there first
+    fn open_codec(dir: &Path, compression: &Compression) -> Result<RecordCodec> {
+        fs::create_dir_all(dir)?;
+        if let Some(dictionary) = compression.dictionary() {
+            let path = dir.join(format!("{:08x}.dict", dictionary.id()));
+            if !path.exists() {
+                let tmp_path = path.with_extension("dict.tmp");
+                let mut file = File::create(&tmp_path)?;
+                file.write_all(dictionary.as_bytes())?;
+                file.sync_all()?;
+                fs::rename(&tmp_path, &path)?;
+                File::open(dir)?.sync_all()?;
+            }
+        }
+
+        let mut codec = RecordCodec::default();
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            if path.extension().is_some_and(|e| e == "dict") {
+                codec.add_dictionary(&Dictionary::from_bytes(fs::read(&path)?));
+            }
+        }
+        codec.configure(compression.clone());
+        Ok(codec)
+    }
+
+    /// Sync unsynced appends every `interval` until the store is dropped
+    fn spawn_syncer(state: Weak<Mutex<LogState>>, interval: std::time::Duration) {
+        std::thread::spawn(move || loop {
+            std::thread::sleep(interval);
+            let Some(state) = state.upgrade() else {
+                break;
+            };
+            let result = lock_state(&state).sync();
+            if let Err(e) = result {
+                tracing::warn!("Background sync of file log failed: {}", e);
+            }
+        });
+    }
+
+    fn lock(&self) -> MutexGuard<'_, LogState> {
+        lock_state(&self.state)
+    }
+
+    /// Run `f` on the log off the async runtime, as it reads, writes and
+    /// syncs files
+    async fn with_log<T: Send + 'static>(
+        &self,
+        f: impl FnOnce(&mut LogState) -> Result<T> + Send + 'static,
+    ) -> Result<T> {
+        let state = self.state.clone();
+        tokio::task::spawn_blocking(move || f(&mut lock_state(&state)))
+            .await
+            .map_err(|e| anyhow!(e))?
+    }
+
+    /// Force all appended records to disk, regardless of durability mode
+    pub fn flush(&self) -> Result<()> {
+        self.lock().sync()
+    }
+
+    /// Number of segment files, including the active one
+    pub fn segment_count(&self) -> usize {
+        self.lock().segments.len()
+    }
+
+    /// Delete sealed segments whose events all have versions below `version`
+    pub fn remove_segments_before(&self, version: u64) -> Result<usize> {
+        self.lock()
+            .remove_segments(|s| s.last_version.is_some_and(|last| last < version))
+    }
+
+    /// Delete sealed segments whose events all happened before `before`
+    pub fn remove_segments_older_than(&self, before: DateTime<Utc>) -> Result<usize> {
+        self.lock()
+            .remove_segments(|s| s.max_timestamp.is_some_and(|max| max < before))
+    }
+
+    fn snapshot_path(&self, twin_id: TwinId) -> PathBuf {
+        self.snapshots_dir.join(format!("{twin_id}.json"))
+    }
+
+    /// Every stored snapshot with its file
+    fn snapshot_files(&self) -> Result<Vec<(PathBuf, TwinSnapshot)>> {
+        let mut snapshots = Vec::new();
+        for entry in fs::read_dir(&self.snapshots_dir)? {
+            let path = entry?.path();
+            if path.extension().is_none_or(|e| e != "json") {
+                continue;
+            }
+            let snapshot = decode_snapshot(&self.codec, &fs::read(&path)?)?;
+            snapshots.push((path, snapshot));
+        }
+        Ok(snapshots)
+    }
+}
+
+fn decode_snapshot(codec: &RecordCodec, stored: &[u8]) -> Result<TwinSnapshot> {
+    serde_json::from_slice(&codec.decode(stored)?).map_err(|e| anyhow!(e))
+}
+
+fn lock_state(state: &Mutex<LogState>) -> MutexGuard<'_, LogState> {
+    state
+        .lock()
+        .unwrap_or_else(std::sync::PoisonError::into_inner)
+}
+
+#[async_trait]
+impl EventStore for FileEventStore {
+    async fn append_envelope(&self, envelope: EventEnvelope) -> Result<u64> {
+        let versions = self
+            .with_log(move |state| state.append(std::slice::from_ref(&envelope)))
+            .await?;
+        versions
+            .first()
+            .copied()
+            .ok_or_else(|| anyhow!("Append produced no version"))
+    }
+
+    async fn append_envelopes(&self, envelopes: Vec<EventEnvelope>) -> Result<Vec<u64>> {
+        self.with_log(move |state| state.append(&envelopes)).await
+    }
+
+    async fn import_envelopes(&self, envelopes: Vec<(u64, EventEnvelope)>) -> Result<()> {
+        self.with_log(move |state| {
+            check_import_versions(state.next_version - 1, &envelopes)?;
+            state.write(envelopes.iter().map(|(v, e)| (*v, e)))?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn get_envelopes(
+        &self,
+        twin_id: TwinId,
+        after_version: u64,
+    ) -> Result<Vec<(u64, EventEnvelope)>> {
+        self.with_log(move |state| {
+            let Some(versions) = state.twin_events.get(&twin_id) else {
+                return Ok(Vec::new());
+            };
+            let start = versions.partition_point(|v| *v <= after_version);
+            state.read_all_versions(&versions[start..])
+        })
+        .await
+    }
+
+    async fn get_envelopes_before(
+        &self,
+        twin_id: TwinId,
+        before_version: u64,
+        limit: usize,
+    ) -> Result<Vec<(u64, EventEnvelope)>> {
+        self.with_log(move |state| {
+            let Some(versions) = state.twin_events.get(&twin_id) else {
+                return Ok(Vec::new());
+            };
+            let end = versions.partition_point(|v| *v < before_version);
+            state.read_all_versions(&versions[end.saturating_sub(limit)..end])
+        })
+        .await
+    }
+
+    async fn get_envelopes_after(
+        &self,
+        after_version: u64,
+        limit: usize,
+    ) -> Result<Vec<(u64, EventEnvelope)>> {
+        self.with_log(move |state| state.read_after(after_version, limit))
+            .await
+    }
+
+    async fn get_envelopes_in_range(
+        &self,
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+    ) -> Result<Vec<(u64, EventEnvelope)>> {
+        self.with_log(move |state| {
+            let mut events = Vec::new();
+            for segment in state.segments.values().filter(|s| s.overlaps(start, end)) {
+                events.extend(state.scan_segment(segment, |envelope| {
+                    let timestamp = envelope.event.timestamp();
+                    timestamp >= start && timestamp <= end
+                })?);
+            }
+            Ok(events)
+        })
+        .await
+    }
+
+    async fn get_latest_version(&self) -> Result<u64> {
+        Ok(self.lock().next_version - 1)
+    }
+}
+
+#[async_trait]
+impl SnapshotStore for FileEventStore {
+    async fn save_snapshot(&self, snapshot: TwinSnapshot) -> Result<()> {
+        let path = self.snapshot_path(snapshot.twin_id);
+        let tmp_path = path.with_extension("json.tmp");
+        let codec = self.codec.clone();
+
+        tokio::task::spawn_blocking(move || {
+            let record = serde_json::to_vec(&snapshot).map_err(|e| anyhow!(e))?;
+            let mut file = File::create(&tmp_path)?;
+            file.write_all(&codec.encode(record)?)?;
+            file.sync_all()?;
+            fs::rename(&tmp_path, &path)?;
+            Ok(())
+        })
+        .await
+        .map_err(|e| anyhow!(e))?
+    }
+
+    async fn get_snapshot(&self, twin_id: TwinId) -> Result<Option<TwinSnapshot>> {
+        match fs::read(self.snapshot_path(twin_id)) {
+            Ok(data) => Ok(Some(decode_snapshot(&self.codec, &data)?)),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn list_snapshots(&self) -> Result<Vec<TwinSnapshot>> {
+        self.snapshot_files()?
+            .into_iter()
+            .map(|(_, snapshot)| Ok(snapshot))
+            .collect()
+    }
+
+    async fn list_snapshots_after(
+        &self,
+        after: Option<TwinId>,
+        limit: usize,
+    ) -> Result<Vec<TwinSnapshot>> {
+        let mut twins = Vec::new();
+        for entry in fs::read_dir(&self.snapshots_dir)? {
+            let path = entry?.path();
+            if path.extension().is_none_or(|e| e != "json") {
+                continue;
+            }
+            let twin_id = path
+                .file_stem()
+                .and_then(|s| s.to_str())
+                .and_then(|s| s.parse().ok())
+                .map(TwinId)
+                .ok_or_else(|| anyhow!("Unexpected snapshot file {}", path.display()))?;
+            if after.is_none_or(|after| twin_id > after) {
+                twins.push(twin_id);
+            }
+        }
+        twins.sort_unstable();
+        twins.truncate(limit);
+
+        let mut snapshots = Vec::with_capacity(twins.len());
+        for twin_id in twins {
+            // A snapshot removed since the listing is skipped
+            if let Some(snapshot) = self.get_snapshot(twin_id).await? {
+                snapshots.push(snapshot);
+            }
+        }
+        Ok(snapshots)
+    }
+
+    async fn cleanup_old_snapshots(&self, before: DateTime<Utc>) -> Result<u64> {
+        let mut count = 0;
+
+        for (path, snapshot) in self.snapshot_files()? {
+            if snapshot.timestamp < before {
+                fs::remove_file(&path)?;
+                count += 1;
+            }
+        }
+
+        Ok(count)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::event::{EventMetadata, TwinEvent};
+
+    fn telemetry(twin_id: TwinId) -> EventEnvelope {
+        EventEnvelope {
+            metadata: EventMetadata::new(),
+            event: TwinEvent::TelemetryReceived {
+                twin_id,
+                data: vec![("value".to_string(), 1.0)],
+                timestamp: Utc::now(),
+            },
+        }
+    }
+
+    #[test]
+    fn test_discarded_writes_leave_no_trace() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = FileStoreConfig {
+            index_interval_bytes: 1,
+            ..FileStoreConfig::default()
+        };
+        let twin_id = TwinId::new();
+        let mut state =
+            LogState::open(dir.path().to_path_buf(), config.clone(), Arc::default()).unwrap();
+        state.append(&[telemetry(twin_id)]).unwrap();
+
+        // Written and flushed, then failing before it is accounted for
+        let segment = state.active_mut().unwrap().clone();
+        let (log_len, index_len) = (segment.size, (segment.index.len() * INDEX_ENTRY_LEN) as u64);
+        let lost = telemetry(twin_id);
+        let record = encode_record(&state.codec, 2, &lost).unwrap();
+        state
+            .write_records(&mut segment.clone(), &[(2, &lost, record)])
+            .unwrap();
+        state.discard_writes(log_len, index_len).unwrap();
+
+        assert_eq!(state.append(&[telemetry(twin_id)]).unwrap(), vec![2]);
+        drop(state);
+        let state = LogState::open(dir.path().to_path_buf(), config, Arc::default()).unwrap();
+        assert_eq!(state.twin_events[&twin_id], vec![1, 2]);
+        let segment = state.segments.values().next().unwrap();
+        assert_eq!(segment.index.len(), 2);
+    }
+
+    #[test]
+    fn test_damage_inside_the_log_is_an_error() {
+        let dir = tempfile::tempdir().unwrap();
+        let twin_id = TwinId::new();
+        let mut state = LogState::open(
+            dir.path().to_path_buf(),
+            FileStoreConfig::default(),
+            Arc::default(),
+        )
+        .unwrap();
+        let envelopes: Vec<_> = (0..3).map(|_| telemetry(twin_id)).collect();
+        state.append(&envelopes).unwrap();
+
+        // Flip a byte in the middle record after it has been accounted for
+        let segment = state.segments.values().next().unwrap().clone();
+        let path = segment_path(&state.segments_dir, segment.first_version, "log");
+        let mut bytes = std::fs::read(&path).unwrap();
+        let middle = bytes.len() / 2;
+        bytes[middle] ^= 0xff;
+        std::fs::write(&path, bytes).unwrap();
+
+        assert!(state.read_after(0, 10).is_err());
+        assert!(state.read_versions(&segment, &[1, 2, 3]).is_err());
+        assert!(state.scan_segment(&segment, |_| true).is_err());
+    }
+}
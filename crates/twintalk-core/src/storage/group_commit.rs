@@ -0,0 +1,97 @@
+//! Group commit for concurrent appends
+//!
+//! Concurrent appenders queue their events for a committer task, which
+//! writes everything queued so far with a single `append_envelopes`, so N
+//! concurrent appends cost one flush instead of N.
+//!
+//! The committer runs on its own, so a queued event is committed even if
+//! the caller that queued it is cancelled; that caller just never learns
+//! its version. With `Durability::EveryEvent` the event is then durable
+//! once committed, as usual, but nobody was told it was.
+
+use crate::event::{EventEnvelope, EventStore, TwinEvent};
+use anyhow::{anyhow, Result};
+use std::sync::{Arc, OnceLock};
+use tokio::sync::{mpsc, oneshot};
+
+type Pending = (EventEnvelope, oneshot::Sender<Result<u64, String>>);
+
+/// Batches concurrent appends to an `EventStore`
+pub struct GroupCommitter {
+    store: Arc<dyn EventStore>,
+    queue: OnceLock<mpsc::UnboundedSender<Pending>>,
+    max_batch: usize,
+}
+
+impl GroupCommitter {
+    /// Create a group committer writing to `store`, committing at most
+    /// `max_batch` events per `append_envelopes` call
+    pub fn new(store: Arc<dyn EventStore>, max_batch: usize) -> Self {
+        Self {
+            store,
+            queue: OnceLock::new(),
+            max_batch: max_batch.max(1),
+        }
+    }
+
+    /// Append an event with fresh metadata, possibly committing it together
+    /// with others
+    pub async fn append(&self, event: TwinEvent) -> Result<u64> {
+        self.append_envelope(EventEnvelope::new(event)).await
+    }
+
+    /// Append an event with its metadata, possibly committing it together
+    /// with others
+    pub async fn append_envelope(&self, envelope: EventEnvelope) -> Result<u64> {
+        let (tx, rx) = oneshot::channel();
+        // The committer is started on first use, from within the runtime
+        self.queue
+            .get_or_init(|| {
+                let (queue, pending) = mpsc::unbounded_channel();
+                tokio::spawn(commit_queued(self.store.clone(), pending, self.max_batch));
+                queue
+            })
+            .send((envelope, tx))
+            .map_err(|_| anyhow!("Group commit has stopped"))?;
+
+        rx.await
+            .map_err(|_| anyhow!("Group commit dropped the event"))?
+            .map_err(|e| anyhow!("Group commit failed: {e}"))
+    }
+}
+
+/// Commit queued events until the committer is dropped, each time taking
+/// everything queued so far, up to `max_batch`
+async fn commit_queued(
+    store: Arc<dyn EventStore>,
+    mut pending: mpsc::UnboundedReceiver<Pending>,
+    max_batch: usize,
+) {
+    while let Some(first) = pending.recv().await {
+        let mut batch = vec![first];
+        while batch.len() < max_batch {
+            let Ok(next) = pending.try_recv() else {
+                break;
+            };
+            batch.push(next);
+        }
+        commit(store.as_ref(), batch).await;
+    }
+}
+
+async fn commit(store: &dyn EventStore, batch: Vec<Pending>) {
+    let (envelopes, senders): (Vec<_>, Vec<_>) = batch.into_iter().unzip();
+    match store.append_envelopes(envelopes).await {
+        Ok(versions) => {
+            for (tx, version) in senders.into_iter().zip(versions) {
+                let _ = tx.send(Ok(version));
+            }
+        }
+        Err(e) => {
+            let message = e.to_string();
+            for tx in senders {
+                let _ = tx.send(Err(message.clone()));
+            }
+        }
+    }
+}
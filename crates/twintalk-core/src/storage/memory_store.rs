@@ -1,18 +1,21 @@
 //! In-memory event store for testing and development
 
-use crate::event::{EventStore, SnapshotStore, TwinEvent, TwinSnapshot};
+use crate::event::{check_import_versions, EventEnvelope, EventStore, SnapshotStore, TwinSnapshot};
+use crate::storage::audit::is_chained;
+use crate::storage::retention::{CompactionReport, RetentionPolicy};
 use crate::twin::TwinId;
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use async_trait::async_trait;
 use chrono::{DateTime, Utc};
 use dashmap::DashMap;
+use std::collections::HashSet;
 use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
 
 /// In-memory event store (non-persistent)
 #[derive(Clone)]
 pub struct MemoryEventStore {
-    events: Arc<DashMap<u64, TwinEvent>>,
+    events: Arc<DashMap<u64, EventEnvelope>>,
     twin_events: Arc<DashMap<TwinId, Vec<u64>>>,
     snapshots: Arc<DashMap<TwinId, TwinSnapshot>>,
     version_counter: Arc<AtomicU64>,
@@ -28,6 +31,40 @@ impl MemoryEventStore {
             version_counter: Arc::new(AtomicU64::new(0)),
         }
     }
+
+    /// Remove events expired under `policy` that are folded into a snapshot
+    pub fn compact(&self, policy: &RetentionPolicy, now: DateTime<Utc>) -> CompactionReport {
+        let mut report = CompactionReport::default();
+        let snapshots: Vec<TwinSnapshot> = self.snapshots.iter().map(|s| s.clone()).collect();
+
+        for snapshot in snapshots {
+            // Holding the index entry keeps appends for this twin out
+            let Some(mut versions) = self.twin_events.get_mut(&snapshot.twin_id) else {
+                continue;
+            };
+            let events: Vec<_> = versions
+                .iter()
+                .filter_map(|v| self.events.get(v).map(|e| (*v, e.clone())))
+                .collect();
+            let removed =
+                policy.compactable(&snapshot, events.iter().map(|(v, e)| (*v, &e.event)), now);
+            if !removed.is_empty() && events.iter().any(|(_, e)| is_chained(&e.metadata)) {
+                report.chained += 1;
+                continue;
+            }
+
+            let removed_versions: HashSet<u64> = removed.iter().map(|(v, _)| *v).collect();
+            versions.retain(|v| !removed_versions.contains(v));
+            drop(versions);
+
+            for version in &removed_versions {
+                self.events.remove(version);
+            }
+            report.record_twin(&removed);
+        }
+
+        report
+    }
 }
 
 impl Default for MemoryEventStore {
@@ -38,22 +75,60 @@ impl Default for MemoryEventStore {
 
 #[async_trait]
 impl EventStore for MemoryEventStore {
-    async fn append(&self, event: TwinEvent) -> Result<u64> {
+    async fn append_envelope(&self, envelope: EventEnvelope) -> Result<u64> {
         let version = self.version_counter.fetch_add(1, Ordering::SeqCst) + 1;
-        let twin_id = event.twin_id();
+        let twin_id = envelope.event.twin_id();
 
-        self.events.insert(version, event);
+        self.events.insert(version, envelope);
 
         self.twin_events.entry(twin_id).or_default().push(version);
 
         Ok(version)
     }
 
-    async fn get_events(
+    async fn append_envelopes(&self, envelopes: Vec<EventEnvelope>) -> Result<Vec<u64>> {
+        // Reserve a contiguous range of versions for the whole batch
+        let count = envelopes.len() as u64;
+        let first = self.version_counter.fetch_add(count, Ordering::SeqCst) + 1;
+
+        let mut versions = Vec::with_capacity(envelopes.len());
+        for (version, envelope) in (first..).zip(envelopes) {
+            self.twin_events
+                .entry(envelope.event.twin_id())
+                .or_default()
+                .push(version);
+            self.events.insert(version, envelope);
+            versions.push(version);
+        }
+
+        Ok(versions)
+    }
+
+    async fn import_envelopes(&self, envelopes: Vec<(u64, EventEnvelope)>) -> Result<()> {
+        let Some(&(last, _)) = envelopes.last() else {
+            return Ok(());
+        };
+        let latest = self.version_counter.load(Ordering::SeqCst);
+        check_import_versions(latest, &envelopes)?;
+        self.version_counter
+            .compare_exchange(latest, last, Ordering::SeqCst, Ordering::SeqCst)
+            .map_err(|_| anyhow!("Events were appended during import"))?;
+
+        for (version, envelope) in envelopes {
+            self.twin_events
+                .entry(envelope.event.twin_id())
+                .or_default()
+                .push(version);
+            self.events.insert(version, envelope);
+        }
+        Ok(())
+    }
+
+    async fn get_envelopes(
         &self,
         twin_id: TwinId,
         after_version: u64,
-    ) -> Result<Vec<(u64, TwinEvent)>> {
+    ) -> Result<Vec<(u64, EventEnvelope)>> {
         let versions = self
             .twin_events
             .get(&twin_id)
@@ -73,20 +148,72 @@ impl EventStore for MemoryEventStore {
         Ok(events)
     }
 
-    async fn get_events_in_range(
+    async fn get_envelopes_before(
+        &self,
+        twin_id: TwinId,
+        before_version: u64,
+        limit: usize,
+    ) -> Result<Vec<(u64, EventEnvelope)>> {
+        let mut versions: Vec<u64> = self
+            .twin_events
+            .get(&twin_id)
+            .map(|v| v.iter().copied().filter(|v| *v < before_version).collect())
+            .unwrap_or_default();
+        versions.sort_unstable();
+        let skip = versions.len().saturating_sub(limit);
+
+        let events = versions[skip..]
+            .iter()
+            .filter_map(|version| {
+                self.events
+                    .get(version)
+                    .map(|envelope| (*version, envelope.clone()))
+            })
+            .collect();
+        Ok(events)
+    }
+
+    async fn get_envelopes_after(
+        &self,
+        after_version: u64,
+        limit: usize,
+    ) -> Result<Vec<(u64, EventEnvelope)>> {
+        // Imported versions may leave gaps, so select by key rather than
+        // walking the version range
+        let mut versions: Vec<u64> = self
+            .events
+            .iter()
+            .map(|entry| *entry.key())
+            .filter(|version| *version > after_version)
+            .collect();
+        versions.sort_unstable();
+        versions.truncate(limit);
+
+        let events = versions
+            .into_iter()
+            .filter_map(|version| {
+                self.events
+                    .get(&version)
+                    .map(|envelope| (version, envelope.clone()))
+            })
+            .collect();
+        Ok(events)
+    }
+
+    async fn get_envelopes_in_range(
         &self,
         start: DateTime<Utc>,
         end: DateTime<Utc>,
-    ) -> Result<Vec<(u64, TwinEvent)>> {
+    ) -> Result<Vec<(u64, EventEnvelope)>> {
         let mut events = Vec::new();
 
         for entry in self.events.iter() {
             let version = *entry.key();
-            let event = entry.value();
-            let timestamp = event.timestamp();
+            let envelope = entry.value();
+            let timestamp = envelope.event.timestamp();
 
             if timestamp >= start && timestamp <= end {
-                events.push((version, event.clone()));
+                events.push((version, envelope.clone()));
             }
         }
 
@@ -110,6 +237,10 @@ impl SnapshotStore for MemoryEventStore {
         Ok(self.snapshots.get(&twin_id).map(|s| s.clone()))
     }
 
+    async fn list_snapshots(&self) -> Result<Vec<TwinSnapshot>> {
+        Ok(self.snapshots.iter().map(|s| s.clone()).collect())
+    }
+
     async fn cleanup_old_snapshots(&self, before: DateTime<Utc>) -> Result<u64> {
         let mut count = 0;
         let mut to_remove = Vec::new();
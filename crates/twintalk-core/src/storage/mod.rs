@@ -1,7 +1,38 @@
 //! Storage implementations for events and snapshots
 
+pub mod audit;
+pub mod compression;
+pub mod conformance;
+pub mod consistency;
+pub mod durability;
+#[cfg(feature = "encryption")]
+pub mod encryption;
+pub mod file_store;
+pub mod group_commit;
 pub mod memory_store;
+pub mod ndjson;
+#[cfg(feature = "parquet")]
+pub mod parquet_export;
+pub mod retention;
+pub mod schema;
 pub mod sled_store;
+#[cfg(feature = "sqlite")]
+pub mod sqlite_store;
 
+pub use audit::{AuditProof, HashChainedStore};
+pub use compression::{Compression, CompressionReport, Dictionary};
+pub use consistency::{ConsistencyReport, Inconsistency};
+pub use durability::Durability;
+#[cfg(feature = "encryption")]
+pub use encryption::{reencrypt, EncryptedStore, KeyRing};
+pub use file_store::{FileEventStore, FileStoreConfig};
+pub use group_commit::GroupCommitter;
 pub use memory_store::MemoryEventStore;
+pub use ndjson::{export_ndjson, import_ndjson, ImportOptions, TransferReport};
+#[cfg(feature = "parquet")]
+pub use parquet_export::{ExportCheckpoint, ParquetExportReport, ParquetExporter};
+pub use retention::{CompactionReport, Retention, RetentionPolicy};
+pub use schema::UpcasterRegistry;
 pub use sled_store::SledEventStore;
+#[cfg(feature = "sqlite")]
+pub use sqlite_store::SqliteEventStore;
@@ -0,0 +1,426 @@
+//! Parquet export of telemetry for analytics
+//!
+//! `ParquetExporter` turns `TelemetryReceived` and `PropertyChanged` events
+//! into one row per property value, written as Hive-style partitions:
+//!
+//! ```text
+//! <dir>/class=Sensor/date=2024-05-01/part-00000000000000000001-00000000000000000420.parquet
+//! <dir>/_checkpoint.json
+//! ```
+//!
+//! Class names are percent-encoded in partition directories, as Hive
+//! readers expect, so every class gets a partition of its own: `Air Handler`
+//! is written to `class=Air%20Handler`.
+//!
+//! | column       | type                     |                                       |
+//! |--------------|--------------------------|---------------------------------------|
+//! | `version`    | `UInt64`                 | event version                         |
+//! | `twin_id`    | `Utf8`                   |                                       |
+//! | `class`      | `Utf8`                   | twin class, `unknown` if never seen   |
+//! | `property`   | `Utf8`                   |                                       |
+//! | `value`      | `Float64`, nullable      | numeric values and quantities         |
+//! | `value_text` | `Utf8`, nullable         | units of quantities, and all other values as displayed |
+//! | `type`       | `Utf8`                   | `Value::type_name`, `Float` for telemetry |
+//! | `event`      | `Utf8`                   | event kind                            |
+//! | `time`       | `Timestamp(µs, UTC)`     | event timestamp                       |
+//!
+//! Each run exports the events after the checkpoint and then advances it,
+//! writing new part files next to the old ones. Rows are written out every
+//! 100 000 rows (see `with_flush_rows`), so a long run leaves several part files per
+//! partition, each named after the versions it covers. Part files and the
+//! checkpoint are synced to disk before the checkpoint moves. Events are
+//! exported at least once: a run that fails after writing files but before
+//! saving the checkpoint exports those events again on the next run.
+//!
+//! Concurrent appends may become visible out of version order, so versions
+//! missing below the newest exported one are kept in the checkpoint and
+//! exported by the next run if they have been committed by then. Versions
+//! still missing after that are taken to never have been committed.
+
+use crate::event::{EventStore, SnapshotStore, TwinEvent};
+use crate::quantity::Quantity;
+use crate::twin::TwinId;
+use crate::value::Value;
+use anyhow::{anyhow, Result};
+use arrow_array::builder::{
+    Float64Builder, StringBuilder, TimestampMicrosecondBuilder, UInt64Builder,
+};
+use arrow_array::{ArrayRef, RecordBatch};
+use arrow_schema::{DataType, Field, Schema, SchemaRef, TimeUnit};
+use chrono::{DateTime, NaiveDate, Utc};
+use parquet::arrow::ArrowWriter;
+use parquet::basic::{Compression, ZstdLevel};
+use parquet::file::properties::WriterProperties;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Write as _;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// File holding the checkpoint, inside the export directory
+const CHECKPOINT_FILE: &str = "_checkpoint.json";
+
+/// Class of twins whose class was never seen
+const UNKNOWN_CLASS: &str = "unknown";
+
+/// Events read from the store at a time
+const READ_PAGE: usize = 1000;
+
+/// Rows buffered across partitions before they are written out, by default
+const FLUSH_ROWS: usize = 100_000;
+
+/// Widest gap between versions kept for the next run. Wider gaps are left
+/// by retention or sparse imports rather than by commits in flight.
+const MAX_MISSING_GAP: u64 = 1000;
+
+/// Progress of an incremental export
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ExportCheckpoint {
+    /// Highest event version exported
+    pub last_version: u64,
+    /// Versions below `last_version` that were not visible yet when the
+    /// last run read the log
+    #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+    pub missing: BTreeSet<u64>,
+    /// Class of every twin seen, since later runs no longer see the
+    /// `Created` events
+    pub classes: BTreeMap<TwinId, String>,
+}
+
+/// What an export run wrote
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ParquetExportReport {
+    /// Rows written
+    pub rows: u64,
+    /// Part files written, sorted
+    pub files: Vec<PathBuf>,
+    /// The checkpoint after the run
+    pub last_version: u64,
+}
+
+/// One exported property value
+struct Row {
+    version: u64,
+    twin_id: TwinId,
+    property: String,
+    value: Option<f64>,
+    value_text: Option<String>,
+    kind: &'static str,
+    event: &'static str,
+    time: DateTime<Utc>,
+}
+
+impl Row {
+    fn from_event(version: u64, event: &TwinEvent) -> Vec<Self> {
+        match event {
+            TwinEvent::TelemetryReceived {
+                twin_id,
+                data,
+                timestamp,
+            } => data
+                .iter()
+                .map(|(property, value)| Self {
+                    version,
+                    twin_id: *twin_id,
+                    property: property.clone(),
+                    value: Some(*value),
+                    value_text: None,
+                    kind: "Float",
+                    event: event.kind(),
+                    time: *timestamp,
+                })
+                .collect(),
+            TwinEvent::PropertyChanged {
+                twin_id,
+                property,
+                new_value,
+                timestamp,
+                ..
+            } => {
+                let value = new_value
+                    .as_f64()
+                    .or_else(|| new_value.as_quantity().map(Quantity::value));
+                let value_text = match new_value {
+                    Value::Integer(_) | Value::Float(_) => None,
+                    Value::String(s) | Value::Symbol(s) => Some(s.clone()),
+                    Value::Quantity(q) => Some(q.unit().to_string()),
+                    other => Some(other.to_string()),
+                };
+                vec![Self {
+                    version,
+                    twin_id: *twin_id,
+                    property: property.clone(),
+                    value,
+                    value_text,
+                    kind: new_value.type_name(),
+                    event: event.kind(),
+                    time: *timestamp,
+                }]
+            }
+            _ => Vec::new(),
+        }
+    }
+}
+
+/// Rows read but not written yet, by class and date
+#[derive(Default)]
+struct Pending {
+    partitions: BTreeMap<(String, NaiveDate), Vec<Row>>,
+    rows: usize,
+    first_version: Option<u64>,
+    last_version: u64,
+}
+
+impl Pending {
+    fn add(&mut self, version: u64) {
+        self.first_version.get_or_insert(version);
+        self.last_version = version;
+    }
+}
+
+/// Writes telemetry history to partitioned Parquet files
+#[derive(Debug, Clone)]
+pub struct ParquetExporter {
+    dir: PathBuf,
+    properties: WriterProperties,
+    flush_rows: usize,
+}
+
+impl ParquetExporter {
+    /// Export into `dir`, which holds the partitions and the checkpoint
+    pub fn new(dir: impl AsRef<Path>) -> Self {
+        let properties = WriterProperties::builder()
+            .set_compression(Compression::ZSTD(ZstdLevel::default()))
+            .build();
+        Self {
+            dir: dir.as_ref().to_path_buf(),
+            properties,
+            flush_rows: FLUSH_ROWS,
+        }
+    }
+
+    /// Use these Parquet writer properties instead of zstd defaults
+    #[must_use]
+    pub fn with_properties(mut self, properties: WriterProperties) -> Self {
+        self.properties = properties;
+        self
+    }
+
+    /// Write rows out once this many are buffered, bounding the memory a
+    /// run uses at the cost of more, smaller part files
+    #[must_use]
+    pub fn with_flush_rows(mut self, rows: usize) -> Self {
+        self.flush_rows = rows.max(1);
+        self
+    }
+
+    /// The arrow schema of exported files
+    pub fn schema() -> SchemaRef {
+        let time = DataType::Timestamp(TimeUnit::Microsecond, Some("UTC".into()));
+        Arc::new(Schema::new(vec![
+            Field::new("version", DataType::UInt64, false),
+            Field::new("twin_id", DataType::Utf8, false),
+            Field::new("class", DataType::Utf8, false),
+            Field::new("property", DataType::Utf8, false),
+            Field::new("value", DataType::Float64, true),
+            Field::new("value_text", DataType::Utf8, true),
+            Field::new("type", DataType::Utf8, false),
+            Field::new("event", DataType::Utf8, false),
+            Field::new("time", time, false),
+        ]))
+    }
+
+    /// The stored checkpoint, or an empty one before the first export
+    pub fn checkpoint(&self) -> Result<ExportCheckpoint> {
+        match fs::read(self.dir.join(CHECKPOINT_FILE)) {
+            Ok(data) => serde_json::from_slice(&data).map_err(|e| anyhow!(e)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(ExportCheckpoint::default()),
+            Err(e) => Err(anyhow!(e)),
+        }
+    }
+
+    fn save_checkpoint(&self, checkpoint: &ExportCheckpoint) -> Result<()> {
+        let path = self.dir.join(CHECKPOINT_FILE);
+        let tmp = path.with_extension("json.tmp");
+        let mut file = fs::File::create(&tmp)?;
+        file.write_all(&serde_json::to_vec_pretty(checkpoint).map_err(|e| anyhow!(e))?)?;
+        file.sync_all()?;
+        fs::rename(tmp, path)?;
+        sync_dir(&self.dir)
+    }
+
+    /// Export the events appended since the last run
+    pub async fn export<S>(&self, store: &S) -> Result<ParquetExportReport>
+    where
+        S: EventStore + SnapshotStore + ?Sized,
+    {
+        fs::create_dir_all(&self.dir)?;
+        let mut checkpoint = self.checkpoint()?;
+        for snapshot in store.list_snapshots().await? {
+            checkpoint
+                .classes
+                .entry(snapshot.twin_id)
+                .or_insert(snapshot.class_name);
+        }
+
+        // Read from the oldest missing version, so versions committed out
+        // of order since the last run are picked up
+        let previous = checkpoint.last_version;
+        let missing = std::mem::take(&mut checkpoint.missing);
+        let mut after = missing.first().map_or(previous, |first| first - 1);
+
+        let mut pending = Pending::default();
+        let mut report = ParquetExportReport::default();
+        let mut dirs = BTreeSet::new();
+        let mut last_version = previous;
+        loop {
+            let page = store.get_envelopes_after(after, READ_PAGE).await?;
+            let Some((last, _)) = page.last() else {
+                break;
+            };
+            after = *last;
+
+            let wanted = page
+                .iter()
+                .filter(|(version, _)| *version > previous || missing.contains(version));
+            for (version, envelope) in wanted {
+                if *version > previous {
+                    if *version - last_version <= MAX_MISSING_GAP {
+                        checkpoint.missing.extend(last_version + 1..*version);
+                    }
+                    last_version = *version;
+                }
+                pending.add(*version);
+                if let TwinEvent::Created {
+                    twin_id,
+                    class_name,
+                    ..
+                } = &envelope.event
+                {
+                    checkpoint.classes.insert(*twin_id, class_name.clone());
+                }
+                for row in Row::from_event(*version, &envelope.event) {
+                    let class = checkpoint
+                        .classes
+                        .get(&row.twin_id)
+                        .map_or(UNKNOWN_CLASS, String::as_str);
+                    pending
+                        .partitions
+                        .entry((class.to_string(), row.time.date_naive()))
+                        .or_default()
+                        .push(row);
+                    pending.rows += 1;
+                }
+                if pending.rows >= self.flush_rows {
+                    self.flush(std::mem::take(&mut pending), &mut report, &mut dirs)?;
+                }
+            }
+        }
+        self.flush(pending, &mut report, &mut dirs)?;
+
+        // New part files must be on disk before the checkpoint passes them
+        for dir in &dirs {
+            sync_dir(dir)?;
+        }
+        report.files.sort();
+        report.last_version = last_version;
+        checkpoint.last_version = last_version;
+        self.save_checkpoint(&checkpoint)?;
+        Ok(report)
+    }
+
+    /// Write pending rows as one part file per partition, named after the
+    /// versions they were read from, and note the directories to sync
+    fn flush(
+        &self,
+        pending: Pending,
+        report: &mut ParquetExportReport,
+        dirs: &mut BTreeSet<PathBuf>,
+    ) -> Result<()> {
+        let Some(first_version) = pending.first_version else {
+            return Ok(());
+        };
+        let part = format!(
+            "part-{first_version:020}-{:020}.parquet",
+            pending.last_version
+        );
+        for ((class, date), rows) in pending.partitions {
+            let class_dir = self.dir.join(format!("class={}", partition_value(&class)));
+            let dir = class_dir.join(format!("date={date}"));
+            fs::create_dir_all(&dir)?;
+            let path = dir.join(&part);
+            self.write_file(&path, &class, &rows)?;
+            report.rows += rows.len() as u64;
+            report.files.push(path);
+            dirs.insert(dir);
+            dirs.insert(class_dir);
+            dirs.insert(self.dir.clone());
+        }
+        Ok(())
+    }
+
+    fn write_file(&self, path: &Path, class: &str, rows: &[Row]) -> Result<()> {
+        let mut version = UInt64Builder::with_capacity(rows.len());
+        let mut twin_id = StringBuilder::new();
+        let mut class_column = StringBuilder::new();
+        let mut property = StringBuilder::new();
+        let mut value = Float64Builder::with_capacity(rows.len());
+        let mut value_text = StringBuilder::new();
+        let mut kind = StringBuilder::new();
+        let mut event = StringBuilder::new();
+        let mut time = TimestampMicrosecondBuilder::with_capacity(rows.len()).with_timezone("UTC");
+
+        for row in rows {
+            version.append_value(row.version);
+            twin_id.append_value(row.twin_id.to_string());
+            class_column.append_value(class);
+            property.append_value(&row.property);
+            value.append_option(row.value);
+            value_text.append_option(row.value_text.as_deref());
+            kind.append_value(row.kind);
+            event.append_value(row.event);
+            time.append_value(row.time.timestamp_micros());
+        }
+
+        let columns: Vec<ArrayRef> = vec![
+            Arc::new(version.finish()),
+            Arc::new(twin_id.finish()),
+            Arc::new(class_column.finish()),
+            Arc::new(property.finish()),
+            Arc::new(value.finish()),
+            Arc::new(value_text.finish()),
+            Arc::new(kind.finish()),
+            Arc::new(event.finish()),
+            Arc::new(time.finish()),
+        ];
+        let batch = RecordBatch::try_new(Self::schema(), columns).map_err(|e| anyhow!(e))?;
+
+        let file = fs::File::create(path)?;
+        let mut writer = ArrowWriter::try_new(file, Self::schema(), Some(self.properties.clone()))
+            .map_err(|e| anyhow!(e))?;
+        writer.write(&batch).map_err(|e| anyhow!(e))?;
+        writer.into_inner().map_err(|e| anyhow!(e))?.sync_all()?;
+        Ok(())
+    }
+}
+
+fn sync_dir(dir: &Path) -> Result<()> {
+    fs::File::open(dir)?.sync_all()?;
+    Ok(())
+}
+
+/// A class name usable as a partition directory, with every byte other
+/// than ASCII letters, digits, `_` and `-` percent-encoded
+fn partition_value(class: &str) -> String {
+    let mut encoded = String::with_capacity(class.len());
+    for byte in class.bytes() {
+        if byte.is_ascii_alphanumeric() || byte == b'_' || byte == b'-' {
+            encoded.push(char::from(byte));
+        } else {
+            let _ = write!(encoded, "%{byte:02X}");
+        }
+    }
+    encoded
+}
@@ -0,0 +1,151 @@
+//! Retention policies and compaction of the event log
+//!
+//! A `RetentionPolicy` says how long events are kept, per event kind, per
+//! class, or per kind within a class:
+//!
+//! ```
+//! use std::time::Duration;
+//! use twintalk_core::storage::retention::{Retention, RetentionPolicy};
+//!
+//! let week = Duration::from_secs(7 * 24 * 3600);
+//! let policy = RetentionPolicy::new()
+//!     .kind("TelemetryReceived", Retention::MaxAge(week))
+//!     .class_kind("Meter", "TelemetryReceived", Retention::Forever);
+//! ```
+//!
+//! Compaction only removes expired events that are folded into the twin's
+//! stored snapshot (`version <= snapshot.event_version`), so replaying the
+//! snapshot plus the remaining events yields the same state. Events of twins
+//! without a snapshot are never removed. Deleting a snapshot with
+//! `cleanup_old_snapshots` after compaction loses the state it covered.
+//!
+//! Twins whose events are hash-chained by `HashChainedStore` are not
+//! compacted either: the audit chain links every event to the one before,
+//! so removing any would show as tampering. They are counted in
+//! `CompactionReport::chained`.
+
+use crate::event::{TwinEvent, TwinSnapshot};
+use chrono::{DateTime, Utc};
+use std::collections::{BTreeMap, HashMap};
+use std::time::Duration;
+
+/// How long events are kept
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Retention {
+    /// Never remove
+    #[default]
+    Forever,
+    /// Remove once older than this, if covered by a snapshot
+    MaxAge(Duration),
+}
+
+impl Retention {
+    fn is_expired(self, timestamp: DateTime<Utc>, now: DateTime<Utc>) -> bool {
+        match self {
+            Self::Forever => false,
+            Self::MaxAge(max_age) => {
+                chrono::Duration::from_std(max_age).is_ok_and(|max_age| timestamp < now - max_age)
+            }
+        }
+    }
+}
+
+/// Retention rules; the most specific matching rule wins, in the order
+/// class and kind, class, kind. Unmatched events are kept forever.
+#[derive(Debug, Clone, Default)]
+pub struct RetentionPolicy {
+    kinds: HashMap<String, Retention>,
+    classes: HashMap<String, Retention>,
+    class_kinds: HashMap<(String, String), Retention>,
+}
+
+impl RetentionPolicy {
+    /// Create a policy that keeps everything
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Retain events of `kind` (see `TwinEvent::kind`) for all classes
+    #[must_use]
+    pub fn kind(mut self, kind: impl Into<String>, retention: Retention) -> Self {
+        self.kinds.insert(kind.into(), retention);
+        self
+    }
+
+    /// Retain all events of twins of `class`
+    #[must_use]
+    pub fn class(mut self, class: impl Into<String>, retention: Retention) -> Self {
+        self.classes.insert(class.into(), retention);
+        self
+    }
+
+    /// Retain events of `kind` for twins of `class`
+    #[must_use]
+    pub fn class_kind(
+        mut self,
+        class: impl Into<String>,
+        kind: impl Into<String>,
+        retention: Retention,
+    ) -> Self {
+        self.class_kinds
+            .insert((class.into(), kind.into()), retention);
+        self
+    }
+
+    /// The retention applying to events of `kind` for twins of `class`
+    pub fn retention(&self, class: &str, kind: &str) -> Retention {
+        self.class_kinds
+            .get(&(class.to_string(), kind.to_string()))
+            .or_else(|| self.classes.get(class))
+            .or_else(|| self.kinds.get(kind))
+            .copied()
+            .unwrap_or_default()
+    }
+
+    /// Versions of a twin's events that compaction may remove: expired and
+    /// folded into `snapshot`
+    pub(crate) fn compactable<'a>(
+        &self,
+        snapshot: &TwinSnapshot,
+        events: impl IntoIterator<Item = (u64, &'a TwinEvent)>,
+        now: DateTime<Utc>,
+    ) -> Vec<(u64, &'static str)> {
+        events
+            .into_iter()
+            .filter(|(version, event)| {
+                *version <= snapshot.event_version
+                    && self
+                        .retention(&snapshot.class_name, event.kind())
+                        .is_expired(event.timestamp(), now)
+            })
+            .map(|(version, event)| (version, event.kind()))
+            .collect()
+    }
+}
+
+/// What a compaction run removed
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CompactionReport {
+    /// Twins that lost at least one event
+    pub twins: usize,
+    /// Events removed
+    pub removed: u64,
+    /// Events removed per event kind
+    pub removed_by_kind: BTreeMap<String, u64>,
+    /// Twins with expired events that were kept because they are
+    /// hash-chained
+    pub chained: usize,
+}
+
+impl CompactionReport {
+    pub(crate) fn record_twin(&mut self, removed: &[(u64, &'static str)]) {
+        if removed.is_empty() {
+            return;
+        }
+        self.twins += 1;
+        for (_, kind) in removed {
+            self.removed += 1;
+            *self.removed_by_kind.entry((*kind).to_string()).or_default() += 1;
+        }
+    }
+}
@@ -0,0 +1,363 @@
+//! Event schema versioning and upcasting
+//!
+//! Persistent stores write each event as an envelope carrying the schema
+//! version it was encoded with:
+//!
+//! ```json
+//! {"schema_version": 1, "metadata": {"event_id": ...}, "event": {"type": "Created", ...}}
+//! ```
+//!
+//! When `TwinEvent` changes shape, `CURRENT_SCHEMA_VERSION` is bumped and an
+//! upcaster is registered that rewrites the JSON of the previous version into
+//! the new one. Old records are then upgraded step by step at read time, and
+//! the log never needs rewriting.
+//!
+//! The sled store originally wrote events and snapshots with bincode, before
+//! records carried a schema version. Those records are recognised by not
+//! being JSON and are read as `LEGACY_SCHEMA_VERSION`, so such a database
+//! opens without migration.
+
+use crate::event::{EventEnvelope, EventMetadata, TwinEvent, TwinSnapshot};
+use anyhow::{anyhow, bail, Result};
+use serde::Serialize;
+use serde_json::{json, Map, Value as Json};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::Arc;
+use uuid::Uuid;
+
+/// Schema version written for new events
+pub const CURRENT_SCHEMA_VERSION: u32 = 1;
+
+/// Schema version assumed for records written before versioning existed,
+/// whether bare JSON or the bincode of the original sled store
+pub const LEGACY_SCHEMA_VERSION: u32 = 1;
+
+/// Rewrites an event's JSON from one schema version to the next
+pub type Upcaster = Arc<dyn Fn(Json) -> Result<Json> + Send + Sync>;
+
+#[derive(Serialize)]
+struct EnvelopeRef<'a> {
+    schema_version: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    metadata: Option<&'a EventMetadata>,
+    event: &'a TwinEvent,
+}
+
+/// Encode an event into a versioned envelope without metadata
+pub fn encode_event(event: &TwinEvent) -> Result<Vec<u8>> {
+    serde_json::to_vec(&EnvelopeRef {
+        schema_version: CURRENT_SCHEMA_VERSION,
+        metadata: None,
+        event,
+    })
+    .map_err(|e| anyhow!(e))
+}
+
+/// Encode an event and its metadata into a versioned envelope
+pub fn encode_envelope(envelope: &EventEnvelope) -> Result<Vec<u8>> {
+    serde_json::to_vec(&EnvelopeRef {
+        schema_version: CURRENT_SCHEMA_VERSION,
+        metadata: Some(&envelope.metadata),
+        event: &envelope.event,
+    })
+    .map_err(|e| anyhow!(e))
+}
+
+/// Upcasters keyed by the schema version they upgrade from
+#[derive(Clone, Default)]
+pub struct UpcasterRegistry {
+    upcasters: BTreeMap<u32, Upcaster>,
+}
+
+impl UpcasterRegistry {
+    /// Create a registry with the built-in upcasters
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Register the upcaster from `from_version` to `from_version + 1`,
+    /// replacing any previous one
+    pub fn register(
+        &mut self,
+        from_version: u32,
+        upcaster: impl Fn(Json) -> Result<Json> + Send + Sync + 'static,
+    ) -> &mut Self {
+        self.upcasters.insert(from_version, Arc::new(upcaster));
+        self
+    }
+
+    /// Upgrade event JSON written at `schema_version` to the current schema
+    /// and decode it
+    pub fn upcast(&self, schema_version: u32, mut event: Json) -> Result<TwinEvent> {
+        if schema_version > CURRENT_SCHEMA_VERSION {
+            bail!(
+                "Event schema version {schema_version} is newer than supported version \
+                 {CURRENT_SCHEMA_VERSION}"
+            );
+        }
+
+        for version in schema_version..CURRENT_SCHEMA_VERSION {
+            let upcaster = self
+                .upcasters
+                .get(&version)
+                .ok_or_else(|| anyhow!("No upcaster from event schema version {version}"))?;
+            event = upcaster(event)?;
+        }
+
+        serde_json::from_value(event).map_err(|e| anyhow!(e))
+    }
+
+    /// Decode a stored record, upgrading it if it is older than current.
+    ///
+    /// Records without an envelope predate versioning and are read as
+    /// `LEGACY_SCHEMA_VERSION`.
+    pub fn decode_event(&self, data: &[u8]) -> Result<TwinEvent> {
+        Ok(self.decode_envelope(data)?.event)
+    }
+
+    /// Decode a stored record with its metadata, upgrading the event if it
+    /// is older than current
+    pub fn decode_envelope(&self, data: &[u8]) -> Result<EventEnvelope> {
+        if is_bincode(data) {
+            let event = LegacyReader::new(data)
+                .event()
+                .map_err(|e| anyhow!("Cannot decode pre-versioning bincode event: {e}"))?;
+            return Ok(EventEnvelope {
+                metadata: EventMetadata::default(),
+                event: self.upcast(LEGACY_SCHEMA_VERSION, event)?,
+            });
+        }
+        let json: Json = serde_json::from_slice(data).map_err(|e| anyhow!(e))?;
+        let (schema_version, metadata, event) = split_envelope(json)?;
+        let metadata = metadata
+            .map(|m| serde_json::from_value(m).map_err(|e| anyhow!(e)))
+            .transpose()?
+            .unwrap_or_default();
+        Ok(EventEnvelope {
+            metadata,
+            event: self.upcast(schema_version, event)?,
+        })
+    }
+}
+
+impl fmt::Debug for UpcasterRegistry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("UpcasterRegistry")
+            .field("from_versions", &self.upcasters.keys().collect::<Vec<_>>())
+            .finish()
+    }
+}
+
+/// Split a stored record into its schema version, metadata and event JSON
+fn split_envelope(json: Json) -> Result<(u32, Option<Json>, Json)> {
+    match json {
+        Json::Object(mut map) if map.contains_key("schema_version") => {
+            let schema_version = map
+                .get("schema_version")
+                .and_then(Json::as_u64)
+                .and_then(|v| u32::try_from(v).ok())
+                .ok_or_else(|| anyhow!("Invalid event schema version"))?;
+            let event = map
+                .remove("event")
+                .ok_or_else(|| anyhow!("Event envelope without event"))?;
+            Ok((schema_version, map.remove("metadata"), event))
+        }
+        legacy => Ok((LEGACY_SCHEMA_VERSION, None, legacy)),
+    }
+}
+
+/// Decode a stored snapshot, which is JSON unless the original sled store
+/// wrote it with bincode
+pub(crate) fn decode_snapshot(data: &[u8]) -> Result<TwinSnapshot> {
+    let json = if is_bincode(data) {
+        LegacyReader::new(data)
+            .snapshot()
+            .map_err(|e| anyhow!("Cannot decode pre-versioning bincode snapshot: {e}"))?
+    } else {
+        serde_json::from_slice(data).map_err(|e| anyhow!(e))?
+    };
+    serde_json::from_value(json).map_err(|e| anyhow!(e))
+}
+
+/// Whether a record was written by the original sled store. JSON records
+/// are objects; a bincode record starts with a length, of the event's
+/// type name or of the snapshot's twin id.
+fn is_bincode(data: &[u8]) -> bool {
+    data.first().is_some_and(|first| *first != b'{')
+}
+
+/// Names of the `Value` variants in the order the original sled store
+/// numbered them
+const LEGACY_VALUE_TYPES: [&str; 9] = [
+    "Nil", "Boolean", "Integer", "Float", "String", "Symbol", "Array", "Map", "Bytes",
+];
+
+/// Reads the bincode records of the original sled store (bincode's
+/// standard configuration) into the JSON the upcasters start from
+struct LegacyReader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> LegacyReader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self { data }
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+        if len > self.data.len() {
+            bail!("Truncated record");
+        }
+        let (taken, rest) = self.data.split_at(len);
+        self.data = rest;
+        Ok(taken)
+    }
+
+    fn fixed<const N: usize>(&mut self) -> Result<[u8; N]> {
+        Ok(self.take(N)?.try_into()?)
+    }
+
+    fn u8(&mut self) -> Result<u8> {
+        Ok(self.fixed::<1>()?[0])
+    }
+
+    /// Integers are variable length: small ones in a byte, larger ones
+    /// after a marker giving their width
+    fn varint(&mut self) -> Result<u64> {
+        Ok(match self.u8()? {
+            251 => u64::from(u16::from_le_bytes(self.fixed()?)),
+            252 => u64::from(u32::from_le_bytes(self.fixed()?)),
+            253 => u64::from_le_bytes(self.fixed()?),
+            byte @ 0..=250 => u64::from(byte),
+            marker => bail!("Invalid integer marker {marker}"),
+        })
+    }
+
+    fn len(&mut self) -> Result<usize> {
+        usize::try_from(self.varint()?).map_err(|e| anyhow!(e))
+    }
+
+    fn string(&mut self) -> Result<String> {
+        let len = self.len()?;
+        String::from_utf8(self.take(len)?.to_vec()).map_err(|e| anyhow!(e))
+    }
+
+    fn f64(&mut self) -> Result<f64> {
+        Ok(f64::from_le_bytes(self.fixed()?))
+    }
+
+    fn twin_id(&mut self) -> Result<Json> {
+        let len = self.len()?;
+        let id = Uuid::from_slice(self.take(len)?).map_err(|e| anyhow!(e))?;
+        Ok(Json::String(id.to_string()))
+    }
+
+    fn option(&mut self, read: impl FnOnce(&mut Self) -> Result<Json>) -> Result<Json> {
+        match self.u8()? {
+            0 => Ok(Json::Null),
+            1 => read(self),
+            tag => bail!("Invalid option tag {tag}"),
+        }
+    }
+
+    fn value(&mut self) -> Result<Json> {
+        let index = self.varint()?;
+        let kind = usize::try_from(index)
+            .ok()
+            .and_then(|i| LEGACY_VALUE_TYPES.get(i))
+            .ok_or_else(|| anyhow!("Unknown value type {index}"))?;
+        let value = match *kind {
+            "Nil" => return Ok(json!({ "type": "Nil" })),
+            "Boolean" => Json::Bool(self.u8()? != 0),
+            "Integer" => {
+                // Zigzag encoded
+                let n = self.varint()?;
+                Json::from((n >> 1).cast_signed() ^ -(n & 1).cast_signed())
+            }
+            "Float" => Json::from(self.f64()?),
+            "String" | "Symbol" => Json::String(self.string()?),
+            "Array" => Json::Array(self.list(Self::value)?),
+            "Map" => {
+                let len = self.len()?;
+                let mut map = Map::new();
+                for _ in 0..len {
+                    map.insert(self.string()?, self.value()?);
+                }
+                Json::Object(map)
+            }
+            _ => {
+                let len = self.len()?;
+                Json::from(self.take(len)?.to_vec())
+            }
+        };
+        Ok(json!({ "type": kind, "value": value }))
+    }
+
+    fn list(&mut self, mut read: impl FnMut(&mut Self) -> Result<Json>) -> Result<Vec<Json>> {
+        let len = self.len()?;
+        (0..len).map(|_| read(self)).collect()
+    }
+
+    /// An event, as the JSON of its type and fields
+    fn event(mut self) -> Result<Json> {
+        let kind = self.string()?;
+        let mut fields = Map::new();
+        fields.insert("twin_id".into(), self.twin_id()?);
+        match kind.as_str() {
+            "Created" => {
+                fields.insert("class_name".into(), Json::String(self.string()?));
+            }
+            "PropertyChanged" => {
+                fields.insert("property".into(), Json::String(self.string()?));
+                fields.insert("old_value".into(), self.option(Self::value)?);
+                fields.insert("new_value".into(), self.value()?);
+            }
+            "TelemetryReceived" => {
+                let data = self.list(|r| Ok(json!([r.string()?, r.f64()?])))?;
+                fields.insert("data".into(), Json::Array(data));
+            }
+            "MessageSent" => {
+                fields.insert("selector".into(), Json::String(self.string()?));
+                fields.insert("args".into(), Json::Array(self.list(Self::value)?));
+                let result = match self.varint()? {
+                    0 => json!({ "Ok": self.value()? }),
+                    1 => json!({ "Err": self.string()? }),
+                    tag => bail!("Invalid result tag {tag}"),
+                };
+                fields.insert("result".into(), result);
+            }
+            "Cloned" => {
+                fields.insert("source_id".into(), self.twin_id()?);
+            }
+            "Destroyed" => {}
+            other => bail!("Unknown event type '{other}'"),
+        }
+        fields.insert("timestamp".into(), Json::String(self.string()?));
+        fields.insert("type".into(), Json::String(kind));
+        self.finish(Json::Object(fields))
+    }
+
+    /// A snapshot, as its JSON
+    fn snapshot(mut self) -> Result<Json> {
+        let mut fields = Map::new();
+        fields.insert("twin_id".into(), self.twin_id()?);
+        fields.insert("class_name".into(), Json::String(self.string()?));
+        let len = self.len()?;
+        let mut properties = Map::new();
+        for _ in 0..len {
+            properties.insert(self.string()?, self.value()?);
+        }
+        fields.insert("properties".into(), Json::Object(properties));
+        fields.insert("parent_id".into(), self.option(Self::twin_id)?);
+        fields.insert("event_version".into(), Json::from(self.varint()?));
+        fields.insert("timestamp".into(), Json::String(self.string()?));
+        self.finish(Json::Object(fields))
+    }
+
+    fn finish(self, json: Json) -> Result<Json> {
+        if !self.data.is_empty() {
+            bail!("{} unexpected trailing bytes", self.data.len());
+        }
+        Ok(json)
+    }
+}
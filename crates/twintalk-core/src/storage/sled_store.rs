@@ -2,13 +2,56 @@
 //!
 //! Uses an embedded database for persistent event storage.
 
-use crate::event::{EventStore, SnapshotStore, TwinEvent, TwinSnapshot};
+use crate::event::{check_import_versions, EventEnvelope, EventStore, SnapshotStore, TwinSnapshot};
+use crate::storage::audit::is_chained;
+use crate::storage::compression::{Compression, CompressionReport, Dictionary, RecordCodec};
+use crate::storage::consistency::{ConsistencyReport, Inconsistency};
+use crate::storage::durability::Durability;
+use crate::storage::retention::{CompactionReport, RetentionPolicy};
+use crate::storage::schema::{self, UpcasterRegistry};
 use crate::twin::TwinId;
 use anyhow::{anyhow, Result};
 use async_trait::async_trait;
 use chrono::{DateTime, Utc};
+use serde::Serialize;
 use sled::{Db, Tree};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::ops::Bound;
 use std::sync::atomic::{AtomicU64, Ordering};
+use uuid::Uuid;
+
+/// Encode a snapshot record, decoded by `schema::decode_snapshot`.
+///
+/// Records use a self-describing encoding because `Value` is a tagged enum,
+/// which bincode cannot decode. Events go through `schema::encode_envelope`.
+fn encode_record<T: Serialize>(record: &T) -> Result<Vec<u8>> {
+    serde_json::to_vec(record).map_err(|e| anyhow!(e))
+}
+
+/// Key in the `meta` tree holding the highest version ever assigned, which
+/// outlives compaction of the newest events
+const LAST_VERSION_KEY: &[u8] = b"last_version";
+
+/// Prefix of the `meta` keys holding compression dictionaries by id
+const DICTIONARY_PREFIX: &[u8] = b"dictionary/";
+
+/// Most recent records sampled when training a dictionary
+const DICTIONARY_SAMPLES: usize = 10_000;
+
+fn decode_version(data: &[u8]) -> Option<u64> {
+    Some(u64::from_be_bytes(data.try_into().ok()?))
+}
+
+/// Encode a twin's index entry: its event versions, ascending
+fn encode_index(versions: &[u64]) -> Result<Vec<u8>> {
+    bincode::serde::encode_to_vec(versions, bincode::config::standard()).map_err(|e| anyhow!(e))
+}
+
+fn decode_index(data: &[u8]) -> Result<Vec<u64>> {
+    bincode::serde::decode_from_slice::<Vec<u64>, _>(data, bincode::config::standard())
+        .map(|(decoded, _)| decoded)
+        .map_err(|e| anyhow!(e))
+}
 
 /// `Sled`-based persistent event store
 pub struct SledEventStore {
@@ -16,97 +59,534 @@ pub struct SledEventStore {
     events: Tree,
     snapshots: Tree,
     twin_events: Tree, // Index: twin_id -> event_ids
+    meta: Tree,
     version_counter: AtomicU64,
+    durability: Durability,
+    upcasters: UpcasterRegistry,
+    codec: RecordCodec,
 }
 
 impl SledEventStore {
-    /// Create a new `Sled` event store
+    /// Create a new `Sled` event store that flushes on every append
     pub fn new(path: &str) -> Result<Self> {
-        let db = sled::open(path).map_err(|e| anyhow!(e))?;
+        Self::with_durability(path, Durability::EveryEvent)
+    }
+
+    /// Create a new `Sled` event store with the given durability mode
+    pub fn with_durability(path: &str, durability: Durability) -> Result<Self> {
+        let flush_every_ms = match durability {
+            Durability::EveryEvent | Durability::OsManaged => None,
+            Durability::Interval(interval) => Some(
+                u64::try_from(interval.as_millis())
+                    .unwrap_or(u64::MAX)
+                    .max(1),
+            ),
+        };
+        let db = sled::Config::new()
+            .path(path)
+            .flush_every_ms(flush_every_ms)
+            .open()
+            .map_err(|e| anyhow!(e))?;
         let events = db.open_tree("events").map_err(|e| anyhow!(e))?;
         let snapshots = db.open_tree("snapshots").map_err(|e| anyhow!(e))?;
         let twin_events = db.open_tree("twin_events").map_err(|e| anyhow!(e))?;
+        let meta = db.open_tree("meta").map_err(|e| anyhow!(e))?;
 
         // Initialize version counter
-        let latest_version = events
+        let last_event = events
             .last()
             .map_err(|e| anyhow!(e))?
-            .and_then(|(k, _)| {
-                let bytes: [u8; 8] = k.as_ref().try_into().ok()?;
-                Some(u64::from_be_bytes(bytes))
-            })
-            .unwrap_or(0);
+            .and_then(|(k, _)| decode_version(&k));
+        let last_compacted = meta
+            .get(LAST_VERSION_KEY)
+            .map_err(|e| anyhow!(e))?
+            .and_then(|v| decode_version(&v));
+        let latest_version = last_event.max(last_compacted).unwrap_or(0);
+
+        // Every dictionary ever used, so older records stay readable
+        let mut codec = RecordCodec::default();
+        for item in meta.scan_prefix(DICTIONARY_PREFIX) {
+            let (_, bytes) = item.map_err(|e| anyhow!(e))?;
+            codec.add_dictionary(&Dictionary::from_bytes(bytes.to_vec()));
+        }
 
         Ok(Self {
             db,
             events,
             snapshots,
             twin_events,
+            meta,
             version_counter: AtomicU64::new(latest_version),
+            durability,
+            upcasters: UpcasterRegistry::new(),
+            codec,
         })
     }
 
-    /// Helper to add event to twin index
-    fn index_event(&self, twin_id: TwinId, version: u64) -> Result<()> {
-        let twin_key = twin_id.0.as_bytes();
-        let _version_bytes = version.to_be_bytes();
-
-        // Get existing versions for this twin
-        let mut versions =
-            if let Some(data) = self.twin_events.get(twin_key).map_err(|e| anyhow!(e))? {
-                bincode::serde::decode_from_slice::<Vec<u64>, _>(&data, bincode::config::standard())
-                    .map(|(decoded, _)| decoded)
-                    .map_err(|e| anyhow!(e))?
-            } else {
-                Vec::new()
-            };
+    /// Use these upcasters when decoding events written with older schemas
+    #[must_use]
+    pub fn with_upcasters(mut self, upcasters: UpcasterRegistry) -> Self {
+        self.upcasters = upcasters;
+        self
+    }
+
+    /// Compress records written from now on. A dictionary is saved in the
+    /// store so its records stay readable after reopening.
+    pub fn with_compression(mut self, compression: Compression) -> Result<Self> {
+        if let Some(dictionary) = compression.dictionary() {
+            let key = [DICTIONARY_PREFIX, &dictionary.id().to_be_bytes()].concat();
+            self.meta
+                .insert(key, dictionary.as_bytes())
+                .map_err(|e| anyhow!(e))?;
+            self.db.flush().map_err(|e| anyhow!(e))?;
+        }
+        self.codec.configure(compression);
+        Ok(self)
+    }
+
+    /// Train a compression dictionary of at most `max_size` bytes on the
+    /// most recent event records and the snapshots
+    pub fn train_dictionary(&self, max_size: usize) -> Result<Dictionary> {
+        let mut samples = Vec::new();
+        for item in self.events.iter().rev().take(DICTIONARY_SAMPLES) {
+            let (_, value) = item.map_err(|e| anyhow!(e))?;
+            samples.push(self.codec.decode(&value)?.into_owned());
+        }
+        for item in self.snapshots.iter().take(DICTIONARY_SAMPLES) {
+            let (_, value) = item.map_err(|e| anyhow!(e))?;
+            samples.push(self.codec.decode(&value)?.into_owned());
+        }
+        Dictionary::train(&samples, max_size)
+    }
+
+    /// Rewrite every stored record with the current compression, e.g.
+    /// after switching to a trained dictionary. Returns the sizes after
+    /// rewriting.
+    pub fn recompress(&self) -> Result<CompressionReport> {
+        let mut report = CompressionReport::default();
+        for tree in [&self.events, &self.snapshots] {
+            for item in tree {
+                let (key, stored) = item.map_err(|e| anyhow!(e))?;
+                let raw = self.codec.decode(&stored)?.into_owned();
+                let rewritten = self.codec.encode(raw.clone())?;
+                report.record(&raw, &rewritten);
+                if rewritten != stored.as_ref() {
+                    // Leave records that changed meanwhile alone, and don't
+                    // resurrect records removed by compaction
+                    let _ = tree
+                        .compare_and_swap(key, Some(stored), Some(rewritten))
+                        .map_err(|e| anyhow!(e))?;
+                }
+            }
+        }
+        self.db.flush().map_err(|e| anyhow!(e))?;
+        Ok(report)
+    }
+
+    /// Uncompressed and stored size of all event and snapshot records
+    pub fn compression_report(&self) -> Result<CompressionReport> {
+        let mut report = CompressionReport::default();
+        for tree in [&self.events, &self.snapshots] {
+            for item in tree {
+                let (_, stored) = item.map_err(|e| anyhow!(e))?;
+                report.record(&self.codec.decode(&stored)?, &stored);
+            }
+        }
+        Ok(report)
+    }
+
+    fn encode_event(&self, envelope: &EventEnvelope) -> Result<Vec<u8>> {
+        self.codec.encode(schema::encode_envelope(envelope)?)
+    }
+
+    fn decode_event(&self, stored: &[u8]) -> Result<EventEnvelope> {
+        self.upcasters.decode_envelope(&self.codec.decode(stored)?)
+    }
+
+    fn encode_snapshot(&self, snapshot: &TwinSnapshot) -> Result<Vec<u8>> {
+        self.codec.encode(encode_record(snapshot)?)
+    }
+
+    fn decode_snapshot(&self, stored: &[u8]) -> Result<TwinSnapshot> {
+        schema::decode_snapshot(&self.codec.decode(stored)?)
+    }
 
-        versions.push(version);
+    /// The durability mode this store was opened with
+    pub fn durability(&self) -> Durability {
+        self.durability
+    }
+
+    /// Force all buffered writes to disk, regardless of durability mode
+    pub async fn flush(&self) -> Result<()> {
+        // Concurrent `flush_async` calls can stall in sled 0.34, so flush
+        // synchronously on the blocking pool instead
+        let db = self.db.clone();
+        tokio::task::spawn_blocking(move || db.flush())
+            .await
+            .map_err(|e| anyhow!(e))?
+            .map_err(|e| anyhow!(e))?;
+        Ok(())
+    }
 
-        let encoded = bincode::serde::encode_to_vec(&versions, bincode::config::standard())
+    /// Flush if the durability mode requires it after every write
+    async fn flush_for_durability(&self) -> Result<()> {
+        if self.durability.flushes_on_append() {
+            self.flush().await?;
+        }
+        Ok(())
+    }
+
+    /// Remove events expired under `policy` that are folded into a snapshot
+    pub async fn compact(
+        &self,
+        policy: &RetentionPolicy,
+        now: DateTime<Utc>,
+    ) -> Result<CompactionReport> {
+        // Removing the newest events must not let versions be reused
+        let last_version = self.version_counter.load(Ordering::SeqCst);
+        self.meta
+            .insert(LAST_VERSION_KEY, &last_version.to_be_bytes())
             .map_err(|e| anyhow!(e))?;
+
+        let mut report = CompactionReport::default();
+        for item in &self.snapshots {
+            let (_, value) = item.map_err(|e| anyhow!(e))?;
+            let snapshot = self.decode_snapshot(&value)?;
+
+            let events = self
+                .get_envelopes(snapshot.twin_id, 0)
+                .await?
+                .into_iter()
+                .take_while(|(v, _)| *v <= snapshot.event_version)
+                .collect::<Vec<_>>();
+            let removed =
+                policy.compactable(&snapshot, events.iter().map(|(v, e)| (*v, &e.event)), now);
+            if removed.is_empty() {
+                continue;
+            }
+            if events.iter().any(|(_, e)| is_chained(&e.metadata)) {
+                report.chained += 1;
+                continue;
+            }
+
+            let removed_versions: HashSet<u64> = removed.iter().map(|(v, _)| *v).collect();
+            self.update_index(snapshot.twin_id, |versions| {
+                versions.retain(|v| !removed_versions.contains(v));
+            })?;
+            let mut batch = sled::Batch::default();
+            for version in &removed_versions {
+                batch.remove(&version.to_be_bytes());
+            }
+            self.events.apply_batch(batch).map_err(|e| anyhow!(e))?;
+            report.record_twin(&removed);
+        }
+
+        self.flush_for_durability().await?;
+        Ok(report)
+    }
+
+    /// Check the event tree, twin index and snapshots against each other
+    /// without changing anything
+    pub fn check(&self) -> Result<ConsistencyReport> {
+        let mut report = ConsistencyReport::default();
+        let latest_version = self.version_counter.load(Ordering::SeqCst);
+
+        // Owner of every decodable event, from the primary event tree
+        let mut owners = BTreeMap::new();
+        let mut undecodable = HashSet::new();
+        let mut previous = 0;
+        for item in &self.events {
+            let (key, value) = item.map_err(|e| anyhow!(e))?;
+            report.events += 1;
+            let Some(version) = decode_version(&key) else {
+                report.issues.push(Inconsistency::UndecodableEvent {
+                    key: hex::encode(&key),
+                    error: "invalid version key".to_string(),
+                });
+                continue;
+            };
+            report.record_gap(previous, version);
+            previous = version;
+            match self.decode_event(&value) {
+                Ok(envelope) => {
+                    owners.insert(version, envelope.event.twin_id());
+                }
+                Err(e) => {
+                    undecodable.insert(version);
+                    report.issues.push(Inconsistency::UndecodableEvent {
+                        key: version.to_string(),
+                        error: e.to_string(),
+                    });
+                }
+            }
+        }
+        report.record_gap(previous, latest_version + 1);
+
+        let mut indexed = HashSet::new();
+        for item in &self.twin_events {
+            let (key, value) = item.map_err(|e| anyhow!(e))?;
+            report.twins += 1;
+            let decoded = Uuid::from_slice(&key)
+                .map_err(|e| anyhow!(e))
+                .and_then(|id| Ok((TwinId(id), decode_index(&value)?)));
+            let (twin_id, versions) = match decoded {
+                Ok(decoded) => decoded,
+                Err(e) => {
+                    report.issues.push(Inconsistency::UndecodableIndex {
+                        key: hex::encode(&key),
+                        error: e.to_string(),
+                    });
+                    continue;
+                }
+            };
+
+            for version in versions {
+                match owners.get(&version) {
+                    Some(owner) if *owner == twin_id => {
+                        indexed.insert(version);
+                    }
+                    Some(owner) => report.issues.push(Inconsistency::MisindexedEvent {
+                        twin_id,
+                        version,
+                        actual: *owner,
+                    }),
+                    None if undecodable.contains(&version) => {}
+                    None => report
+                        .issues
+                        .push(Inconsistency::MissingEvent { twin_id, version }),
+                }
+            }
+        }
+        for (version, twin_id) in owners {
+            if !indexed.contains(&version) {
+                report
+                    .issues
+                    .push(Inconsistency::OrphanedEvent { twin_id, version });
+            }
+        }
+
+        for item in &self.snapshots {
+            let (key, value) = item.map_err(|e| anyhow!(e))?;
+            report.snapshots += 1;
+            match self.decode_snapshot(&value) {
+                Ok(snapshot) if snapshot.event_version > latest_version => {
+                    report.issues.push(Inconsistency::SnapshotAhead {
+                        twin_id: snapshot.twin_id,
+                        event_version: snapshot.event_version,
+                        latest_version,
+                    });
+                }
+                Ok(_) => {}
+                Err(e) => report.issues.push(Inconsistency::UndecodableSnapshot {
+                    key: hex::encode(&key),
+                    error: e.to_string(),
+                }),
+            }
+        }
+
+        Ok(report)
+    }
+
+    /// Fix what `check` finds, returning its report from before the repair.
+    ///
+    /// The twin index is rebuilt from the event tree. Undecodable events and
+    /// snapshots move to the `quarantine` tree, keyed `events/<key>` and
+    /// `snapshots/<key>`, so reads stop failing on them. The version counter
+    /// is raised past snapshots that are ahead of the log. Takes `&mut self`
+    /// because concurrent appends would be lost from the rebuilt index.
+    pub async fn repair(&mut self) -> Result<ConsistencyReport> {
+        let report = self.check()?;
+        let quarantine = self.db.open_tree("quarantine").map_err(|e| anyhow!(e))?;
+
+        let mut index: HashMap<TwinId, Vec<u64>> = HashMap::new();
+        let mut damaged = Vec::new();
+        for item in &self.events {
+            let (key, value) = item.map_err(|e| anyhow!(e))?;
+            let owner = decode_version(&key)
+                .ok_or_else(|| anyhow!("Invalid key"))
+                .and_then(|version| Ok((version, self.decode_event(&value)?.event.twin_id())));
+            match owner {
+                Ok((version, twin_id)) => index.entry(twin_id).or_default().push(version),
+                Err(_) => damaged.push((key, value)),
+            }
+        }
+        for (key, value) in damaged {
+            quarantine
+                .insert([b"events/".as_slice(), &key].concat(), value)
+                .map_err(|e| anyhow!(e))?;
+            self.events.remove(key).map_err(|e| anyhow!(e))?;
+        }
+
+        let mut snapshot_version = 0;
+        let mut damaged = Vec::new();
+        for item in &self.snapshots {
+            let (key, value) = item.map_err(|e| anyhow!(e))?;
+            match self.decode_snapshot(&value) {
+                Ok(snapshot) => snapshot_version = snapshot_version.max(snapshot.event_version),
+                Err(_) => damaged.push((key, value)),
+            }
+        }
+        for (key, value) in damaged {
+            quarantine
+                .insert([b"snapshots/".as_slice(), &key].concat(), value)
+                .map_err(|e| anyhow!(e))?;
+            self.snapshots.remove(key).map_err(|e| anyhow!(e))?;
+        }
+
+        // The event tree iterates in version order, so each list is sorted
+        self.twin_events.clear().map_err(|e| anyhow!(e))?;
+        let mut batch = sled::Batch::default();
+        for (twin_id, versions) in index {
+            batch.insert(twin_id.0.as_bytes(), encode_index(&versions)?);
+        }
         self.twin_events
-            .insert(twin_key, encoded)
+            .apply_batch(batch)
             .map_err(|e| anyhow!(e))?;
 
-        Ok(())
+        let latest_version = self.version_counter.get_mut();
+        if snapshot_version > *latest_version {
+            *latest_version = snapshot_version;
+            self.meta
+                .insert(LAST_VERSION_KEY, &snapshot_version.to_be_bytes())
+                .map_err(|e| anyhow!(e))?;
+        }
+
+        self.flush().await?;
+        Ok(report)
+    }
+
+    /// Helper to add events to twin index
+    fn index_events(&self, twin_id: TwinId, new_versions: &[u64]) -> Result<()> {
+        self.update_index(twin_id, |versions| {
+            versions.extend_from_slice(new_versions);
+            versions.sort_unstable();
+        })
+    }
+
+    /// Rewrite a twin's index entry
+    fn update_index(&self, twin_id: TwinId, update: impl Fn(&mut Vec<u64>)) -> Result<()> {
+        let twin_key = twin_id.0.as_bytes();
+
+        // Compare-and-swap so concurrent appends for one twin don't lose
+        // each other's index entries
+        loop {
+            let current = self.twin_events.get(twin_key).map_err(|e| anyhow!(e))?;
+
+            // Get existing versions for this twin
+            let mut versions = if let Some(data) = &current {
+                decode_index(data)?
+            } else {
+                Vec::new()
+            };
+
+            update(&mut versions);
+
+            let encoded = encode_index(&versions)?;
+            let swapped = self
+                .twin_events
+                .compare_and_swap(twin_key, current, Some(encoded))
+                .map_err(|e| anyhow!(e))?;
+            if swapped.is_ok() {
+                return Ok(());
+            }
+        }
     }
 }
 
 #[async_trait]
 impl EventStore for SledEventStore {
-    async fn append(&self, event: TwinEvent) -> Result<u64> {
+    async fn append_envelope(&self, envelope: EventEnvelope) -> Result<u64> {
         let version = self.version_counter.fetch_add(1, Ordering::SeqCst) + 1;
         let version_bytes = version.to_be_bytes();
 
-        let encoded = bincode::serde::encode_to_vec(&event, bincode::config::standard())
-            .map_err(|e| anyhow!(e))?;
+        let encoded = self.encode_event(&envelope)?;
 
         self.events
             .insert(version_bytes, encoded)
             .map_err(|e| anyhow!(e))?;
 
         // Index by twin
-        self.index_event(event.twin_id(), version)?;
+        self.index_events(envelope.event.twin_id(), &[version])?;
 
         // Flush to ensure durability
-        self.db.flush_async().await.map_err(|e| anyhow!(e))?;
+        self.flush_for_durability().await?;
 
         Ok(version)
     }
 
-    async fn get_events(
+    async fn append_envelopes(&self, envelopes: Vec<EventEnvelope>) -> Result<Vec<u64>> {
+        if envelopes.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        // Reserve a contiguous range of versions for the whole batch
+        let count = envelopes.len() as u64;
+        let first = self.version_counter.fetch_add(count, Ordering::SeqCst) + 1;
+
+        let mut batch = sled::Batch::default();
+        let mut by_twin: HashMap<TwinId, Vec<u64>> = HashMap::new();
+        let mut versions = Vec::with_capacity(envelopes.len());
+
+        for (version, envelope) in (first..).zip(&envelopes) {
+            let encoded = self.encode_event(envelope)?;
+            batch.insert(&version.to_be_bytes(), encoded);
+            by_twin
+                .entry(envelope.event.twin_id())
+                .or_default()
+                .push(version);
+            versions.push(version);
+        }
+
+        self.events.apply_batch(batch).map_err(|e| anyhow!(e))?;
+
+        // One index update per twin rather than per event
+        for (twin_id, twin_versions) in by_twin {
+            self.index_events(twin_id, &twin_versions)?;
+        }
+
+        // A single flush covers the whole batch
+        self.flush_for_durability().await?;
+
+        Ok(versions)
+    }
+
+    async fn import_envelopes(&self, envelopes: Vec<(u64, EventEnvelope)>) -> Result<()> {
+        let Some(&(last, _)) = envelopes.last() else {
+            return Ok(());
+        };
+        let latest = self.version_counter.load(Ordering::SeqCst);
+        check_import_versions(latest, &envelopes)?;
+        self.version_counter
+            .compare_exchange(latest, last, Ordering::SeqCst, Ordering::SeqCst)
+            .map_err(|_| anyhow!("Events were appended during import"))?;
+
+        let mut batch = sled::Batch::default();
+        let mut by_twin: HashMap<TwinId, Vec<u64>> = HashMap::new();
+        for (version, envelope) in &envelopes {
+            batch.insert(&version.to_be_bytes(), self.encode_event(envelope)?);
+            by_twin
+                .entry(envelope.event.twin_id())
+                .or_default()
+                .push(*version);
+        }
+        self.events.apply_batch(batch).map_err(|e| anyhow!(e))?;
+        for (twin_id, twin_versions) in by_twin {
+            self.index_events(twin_id, &twin_versions)?;
+        }
+
+        self.flush_for_durability().await
+    }
+
+    async fn get_envelopes(
         &self,
         twin_id: TwinId,
         after_version: u64,
-    ) -> Result<Vec<(u64, TwinEvent)>> {
+    ) -> Result<Vec<(u64, EventEnvelope)>> {
         let twin_key = twin_id.0.as_bytes();
 
         // Get all versions for this twin
         let versions = if let Some(data) = self.twin_events.get(twin_key).map_err(|e| anyhow!(e))? {
-            bincode::serde::decode_from_slice::<Vec<u64>, _>(&data, bincode::config::standard())
-                .map(|(decoded, _)| decoded)
-                .map_err(|e| anyhow!(e))?
+            decode_index(&data)?
         } else {
             return Ok(vec![]);
         };
@@ -117,11 +597,8 @@ impl EventStore for SledEventStore {
             if version > after_version {
                 let version_bytes = version.to_be_bytes();
                 if let Some(data) = self.events.get(version_bytes).map_err(|e| anyhow!(e))? {
-                    let event: TwinEvent =
-                        bincode::serde::decode_from_slice(&data, bincode::config::standard())
-                            .map(|(decoded, _)| decoded)
-                            .map_err(|e| anyhow!(e))?;
-                    events.push((version, event));
+                    let envelope = self.decode_event(&data)?;
+                    events.push((version, envelope));
                 }
             }
         }
@@ -129,11 +606,63 @@ impl EventStore for SledEventStore {
         Ok(events)
     }
 
-    async fn get_events_in_range(
+    async fn get_envelopes_before(
+        &self,
+        twin_id: TwinId,
+        before_version: u64,
+        limit: usize,
+    ) -> Result<Vec<(u64, EventEnvelope)>> {
+        let twin_key = twin_id.0.as_bytes();
+        let Some(data) = self.twin_events.get(twin_key).map_err(|e| anyhow!(e))? else {
+            return Ok(vec![]);
+        };
+        let versions = decode_index(&data)?;
+        let end = versions.partition_point(|v| *v < before_version);
+        let start = end.saturating_sub(limit);
+
+        let mut events = Vec::with_capacity(end - start);
+        for &version in &versions[start..end] {
+            if let Some(data) = self
+                .events
+                .get(version.to_be_bytes())
+                .map_err(|e| anyhow!(e))?
+            {
+                events.push((version, self.decode_event(&data)?));
+            }
+        }
+
+        Ok(events)
+    }
+
+    async fn get_envelopes_after(
+        &self,
+        after_version: u64,
+        limit: usize,
+    ) -> Result<Vec<(u64, EventEnvelope)>> {
+        let from = (
+            Bound::Excluded(after_version.to_be_bytes()),
+            Bound::Unbounded,
+        );
+        let mut events = Vec::new();
+
+        for item in self.events.range(from).take(limit) {
+            let (key, value) = item.map_err(|e| anyhow!(e))?;
+            let version = u64::from_be_bytes(
+                key.as_ref()
+                    .try_into()
+                    .map_err(|_| anyhow!("Invalid key"))?,
+            );
+            events.push((version, self.decode_event(&value)?));
+        }
+
+        Ok(events)
+    }
+
+    async fn get_envelopes_in_range(
         &self,
         start: DateTime<Utc>,
         end: DateTime<Utc>,
-    ) -> Result<Vec<(u64, TwinEvent)>> {
+    ) -> Result<Vec<(u64, EventEnvelope)>> {
         let mut events = Vec::new();
 
         for item in &self.events {
@@ -143,14 +672,11 @@ impl EventStore for SledEventStore {
                     .try_into()
                     .map_err(|_| anyhow!("Invalid key"))?,
             );
-            let event: TwinEvent =
-                bincode::serde::decode_from_slice(&value, bincode::config::standard())
-                    .map(|(decoded, _)| decoded)
-                    .map_err(|e| anyhow!(e))?;
+            let envelope = self.decode_event(&value)?;
 
-            let timestamp = event.timestamp();
+            let timestamp = envelope.event.timestamp();
             if timestamp >= start && timestamp <= end {
-                events.push((version, event));
+                events.push((version, envelope));
             }
         }
 
@@ -166,13 +692,12 @@ impl EventStore for SledEventStore {
 impl SnapshotStore for SledEventStore {
     async fn save_snapshot(&self, snapshot: TwinSnapshot) -> Result<()> {
         let key = snapshot.twin_id.0.as_bytes();
-        let encoded = bincode::serde::encode_to_vec(&snapshot, bincode::config::standard())
-            .map_err(|e| anyhow!(e))?;
+        let encoded = self.encode_snapshot(&snapshot)?;
 
         self.snapshots
             .insert(key, encoded)
             .map_err(|e| anyhow!(e))?;
-        self.db.flush_async().await.map_err(|e| anyhow!(e))?;
+        self.flush_for_durability().await?;
 
         Ok(())
     }
@@ -181,25 +706,49 @@ impl SnapshotStore for SledEventStore {
         let key = twin_id.0.as_bytes();
 
         if let Some(data) = self.snapshots.get(key).map_err(|e| anyhow!(e))? {
-            let snapshot = bincode::serde::decode_from_slice(&data, bincode::config::standard())
-                .map(|(decoded, _)| decoded)
-                .map_err(|e| anyhow!(e))?;
+            let snapshot = self.decode_snapshot(&data)?;
             Ok(Some(snapshot))
         } else {
             Ok(None)
         }
     }
 
+    async fn list_snapshots(&self) -> Result<Vec<TwinSnapshot>> {
+        self.snapshots
+            .iter()
+            .map(|item| {
+                let (_, value) = item.map_err(|e| anyhow!(e))?;
+                self.decode_snapshot(&value)
+            })
+            .collect()
+    }
+
+    async fn list_snapshots_after(
+        &self,
+        after: Option<TwinId>,
+        limit: usize,
+    ) -> Result<Vec<TwinSnapshot>> {
+        let start = match after {
+            Some(after) => Bound::Excluded(after.0.as_bytes().to_vec()),
+            None => Bound::Unbounded,
+        };
+        self.snapshots
+            .range((start, Bound::Unbounded))
+            .take(limit)
+            .map(|item| {
+                let (_, value) = item.map_err(|e| anyhow!(e))?;
+                self.decode_snapshot(&value)
+            })
+            .collect()
+    }
+
     async fn cleanup_old_snapshots(&self, before: DateTime<Utc>) -> Result<u64> {
         let mut count = 0;
         let mut to_remove = Vec::new();
 
         for item in &self.snapshots {
             let (key, value) = item.map_err(|e| anyhow!(e))?;
-            let snapshot: TwinSnapshot =
-                bincode::serde::decode_from_slice(&value, bincode::config::standard())
-                    .map(|(decoded, _)| decoded)
-                    .map_err(|e| anyhow!(e))?;
+            let snapshot = self.decode_snapshot(&value)?;
 
             if snapshot.timestamp < before {
                 to_remove.push(key);
@@ -211,7 +760,7 @@ impl SnapshotStore for SledEventStore {
             self.snapshots.remove(key).map_err(|e| anyhow!(e))?;
         }
 
-        self.db.flush_async().await.map_err(|e| anyhow!(e))?;
+        self.flush_for_durability().await?;
         Ok(count)
     }
 }
@@ -0,0 +1,705 @@
+//! `SQLite`-based event store implementation
+//!
+//! Keeps the event log in plain tables so it can be inspected with SQL:
+//!
+//! ```sql
+//! SELECT version, kind, timestamp, payload FROM events
+//! WHERE twin_id = '...' ORDER BY version;
+//! ```
+//!
+//! Payloads are JSON, so `json_extract(payload, '$.property')` works too,
+//! unless the store is opened `with_compression`: compressed payloads are
+//! stored as BLOBs (see `storage::compression`), and the dictionaries they
+//! need are kept in a `dictionaries` table.
+//! Each row records the `schema_version` its payload was written with, and
+//! older payloads are upcast when read. Event metadata has its own columns,
+//! so audits can select by `correlation_id` or `principal`.
+//!
+//! The database runs in WAL mode and honours `Durability` through its
+//! `synchronous` setting: `FULL` syncs the WAL on every commit, `Interval`
+//! commits with `NORMAL` and checkpoints (syncing the WAL) from a
+//! background thread, and `OsManaged` turns syncing off. All `SQLite` calls
+//! run on tokio's blocking pool.
+
+use crate::event::{
+    check_import_versions, EventEnvelope, EventMetadata, EventStore, SnapshotStore, TwinSnapshot,
+};
+use crate::storage::compression::{is_compressed, Compression, Dictionary, RecordCodec};
+use crate::storage::durability::Durability;
+use crate::storage::schema::{UpcasterRegistry, CURRENT_SCHEMA_VERSION};
+use crate::twin::TwinId;
+use anyhow::{anyhow, bail, Result};
+use async_trait::async_trait;
+use chrono::{DateTime, SecondsFormat, Utc};
+use rusqlite::types::Value;
+use rusqlite::{params, params_from_iter, Connection, OptionalExtension};
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, Weak};
+use std::time::Duration;
+use uuid::Uuid;
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS events (
+        version   INTEGER PRIMARY KEY,
+        twin_id   TEXT NOT NULL,
+        kind      TEXT NOT NULL,
+        timestamp TEXT NOT NULL,
+        payload   TEXT NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS idx_events_twin ON events (twin_id, version);
+    CREATE INDEX IF NOT EXISTS idx_events_timestamp ON events (timestamp);
+
+    CREATE TABLE IF NOT EXISTS snapshots (
+        twin_id       TEXT PRIMARY KEY,
+        class_name    TEXT NOT NULL,
+        event_version INTEGER NOT NULL,
+        timestamp     TEXT NOT NULL,
+        payload       TEXT NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS idx_snapshots_timestamp ON snapshots (timestamp);
+
+    CREATE TABLE IF NOT EXISTS dictionaries (
+        id    INTEGER PRIMARY KEY,
+        bytes BLOB NOT NULL
+    );
+";
+
+/// Event columns added after the first release, created on open when
+/// missing. Rows predating them are legacy (version 1) payloads without
+/// metadata.
+const ADDED_EVENT_COLUMNS: &[(&str, &str)] = &[
+    ("schema_version", "INTEGER NOT NULL DEFAULT 1"),
+    ("event_id", "TEXT"),
+    ("correlation_id", "TEXT"),
+    ("causation_id", "TEXT"),
+    ("principal", "TEXT"),
+    ("headers", "TEXT"),
+];
+
+/// Timestamps are stored as fixed-width RFC 3339 strings, so that text
+/// ordering in SQL matches time ordering
+fn format_timestamp(timestamp: DateTime<Utc>) -> String {
+    timestamp.to_rfc3339_opts(SecondsFormat::Nanos, true)
+}
+
+/// `SQLite` stores integers as signed 64-bit
+fn to_sql_version(version: u64) -> Result<i64> {
+    i64::try_from(version).map_err(|_| anyhow!("Version {version} out of range"))
+}
+
+fn from_sql_version(version: i64) -> Result<u64> {
+    u64::try_from(version).map_err(|_| anyhow!("Invalid stored version {version}"))
+}
+
+const EVENT_COLUMNS: &str = "version, payload, schema_version, \
+    event_id, correlation_id, causation_id, principal, headers";
+
+/// A payload as stored: JSON text, or a compressed BLOB
+fn encode_payload(codec: &RecordCodec, json: String) -> Result<Value> {
+    let stored = codec.encode(json.into_bytes())?;
+    if is_compressed(&stored) {
+        Ok(Value::Blob(stored))
+    } else {
+        Ok(Value::Text(
+            String::from_utf8(stored).map_err(|e| anyhow!(e))?,
+        ))
+    }
+}
+
+fn decode_payload<T: serde::de::DeserializeOwned>(
+    codec: &RecordCodec,
+    payload: Value,
+) -> Result<T> {
+    let stored = match payload {
+        Value::Text(text) => text.into_bytes(),
+        Value::Blob(blob) => blob,
+        other => bail!("Unexpected payload type {:?}", other.data_type()),
+    };
+    serde_json::from_slice(&codec.decode(&stored)?).map_err(|e| anyhow!(e))
+}
+
+/// A row selected with `EVENT_COLUMNS`
+struct EventRow {
+    version: i64,
+    payload: Value,
+    schema_version: u32,
+    event_id: Option<String>,
+    correlation_id: Option<String>,
+    causation_id: Option<String>,
+    principal: Option<String>,
+    headers: Option<String>,
+}
+
+impl EventRow {
+    fn decode(
+        self,
+        upcasters: &UpcasterRegistry,
+        codec: &RecordCodec,
+    ) -> Result<(u64, EventEnvelope)> {
+        let parse_id = |id: Option<String>| {
+            id.map(|id| Uuid::parse_str(&id).map_err(|e| anyhow!(e)))
+                .transpose()
+        };
+        let metadata = EventMetadata {
+            event_id: parse_id(self.event_id)?.unwrap_or_default(),
+            correlation_id: parse_id(self.correlation_id)?.unwrap_or_default(),
+            causation_id: parse_id(self.causation_id)?,
+            principal: self.principal,
+            headers: self
+                .headers
+                .map(|h| serde_json::from_str(&h).map_err(|e| anyhow!(e)))
+                .transpose()?
+                .unwrap_or_default(),
+        };
+
+        let json = decode_payload(codec, self.payload)?;
+        let event = upcasters.upcast(self.schema_version, json)?;
+        Ok((
+            from_sql_version(self.version)?,
+            EventEnvelope { metadata, event },
+        ))
+    }
+}
+
+/// Connection and version counter, shared with the blocking pool and the
+/// background checkpointer
+struct SqliteState {
+    conn: Mutex<Connection>,
+    version_counter: AtomicU64,
+}
+
+impl SqliteState {
+    fn lock(&self) -> MutexGuard<'_, Connection> {
+        self.conn
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner)
+    }
+
+    /// Insert events with their stored payloads under one transaction,
+    /// assigning consecutive versions
+    fn insert_events(&self, events: &[(EventEnvelope, Value)]) -> Result<Vec<u64>> {
+        self.write_events(|latest| {
+            Ok((latest + 1..)
+                .zip(events)
+                .map(|(version, (envelope, payload))| (version, envelope, payload))
+                .collect())
+        })
+    }
+
+    /// Insert events under one transaction at the versions `assign` picks
+    /// given the latest version
+    fn write_events<'a>(
+        &self,
+        assign: impl FnOnce(u64) -> Result<Vec<(u64, &'a EventEnvelope, &'a Value)>>,
+    ) -> Result<Vec<u64>> {
+        let mut conn = self.lock();
+        let tx = conn.transaction().map_err(|e| anyhow!(e))?;
+
+        // Versions are assigned while holding the connection lock, so they
+        // are committed in order
+        let records = assign(self.version_counter.load(Ordering::SeqCst))?;
+        let mut versions = Vec::with_capacity(records.len());
+        {
+            let mut stmt = tx
+                .prepare_cached(
+                    "INSERT INTO events
+                     (version, twin_id, kind, timestamp, payload, schema_version,
+                      event_id, correlation_id, causation_id, principal, headers)
+                     VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11)",
+                )
+                .map_err(|e| anyhow!(e))?;
+
+            for (version, EventEnvelope { metadata, event }, payload) in records {
+                let headers = serde_json::to_string(&metadata.headers).map_err(|e| anyhow!(e))?;
+                stmt.execute(params![
+                    to_sql_version(version)?,
+                    event.twin_id().to_string(),
+                    event.kind(),
+                    format_timestamp(event.timestamp()),
+                    payload,
+                    CURRENT_SCHEMA_VERSION,
+                    metadata.event_id.to_string(),
+                    metadata.correlation_id.to_string(),
+                    metadata.causation_id.map(|id| id.to_string()),
+                    metadata.principal,
+                    headers,
+                ])
+                .map_err(|e| anyhow!(e))?;
+                versions.push(version);
+            }
+        }
+        tx.commit().map_err(|e| anyhow!(e))?;
+
+        if let Some(&last) = versions.last() {
+            self.version_counter.store(last, Ordering::SeqCst);
+        }
+        drop(conn); // Release only after the counter moved past this batch
+        Ok(versions)
+    }
+
+    /// Run an event query selecting `EVENT_COLUMNS`, in version order with
+    /// at most `limit` rows
+    fn select_events(
+        &self,
+        filter: &str,
+        limit: Option<usize>,
+        params: Vec<Value>,
+    ) -> Result<Vec<EventRow>> {
+        let limit = limit.map(|n| format!(" LIMIT {n}")).unwrap_or_default();
+        let sql =
+            format!("SELECT {EVENT_COLUMNS} FROM events WHERE {filter} ORDER BY version{limit}");
+        let conn = self.lock();
+        let mut stmt = conn.prepare_cached(&sql).map_err(|e| anyhow!(e))?;
+        let rows = stmt
+            .query_map(params_from_iter(params), |row| {
+                Ok(EventRow {
+                    version: row.get(0)?,
+                    payload: row.get(1)?,
+                    schema_version: row.get(2)?,
+                    event_id: row.get(3)?,
+                    correlation_id: row.get(4)?,
+                    causation_id: row.get(5)?,
+                    principal: row.get(6)?,
+                    headers: row.get(7)?,
+                })
+            })
+            .map_err(|e| anyhow!(e))?
+            .collect::<rusqlite::Result<Vec<_>>>()
+            .map_err(|e| anyhow!(e))?;
+        drop(stmt);
+        drop(conn);
+        Ok(rows)
+    }
+
+    /// Checkpoint the WAL into the database; the checkpoint syncs the WAL
+    /// first unless syncing is off
+    fn checkpoint(&self) -> Result<()> {
+        self.lock()
+            .query_row("PRAGMA wal_checkpoint(PASSIVE)", [], |_| Ok(()))
+            .map_err(|e| anyhow!(e))
+    }
+}
+
+/// `SQLite`-based persistent event store
+pub struct SqliteEventStore {
+    state: Arc<SqliteState>,
+    durability: Durability,
+    upcasters: UpcasterRegistry,
+    codec: RecordCodec,
+}
+
+impl SqliteEventStore {
+    /// Open (or create) an `SQLite` event store at the given path that
+    /// syncs on every append
+    pub fn new(path: impl AsRef<Path>) -> Result<Self> {
+        Self::with_durability(path, Durability::EveryEvent)
+    }
+
+    /// Open (or create) an `SQLite` event store with the given durability mode
+    pub fn with_durability(path: impl AsRef<Path>, durability: Durability) -> Result<Self> {
+        let conn = Connection::open(path).map_err(|e| anyhow!(e))?;
+        conn.pragma_update(None, "journal_mode", "WAL")
+            .map_err(|e| anyhow!(e))?;
+        conn.pragma_update(None, "synchronous", synchronous_level(durability))
+            .map_err(|e| anyhow!(e))?;
+        let store = Self::from_connection(conn, durability)?;
+
+        if let Durability::Interval(interval) = durability {
+            Self::spawn_checkpointer(Arc::downgrade(&store.state), interval);
+        }
+
+        Ok(store)
+    }
+
+    /// Create a non-persistent `SQLite` event store
+    pub fn in_memory() -> Result<Self> {
+        let conn = Connection::open_in_memory().map_err(|e| anyhow!(e))?;
+        Self::from_connection(conn, Durability::default())
+    }
+
+    fn from_connection(conn: Connection, durability: Durability) -> Result<Self> {
+        conn.execute_batch(SCHEMA).map_err(|e| anyhow!(e))?;
+
+        for (column, definition) in ADDED_EVENT_COLUMNS {
+            let exists = conn
+                .prepare("SELECT 1 FROM pragma_table_info('events') WHERE name = ?1")
+                .and_then(|mut stmt| stmt.exists([column]))
+                .map_err(|e| anyhow!(e))?;
+            if !exists {
+                conn.execute_batch(&format!(
+                    "ALTER TABLE events ADD COLUMN {column} {definition}"
+                ))
+                .map_err(|e| anyhow!(e))?;
+            }
+        }
+        conn.execute_batch(
+            "CREATE INDEX IF NOT EXISTS idx_events_correlation ON events (correlation_id)",
+        )
+        .map_err(|e| anyhow!(e))?;
+
+        let mut codec = RecordCodec::default();
+        let dictionaries = conn
+            .prepare("SELECT bytes FROM dictionaries")
+            .and_then(|mut stmt| {
+                stmt.query_map([], |row| row.get::<_, Vec<u8>>(0))?
+                    .collect::<rusqlite::Result<Vec<_>>>()
+            })
+            .map_err(|e| anyhow!(e))?;
+        for bytes in dictionaries {
+            codec.add_dictionary(&Dictionary::from_bytes(bytes));
+        }
+
+        // Initialize version counter
+        let latest_version: i64 = conn
+            .query_row("SELECT COALESCE(MAX(version), 0) FROM events", [], |row| {
+                row.get(0)
+            })
+            .map_err(|e| anyhow!(e))?;
+
+        Ok(Self {
+            state: Arc::new(SqliteState {
+                conn: Mutex::new(conn),
+                version_counter: AtomicU64::new(from_sql_version(latest_version)?),
+            }),
+            durability,
+            upcasters: UpcasterRegistry::new(),
+            codec,
+        })
+    }
+
+    /// Use these upcasters when decoding events written with older schemas
+    #[must_use]
+    pub fn with_upcasters(mut self, upcasters: UpcasterRegistry) -> Self {
+        self.upcasters = upcasters;
+        self
+    }
+
+    /// Compress payloads written from now on. A dictionary is saved in the
+    /// database so its payloads stay readable after reopening.
+    pub fn with_compression(mut self, compression: Compression) -> Result<Self> {
+        if let Some(dictionary) = compression.dictionary() {
+            self.state
+                .lock()
+                .execute(
+                    "INSERT OR IGNORE INTO dictionaries (id, bytes) VALUES (?1, ?2)",
+                    params![dictionary.id(), dictionary.as_bytes()],
+                )
+                .map_err(|e| anyhow!(e))?;
+        }
+        self.codec.configure(compression);
+        Ok(self)
+    }
+
+    /// Checkpoint the WAL every `interval` until the store is dropped
+    fn spawn_checkpointer(state: Weak<SqliteState>, interval: Duration) {
+        std::thread::spawn(move || loop {
+            std::thread::sleep(interval);
+            let Some(state) = state.upgrade() else {
+                break;
+            };
+            if let Err(e) = state.checkpoint() {
+                tracing::warn!("Background checkpoint of SQLite log failed: {}", e);
+            }
+        });
+    }
+
+    /// The durability mode this store was opened with
+    pub fn durability(&self) -> Durability {
+        self.durability
+    }
+
+    /// Force all committed events to disk, regardless of durability mode
+    pub async fn flush(&self) -> Result<()> {
+        let level = synchronous_level(self.durability);
+        self.run(move |state| {
+            let conn = state.lock();
+            conn.pragma_update(None, "synchronous", "FULL")
+                .map_err(|e| anyhow!(e))?;
+            let checkpoint = conn
+                .query_row("PRAGMA wal_checkpoint(FULL)", [], |_| Ok(()))
+                .map_err(|e| anyhow!(e));
+            conn.pragma_update(None, "synchronous", level)
+                .map_err(|e| anyhow!(e))?;
+            checkpoint
+        })
+        .await
+    }
+
+    /// Run `f` off the async runtime, as `SQLite` blocks on disk I/O
+    async fn run<T: Send + 'static>(
+        &self,
+        f: impl FnOnce(&SqliteState) -> Result<T> + Send + 'static,
+    ) -> Result<T> {
+        let state = self.state.clone();
+        tokio::task::spawn_blocking(move || f(&state))
+            .await
+            .map_err(|e| anyhow!(e))?
+    }
+
+    /// Run an event query on the blocking pool and decode the rows
+    async fn query_events(
+        &self,
+        filter: &'static str,
+        limit: Option<usize>,
+        params: Vec<Value>,
+    ) -> Result<Vec<(u64, EventEnvelope)>> {
+        let rows = self
+            .run(move |state| state.select_events(filter, limit, params))
+            .await?;
+        rows.into_iter()
+            .map(|row| row.decode(&self.upcasters, &self.codec))
+            .collect()
+    }
+
+    /// An event's payload as stored, encoded before it goes to the
+    /// blocking pool
+    fn encode_event(&self, envelope: &EventEnvelope) -> Result<Value> {
+        let json = serde_json::to_string(&envelope.event).map_err(|e| anyhow!(e))?;
+        encode_payload(&self.codec, json)
+    }
+
+    fn encode_events(&self, envelopes: Vec<EventEnvelope>) -> Result<Vec<(EventEnvelope, Value)>> {
+        envelopes
+            .into_iter()
+            .map(|envelope| {
+                let payload = self.encode_event(&envelope)?;
+                Ok((envelope, payload))
+            })
+            .collect()
+    }
+}
+
+/// The `synchronous` setting implementing a durability mode in WAL mode
+fn synchronous_level(durability: Durability) -> &'static str {
+    match durability {
+        Durability::EveryEvent => "FULL",
+        Durability::Interval(_) => "NORMAL",
+        Durability::OsManaged => "OFF",
+    }
+}
+
+#[async_trait]
+impl EventStore for SqliteEventStore {
+    async fn append_envelope(&self, envelope: EventEnvelope) -> Result<u64> {
+        let events = self.encode_events(vec![envelope])?;
+        let versions = self.run(move |state| state.insert_events(&events)).await?;
+        versions
+            .first()
+            .copied()
+            .ok_or_else(|| anyhow!("Append produced no version"))
+    }
+
+    async fn append_envelopes(&self, envelopes: Vec<EventEnvelope>) -> Result<Vec<u64>> {
+        let events = self.encode_events(envelopes)?;
+        self.run(move |state| state.insert_events(&events)).await
+    }
+
+    async fn import_envelopes(&self, envelopes: Vec<(u64, EventEnvelope)>) -> Result<()> {
+        let payloads = envelopes
+            .iter()
+            .map(|(_, envelope)| self.encode_event(envelope))
+            .collect::<Result<Vec<_>>>()?;
+        self.run(move |state| {
+            state.write_events(|latest| {
+                check_import_versions(latest, &envelopes)?;
+                Ok(envelopes
+                    .iter()
+                    .zip(&payloads)
+                    .map(|((v, e), payload)| (*v, e, payload))
+                    .collect())
+            })?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn get_envelopes(
+        &self,
+        twin_id: TwinId,
+        after_version: u64,
+    ) -> Result<Vec<(u64, EventEnvelope)>> {
+        self.query_events(
+            "twin_id = ?1 AND version > ?2",
+            None,
+            vec![
+                Value::Text(twin_id.to_string()),
+                Value::Integer(to_sql_version(after_version)?),
+            ],
+        )
+        .await
+    }
+
+    async fn get_envelopes_in_range(
+        &self,
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+    ) -> Result<Vec<(u64, EventEnvelope)>> {
+        self.query_events(
+            "timestamp >= ?1 AND timestamp <= ?2",
+            None,
+            vec![
+                Value::Text(format_timestamp(start)),
+                Value::Text(format_timestamp(end)),
+            ],
+        )
+        .await
+    }
+
+    async fn get_envelopes_before(
+        &self,
+        twin_id: TwinId,
+        before_version: u64,
+        limit: usize,
+    ) -> Result<Vec<(u64, EventEnvelope)>> {
+        self.query_events(
+            "twin_id = ?1 AND version < ?2 AND version >= (
+                 SELECT MIN(version) FROM (
+                     SELECT version FROM events WHERE twin_id = ?1 AND version < ?2
+                     ORDER BY version DESC LIMIT ?3))",
+            None,
+            vec![
+                Value::Text(twin_id.to_string()),
+                // No stored version lies beyond the SQL range
+                Value::Integer(i64::try_from(before_version).unwrap_or(i64::MAX)),
+                Value::Integer(i64::try_from(limit).unwrap_or(i64::MAX)),
+            ],
+        )
+        .await
+    }
+
+    async fn get_envelopes_after(
+        &self,
+        after_version: u64,
+        limit: usize,
+    ) -> Result<Vec<(u64, EventEnvelope)>> {
+        self.query_events(
+            "version > ?1",
+            Some(limit),
+            vec![Value::Integer(to_sql_version(after_version)?)],
+        )
+        .await
+    }
+
+    async fn get_latest_version(&self) -> Result<u64> {
+        Ok(self.state.version_counter.load(Ordering::SeqCst))
+    }
+}
+
+#[async_trait]
+impl SnapshotStore for SqliteEventStore {
+    async fn save_snapshot(&self, snapshot: TwinSnapshot) -> Result<()> {
+        let json = serde_json::to_string(&snapshot).map_err(|e| anyhow!(e))?;
+        let payload = encode_payload(&self.codec, json)?;
+        self.run(move |state| {
+            state
+                .lock()
+                .execute(
+                    "INSERT OR REPLACE INTO snapshots
+                     (twin_id, class_name, event_version, timestamp, payload)
+                     VALUES (?1, ?2, ?3, ?4, ?5)",
+                    params![
+                        snapshot.twin_id.to_string(),
+                        snapshot.class_name,
+                        to_sql_version(snapshot.event_version)?,
+                        format_timestamp(snapshot.timestamp),
+                        payload,
+                    ],
+                )
+                .map_err(|e| anyhow!(e))?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn get_snapshot(&self, twin_id: TwinId) -> Result<Option<TwinSnapshot>> {
+        let payload: Option<Value> = self
+            .run(move |state| {
+                state
+                    .lock()
+                    .query_row(
+                        "SELECT payload FROM snapshots WHERE twin_id = ?1",
+                        params![twin_id.to_string()],
+                        |row| row.get(0),
+                    )
+                    .optional()
+                    .map_err(|e| anyhow!(e))
+            })
+            .await?;
+
+        payload.map(|p| decode_payload(&self.codec, p)).transpose()
+    }
+
+    async fn list_snapshots(&self) -> Result<Vec<TwinSnapshot>> {
+        let payloads = self
+            .run(|state| {
+                let conn = state.lock();
+                let mut stmt = conn
+                    .prepare_cached("SELECT payload FROM snapshots")
+                    .map_err(|e| anyhow!(e))?;
+                let payloads = stmt
+                    .query_map([], |row| row.get::<_, Value>(0))
+                    .map_err(|e| anyhow!(e))?
+                    .collect::<rusqlite::Result<Vec<_>>>()
+                    .map_err(|e| anyhow!(e))?;
+                drop(stmt);
+                drop(conn);
+                Ok(payloads)
+            })
+            .await?;
+
+        payloads
+            .into_iter()
+            .map(|p| decode_payload(&self.codec, p))
+            .collect()
+    }
+
+    async fn list_snapshots_after(
+        &self,
+        after: Option<TwinId>,
+        limit: usize,
+    ) -> Result<Vec<TwinSnapshot>> {
+        // Hyphenated lowercase ids sort as text in the same order as twin ids
+        let after = after.map(|id| id.to_string()).unwrap_or_default();
+        let limit = i64::try_from(limit).unwrap_or(i64::MAX);
+        let payloads = self
+            .run(move |state| {
+                let conn = state.lock();
+                let mut stmt = conn
+                    .prepare_cached(
+                        "SELECT payload FROM snapshots WHERE twin_id > ?1
+                         ORDER BY twin_id LIMIT ?2",
+                    )
+                    .map_err(|e| anyhow!(e))?;
+                let payloads = stmt
+                    .query_map(params![after, limit], |row| row.get::<_, Value>(0))
+                    .map_err(|e| anyhow!(e))?
+                    .collect::<rusqlite::Result<Vec<_>>>()
+                    .map_err(|e| anyhow!(e))?;
+                drop(stmt);
+                drop(conn);
+                Ok(payloads)
+            })
+            .await?;
+
+        payloads
+            .into_iter()
+            .map(|p| decode_payload(&self.codec, p))
+            .collect()
+    }
+
+    async fn cleanup_old_snapshots(&self, before: DateTime<Utc>) -> Result<u64> {
+        let deleted = self
+            .run(move |state| {
+                state
+                    .lock()
+                    .execute(
+                        "DELETE FROM snapshots WHERE timestamp < ?1",
+                        params![format_timestamp(before)],
+                    )
+                    .map_err(|e| anyhow!(e))
+            })
+            .await?;
+        Ok(deleted as u64)
+    }
+}
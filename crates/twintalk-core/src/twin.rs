@@ -2,17 +2,26 @@
 //!
 //! Twins are the core entities that receive telemetry and respond to messages.
 
+use crate::alert::{AlertRule, TwinAlerts};
+use crate::alias::Alias;
+use crate::computed::{ComputedProperty, ComputedValues};
+use crate::event::TwinEvent;
+use crate::history::{self, HistoryConfig, PropertyHistory, TwinHistory};
 use crate::message::Message;
+use crate::property::{self, PropertyMetadata};
+use crate::quantity::{self, Quantity};
+use crate::relationship::Relationship;
+use crate::validation::{ClassSchema, ValidationError};
 use crate::value::Value;
 use anyhow::{anyhow, Result};
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, BTreeSet};
 use std::fmt;
 use uuid::Uuid;
 
 /// Unique identifier for a twin
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
 pub struct TwinId(pub Uuid);
 
 impl TwinId {
@@ -43,11 +52,26 @@ pub struct TwinState {
     pub parent_id: Option<TwinId>, // For prototype chain
     pub created_at: DateTime<Utc>,
     pub updated_at: DateTime<Utc>,
+    #[serde(default)]
+    pub metadata: BTreeMap<String, PropertyMetadata>,
+    /// Outgoing relationships to other twins
+    #[serde(default)]
+    pub relationships: BTreeSet<Relationship>,
+    /// External names of the twin
+    #[serde(default)]
+    pub aliases: BTreeSet<Alias>,
+    /// Labels for finding the twin, such as `site=berlin`
+    #[serde(default)]
+    pub labels: BTreeMap<String, String>,
 }
 
 /// Active twin instance with behavior
 pub struct Twin {
     state: TwinState,
+    history: TwinHistory,
+    alerts: TwinAlerts,
+    computed: ComputedValues,
+    schema: ClassSchema,
 }
 
 impl Twin {
@@ -62,13 +86,86 @@ impl Twin {
                 parent_id: None,
                 created_at: now,
                 updated_at: now,
+                metadata: BTreeMap::new(),
+                relationships: BTreeSet::new(),
+                aliases: BTreeSet::new(),
+                labels: BTreeMap::new(),
             },
+            history: TwinHistory::default(),
+            alerts: TwinAlerts::default(),
+            computed: ComputedValues::default(),
+            schema: ClassSchema::default(),
         }
     }
 
     /// Create from existing state (for loading from persistence)
     pub fn from_state(state: TwinState) -> Self {
-        Self { state }
+        Self {
+            state,
+            history: TwinHistory::default(),
+            alerts: TwinAlerts::default(),
+            computed: ComputedValues::default(),
+            schema: ClassSchema::default(),
+        }
+    }
+
+    /// Keep telemetry history according to `config`, dropping any kept so far
+    #[must_use]
+    pub fn with_history(mut self, config: HistoryConfig) -> Self {
+        self.history = TwinHistory::new(config);
+        self
+    }
+
+    /// Evaluate these alert rules on telemetry updates
+    #[must_use]
+    pub fn with_alert_rules(mut self, rules: Vec<AlertRule>) -> Self {
+        self.alerts.set_rules(rules);
+        self
+    }
+
+    /// Replace the alert rules, keeping the state of those still present
+    pub fn set_alert_rules(&mut self, rules: Vec<AlertRule>) {
+        self.alerts.set_rules(rules);
+    }
+
+    /// The alert rules and the twin's state on each
+    pub fn alerts(&self) -> &TwinAlerts {
+        &self.alerts
+    }
+
+    /// Calculate these properties from the others
+    #[must_use]
+    pub fn with_computed(mut self, properties: Vec<ComputedProperty>) -> Self {
+        self.set_computed(properties);
+        self
+    }
+
+    /// Replace the computed properties and recalculate them
+    pub fn set_computed(&mut self, properties: Vec<ComputedProperty>) {
+        self.computed
+            .set_properties(properties, &mut self.state.properties);
+    }
+
+    /// The computed properties
+    pub fn computed(&self) -> &[ComputedProperty] {
+        self.computed.properties()
+    }
+
+    /// Validate writes and telemetry against `schema`
+    #[must_use]
+    pub fn with_schema(mut self, schema: ClassSchema) -> Self {
+        self.schema = schema;
+        self
+    }
+
+    /// Replace the schema; properties already set are not revalidated
+    pub fn set_schema(&mut self, schema: ClassSchema) {
+        self.schema = schema;
+    }
+
+    /// The schema writes and telemetry are validated against
+    pub fn schema(&self) -> &ClassSchema {
+        &self.schema
     }
 
     /// Get the twin's ID
@@ -86,6 +183,115 @@ impl Twin {
         &self.state
     }
 
+    /// Unit, quality and timestamps of a property
+    pub fn metadata(&self, property: &str) -> Option<&PropertyMetadata> {
+        self.state.metadata.get(property)
+    }
+
+    /// The value of a property, stored or computed
+    pub fn property(&self, name: &str) -> Option<&Value> {
+        self.state
+            .properties
+            .get(name)
+            .or_else(|| self.computed.values().get(name))
+    }
+
+    /// The value of a property as a quantity, in its declared unit if it
+    /// is a plain number
+    pub fn quantity(&self, property: &str) -> Option<Quantity> {
+        let value = self.property(property)?;
+        quantity::as_quantity(value, self.unit_of(property))
+    }
+
+    /// Convert telemetry sent in any units to the declared units of its
+    /// properties
+    pub fn to_declared_units(&self, data: &[(String, Quantity)]) -> Result<Vec<(String, f64)>> {
+        data.iter()
+            .map(|(name, q)| {
+                let unit = self
+                    .unit_of(name)
+                    .ok_or_else(|| anyhow!("{name} has no unit"))?;
+                let value = q.to(unit).map_err(|e| anyhow!("{name}: {e}"))?;
+                Ok((name.clone(), value.value()))
+            })
+            .collect()
+    }
+
+    /// The unit declared in the property's metadata, or in the schema
+    fn unit_of(&self, property: &str) -> Option<&str> {
+        self.state
+            .metadata
+            .get(property)
+            .and_then(|m| m.unit.as_deref())
+            .or_else(|| self.schema.get(property)?.unit.as_deref())
+    }
+
+    /// Relationships to other twins
+    pub fn relationships(&self) -> &BTreeSet<Relationship> {
+        &self.state.relationships
+    }
+
+    /// Apply a recorded `RelationshipAdded` or `RelationshipRemoved`
+    pub(crate) fn replay_relationship(&mut self, event: &TwinEvent) {
+        match event {
+            TwinEvent::RelationshipAdded { kind, target, .. } => {
+                self.state.relationships.insert(Relationship {
+                    kind: kind.clone(),
+                    target: *target,
+                });
+            }
+            TwinEvent::RelationshipRemoved { kind, target, .. } => {
+                self.state.relationships.remove(&Relationship {
+                    kind: kind.clone(),
+                    target: *target,
+                });
+            }
+            _ => {}
+        }
+    }
+
+    /// External names of the twin
+    pub fn aliases(&self) -> &BTreeSet<Alias> {
+        &self.state.aliases
+    }
+
+    /// Apply a recorded `AliasAssigned`, `AliasRemoved` or `Cloned`
+    pub(crate) fn replay_identity(&mut self, event: &TwinEvent) {
+        match event {
+            TwinEvent::AliasAssigned { alias, .. } => {
+                self.state.aliases.insert(alias.clone());
+            }
+            TwinEvent::AliasRemoved { alias, .. } => {
+                self.state.aliases.remove(alias);
+            }
+            TwinEvent::Cloned { source_id, .. } => self.state.parent_id = Some(*source_id),
+            _ => {}
+        }
+    }
+
+    /// Labels for finding the twin
+    pub fn labels(&self) -> &BTreeMap<String, String> {
+        &self.state.labels
+    }
+
+    /// Apply a recorded `LabelSet` or `LabelRemoved`
+    pub(crate) fn replay_label(&mut self, event: &TwinEvent) {
+        match event {
+            TwinEvent::LabelSet { key, value, .. } => {
+                self.state.labels.insert(key.clone(), value.clone());
+            }
+            TwinEvent::LabelRemoved { key, .. } => {
+                self.state.labels.remove(key);
+            }
+            _ => {}
+        }
+    }
+
+    /// Recent telemetry of a property
+    pub fn history(&self, property: &str) -> Option<&PropertyHistory> {
+        self.history.get(property)
+    }
+
     /// Clone this twin (prototype-based)
     #[must_use]
     pub fn clone_twin(&self) -> Self {
@@ -94,8 +300,19 @@ impl Twin {
         new_state.parent_id = Some(self.state.id);
         new_state.created_at = Utc::now();
         new_state.updated_at = new_state.created_at;
+        // Relationships and aliases are recorded for the original only
+        new_state.relationships.clear();
+        new_state.aliases.clear();
 
-        Self { state: new_state }
+        let mut alerts = TwinAlerts::default();
+        alerts.set_rules(self.alerts.rules().to_vec());
+        Self {
+            state: new_state,
+            history: TwinHistory::new(self.history.config()),
+            alerts,
+            computed: self.computed.clone(),
+            schema: self.schema.clone(),
+        }
     }
 
     /// Send a message to this twin
@@ -103,22 +320,17 @@ impl Twin {
         self.state.updated_at = Utc::now();
 
         match message {
-            Message::GetProperty(name) => Ok(self
-                .state
-                .properties
-                .get(name)
-                .cloned()
-                .unwrap_or(Value::Nil)),
+            Message::GetProperty(name) => Ok(self.property(name).cloned().unwrap_or(Value::Nil)),
 
             Message::SetProperty(name, value) => {
-                self.state.properties.insert(name.clone(), value.clone());
+                let now = self.state.updated_at;
+                self.write_properties(&[(name.clone(), value.clone())], now)?;
                 Ok(Value::Nil)
             }
 
             Message::UpdateProperties(updates) => {
-                for (name, value) in updates {
-                    self.state.properties.insert(name.clone(), value.clone());
-                }
+                let now = self.state.updated_at;
+                self.write_properties(updates, now)?;
                 Ok(Value::Nil)
             }
 
@@ -130,7 +342,16 @@ impl Twin {
 
             Message::GetClass => Ok(Value::String(self.state.class_name.clone())),
 
-            Message::GetAllProperties => Ok(Value::Map(self.state.properties.clone())),
+            Message::GetAllProperties => {
+                let mut properties = self.state.properties.clone();
+                properties.extend(
+                    self.computed
+                        .values()
+                        .iter()
+                        .map(|(name, value)| (name.clone(), value.clone())),
+                );
+                Ok(Value::Map(properties))
+            }
 
             Message::RespondsTo(selector) => {
                 let responds = Self::responds_to_builtin(selector);
@@ -145,12 +366,155 @@ impl Twin {
 
     /// Update from telemetry data
     pub fn update_telemetry(&mut self, data: BTreeMap<String, f64>) -> Result<()> {
+        let data: Vec<(String, f64)> = data.into_iter().collect();
+        self.apply_telemetry(&data, Utc::now())?;
+        Ok(())
+    }
+
+    /// Check telemetry against the class schema
+    pub fn validate_telemetry(&self, data: &[(String, f64)]) -> Result<(), ValidationError> {
+        self.schema.validate_telemetry(&self.state.class_name, data)
+    }
+
+    /// Check telemetry can be applied: it names no computed property and
+    /// satisfies the class schema
+    pub fn check_telemetry(&self, data: &[(String, f64)]) -> Result<()> {
+        for (name, _) in data {
+            self.check_not_computed(name)?;
+        }
+        Ok(self.validate_telemetry(data)?)
+    }
+
+    /// Check all properties against the class schema, including that the
+    /// required ones are set
+    pub fn validate(&self) -> Result<(), ValidationError> {
+        self.schema
+            .validate_state(&self.state.class_name, &self.state.properties)
+    }
+
+    /// Update from telemetry data taken at `timestamp`, returning the
+    /// alerts it raised and cleared
+    pub fn apply_telemetry(
+        &mut self,
+        data: &[(String, f64)],
+        timestamp: DateTime<Utc>,
+    ) -> Result<Vec<TwinEvent>> {
+        self.check_telemetry(data)?;
+        self.replay_telemetry(data, timestamp)?;
+        Ok(self.alerts.evaluate(
+            self.state.id,
+            &self.state.properties,
+            &self.state.metadata,
+            data,
+            timestamp,
+        ))
+    }
+
+    /// Apply recorded telemetry without evaluating alert rules, whose
+    /// outcome was recorded too. Values for properties computed since are
+    /// skipped.
+    pub(crate) fn replay_telemetry(
+        &mut self,
+        data: &[(String, f64)],
+        timestamp: DateTime<Utc>,
+    ) -> Result<()> {
+        let data: Vec<(String, f64)> = data
+            .iter()
+            .filter(|(name, _)| !self.computed.is_computed(name))
+            .cloned()
+            .collect();
         let updates: Vec<(String, Value)> = data
-            .into_iter()
-            .map(|(k, v)| (k, Value::Float(v.into())))
+            .iter()
+            .map(|(k, v)| (k.clone(), Value::Float((*v).into())))
             .collect();
 
-        self.send(&Message::UpdateProperties(updates))?;
+        self.state.updated_at = timestamp;
+        self.store_properties(&updates, timestamp)?;
+        self.history.record(&data, timestamp);
+        Ok(())
+    }
+
+    /// Apply a recorded property change at the time it was made
+    pub(crate) fn replay_property(
+        &mut self,
+        name: &str,
+        value: &Value,
+        timestamp: DateTime<Utc>,
+    ) -> Result<()> {
+        self.state.updated_at = timestamp;
+        self.store_properties(&[(name.to_string(), value.clone())], timestamp)
+    }
+
+    /// Validate and set properties
+    fn write_properties(
+        &mut self,
+        updates: &[(String, Value)],
+        timestamp: DateTime<Utc>,
+    ) -> Result<()> {
+        self.schema
+            .validate_properties(&self.state.class_name, updates)?;
+        self.store_properties(updates, timestamp)
+    }
+
+    /// Set properties, noting when their values changed, and recalculate
+    /// the computed properties depending on them. Quantities are converted
+    /// to the unit of the property.
+    fn store_properties(
+        &mut self,
+        updates: &[(String, Value)],
+        timestamp: DateTime<Utc>,
+    ) -> Result<()> {
+        let conformed = updates
+            .iter()
+            .map(|(name, value)| {
+                self.check_not_computed(name)?;
+                let current = self.state.properties.get(name);
+                let value = quantity::conform(name, value, self.unit_of(name), current)?;
+                Ok((name, value))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        for (name, value) in conformed {
+            if self.state.properties.get(name) != Some(&value) {
+                self.state.properties.insert(name.clone(), value);
+                self.state
+                    .metadata
+                    .entry(name.clone())
+                    .or_default()
+                    .last_changed = Some(timestamp);
+            }
+        }
+        let changed: Vec<&str> = updates.iter().map(|(name, _)| name.as_str()).collect();
+        self.computed
+            .recompute(&mut self.state.properties, Some(&changed));
+        Ok(())
+    }
+
+    /// Restore the alerts raised when a snapshot was taken
+    pub(crate) fn restore_alerts(&mut self, rules: &BTreeSet<String>) {
+        for rule in rules {
+            self.alerts.set_active(rule, true);
+        }
+    }
+
+    /// Restore alert state from a recorded `AlertRaised` or `AlertCleared`
+    pub(crate) fn replay_alert(&mut self, event: &TwinEvent) {
+        match event {
+            TwinEvent::AlertRaised { rule, .. } => self.alerts.set_active(rule, true),
+            TwinEvent::AlertCleared { rule, .. } => self.alerts.set_active(rule, false),
+            _ => {}
+        }
+    }
+
+    /// Record telemetry in history without changing properties, for
+    /// samples the state already reflects
+    pub(crate) fn record_history(&mut self, data: &[(String, f64)], timestamp: DateTime<Utc>) {
+        self.history.record(data, timestamp);
+    }
+
+    fn check_not_computed(&self, name: &str) -> Result<()> {
+        if self.computed.is_computed(name) {
+            return Err(anyhow!("Property {name} is computed"));
+        }
         Ok(())
     }
 
@@ -159,32 +523,78 @@ impl Twin {
         matches!(
             selector,
             "class" | "allProperties" | "clone" | "respondsTo:" | "checkAlert"
-        )
+        ) || history::SELECTORS.contains(&selector)
+            || property::SELECTORS.contains(&selector)
+            || quantity::SELECTORS.contains(&selector)
     }
 
     /// Handle custom messages
-    fn handle_custom_message(&mut self, selector: &str, _args: &[Value]) -> Result<Value> {
+    fn handle_custom_message(&mut self, selector: &str, args: &[Value]) -> Result<Value> {
         match selector {
+            // Whether any alert is raised; twins without rules check the
+            // current properties against the legacy rule instead
             "checkAlert" => {
-                let temp = self
-                    .state
-                    .properties
-                    .get("temperature")
-                    .and_then(Value::as_f64)
-                    .unwrap_or(0.0);
-                let threshold = self
-                    .state
-                    .properties
-                    .get("threshold")
-                    .and_then(Value::as_f64)
-                    .unwrap_or(30.0);
-
-                let alert = temp > threshold;
+                let alert = if self.alerts.rules().is_empty() {
+                    let rule = AlertRule::legacy();
+                    rule.holds(&self.state.properties, self.unit_of(&rule.property))
+                        .unwrap_or(false)
+                } else {
+                    self.alerts.active().next().is_some()
+                };
                 self.state
                     .properties
                     .insert("alert".to_string(), Value::Boolean(alert));
                 Ok(Value::Boolean(alert))
             }
+            _ if history::SELECTORS.contains(&selector) => self.history.aggregate(selector, args),
+            _ if property::SELECTORS.contains(&selector) => {
+                property::handle(&mut self.state.metadata, selector, args)
+            }
+            _ if quantity::SELECTORS.contains(&selector) => {
+                self.handle_quantity_message(selector, args)
+            }
+            _ => Err(anyhow!("Twin does not understand: {selector}")),
+        }
+    }
+
+    /// Answer a quantity message about a property
+    fn handle_quantity_message(&mut self, selector: &str, args: &[Value]) -> Result<Value> {
+        let name = args
+            .first()
+            .and_then(Value::as_str)
+            .ok_or_else(|| anyhow!("{selector} expects a property name"))?;
+        let current = self.quantity(name);
+        if selector == "quantityOf:" {
+            return Ok(current.map_or(Value::Nil, Value::Quantity));
+        }
+        let current = current.ok_or_else(|| anyhow!("{name} has no numeric value"))?;
+        let arg = args
+            .get(1)
+            .ok_or_else(|| anyhow!("{selector} expects a value"))?;
+        let other = || {
+            quantity::as_quantity(arg, Some(current.unit()))
+                .ok_or_else(|| anyhow!("{selector} expects a quantity, got {arg}"))
+        };
+
+        match selector {
+            "convert:to:" => {
+                let unit = arg
+                    .as_str()
+                    .ok_or_else(|| anyhow!("{selector} expects a unit name"))?;
+                Ok(Value::Quantity(current.to(unit)?))
+            }
+            "compare:with:" => Ok(Value::Integer(current.try_cmp(&other()?)? as i64)),
+            "increase:by:" => {
+                let sum = current.try_add(&other()?)?;
+                // Keep the property a plain number if it was one
+                let value = match self.state.properties.get(name) {
+                    Some(Value::Quantity(_)) => Value::Quantity(sum),
+                    _ => Value::from(sum.value()),
+                };
+                let now = self.state.updated_at;
+                self.write_properties(&[(name.to_string(), value.clone())], now)?;
+                Ok(value)
+            }
             _ => Err(anyhow!("Twin does not understand: {selector}")),
         }
     }
@@ -0,0 +1,477 @@
+//! Per-class property schemas
+//!
+//! A class declares the type of its properties, with optional ranges,
+//! allowed values, unit and required-ness. Writes and telemetry that break
+//! the schema are rejected with a `ValidationError` listing every
+//! violation, which callers can downcast to from `anyhow::Error`:
+//!
+//! ```
+//! use twintalk_core::validation::{ClassSchema, PropertySchema};
+//!
+//! let schema = ClassSchema::new()
+//!     .property("temperature", PropertySchema::number().range(-40.0, 125.0).unit("°C"))
+//!     .property("threshold", PropertySchema::number().required())
+//!     .property("mode", PropertySchema::symbol().one_of(["heating", "cooling"]));
+//! ```
+//!
+//! Properties not in the schema are unconstrained unless the schema is
+//! `strict`. Events already recorded are replayed without validation, so
+//! tightening a schema never makes a twin unloadable.
+
+use crate::value::Value;
+use serde_json::json;
+use std::collections::BTreeMap;
+use std::fmt;
+use thiserror::Error;
+
+/// The type a property's values must have
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum PropertyType {
+    Any,
+    Boolean,
+    Integer,
+    /// Integers, floats and quantities
+    Number,
+    String,
+    Symbol,
+    Array,
+    Map,
+    Bytes,
+}
+
+impl PropertyType {
+    /// Whether `value` has this type
+    pub fn accepts(self, value: &Value) -> bool {
+        matches!(
+            (self, value),
+            (Self::Any, _)
+                | (Self::Boolean, Value::Boolean(_))
+                | (Self::Integer, Value::Integer(_))
+                | (
+                    Self::Number,
+                    Value::Integer(_) | Value::Float(_) | Value::Quantity(_)
+                )
+                | (Self::String, Value::String(_))
+                | (Self::Symbol, Value::Symbol(_))
+                | (Self::Array, Value::Array(_))
+                | (Self::Map, Value::Map(_))
+                | (Self::Bytes, Value::Bytes(_))
+        )
+    }
+
+    fn json_type(self) -> Option<&'static str> {
+        match self {
+            Self::Any => None,
+            Self::Boolean => Some("boolean"),
+            Self::Integer => Some("integer"),
+            Self::Number => Some("number"),
+            Self::String | Self::Symbol | Self::Bytes => Some("string"),
+            Self::Array => Some("array"),
+            Self::Map => Some("object"),
+        }
+    }
+}
+
+impl fmt::Display for PropertyType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(self, f)
+    }
+}
+
+/// Constraints on one property
+#[derive(Debug, Clone, PartialEq)]
+pub struct PropertySchema {
+    pub kind: PropertyType,
+    pub min: Option<f64>,
+    pub max: Option<f64>,
+    /// Allowed values; any value if empty
+    pub allowed: Vec<Value>,
+    /// Unit of plain numbers and of the range; quantities must convert to it
+    pub unit: Option<String>,
+    /// Whether the property must be set and never nil
+    pub required: bool,
+}
+
+impl PropertySchema {
+    /// Values of `kind`
+    pub fn of(kind: PropertyType) -> Self {
+        Self {
+            kind,
+            min: None,
+            max: None,
+            allowed: Vec::new(),
+            unit: None,
+            required: false,
+        }
+    }
+
+    pub fn any() -> Self {
+        Self::of(PropertyType::Any)
+    }
+
+    pub fn boolean() -> Self {
+        Self::of(PropertyType::Boolean)
+    }
+
+    pub fn integer() -> Self {
+        Self::of(PropertyType::Integer)
+    }
+
+    pub fn number() -> Self {
+        Self::of(PropertyType::Number)
+    }
+
+    pub fn string() -> Self {
+        Self::of(PropertyType::String)
+    }
+
+    pub fn symbol() -> Self {
+        Self::of(PropertyType::Symbol)
+    }
+
+    /// At least `min`
+    #[must_use]
+    pub fn min(mut self, min: f64) -> Self {
+        self.min = Some(min);
+        self
+    }
+
+    /// At most `max`
+    #[must_use]
+    pub fn max(mut self, max: f64) -> Self {
+        self.max = Some(max);
+        self
+    }
+
+    /// Between `min` and `max`, inclusive
+    #[must_use]
+    pub fn range(self, min: f64, max: f64) -> Self {
+        self.min(min).max(max)
+    }
+
+    /// Only these values. Strings given for a symbol property are taken
+    /// as symbols.
+    #[must_use]
+    pub fn one_of<I, V>(mut self, values: I) -> Self
+    where
+        I: IntoIterator<Item = V>,
+        V: Into<Value>,
+    {
+        self.allowed = values
+            .into_iter()
+            .map(|value| match (self.kind, value.into()) {
+                (PropertyType::Symbol, Value::String(s)) => Value::Symbol(s),
+                (_, value) => value,
+            })
+            .collect();
+        self
+    }
+
+    /// Measured in `unit`
+    #[must_use]
+    pub fn unit(mut self, unit: impl Into<String>) -> Self {
+        self.unit = Some(unit.into());
+        self
+    }
+
+    /// Must be set, and never to nil
+    #[must_use]
+    pub fn required(mut self) -> Self {
+        self.required = true;
+        self
+    }
+
+    /// What is wrong with `value`, if anything
+    pub fn check(&self, value: &Value) -> Option<ViolationKind> {
+        if *value == Value::Nil {
+            return self.required.then_some(ViolationKind::Missing);
+        }
+        if !self.kind.accepts(value) {
+            return Some(ViolationKind::WrongType {
+                expected: self.kind,
+                actual: value.type_name(),
+            });
+        }
+        if !self.allowed.is_empty() && !self.allowed.contains(value) {
+            return Some(ViolationKind::NotAllowed {
+                value: value.clone(),
+            });
+        }
+        let number = match (value, &self.unit) {
+            (Value::Quantity(q), Some(unit)) => match q.to(unit) {
+                Ok(q) => Some(q.value()),
+                Err(_) => {
+                    return Some(ViolationKind::WrongUnit {
+                        expected: unit.clone(),
+                        actual: q.unit().to_string(),
+                    })
+                }
+            },
+            (Value::Quantity(q), None) => Some(q.value()),
+            (other, _) => other.as_f64(),
+        };
+        let out_of_range = number.filter(|n| {
+            self.min.is_some_and(|min| *n < min) || self.max.is_some_and(|max| *n > max)
+        });
+        out_of_range.map(|value| ViolationKind::OutOfRange {
+            value,
+            min: self.min,
+            max: self.max,
+        })
+    }
+
+    fn to_json_schema(&self) -> serde_json::Value {
+        let mut schema = serde_json::Map::new();
+        if let Some(kind) = self.kind.json_type() {
+            schema.insert("type".to_string(), json!(kind));
+        }
+        if self.kind == PropertyType::Bytes {
+            schema.insert("contentEncoding".to_string(), json!("base64"));
+        }
+        if let Some(min) = self.min {
+            schema.insert("minimum".to_string(), json!(min));
+        }
+        if let Some(max) = self.max {
+            schema.insert("maximum".to_string(), json!(max));
+        }
+        if !self.allowed.is_empty() {
+            let allowed: Vec<_> = self.allowed.iter().map(to_json).collect();
+            schema.insert("enum".to_string(), json!(allowed));
+        }
+        if let Some(unit) = &self.unit {
+            schema.insert("x-unit".to_string(), json!(unit));
+        }
+        serde_json::Value::Object(schema)
+    }
+}
+
+/// The plain JSON form of a value: symbols as strings, quantities as
+/// their number
+fn to_json(value: &Value) -> serde_json::Value {
+    match value {
+        Value::Nil => serde_json::Value::Null,
+        Value::Boolean(b) => json!(b),
+        Value::Integer(i) => json!(i),
+        Value::Float(f) => json!(f.into_inner()),
+        Value::String(s) | Value::Symbol(s) => json!(s),
+        Value::Array(values) => values.iter().map(to_json).collect(),
+        Value::Map(map) => map
+            .iter()
+            .map(|(k, v)| (k.clone(), to_json(v)))
+            .collect::<serde_json::Map<_, _>>()
+            .into(),
+        Value::Bytes(bytes) => json!(bytes),
+        Value::Quantity(q) => json!(q.value()),
+    }
+}
+
+/// The property schemas of a class
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ClassSchema {
+    properties: BTreeMap<String, PropertySchema>,
+    strict: bool,
+}
+
+impl ClassSchema {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Constrain the property `name`
+    #[must_use]
+    pub fn property(mut self, name: impl Into<String>, schema: PropertySchema) -> Self {
+        self.properties.insert(name.into(), schema);
+        self
+    }
+
+    /// Reject properties not in the schema
+    #[must_use]
+    pub fn strict(mut self) -> Self {
+        self.strict = true;
+        self
+    }
+
+    pub fn is_strict(&self) -> bool {
+        self.strict
+    }
+
+    /// The schema of the property `name`
+    pub fn get(&self, name: &str) -> Option<&PropertySchema> {
+        self.properties.get(name)
+    }
+
+    pub fn properties(&self) -> &BTreeMap<String, PropertySchema> {
+        &self.properties
+    }
+
+    /// Whether the schema constrains anything
+    pub fn is_empty(&self) -> bool {
+        self.properties.is_empty() && !self.strict
+    }
+
+    fn check(&self, name: &str, value: &Value) -> Option<Violation> {
+        let kind = match self.properties.get(name) {
+            Some(schema) => schema.check(value)?,
+            None if self.strict => ViolationKind::Undeclared,
+            None => return None,
+        };
+        Some(Violation {
+            property: name.to_string(),
+            kind,
+        })
+    }
+
+    /// Check properties about to be written
+    pub fn validate_properties(
+        &self,
+        class_name: &str,
+        updates: &[(String, Value)],
+    ) -> Result<(), ValidationError> {
+        let violations = updates
+            .iter()
+            .filter_map(|(name, value)| self.check(name, value))
+            .collect();
+        ValidationError::check(class_name, violations)
+    }
+
+    /// Check telemetry about to be applied
+    pub fn validate_telemetry(
+        &self,
+        class_name: &str,
+        data: &[(String, f64)],
+    ) -> Result<(), ValidationError> {
+        let violations = data
+            .iter()
+            .filter_map(|(name, value)| self.check(name, &Value::from(*value)))
+            .collect();
+        ValidationError::check(class_name, violations)
+    }
+
+    /// Check a whole set of properties, including that the required ones
+    /// are present
+    pub fn validate_state(
+        &self,
+        class_name: &str,
+        properties: &BTreeMap<String, Value>,
+    ) -> Result<(), ValidationError> {
+        let mut violations: Vec<Violation> = properties
+            .iter()
+            .filter_map(|(name, value)| self.check(name, value))
+            .collect();
+        violations.extend(
+            self.properties
+                .iter()
+                .filter(|(name, schema)| schema.required && !properties.contains_key(*name))
+                .map(|(name, _)| Violation {
+                    property: name.clone(),
+                    kind: ViolationKind::Missing,
+                }),
+        );
+        ValidationError::check(class_name, violations)
+    }
+
+    /// The schema as a JSON Schema (draft 2020-12) object describing the
+    /// properties as plain JSON: symbols as strings and quantities as
+    /// numbers in the property's unit
+    pub fn to_json_schema(&self, class_name: &str) -> serde_json::Value {
+        let properties: serde_json::Map<_, _> = self
+            .properties
+            .iter()
+            .map(|(name, schema)| (name.clone(), schema.to_json_schema()))
+            .collect();
+        let required: Vec<&String> = self
+            .properties
+            .iter()
+            .filter(|(_, schema)| schema.required)
+            .map(|(name, _)| name)
+            .collect();
+        json!({
+            "$schema": "https://json-schema.org/draft/2020-12/schema",
+            "title": class_name,
+            "type": "object",
+            "properties": properties,
+            "required": required,
+            "additionalProperties": !self.strict,
+        })
+    }
+}
+
+/// What is wrong with a property value
+#[derive(Debug, Clone, PartialEq)]
+pub enum ViolationKind {
+    /// A required property is unset or nil
+    Missing,
+    /// Not in a strict schema
+    Undeclared,
+    WrongType {
+        expected: PropertyType,
+        actual: &'static str,
+    },
+    /// A quantity that does not convert to the schema's unit
+    WrongUnit { expected: String, actual: String },
+    OutOfRange {
+        value: f64,
+        min: Option<f64>,
+        max: Option<f64>,
+    },
+    /// Not one of the allowed values
+    NotAllowed { value: Value },
+}
+
+impl fmt::Display for ViolationKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Missing => write!(f, "required"),
+            Self::Undeclared => write!(f, "not declared"),
+            Self::WrongType { expected, actual } => write!(f, "expected {expected}, got {actual}"),
+            Self::WrongUnit { expected, actual } => write!(f, "expected {expected}, got {actual}"),
+            Self::OutOfRange { value, min, max } => {
+                let bound = |b: Option<f64>| b.map_or_else(|| "..".to_string(), |b| b.to_string());
+                write!(f, "{value} outside [{}, {}]", bound(*min), bound(*max))
+            }
+            Self::NotAllowed { value } => write!(f, "{value} not allowed"),
+        }
+    }
+}
+
+/// One property breaking the schema
+#[derive(Debug, Clone, PartialEq)]
+pub struct Violation {
+    pub property: String,
+    pub kind: ViolationKind,
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.property, self.kind)
+    }
+}
+
+/// Values rejected by a class schema
+#[derive(Debug, Clone, PartialEq, Error)]
+#[error("Invalid {class_name}: {}", join(.violations))]
+pub struct ValidationError {
+    pub class_name: String,
+    pub violations: Vec<Violation>,
+}
+
+impl ValidationError {
+    fn check(class_name: &str, violations: Vec<Violation>) -> Result<(), Self> {
+        if violations.is_empty() {
+            Ok(())
+        } else {
+            Err(Self {
+                class_name: class_name.to_string(),
+                violations,
+            })
+        }
+    }
+}
+
+fn join(violations: &[Violation]) -> String {
+    violations
+        .iter()
+        .map(ToString::to_string)
+        .collect::<Vec<_>>()
+        .join("; ")
+}
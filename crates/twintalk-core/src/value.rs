@@ -3,6 +3,7 @@
 //! Supports the minimal set needed for digital twins without
 //! full `Smalltalk` object complexity.
 
+use crate::quantity::Quantity;
 use ordered_float::OrderedFloat;
 use serde::{Deserialize, Serialize};
 use std::collections::BTreeMap;
@@ -39,6 +40,9 @@ pub enum Value {
 
     /// Binary data
     Bytes(Vec<u8>),
+
+    /// Number with a unit
+    Quantity(Quantity),
 }
 
 impl Value {
@@ -71,6 +75,14 @@ impl Value {
         }
     }
 
+    /// The quantity, if this is one
+    pub fn as_quantity(&self) -> Option<&Quantity> {
+        match self {
+            Self::Quantity(q) => Some(q),
+            _ => None,
+        }
+    }
+
     /// Convert to string if possible
     pub fn as_str(&self) -> Option<&str> {
         match self {
@@ -96,6 +108,7 @@ impl Value {
             Self::Array(_) => "Array",
             Self::Map(_) => "Map",
             Self::Bytes(_) => "Bytes",
+            Self::Quantity(_) => "Quantity",
         }
     }
 }
@@ -130,6 +143,7 @@ impl fmt::Display for Value {
                 write!(f, "}}")
             }
             Self::Bytes(b) => write!(f, "<{} bytes>", b.len()),
+            Self::Quantity(q) => write!(f, "{q}"),
         }
     }
 }
@@ -177,6 +191,12 @@ impl From<&str> for Value {
     }
 }
 
+impl From<Quantity> for Value {
+    fn from(q: Quantity) -> Self {
+        Self::Quantity(q)
+    }
+}
+
 impl<T: Into<Self>> From<Vec<T>> for Value {
     fn from(vec: Vec<T>) -> Self {
         Self::Array(vec.into_iter().map(Into::into).collect())
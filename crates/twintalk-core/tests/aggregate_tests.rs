@@ -0,0 +1,303 @@
+//! Tests for aggregate properties
+
+use std::sync::Arc;
+use std::time::Duration;
+use twintalk_core::event::{EventStore, TwinEvent};
+use twintalk_core::storage::MemoryEventStore;
+use twintalk_core::{
+    AggregateProperty, AlertRule, ClassSchema, Message, PropertySchema, RelationshipKind, Runtime,
+    RuntimeConfig, TwinId, Value,
+};
+
+async fn temperature(runtime: &Runtime, twin_id: TwinId, value: f64) {
+    runtime
+        .update_telemetry(twin_id, vec![("temperature".to_string(), value)])
+        .await
+        .unwrap();
+}
+
+async fn property(runtime: &Runtime, twin_id: TwinId, name: &str) -> Value {
+    runtime
+        .send(twin_id, &Message::GetProperty(name.to_string()))
+        .await
+        .unwrap()
+}
+
+async fn building(runtime: &Runtime) -> (TwinId, [TwinId; 3]) {
+    let building = runtime.create_twin("Building").await.unwrap();
+    let mut rooms = Vec::new();
+    for _ in 0..3 {
+        let room = runtime.create_twin("Room").await.unwrap();
+        runtime
+            .relate(building, RelationshipKind::Contains, room)
+            .await
+            .unwrap();
+        rooms.push(room);
+    }
+    (building, rooms.try_into().unwrap())
+}
+
+#[tokio::test]
+async fn test_aggregates_follow_child_telemetry() {
+    let store = MemoryEventStore::new();
+    let runtime = Runtime::with_stores(
+        RuntimeConfig::default(),
+        Arc::new(store.clone()),
+        Arc::new(store.clone()),
+    );
+    let (building, [kitchen, hall, office]) = building(&runtime).await;
+    runtime
+        .add_class_rule("Room", AlertRule::above("hot", "temperature", 30.0))
+        .await;
+    for aggregate in [
+        AggregateProperty::max("maxTemperature", "temperature"),
+        AggregateProperty::mean("meanTemperature", "temperature"),
+        AggregateProperty::count("reporting", "temperature"),
+        AggregateProperty::alerting("alertingRooms"),
+    ] {
+        runtime
+            .define_aggregate("Building", aggregate)
+            .await
+            .unwrap();
+    }
+    assert_eq!(
+        property(&runtime, building, "alertingRooms").await,
+        Value::from(0.0)
+    );
+
+    temperature(&runtime, kitchen, 24.0).await;
+    temperature(&runtime, hall, 18.0).await;
+    assert_eq!(
+        property(&runtime, building, "maxTemperature").await,
+        Value::from(24.0)
+    );
+    assert_eq!(
+        property(&runtime, building, "meanTemperature").await,
+        Value::from(21.0)
+    );
+    assert_eq!(
+        property(&runtime, building, "reporting").await,
+        Value::from(2.0)
+    );
+
+    temperature(&runtime, office, 35.0).await;
+    temperature(&runtime, kitchen, 32.0).await;
+    assert_eq!(
+        property(&runtime, building, "alertingRooms").await,
+        Value::from(2.0)
+    );
+    temperature(&runtime, office, 20.0).await;
+    assert_eq!(
+        property(&runtime, building, "maxTemperature").await,
+        Value::from(32.0)
+    );
+    assert_eq!(
+        property(&runtime, building, "alertingRooms").await,
+        Value::from(1.0)
+    );
+
+    // Rooms leaving the building no longer count
+    runtime
+        .unrelate(building, RelationshipKind::Contains, kitchen)
+        .await
+        .unwrap();
+    assert_eq!(
+        property(&runtime, building, "maxTemperature").await,
+        Value::from(20.0)
+    );
+    assert_eq!(
+        property(&runtime, building, "alertingRooms").await,
+        Value::from(0.0)
+    );
+
+    // Changes are recorded as the building's telemetry, caused by a room's
+    let envelopes = store.get_envelopes(building, 0).await.unwrap();
+    let (_, last) = envelopes.last().unwrap();
+    assert!(matches!(last.event, TwinEvent::TelemetryReceived { .. }));
+    assert!(last.metadata.causation_id.is_some());
+}
+
+#[tokio::test]
+async fn test_aggregates_with_evicted_children() {
+    let store = MemoryEventStore::new();
+    let config = RuntimeConfig {
+        eviction_timeout: Duration::from_millis(10),
+        ..RuntimeConfig::default()
+    };
+    let runtime = Runtime::with_stores(
+        config.clone(),
+        Arc::new(store.clone()),
+        Arc::new(store.clone()),
+    );
+    let (building, [kitchen, hall, office]) = building(&runtime).await;
+    let site = runtime.create_twin("Site").await.unwrap();
+    runtime
+        .relate(site, RelationshipKind::Contains, building)
+        .await
+        .unwrap();
+    temperature(&runtime, kitchen, 24.0).await;
+    temperature(&runtime, hall, 18.0).await;
+
+    runtime
+        .define_aggregate(
+            "Building",
+            AggregateProperty::max("maxTemperature", "temperature"),
+        )
+        .await
+        .unwrap();
+    runtime
+        .define_aggregate(
+            "Site",
+            AggregateProperty::max("maxTemperature", "maxTemperature"),
+        )
+        .await
+        .unwrap();
+    tokio::time::sleep(Duration::from_millis(30)).await;
+    assert_eq!(runtime.evict_inactive().await.unwrap(), 5);
+
+    // Rolled up through the building to the site, without loading any
+    temperature(&runtime, office, 28.0).await;
+    assert_eq!(runtime.stats().await.active_twins, 0);
+    assert_eq!(
+        property(&runtime, site, "maxTemperature").await,
+        Value::from(28.0)
+    );
+    temperature(&runtime, office, 10.0).await;
+    assert_eq!(
+        property(&runtime, building, "maxTemperature").await,
+        Value::from(24.0)
+    );
+
+    // A restarted runtime collects the contributions again
+    let restarted = Runtime::with_stores(config, Arc::new(store.clone()), Arc::new(store));
+    restarted
+        .define_aggregate(
+            "Building",
+            AggregateProperty::max("maxTemperature", "temperature"),
+        )
+        .await
+        .unwrap();
+    temperature(&restarted, hall, 26.0).await;
+    assert_eq!(
+        property(&restarted, building, "maxTemperature").await,
+        Value::from(26.0)
+    );
+}
+
+#[tokio::test]
+async fn test_aggregate_cleared_when_last_child_leaves() {
+    let runtime = Runtime::new(RuntimeConfig::default());
+    let (building, rooms) = building(&runtime).await;
+    runtime
+        .define_aggregate(
+            "Building",
+            AggregateProperty::max("maxTemperature", "temperature"),
+        )
+        .await
+        .unwrap();
+    for (room, value) in rooms.iter().zip([21.0, 23.0, 19.0]) {
+        temperature(&runtime, *room, value).await;
+    }
+    assert_eq!(
+        property(&runtime, building, "maxTemperature").await,
+        Value::from(23.0)
+    );
+
+    for room in rooms {
+        runtime
+            .unrelate(building, RelationshipKind::Contains, room)
+            .await
+            .unwrap();
+    }
+    assert_eq!(
+        property(&runtime, building, "maxTemperature").await,
+        Value::Nil
+    );
+}
+
+#[tokio::test]
+async fn test_failed_rollup_keeps_child_telemetry() {
+    let runtime = Runtime::new(RuntimeConfig::default());
+    let (building, [kitchen, ..]) = building(&runtime).await;
+    runtime
+        .define_schema(
+            "Building",
+            ClassSchema::new().property("maxTemperature", PropertySchema::number().max(30.0)),
+        )
+        .await;
+    runtime
+        .define_aggregate(
+            "Building",
+            AggregateProperty::max("maxTemperature", "temperature"),
+        )
+        .await
+        .unwrap();
+
+    // The building rejects the aggregate, but the reading stands
+    runtime
+        .update_telemetry(kitchen, vec![("temperature".to_string(), 35.0)])
+        .await
+        .unwrap();
+    assert_eq!(
+        property(&runtime, kitchen, "temperature").await,
+        Value::from(35.0)
+    );
+    assert_eq!(
+        property(&runtime, building, "maxTemperature").await,
+        Value::Nil
+    );
+
+    temperature(&runtime, kitchen, 25.0).await;
+    assert_eq!(
+        property(&runtime, building, "maxTemperature").await,
+        Value::from(25.0)
+    );
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_concurrent_rollups_record_the_latest_value() {
+    let store = MemoryEventStore::new();
+    let runtime = Arc::new(Runtime::with_stores(
+        RuntimeConfig::default(),
+        Arc::new(store.clone()),
+        Arc::new(store.clone()),
+    ));
+    let (building, rooms) = building(&runtime).await;
+    runtime
+        .define_aggregate("Building", AggregateProperty::sum("total", "temperature"))
+        .await
+        .unwrap();
+
+    let tasks: Vec<_> = rooms
+        .into_iter()
+        .map(|room| {
+            let runtime = runtime.clone();
+            tokio::spawn(async move {
+                for value in 0..50 {
+                    temperature(&runtime, room, f64::from(value)).await;
+                }
+            })
+        })
+        .collect();
+    for task in tasks {
+        task.await.unwrap();
+    }
+
+    // The last total recorded is the one the building ends with
+    let recorded = store
+        .get_events(building, 0)
+        .await
+        .unwrap()
+        .into_iter()
+        .rev()
+        .find_map(|(_, event)| match event {
+            TwinEvent::TelemetryReceived { data, .. } => Some(data),
+            _ => None,
+        })
+        .unwrap();
+    assert_eq!(recorded, vec![("total".to_string(), 147.0)]);
+    assert_eq!(
+        property(&runtime, building, "total").await,
+        Value::from(147.0)
+    );
+}
@@ -0,0 +1,192 @@
+//! Tests for declarative alert rules
+
+use std::sync::Arc;
+use std::time::Duration;
+use twintalk_core::alert::Threshold;
+use twintalk_core::event::{EventStore, TwinEvent};
+use twintalk_core::storage::{MemoryEventStore, Retention, RetentionPolicy};
+use twintalk_core::{AlertRule, Message, Runtime, RuntimeConfig, Severity, TwinId, Value};
+
+fn check_alert() -> Message {
+    Message::Send {
+        selector: "checkAlert".to_string(),
+        args: vec![],
+    }
+}
+
+async fn vibration(runtime: &Runtime, twin_id: TwinId, value: f64) {
+    runtime
+        .update_telemetry(twin_id, vec![("vibration".to_string(), value)])
+        .await
+        .unwrap();
+}
+
+async fn alert_events(store: &MemoryEventStore, twin_id: TwinId) -> Vec<String> {
+    store
+        .get_events(twin_id, 0)
+        .await
+        .unwrap()
+        .into_iter()
+        .filter_map(|(_, event)| match event {
+            TwinEvent::AlertRaised { rule, value, .. } => Some(format!("raised {rule} at {value}")),
+            TwinEvent::AlertCleared { rule, value, .. } => {
+                Some(format!("cleared {rule} at {value}"))
+            }
+            _ => None,
+        })
+        .collect()
+}
+
+#[tokio::test]
+async fn test_class_rules_raise_and_clear() {
+    let store = MemoryEventStore::new();
+    let runtime = Runtime::with_stores(
+        RuntimeConfig::default(),
+        Arc::new(store.clone()),
+        Arc::new(store.clone()),
+    );
+    let pump = runtime.create_twin("Pump").await.unwrap();
+    let other = runtime.create_twin("Fan").await.unwrap();
+    runtime
+        .add_class_rule(
+            "Pump",
+            AlertRule::above("shaking", "vibration", 8.0)
+                .with_hysteresis(2.0)
+                .with_severity(Severity::Critical),
+        )
+        .await;
+    let mut feed = runtime.subscribe();
+
+    for value in [5.0, 9.0, 10.0, 7.0, 5.5, 9.5] {
+        vibration(&runtime, pump, value).await;
+    }
+    vibration(&runtime, other, 20.0).await;
+
+    assert_eq!(
+        alert_events(&store, pump).await,
+        [
+            "raised shaking at 9",
+            "cleared shaking at 5.5",
+            "raised shaking at 9.5"
+        ]
+    );
+    assert!(alert_events(&store, other).await.is_empty());
+    assert_eq!(
+        runtime.send(pump, &check_alert()).await.unwrap(),
+        Value::Boolean(true)
+    );
+
+    // Alerts are caused by the telemetry that triggered them, and reach
+    // the change feed
+    let mut received = Vec::new();
+    while let Ok((version, envelope)) = feed.try_recv() {
+        received.push((version, envelope));
+    }
+    let (raised_at, raised) = received
+        .iter()
+        .find(|(_, e)| matches!(e.event, TwinEvent::AlertRaised { .. }))
+        .unwrap();
+    let (telemetry_at, telemetry) = &received[1];
+    assert_eq!(*raised_at, telemetry_at + 1);
+    assert_eq!(
+        raised.metadata.causation_id,
+        Some(telemetry.metadata.event_id)
+    );
+    match &raised.event {
+        TwinEvent::AlertRaised {
+            severity,
+            threshold,
+            ..
+        } => {
+            assert_eq!(*severity, Severity::Critical);
+            assert!((threshold - 8.0).abs() < f64::EPSILON);
+        }
+        _ => unreachable!(),
+    }
+    // Telemetry, alerts, and the message and alert flag change of checkAlert
+    assert_eq!(received.len(), 7 + 3 + 2);
+}
+
+#[tokio::test]
+async fn test_twin_rules_override_class_rules() {
+    let store = MemoryEventStore::new();
+    let runtime = Runtime::with_stores(
+        RuntimeConfig::default(),
+        Arc::new(store.clone()),
+        Arc::new(store.clone()),
+    );
+    runtime
+        .add_class_rule("Pump", AlertRule::above("shaking", "vibration", 8.0))
+        .await;
+    let quiet = runtime.create_twin("Pump").await.unwrap();
+    let loud = runtime.create_twin("Pump").await.unwrap();
+    runtime
+        .add_twin_rule(loud, AlertRule::above("shaking", "vibration", 15.0))
+        .await;
+    // Thresholds can come from the twin's own properties
+    runtime
+        .add_twin_rule(
+            quiet,
+            AlertRule::below("stalled", "vibration", Threshold::property("idle", 1.0)),
+        )
+        .await;
+    runtime
+        .send(
+            quiet,
+            &Message::SetProperty("idle".to_string(), Value::from(2.5)),
+        )
+        .await
+        .unwrap();
+
+    vibration(&runtime, quiet, 10.0).await;
+    vibration(&runtime, loud, 10.0).await;
+    vibration(&runtime, quiet, 2.0).await;
+
+    assert_eq!(
+        alert_events(&store, quiet).await,
+        [
+            "raised shaking at 10",
+            "cleared shaking at 2",
+            "raised stalled at 2"
+        ]
+    );
+    assert!(alert_events(&store, loud).await.is_empty());
+}
+
+#[tokio::test]
+async fn test_alerts_survive_eviction() {
+    let store = MemoryEventStore::new();
+    let runtime = Runtime::with_stores(
+        RuntimeConfig {
+            eviction_timeout: Duration::from_millis(10),
+            ..RuntimeConfig::default()
+        },
+        Arc::new(store.clone()),
+        Arc::new(store.clone()),
+    );
+    runtime
+        .add_class_rule(
+            "Pump",
+            AlertRule::above("shaking", "vibration", 8.0).with_hysteresis(2.0),
+        )
+        .await;
+    let pump = runtime.create_twin("Pump").await.unwrap();
+    vibration(&runtime, pump, 9.0).await;
+
+    // The snapshot taken on eviction holds the alert state, so it survives
+    // compacting the event that raised it
+    tokio::time::sleep(Duration::from_millis(30)).await;
+    assert_eq!(runtime.evict_inactive().await.unwrap(), 1);
+    let policy = RetentionPolicy::new().kind("AlertRaised", Retention::MaxAge(Duration::ZERO));
+    assert_eq!(store.compact(&policy, chrono::Utc::now()).removed, 1);
+
+    // Telemetry for the evicted twin is still evaluated, against the
+    // restored state: still raised, so only clearing is recorded
+    vibration(&runtime, pump, 9.5).await;
+    vibration(&runtime, pump, 5.0).await;
+    assert_eq!(alert_events(&store, pump).await, ["cleared shaking at 5"]);
+    assert_eq!(
+        runtime.send(pump, &check_alert()).await.unwrap(),
+        Value::Boolean(false)
+    );
+}
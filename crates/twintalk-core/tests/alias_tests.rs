@@ -0,0 +1,296 @@
+//! Tests for external names of twins
+
+use chrono::{DateTime, Utc};
+use std::sync::Arc;
+use std::time::Duration;
+use twintalk_core::event::{EventEnvelope, EventStore, TwinEvent};
+use twintalk_core::storage::MemoryEventStore;
+use twintalk_core::{msg, Alias, Message, Quantity, Runtime, RuntimeConfig, TwinId, Value};
+
+#[tokio::test]
+async fn test_aliases_unique_per_namespace() {
+    let runtime = Runtime::new(RuntimeConfig::default());
+    let pump = runtime.create_twin("Pump").await.unwrap();
+    let fan = runtime.create_twin("Fan").await.unwrap();
+
+    assert!(runtime
+        .assign_alias(pump, "serial", "SN-1042")
+        .await
+        .unwrap());
+    assert!(!runtime
+        .assign_alias(pump, "serial", "SN-1042")
+        .await
+        .unwrap());
+    assert!(runtime
+        .assign_alias(fan, "serial", "SN-1042")
+        .await
+        .is_err());
+    // The same name in another namespace is another alias
+    assert!(runtime.assign_alias(fan, "mqtt", "SN-1042").await.unwrap());
+    assert!(runtime.assign_alias(pump, "mqtt", "pump-7").await.unwrap());
+
+    assert_eq!(
+        runtime.lookup_alias("serial", "SN-1042").await.unwrap(),
+        Some(pump)
+    );
+    assert_eq!(
+        runtime.lookup_alias("mqtt", "SN-1042").await.unwrap(),
+        Some(fan)
+    );
+    assert_eq!(
+        runtime.aliases_of(pump).await.unwrap(),
+        [
+            Alias::new("mqtt", "pump-7"),
+            Alias::new("serial", "SN-1042")
+        ]
+    );
+
+    assert!(runtime.remove_alias("serial", "SN-1042").await.unwrap());
+    assert!(!runtime.remove_alias("serial", "SN-1042").await.unwrap());
+    assert_eq!(
+        runtime.lookup_alias("serial", "SN-1042").await.unwrap(),
+        None
+    );
+    assert!(runtime
+        .assign_alias(fan, "serial", "SN-1042")
+        .await
+        .unwrap());
+    assert!(runtime
+        .assign_alias(TwinId::new(), "serial", "x")
+        .await
+        .is_err());
+}
+
+#[tokio::test]
+async fn test_telemetry_creates_twins_from_prototype() {
+    let store = MemoryEventStore::new();
+    let runtime = Runtime::with_stores(
+        RuntimeConfig::default(),
+        Arc::new(store.clone()),
+        Arc::new(store.clone()),
+    );
+    let prototype = runtime.create_twin("Thermostat").await.unwrap();
+    runtime
+        .send(prototype, &msg!(threshold: 22.0))
+        .await
+        .unwrap();
+    runtime
+        .send(
+            prototype,
+            &Message::Send {
+                selector: "unitOf:put:".to_string(),
+                args: vec![Value::from("temperature"), Value::from("°C")],
+            },
+        )
+        .await
+        .unwrap();
+
+    // Unknown devices are rejected until the namespace has a prototype
+    let reading = || vec![("temperature".to_string(), 21.5)];
+    assert!(runtime
+        .update_telemetry_by_alias("mqtt", "thermo-1", reading())
+        .await
+        .is_err());
+    runtime.set_prototype("mqtt", prototype);
+
+    let created = runtime
+        .update_telemetry_by_alias("mqtt", "thermo-1", reading())
+        .await
+        .unwrap();
+    assert_ne!(created, prototype);
+    let again = runtime
+        .update_telemetry_by_alias("mqtt", "thermo-1", vec![("temperature".to_string(), 23.0)])
+        .await
+        .unwrap();
+    assert_eq!(again, created);
+
+    let twin = runtime.get_twin(created).await.unwrap();
+    {
+        let twin = twin.twin.read().await;
+        assert_eq!(twin.class_name(), "Thermostat");
+        assert_eq!(twin.state().parent_id, Some(prototype));
+        assert_eq!(
+            twin.quantity("temperature"),
+            Some(Quantity::new(23.0, "°C"))
+        );
+    }
+    assert_eq!(
+        runtime.send(created, &msg!(threshold)).await.unwrap(),
+        Value::from(22.0)
+    );
+    let kinds: Vec<&str> = store
+        .get_events(created, 0)
+        .await
+        .unwrap()
+        .iter()
+        .map(|(_, event)| event.kind())
+        .collect();
+    assert_eq!(
+        kinds,
+        [
+            "Created",
+            "Cloned",
+            "PropertyChanged",
+            "MessageSent",
+            "AliasAssigned",
+            "TelemetryReceived",
+            "TelemetryReceived"
+        ]
+    );
+}
+
+#[tokio::test]
+async fn test_aliases_survive_restart() {
+    let store = MemoryEventStore::new();
+    let config = RuntimeConfig {
+        eviction_timeout: Duration::from_millis(10),
+        ..RuntimeConfig::default()
+    };
+    let runtime = Runtime::with_stores(
+        config.clone(),
+        Arc::new(store.clone()),
+        Arc::new(store.clone()),
+    );
+    let prototype = runtime.create_twin("Meter").await.unwrap();
+    runtime
+        .send(prototype, &msg!(tariff: "night"))
+        .await
+        .unwrap();
+    runtime.set_prototype("serial", prototype);
+    let meter = runtime
+        .update_telemetry_by_alias("serial", "M-1", vec![("power".to_string(), 3.2)])
+        .await
+        .unwrap();
+    let other = runtime.create_twin("Meter").await.unwrap();
+    runtime.assign_alias(other, "serial", "M-2").await.unwrap();
+
+    // Rebuilt from the log
+    let restarted = Runtime::with_stores(
+        config.clone(),
+        Arc::new(store.clone()),
+        Arc::new(MemoryEventStore::new()),
+    );
+    assert_eq!(
+        restarted.lookup_alias("serial", "M-1").await.unwrap(),
+        Some(meter)
+    );
+    assert_eq!(
+        restarted.send(meter, &msg!(tariff)).await.unwrap(),
+        Value::from("night")
+    );
+
+    // And from snapshots, once the events are gone
+    tokio::time::sleep(Duration::from_millis(30)).await;
+    assert_eq!(runtime.evict_inactive().await.unwrap(), 3);
+    let compacted = Runtime::with_stores(
+        config,
+        Arc::new(MemoryEventStore::new()),
+        Arc::new(store.clone()),
+    );
+    assert_eq!(
+        compacted.lookup_alias("serial", "M-2").await.unwrap(),
+        Some(other)
+    );
+    let twin = compacted.get_twin(meter).await.unwrap();
+    assert_eq!(
+        twin.twin.read().await.aliases().iter().collect::<Vec<_>>(),
+        [&Alias::new("serial", "M-1")]
+    );
+    assert!(store
+        .get_events(other, 0)
+        .await
+        .unwrap()
+        .iter()
+        .any(|(_, event)| matches!(event, TwinEvent::AliasAssigned { .. })));
+}
+
+#[tokio::test]
+async fn test_destroyed_twins_lose_their_aliases() {
+    let store = MemoryEventStore::new();
+    let runtime = Runtime::with_stores(
+        RuntimeConfig::default(),
+        Arc::new(store.clone()),
+        Arc::new(store.clone()),
+    );
+    let meter = runtime.create_twin("Meter").await.unwrap();
+    runtime.assign_alias(meter, "serial", "M-9").await.unwrap();
+    store
+        .append(TwinEvent::Destroyed {
+            twin_id: meter,
+            timestamp: chrono::Utc::now(),
+        })
+        .await
+        .unwrap();
+
+    let restarted = Runtime::with_stores(
+        RuntimeConfig::default(),
+        Arc::new(store.clone()),
+        Arc::new(MemoryEventStore::new()),
+    );
+    assert_eq!(restarted.lookup_alias("serial", "M-9").await.unwrap(), None);
+}
+
+/// Event store refusing batches that name a twin
+struct NoAliasStore(MemoryEventStore);
+
+#[async_trait::async_trait]
+impl EventStore for NoAliasStore {
+    async fn append_envelope(&self, envelope: EventEnvelope) -> anyhow::Result<u64> {
+        self.0.append_envelope(envelope).await
+    }
+
+    async fn append_envelopes(&self, envelopes: Vec<EventEnvelope>) -> anyhow::Result<Vec<u64>> {
+        if envelopes
+            .iter()
+            .any(|envelope| matches!(envelope.event, TwinEvent::AliasAssigned { .. }))
+        {
+            anyhow::bail!("Aliases are not accepted");
+        }
+        self.0.append_envelopes(envelopes).await
+    }
+
+    async fn get_envelopes(
+        &self,
+        twin_id: TwinId,
+        after_version: u64,
+    ) -> anyhow::Result<Vec<(u64, EventEnvelope)>> {
+        self.0.get_envelopes(twin_id, after_version).await
+    }
+
+    async fn get_envelopes_in_range(
+        &self,
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+    ) -> anyhow::Result<Vec<(u64, EventEnvelope)>> {
+        self.0.get_envelopes_in_range(start, end).await
+    }
+
+    async fn get_latest_version(&self) -> anyhow::Result<u64> {
+        self.0.get_latest_version().await
+    }
+}
+
+#[tokio::test]
+async fn test_failed_alias_leaves_no_clone() {
+    let store = MemoryEventStore::new();
+    let runtime = Runtime::with_stores(
+        RuntimeConfig::default(),
+        Arc::new(NoAliasStore(store.clone())),
+        Arc::new(store.clone()),
+    );
+    let prototype = runtime.create_twin("Thermostat").await.unwrap();
+    runtime.set_prototype("mqtt", prototype);
+    let before = store.get_latest_version().await.unwrap();
+
+    // The clone and its alias are recorded together or not at all
+    assert!(runtime
+        .update_telemetry_by_alias("mqtt", "thermo-1", vec![("temperature".to_string(), 21.5)])
+        .await
+        .is_err());
+    assert_eq!(store.get_latest_version().await.unwrap(), before);
+    assert_eq!(runtime.stats().await.active_twins, 1);
+    assert_eq!(
+        runtime.lookup_alias("mqtt", "thermo-1").await.unwrap(),
+        None
+    );
+}
@@ -130,6 +130,7 @@ async fn example_event_sourcing() {
         eviction_interval: Duration::from_secs(1),
         snapshot_on_eviction: true,
         max_active_twins: Some(100),
+        ..RuntimeConfig::default()
     }));
 
     // Create and configure twin
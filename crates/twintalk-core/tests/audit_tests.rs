@@ -0,0 +1,196 @@
+//! Tests for the hash-chained audit log
+
+use chrono::Utc;
+use twintalk_core::event::{EventEnvelope, EventStore, TwinEvent};
+use twintalk_core::storage::audit::{
+    chain_hash, BrokenLink, ChainBreak, HASH_HEADER, PREV_HASH_HEADER,
+};
+use twintalk_core::storage::{AuditProof, HashChainedStore, MemoryEventStore, SledEventStore};
+use twintalk_core::{TwinId, Value};
+
+mod common;
+
+fn property_changed(twin_id: TwinId, value: i64) -> TwinEvent {
+    TwinEvent::PropertyChanged {
+        twin_id,
+        property: "setpoint".to_string(),
+        old_value: None,
+        new_value: Value::Integer(value),
+        timestamp: Utc::now(),
+    }
+}
+
+async fn open_chained(path: &str) -> HashChainedStore<SledEventStore> {
+    HashChainedStore::new(common::sled_store(path).await)
+}
+
+/// Edit a stored record behind the store's back, as an attacker with access
+/// to the sled files would
+async fn tamper(path: &str, version: u64, edit: impl FnOnce(&mut serde_json::Value)) {
+    let db = common::sled_db(path).await;
+    let events = db.open_tree("events").unwrap();
+    match events.get(version.to_be_bytes()).unwrap() {
+        Some(data) => {
+            let mut record: serde_json::Value = serde_json::from_slice(&data).unwrap();
+            edit(&mut record);
+            events
+                .insert(version.to_be_bytes(), serde_json::to_vec(&record).unwrap())
+                .unwrap();
+        }
+        None => panic!("no record at version {version}"),
+    }
+    db.flush().unwrap();
+}
+
+/// Write three chained events for one twin and return its id and proof
+async fn chained_history(path: &str) -> (TwinId, AuditProof) {
+    let store = open_chained(path).await;
+    let twin_id = TwinId::new();
+    for value in 1..=3 {
+        store
+            .append(property_changed(twin_id, value))
+            .await
+            .unwrap();
+    }
+    (twin_id, store.export_proof(twin_id).await.unwrap())
+}
+
+#[tokio::test]
+async fn test_chain_verifies_across_reopen() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("events");
+    let path = path.to_str().unwrap();
+    let (twin_id, proof) = chained_history(path).await;
+
+    // The chain continues from the stored head after reopening
+    let store = open_chained(path).await;
+    store
+        .append_batch(vec![
+            property_changed(twin_id, 4),
+            property_changed(twin_id, 5),
+        ])
+        .await
+        .unwrap();
+    assert_eq!(store.verify(twin_id).await.unwrap(), None);
+    assert!(store.verify_all().await.unwrap().is_empty());
+    assert_eq!(store.verify_against(&proof).await.unwrap(), None);
+
+    // Proofs survive being exported as JSON
+    let json = serde_json::to_string(&store.export_proof(twin_id).await.unwrap()).unwrap();
+    let exported: AuditProof = serde_json::from_str(&json).unwrap();
+    assert_eq!(exported.events.len(), 5);
+    assert!(exported.verify().is_ok());
+}
+
+#[tokio::test]
+async fn test_edited_event_is_detected() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("events");
+    let path = path.to_str().unwrap();
+    let (twin_id, proof) = chained_history(path).await;
+
+    tamper(path, 2, |record| {
+        record["event"]["new_value"] = serde_json::json!({"type": "Integer", "value": 99});
+    })
+    .await;
+
+    let store = open_chained(path).await;
+    let expected = BrokenLink {
+        twin_id,
+        version: 2,
+        reason: ChainBreak::ContentMismatch,
+    };
+    assert_eq!(store.verify(twin_id).await.unwrap(), Some(expected.clone()));
+    assert_eq!(store.verify_all().await.unwrap(), vec![expected.clone()]);
+    assert_eq!(store.verify_against(&proof).await.unwrap(), Some(expected));
+}
+
+#[tokio::test]
+async fn test_removed_event_breaks_link() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("events");
+    let path = path.to_str().unwrap();
+    let (twin_id, proof) = chained_history(path).await;
+
+    {
+        let db = common::sled_db(path).await;
+        db.open_tree("events")
+            .unwrap()
+            .remove(2u64.to_be_bytes())
+            .unwrap();
+        db.flush().unwrap();
+    }
+
+    let store = open_chained(path).await;
+    assert_eq!(
+        store.verify(twin_id).await.unwrap(),
+        Some(BrokenLink {
+            twin_id,
+            version: 3,
+            reason: ChainBreak::LinkMismatch,
+        })
+    );
+    assert_eq!(
+        store.verify_against(&proof).await.unwrap().unwrap().reason,
+        ChainBreak::Missing
+    );
+}
+
+#[tokio::test]
+async fn test_rewritten_head_is_detected_by_proof() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("events");
+    let path = path.to_str().unwrap();
+    let (twin_id, proof) = chained_history(path).await;
+
+    // Rewrite the newest event and recompute its hash, leaving an intact
+    // chain that only an earlier proof can contradict
+    tamper(path, 3, |record| {
+        record["event"]["new_value"] = serde_json::json!({"type": "Integer", "value": 99});
+        let envelope = EventEnvelope {
+            metadata: serde_json::from_value(record["metadata"].clone()).unwrap(),
+            event: serde_json::from_value(record["event"].clone()).unwrap(),
+        };
+        let prev = &envelope.metadata.headers[PREV_HASH_HEADER];
+        let hash = chain_hash(prev, &envelope).unwrap();
+        record["metadata"]["headers"][HASH_HEADER] = serde_json::json!(hash);
+    })
+    .await;
+
+    let store = open_chained(path).await;
+    assert_eq!(store.verify(twin_id).await.unwrap(), None);
+    assert_eq!(
+        store.verify_against(&proof).await.unwrap(),
+        Some(BrokenLink {
+            twin_id,
+            version: 3,
+            reason: ChainBreak::ContentMismatch,
+        })
+    );
+}
+
+#[tokio::test]
+async fn test_unchained_events_and_forged_proofs() {
+    let inner = MemoryEventStore::new();
+    let twin_id = TwinId::new();
+    inner.append(property_changed(twin_id, 1)).await.unwrap();
+
+    // Events written around the wrapper carry no hash
+    let store = HashChainedStore::new(inner);
+    assert_eq!(
+        store.verify(twin_id).await.unwrap().unwrap().reason,
+        ChainBreak::Unchained
+    );
+
+    let chained = TwinId::new();
+    store.append(property_changed(chained, 1)).await.unwrap();
+    let mut proof = store.export_proof(chained).await.unwrap();
+    assert!(proof.verify().is_ok());
+
+    proof.events[0].1.event = property_changed(chained, 2);
+    assert_eq!(
+        proof.verify().unwrap_err().reason,
+        ChainBreak::ContentMismatch
+    );
+    assert!(store.verify_against(&proof).await.is_err());
+}
@@ -0,0 +1,48 @@
+//! Helpers shared by the integration tests
+
+#![allow(dead_code)]
+
+use std::fmt::Debug;
+use std::time::Duration;
+use twintalk_core::storage::SledEventStore;
+
+/// Attempts at opening a store before giving up
+const OPEN_ATTEMPTS: usize = 50;
+
+/// Wait between attempts at opening a store
+const OPEN_RETRY_DELAY: Duration = Duration::from_millis(20);
+
+/// Open a store whose previous handle may still hold its file lock, as
+/// sled's background flusher does briefly after a store is dropped
+pub async fn retry_open<T, E: Debug>(mut open: impl FnMut() -> Result<T, E>) -> T {
+    for _ in 1..OPEN_ATTEMPTS {
+        if let Ok(store) = open() {
+            return store;
+        }
+        tokio::time::sleep(OPEN_RETRY_DELAY).await;
+    }
+    open().unwrap()
+}
+
+/// `retry_open` outside the async runtime, returning the last error
+pub fn retry_open_blocking<T, E>(mut open: impl FnMut() -> Result<T, E>) -> Result<T, E> {
+    let mut result = open();
+    for _ in 1..OPEN_ATTEMPTS {
+        if result.is_ok() {
+            break;
+        }
+        std::thread::sleep(OPEN_RETRY_DELAY);
+        result = open();
+    }
+    result
+}
+
+/// Open the sled store at `path`
+pub async fn sled_store(path: &str) -> SledEventStore {
+    retry_open(|| SledEventStore::new(path)).await
+}
+
+/// Open the raw sled database at `path`, to edit records behind the store
+pub async fn sled_db(path: &str) -> sled::Db {
+    retry_open(|| sled::open(path)).await
+}
@@ -0,0 +1,204 @@
+//! Tests for compressed store records
+
+use chrono::Utc;
+use twintalk_core::event::{EventStore, SnapshotStore, TwinEvent, TwinSnapshot};
+use twintalk_core::storage::{
+    Compression, CompressionReport, Dictionary, FileEventStore, FileStoreConfig, SledEventStore,
+};
+use twintalk_core::{TwinId, Value};
+
+mod common;
+
+fn telemetry(twin_id: TwinId, i: u32) -> TwinEvent {
+    TwinEvent::TelemetryReceived {
+        twin_id,
+        data: vec![
+            (
+                "supply_air_temperature".to_string(),
+                f64::from(i % 40) + 0.25,
+            ),
+            (
+                "return_air_temperature".to_string(),
+                f64::from(i % 35) + 0.5,
+            ),
+            ("relative_humidity".to_string(), f64::from(i % 60)),
+        ],
+        timestamp: Utc::now(),
+    }
+}
+
+/// A snapshot with one property, written at `event_version`
+fn air_handler(twin_id: TwinId, event_version: u64) -> TwinSnapshot {
+    TwinSnapshot::new(twin_id, "AirHandler", event_version)
+        .with_properties([("mode".to_string(), Value::from("cooling"))])
+}
+
+async fn fill(store: &impl EventStore, twins: &[TwinId], events: u32) {
+    let batch = (0..events)
+        .map(|i| telemetry(twins[i as usize % twins.len()], i))
+        .collect();
+    store.append_batch(batch).await.unwrap();
+}
+
+#[tokio::test]
+async fn test_dictionary_compression_survives_reopen() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("events");
+    let path = path.to_str().unwrap();
+    let twins: Vec<TwinId> = (0..8).map(|_| TwinId::new()).collect();
+
+    {
+        let store = common::sled_store(path).await;
+        fill(&store, &twins, 1000).await;
+        store
+            .save_snapshot(air_handler(twins[0], 1000))
+            .await
+            .unwrap();
+        let plain = store.compression_report().unwrap();
+        assert_eq!(plain.records, 1001);
+        assert_eq!(plain.compressed, 0);
+        assert!((plain.ratio() - 1.0).abs() < f64::EPSILON);
+
+        let dictionary = store.train_dictionary(8 * 1024).unwrap();
+        let store = store
+            .with_compression(Compression::zstd(3).with_dictionary(dictionary))
+            .unwrap();
+        let report = store.recompress().unwrap();
+        assert_eq!(report.compressed, 1001);
+        assert_eq!(report.raw_bytes, plain.raw_bytes);
+        assert!(report.ratio() > 3.0, "ratio {:.2}", report.ratio());
+        assert_eq!(store.compression_report().unwrap(), report);
+
+        // New records use the dictionary too
+        fill(&store, &twins, 100).await;
+    }
+
+    // A store opened without compression still reads every record
+    let store = common::sled_store(path).await;
+    let report = store.compression_report().unwrap();
+    assert_eq!(report.records, 1101);
+    assert_eq!(report.compressed, 1101);
+    let events = store.get_events(twins[3], 0).await.unwrap();
+    assert_eq!(events.len(), 1100 / 8 + 1);
+    assert!(matches!(
+        &events[0].1,
+        TwinEvent::TelemetryReceived { data, .. } if data[0].0 == "supply_air_temperature"
+    ));
+    let snapshot = store.get_snapshot(twins[0]).await.unwrap().unwrap();
+    assert_eq!(snapshot.properties["mode"], Value::from("cooling"));
+
+    // New plain records mix with the compressed ones
+    fill(&store, &twins, 8).await;
+    let mixed = store.compression_report().unwrap();
+    assert_eq!(mixed.records, 1109);
+    assert_eq!(mixed.compressed, 1101);
+}
+
+#[tokio::test]
+async fn test_plain_zstd_and_empty_report() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("events");
+    let store = SledEventStore::new(path.to_str().unwrap())
+        .unwrap()
+        .with_compression(Compression::zstd(3))
+        .unwrap();
+    assert_eq!(
+        store.compression_report().unwrap(),
+        CompressionReport::default()
+    );
+    assert!(store.train_dictionary(1024).is_err());
+
+    let twin_id = TwinId::new();
+    fill(&store, &[twin_id], 10).await;
+    let report = store.compression_report().unwrap();
+    assert_eq!(report.compressed, 10);
+    assert_eq!(store.get_events(twin_id, 0).await.unwrap().len(), 10);
+}
+
+fn dictionary() -> Dictionary {
+    let samples: Vec<_> = (0..500)
+        .map(|i| serde_json::to_vec(&telemetry(TwinId::new(), i)).unwrap())
+        .collect();
+    Dictionary::train(&samples, 8 * 1024).unwrap()
+}
+
+fn dir_size(path: &std::path::Path) -> u64 {
+    std::fs::read_dir(path)
+        .unwrap()
+        .map(|entry| entry.unwrap().metadata().unwrap().len())
+        .sum()
+}
+
+#[tokio::test]
+async fn test_file_store_compression_survives_reopen() {
+    let dir = tempfile::tempdir().unwrap();
+    let twins: Vec<TwinId> = (0..8).map(|_| TwinId::new()).collect();
+    let compressed = FileStoreConfig {
+        compression: Compression::zstd(3).with_dictionary(dictionary()),
+        ..FileStoreConfig::default()
+    };
+
+    {
+        let plain = FileEventStore::new(dir.path().join("plain")).unwrap();
+        fill(&plain, &twins, 1000).await;
+        let store = FileEventStore::with_config(dir.path().join("zstd"), compressed).unwrap();
+        fill(&store, &twins, 1000).await;
+        store
+            .save_snapshot(air_handler(twins[0], 1000))
+            .await
+            .unwrap();
+
+        let (plain, zstd) = (
+            dir_size(&dir.path().join("plain/segments")),
+            dir_size(&dir.path().join("zstd/segments")),
+        );
+        assert!(zstd * 2 < plain, "{zstd} compressed vs {plain} plain bytes");
+    }
+
+    // Opened without compression, the dictionary is found on disk and new
+    // plain records mix with the compressed ones
+    let store = FileEventStore::new(dir.path().join("zstd")).unwrap();
+    fill(&store, &twins, 8).await;
+    let events = store.get_events(twins[3], 0).await.unwrap();
+    assert_eq!(events.len(), 1008 / 8);
+    assert!(matches!(
+        &events[0].1,
+        TwinEvent::TelemetryReceived { data, .. } if data[0].0 == "supply_air_temperature"
+    ));
+    let snapshot = store.get_snapshot(twins[0]).await.unwrap().unwrap();
+    assert_eq!(snapshot.properties["mode"], Value::from("cooling"));
+}
+
+#[cfg(feature = "sqlite")]
+#[tokio::test]
+async fn test_sqlite_compression_survives_reopen() {
+    use twintalk_core::storage::SqliteEventStore;
+
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("events.db");
+    let twins: Vec<TwinId> = (0..8).map(|_| TwinId::new()).collect();
+
+    {
+        let store = SqliteEventStore::new(&path).unwrap();
+        fill(&store, &twins, 8).await;
+        let store = store
+            .with_compression(Compression::zstd(3).with_dictionary(dictionary()))
+            .unwrap();
+        fill(&store, &twins, 1000).await;
+        store
+            .save_snapshot(air_handler(twins[0], 1008))
+            .await
+            .unwrap();
+    }
+
+    let store = SqliteEventStore::new(&path).unwrap();
+    let events = store.get_events(twins[3], 0).await.unwrap();
+    assert_eq!(events.len(), 1008 / 8);
+    assert!(events.iter().all(|(_, event)| matches!(
+        event,
+        TwinEvent::TelemetryReceived { data, .. } if data[0].0 == "supply_air_temperature"
+    )));
+    let snapshot = store.get_snapshot(twins[0]).await.unwrap().unwrap();
+    assert_eq!(snapshot.properties["mode"], Value::from("cooling"));
+    assert_eq!(store.list_snapshots().await.unwrap().len(), 1);
+}
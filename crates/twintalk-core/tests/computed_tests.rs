@@ -0,0 +1,219 @@
+//! Tests for computed properties
+
+use std::sync::Arc;
+use std::time::Duration;
+use twintalk_core::event::{EventStore, SnapshotStore, TwinEvent};
+use twintalk_core::storage::MemoryEventStore;
+use twintalk_core::{msg, ComputedProperty, Message, Runtime, RuntimeConfig, Twin, Value};
+
+fn power() -> ComputedProperty {
+    ComputedProperty::new("power", ["voltage", "current"], |v| v[0] * v[1])
+}
+
+/// Magnus formula
+fn dew_point() -> ComputedProperty {
+    ComputedProperty::new("dew_point", ["temperature", "humidity"], |v| {
+        let (b, c) = (17.62, 243.12);
+        let gamma = (v[1] / 100.0).ln() + b * v[0] / (c + v[0]);
+        c * gamma / (b - gamma)
+    })
+}
+
+#[test]
+fn test_computed_properties_on_twin() {
+    let mut twin = Twin::new("Meter").with_computed(vec![
+        power(),
+        ComputedProperty::new("kilowatts", ["power"], |v| v[0] / 1000.0),
+    ]);
+
+    // Unset while inputs are missing
+    twin.send(&msg!(voltage: 230.0)).unwrap();
+    assert_eq!(twin.send(&msg!(power)).unwrap(), Value::Nil);
+
+    twin.send(&msg!(current: 10.0)).unwrap();
+    assert_eq!(twin.send(&msg!(power)).unwrap(), Value::from(2300.0));
+    assert_eq!(twin.send(&msg!(kilowatts)).unwrap(), Value::from(2.3));
+
+    twin.update_telemetry([("current".to_string(), 5.0)].into())
+        .unwrap();
+    assert_eq!(twin.send(&msg!(kilowatts)).unwrap(), Value::from(1.15));
+
+    match twin.send(&msg!(allProperties)).unwrap() {
+        Value::Map(properties) => {
+            assert_eq!(properties.get("power"), Some(&Value::from(1150.0)));
+            assert_eq!(properties.len(), 4);
+        }
+        other => panic!("Expected map, got {other}"),
+    }
+    // Computed values are not part of the persisted state
+    assert!(!twin.state().properties.contains_key("power"));
+    assert!(twin.send(&msg!(power: 1.0)).is_err());
+}
+
+#[tokio::test]
+async fn test_computed_properties_in_runtime() {
+    let store = MemoryEventStore::new();
+    let runtime = Runtime::with_stores(
+        RuntimeConfig {
+            eviction_timeout: Duration::from_millis(10),
+            ..RuntimeConfig::default()
+        },
+        Arc::new(store.clone()),
+        Arc::new(store.clone()),
+    );
+    let sensor = runtime.create_twin("Climate").await.unwrap();
+    runtime.define_computed("Climate", dew_point()).await;
+    runtime
+        .define_computed(
+            "Climate",
+            ComputedProperty::new("comfortable", ["dew_point"], |v| {
+                if v[0] < 16.0 {
+                    1.0
+                } else {
+                    0.0
+                }
+            })
+            .persisted(),
+        )
+        .await;
+
+    runtime
+        .update_telemetry(
+            sensor,
+            vec![
+                ("temperature".to_string(), 25.0),
+                ("humidity".to_string(), 60.0),
+            ],
+        )
+        .await
+        .unwrap();
+    let dew_point = runtime
+        .send(sensor, &msg!(dew_point))
+        .await
+        .unwrap()
+        .as_f64()
+        .unwrap();
+    assert!((dew_point - 16.69).abs() < 0.01, "{dew_point}");
+
+    // Persisted computed properties are recorded like any other change
+    runtime.send(sensor, &msg!(humidity: 40.0)).await.unwrap();
+    let changed: Vec<String> = store
+        .get_events(sensor, 0)
+        .await
+        .unwrap()
+        .into_iter()
+        .filter_map(|(_, event)| match event {
+            TwinEvent::PropertyChanged { property, .. } => Some(property),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(changed, ["comfortable", "humidity"]);
+
+    tokio::time::sleep(Duration::from_millis(30)).await;
+    assert_eq!(runtime.evict_inactive().await.unwrap(), 1);
+    let snapshot = store.get_snapshot(sensor).await.unwrap().unwrap();
+    assert!(!snapshot.properties.contains_key("dew_point"));
+    assert_eq!(
+        snapshot.properties.get("comfortable"),
+        Some(&Value::from(1.0))
+    );
+
+    // Reloading recalculates them
+    let reloaded = runtime.send(sensor, &msg!(dew_point)).await.unwrap();
+    assert!(reloaded.as_f64().unwrap() < 16.0);
+    assert!(runtime
+        .send(
+            sensor,
+            &Message::SetProperty("dew_point".to_string(), Value::from(1.0))
+        )
+        .await
+        .is_err());
+}
+
+#[tokio::test]
+async fn test_telemetry_for_computed_property_rejected() {
+    let store = MemoryEventStore::new();
+    let config = RuntimeConfig {
+        eviction_timeout: Duration::from_millis(10),
+        ..RuntimeConfig::default()
+    };
+    let runtime = Runtime::with_stores(
+        config.clone(),
+        Arc::new(store.clone()),
+        Arc::new(store.clone()),
+    );
+    runtime.define_computed("Meter", power()).await;
+    let meter = runtime.create_twin("Meter").await.unwrap();
+    runtime
+        .update_telemetry(
+            meter,
+            vec![("voltage".to_string(), 230.0), ("current".to_string(), 2.0)],
+        )
+        .await
+        .unwrap();
+
+    // Rejected before it is recorded, whether the twin is active or not
+    let reading = vec![("power".to_string(), 1.0)];
+    assert!(runtime
+        .update_telemetry(meter, reading.clone())
+        .await
+        .is_err());
+    tokio::time::sleep(Duration::from_millis(30)).await;
+    assert_eq!(runtime.evict_inactive().await.unwrap(), 1);
+    assert!(runtime
+        .update_telemetry(meter, reading.clone())
+        .await
+        .is_err());
+    assert_eq!(store.get_events(meter, 0).await.unwrap().len(), 2);
+
+    // Values recorded before the property was computed are skipped
+    store
+        .append(TwinEvent::TelemetryReceived {
+            twin_id: meter,
+            data: reading,
+            timestamp: chrono::Utc::now(),
+        })
+        .await
+        .unwrap();
+    let reloaded = Runtime::with_stores(
+        config,
+        Arc::new(store.clone()),
+        Arc::new(MemoryEventStore::new()),
+    );
+    reloaded.define_computed("Meter", power()).await;
+    assert_eq!(
+        reloaded.send(meter, &msg!(power)).await.unwrap(),
+        Value::from(460.0)
+    );
+}
+
+#[tokio::test]
+async fn test_removed_computed_property_recorded_as_nil() {
+    let store = MemoryEventStore::new();
+    let runtime = Runtime::with_stores(
+        RuntimeConfig::default(),
+        Arc::new(store.clone()),
+        Arc::new(store.clone()),
+    );
+    let meter = runtime.create_twin("Meter").await.unwrap();
+    runtime.define_computed("Meter", power().persisted()).await;
+    runtime.send(meter, &msg!(voltage: 230.0)).await.unwrap();
+    runtime.send(meter, &msg!(current: 2.0)).await.unwrap();
+
+    // A non-numeric input leaves the power undefined
+    runtime.send(meter, &msg!(current: "n/a")).await.unwrap();
+    let (_, last) = store.get_events(meter, 0).await.unwrap().pop().unwrap();
+    match last {
+        TwinEvent::PropertyChanged {
+            property,
+            old_value,
+            new_value,
+            ..
+        } => {
+            assert_eq!(property, "power");
+            assert_eq!(old_value, Some(Value::from(460.0)));
+            assert_eq!(new_value, Value::Nil);
+        }
+        other => panic!("unexpected event {other:?}"),
+    }
+}
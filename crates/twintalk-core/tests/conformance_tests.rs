@@ -0,0 +1,132 @@
+//! Storage conformance suite applied to every built-in backend
+
+use std::path::PathBuf;
+use tempfile::TempDir;
+use twintalk_core::storage::conformance::StoreFactory;
+use twintalk_core::storage::{FileEventStore, HashChainedStore, MemoryEventStore, SledEventStore};
+
+mod common;
+
+/// Factory for backends that live in a directory
+struct DirFactory<S> {
+    dir: TempDir,
+    generation: usize,
+    open: fn(&PathBuf) -> anyhow::Result<S>,
+}
+
+impl<S> DirFactory<S> {
+    fn new(open: fn(&PathBuf) -> anyhow::Result<S>) -> Self {
+        Self {
+            dir: tempfile::tempdir().unwrap(),
+            generation: 0,
+            open,
+        }
+    }
+
+    fn path(&self) -> PathBuf {
+        self.dir.path().join(format!("store-{}", self.generation))
+    }
+}
+
+impl<S> StoreFactory for DirFactory<S>
+where
+    S: twintalk_core::event::EventStore + twintalk_core::event::SnapshotStore + 'static,
+{
+    type Store = S;
+
+    fn create(&mut self) -> anyhow::Result<S> {
+        self.generation += 1;
+        (self.open)(&self.path())
+    }
+
+    fn reopen(&mut self) -> Option<anyhow::Result<S>> {
+        Some(common::retry_open_blocking(|| (self.open)(&self.path())))
+    }
+}
+
+twintalk_core::event_store_conformance!(memory, || anyhow::Ok(MemoryEventStore::new()));
+
+twintalk_core::event_store_conformance!(
+    sled,
+    DirFactory::new(|path| SledEventStore::new(path.to_str().unwrap()))
+);
+
+twintalk_core::event_store_conformance!(
+    compressed_sled,
+    DirFactory::new(|path| SledEventStore::new(path.to_str().unwrap())?
+        .with_compression(twintalk_core::storage::Compression::zstd(3)))
+);
+
+twintalk_core::event_store_conformance!(file, DirFactory::new(|path| FileEventStore::new(path)));
+
+twintalk_core::event_store_conformance!(
+    compressed_file,
+    DirFactory::new(|path| FileEventStore::with_config(
+        path,
+        twintalk_core::storage::FileStoreConfig {
+            compression: twintalk_core::storage::Compression::zstd(3),
+            ..Default::default()
+        }
+    ))
+);
+
+twintalk_core::event_store_conformance!(hash_chained_memory, || anyhow::Ok(HashChainedStore::new(
+    MemoryEventStore::new()
+)));
+
+twintalk_core::event_store_conformance!(
+    hash_chained_file,
+    DirFactory::new(|path| FileEventStore::new(path).map(HashChainedStore::new))
+);
+
+#[cfg(feature = "sqlite")]
+twintalk_core::event_store_conformance!(
+    sqlite,
+    DirFactory::new(|path| {
+        std::fs::create_dir_all(path)?;
+        twintalk_core::storage::SqliteEventStore::new(path.join("events.db"))
+    })
+);
+
+#[cfg(feature = "sqlite")]
+twintalk_core::event_store_conformance!(
+    compressed_sqlite,
+    DirFactory::new(|path| {
+        std::fs::create_dir_all(path)?;
+        twintalk_core::storage::SqliteEventStore::new(path.join("events.db"))?
+            .with_compression(twintalk_core::storage::Compression::zstd(3))
+    })
+);
+
+#[cfg(feature = "sqlite")]
+twintalk_core::event_store_conformance!(sqlite_in_memory, || {
+    twintalk_core::storage::SqliteEventStore::in_memory()
+});
+
+#[cfg(feature = "encryption")]
+fn encrypted<S>(inner: S) -> twintalk_core::storage::EncryptedStore<S>
+where
+    S: twintalk_core::event::EventStore + twintalk_core::event::SnapshotStore,
+{
+    twintalk_core::storage::EncryptedStore::new(
+        inner,
+        twintalk_core::storage::KeyRing::new("conformance", &[7; 32]),
+    )
+}
+
+#[cfg(feature = "encryption")]
+twintalk_core::event_store_conformance!(encrypted_memory, || anyhow::Ok(encrypted(
+    MemoryEventStore::new()
+)));
+
+#[cfg(feature = "encryption")]
+twintalk_core::event_store_conformance!(
+    encrypted_sled,
+    DirFactory::new(|path| SledEventStore::new(path.to_str().unwrap()).map(encrypted))
+);
+
+#[cfg(feature = "encryption")]
+twintalk_core::event_store_conformance!(
+    encrypted_file,
+    DirFactory::new(|path| FileEventStore::new(path).map(encrypted))
+);
@@ -0,0 +1,144 @@
+//! Tests for the sled consistency check and repair
+
+use chrono::Utc;
+use twintalk_core::event::{EventStore, SnapshotStore, TwinEvent, TwinSnapshot};
+use twintalk_core::storage::{Inconsistency, SledEventStore};
+use twintalk_core::TwinId;
+
+mod common;
+
+fn telemetry(twin_id: TwinId, value: f64) -> TwinEvent {
+    TwinEvent::TelemetryReceived {
+        twin_id,
+        data: vec![("value".to_string(), value)],
+        timestamp: Utc::now(),
+    }
+}
+
+fn snapshot(twin_id: TwinId, event_version: u64) -> TwinSnapshot {
+    TwinSnapshot::new(twin_id, "Sensor", event_version)
+}
+
+#[tokio::test]
+async fn test_healthy_store_is_consistent() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("events");
+    let store = SledEventStore::new(path.to_str().unwrap()).unwrap();
+    let twin_id = TwinId::new();
+    for value in 0..5 {
+        store
+            .append(telemetry(twin_id, f64::from(value)))
+            .await
+            .unwrap();
+    }
+    store.save_snapshot(snapshot(twin_id, 5)).await.unwrap();
+
+    let report = store.check().unwrap();
+    assert!(report.is_consistent(), "{:?}", report.issues);
+    assert_eq!((report.events, report.twins, report.snapshots), (5, 1, 1));
+    assert!(report.gaps.is_empty());
+}
+
+#[tokio::test]
+async fn test_damage_is_found_and_repaired() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("events");
+    let path = path.to_str().unwrap();
+    let indexed = TwinId::new();
+    let orphaned = TwinId::new();
+    let ahead = TwinId::new();
+
+    {
+        let store = common::sled_store(path).await;
+        for value in 0..4 {
+            store
+                .append(telemetry(indexed, f64::from(value)))
+                .await
+                .unwrap();
+        }
+        store.append(telemetry(orphaned, 9.0)).await.unwrap();
+        store.append(telemetry(indexed, 4.0)).await.unwrap();
+    }
+
+    // Simulate a crash between writing events and their index entries,
+    // a lost event, and garbage records
+    {
+        let db = common::sled_db(path).await;
+        let events = db.open_tree("events").unwrap();
+        events.remove(2u64.to_be_bytes()).unwrap();
+        events
+            .insert(6u64.to_be_bytes(), b"not json".to_vec())
+            .unwrap();
+        db.open_tree("twin_events")
+            .unwrap()
+            .remove(orphaned.0.as_bytes())
+            .unwrap();
+        let snapshots = db.open_tree("snapshots").unwrap();
+        snapshots
+            .insert(
+                ahead.0.as_bytes(),
+                serde_json::to_vec(&snapshot(ahead, 50)).unwrap(),
+            )
+            .unwrap();
+        snapshots.insert(b"garbage", b"{".to_vec()).unwrap();
+        db.flush().unwrap();
+    }
+
+    let mut store = common::sled_store(path).await;
+    let report = store.check().unwrap();
+    assert_eq!(report.gaps, vec![2..=2]);
+    assert_eq!(report.issues.len(), 5, "{:?}", report.issues);
+    assert!(report.issues.contains(&Inconsistency::MissingEvent {
+        twin_id: indexed,
+        version: 2,
+    }));
+    assert!(report.issues.contains(&Inconsistency::OrphanedEvent {
+        twin_id: orphaned,
+        version: 5,
+    }));
+    assert!(report.issues.contains(&Inconsistency::SnapshotAhead {
+        twin_id: ahead,
+        event_version: 50,
+        latest_version: 6,
+    }));
+    assert!(report
+        .issues
+        .iter()
+        .any(|i| matches!(i, Inconsistency::UndecodableEvent { key, .. } if key == "6")));
+    assert!(report
+        .issues
+        .iter()
+        .any(|i| matches!(i, Inconsistency::UndecodableSnapshot { .. })));
+    assert!(store.get_events(indexed, 0).await.is_err());
+
+    assert_eq!(store.repair().await.unwrap(), report);
+    let repaired = store.check().unwrap();
+    assert!(repaired.is_consistent(), "{:?}", repaired.issues);
+    assert_eq!(repaired.gaps, vec![2..=2, 6..=50]);
+
+    let versions = |events: Vec<(u64, TwinEvent)>| -> Vec<u64> {
+        events.into_iter().map(|(v, _)| v).collect()
+    };
+    assert_eq!(
+        versions(store.get_events(indexed, 0).await.unwrap()),
+        vec![1, 3, 4]
+    );
+    assert_eq!(
+        versions(store.get_events(orphaned, 0).await.unwrap()),
+        vec![5]
+    );
+    assert_eq!(
+        store.append(telemetry(ahead, 1.0)).await.unwrap(),
+        51,
+        "new events must follow the snapshot"
+    );
+    drop(store);
+
+    // Damaged records are kept aside rather than deleted
+    let db = common::sled_db(path).await;
+    let quarantine = db.open_tree("quarantine").unwrap();
+    assert!(quarantine
+        .contains_key([b"events/".as_slice(), &6u64.to_be_bytes()].concat())
+        .unwrap());
+    assert!(quarantine.contains_key(b"snapshots/garbage").unwrap());
+}
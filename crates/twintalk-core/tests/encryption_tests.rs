@@ -0,0 +1,243 @@
+//! Tests for encryption at rest
+#![cfg(feature = "encryption")]
+
+use chrono::Utc;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use twintalk_core::event::{EventStore, SnapshotStore, TwinEvent};
+use twintalk_core::storage::encryption::{KeyUsage, KEY_ID_HEADER};
+use twintalk_core::storage::{
+    reencrypt, EncryptedStore, KeyRing, MemoryEventStore, SledEventStore, TransferReport,
+};
+use twintalk_core::{msg, Runtime, RuntimeConfig, TwinId, Value};
+
+const OLD_KEY: [u8; 32] = [1; 32];
+const NEW_KEY: [u8; 32] = [2; 32];
+
+fn telemetry(twin_id: TwinId, temperature: f64) -> TwinEvent {
+    TwinEvent::TelemetryReceived {
+        twin_id,
+        data: vec![("temperature".to_string(), temperature)],
+        timestamp: Utc::now(),
+    }
+}
+
+/// A twin with telemetry before and after a snapshot
+async fn populate<S: EventStore + SnapshotStore + 'static>(store: Arc<S>) -> TwinId {
+    let runtime = Runtime::with_stores(RuntimeConfig::default(), store.clone(), store);
+    let twin_id = runtime.create_twin("Premises").await.unwrap();
+    runtime
+        .update_telemetry(twin_id, vec![("temperature".to_string(), 19.5)])
+        .await
+        .unwrap();
+    runtime.snapshot_twin(twin_id).await.unwrap();
+    runtime
+        .update_telemetry(twin_id, vec![("temperature".to_string(), 23.25)])
+        .await
+        .unwrap();
+    twin_id
+}
+
+async fn temperature<S: EventStore + SnapshotStore + 'static>(
+    store: Arc<S>,
+    twin_id: TwinId,
+) -> Value {
+    let runtime = Runtime::with_stores(RuntimeConfig::default(), store.clone(), store);
+    runtime.send(twin_id, &msg!(temperature)).await.unwrap()
+}
+
+#[tokio::test]
+async fn test_records_are_sealed_at_rest() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("events");
+    let store = Arc::new(EncryptedStore::new(
+        SledEventStore::new(path.to_str().unwrap()).unwrap(),
+        KeyRing::new("k1", &OLD_KEY),
+    ));
+    let twin_id = populate(store.clone()).await;
+    let runtime = Runtime::with_stores(RuntimeConfig::default(), store.clone(), store.clone());
+    runtime
+        .assign_alias(twin_id, "meters", "M-4711")
+        .await
+        .unwrap();
+    runtime
+        .set_label(twin_id, "site", "harbourside")
+        .await
+        .unwrap();
+    runtime.snapshot_twin(twin_id).await.unwrap();
+    let snapshot_version = store.get_latest_version().await.unwrap();
+    runtime
+        .update_telemetry(twin_id, vec![("temperature".to_string(), 23.25)])
+        .await
+        .unwrap();
+
+    // The inner store only sees redacted events and sealed snapshots
+    let mut raw = String::new();
+    for (_, envelope) in store.inner().get_envelopes(twin_id, 0).await.unwrap() {
+        assert_eq!(envelope.metadata.headers[KEY_ID_HEADER], "k1");
+        if let TwinEvent::TelemetryReceived { data, .. } = &envelope.event {
+            assert!(data.is_empty());
+        }
+        raw.push_str(&serde_json::to_string(&envelope.event).unwrap());
+    }
+    let sealed = store.inner().get_snapshot(twin_id).await.unwrap().unwrap();
+    assert!(!sealed.properties.contains_key("temperature"));
+    raw.push_str(&serde_json::to_string(&sealed).unwrap());
+    for secret in ["meters", "M-4711", "site", "harbourside"] {
+        assert!(!raw.contains(secret), "{secret} stored in the clear");
+    }
+
+    // Reading through the wrapper restores everything
+    let snapshot = store.get_snapshot(twin_id).await.unwrap().unwrap();
+    assert_eq!(snapshot.properties["temperature"], Value::from(23.25));
+    assert_eq!(snapshot.event_version, snapshot_version);
+    assert_eq!(snapshot.labels["site"], "harbourside");
+    assert_eq!(snapshot.aliases.len(), 1);
+    let envelopes = store.get_envelopes(twin_id, 0).await.unwrap();
+    assert!(envelopes
+        .iter()
+        .all(|(_, e)| !e.metadata.headers.contains_key(KEY_ID_HEADER)));
+    assert_eq!(
+        temperature(store.clone(), twin_id).await,
+        Value::from(23.25)
+    );
+    assert_eq!(
+        store.key_usage().await.unwrap(),
+        KeyUsage {
+            by_key: BTreeMap::from([("k1".to_string(), 7)]),
+            plaintext: 0,
+        }
+    );
+}
+
+#[tokio::test]
+async fn test_wrong_key_and_moved_ciphertext_fail() {
+    let inner = MemoryEventStore::new();
+    let store = EncryptedStore::new(inner.clone(), KeyRing::new("k1", &OLD_KEY));
+    let twin_id = TwinId::new();
+    store.append(telemetry(twin_id, 1.0)).await.unwrap();
+
+    let imposter = EncryptedStore::new(inner.clone(), KeyRing::new("k1", &NEW_KEY));
+    assert!(imposter.get_events(twin_id, 0).await.is_err());
+    let stranger = EncryptedStore::new(inner.clone(), KeyRing::new("k2", &NEW_KEY));
+    let error = stranger.get_events(twin_id, 0).await.unwrap_err();
+    assert!(error.to_string().contains("Unknown encryption key 'k1'"));
+
+    // Copy the sealed payload onto an event of another twin
+    let (_, mut sealed) = inner.get_envelopes(twin_id, 0).await.unwrap().remove(0);
+    let other = TwinId::new();
+    if let TwinEvent::TelemetryReceived {
+        twin_id: target, ..
+    } = &mut sealed.event
+    {
+        *target = other;
+    }
+    inner.append_envelope(sealed).await.unwrap();
+    assert!(store.get_events(other, 0).await.is_err());
+}
+
+#[tokio::test]
+async fn test_swapped_ciphertext_fails() {
+    let inner = MemoryEventStore::new();
+    let store = EncryptedStore::new(inner.clone(), KeyRing::new("k1", &OLD_KEY));
+    let twin_id = TwinId::new();
+    let timestamp = Utc::now();
+    for temperature in [1.0, 2.0] {
+        store
+            .append(TwinEvent::TelemetryReceived {
+                twin_id,
+                data: vec![("temperature".to_string(), temperature)],
+                timestamp,
+            })
+            .await
+            .unwrap();
+    }
+
+    // Same twin, kind and timestamp: only the versions tell them apart
+    let mut sealed = inner.get_envelopes(twin_id, 0).await.unwrap();
+    let (_, second) = sealed.pop().unwrap();
+    let (_, first) = sealed.pop().unwrap();
+    let swapped = MemoryEventStore::new();
+    swapped
+        .import_envelopes(vec![(1, second), (2, first)])
+        .await
+        .unwrap();
+    let store = EncryptedStore::new(swapped, KeyRing::new("k1", &OLD_KEY));
+    let error = store.get_events(twin_id, 0).await.unwrap_err();
+    assert!(error.to_string().contains("Cannot decrypt event 1"));
+}
+
+#[tokio::test]
+async fn test_plaintext_records_are_rejected() {
+    let inner = MemoryEventStore::new();
+    let twin_id = TwinId::new();
+    inner.append(telemetry(twin_id, 1.0)).await.unwrap();
+
+    let store = EncryptedStore::new(inner.clone(), KeyRing::new("k1", &OLD_KEY));
+    store.append(telemetry(twin_id, 2.0)).await.unwrap();
+    let error = store.get_events(twin_id, 0).await.unwrap_err();
+    assert!(error.to_string().contains("is not encrypted"));
+
+    // Opting in reads them, e.g. to re-encrypt an old store
+    let store = EncryptedStore::new(inner, KeyRing::new("k1", &OLD_KEY)).allow_plaintext();
+    assert_eq!(store.get_events(twin_id, 0).await.unwrap().len(), 2);
+
+    let usage = store.key_usage().await.unwrap();
+    assert_eq!(usage.plaintext, 1);
+    assert_eq!(usage.by_key["k1"], 1);
+}
+
+#[tokio::test]
+async fn test_rotation_with_retired_key_and_reencrypt() {
+    let old = Arc::new(EncryptedStore::new(
+        MemoryEventStore::new(),
+        KeyRing::new("k1", &OLD_KEY),
+    ));
+    let twin_id = populate(old.clone()).await;
+
+    // New writes use the new key; old records stay readable
+    let rotated = Arc::new(EncryptedStore::new(
+        old.inner().clone(),
+        KeyRing::new("k2", &NEW_KEY).with_retired_key("k1", &OLD_KEY),
+    ));
+    rotated.append(telemetry(twin_id, 30.0)).await.unwrap();
+    let usage = rotated.key_usage().await.unwrap();
+    assert_eq!(usage.by_key["k1"], 4);
+    assert_eq!(usage.by_key["k2"], 1);
+    assert_eq!(
+        temperature(rotated.clone(), twin_id).await,
+        Value::from(30.0)
+    );
+
+    // Re-encrypting into fresh storage retires the old key
+    let fresh = Arc::new(EncryptedStore::new(
+        MemoryEventStore::new(),
+        KeyRing::new("k2", &NEW_KEY),
+    ));
+    let report = reencrypt(rotated.as_ref(), fresh.as_ref()).await.unwrap();
+    assert_eq!(
+        report,
+        TransferReport {
+            events: 4,
+            snapshots: 1,
+            skipped: 0,
+        }
+    );
+    assert_eq!(
+        fresh.key_usage().await.unwrap().by_key,
+        BTreeMap::from([("k2".to_string(), 5)])
+    );
+    assert_eq!(
+        fresh.get_latest_version().await.unwrap(),
+        rotated.get_latest_version().await.unwrap()
+    );
+    assert_eq!(temperature(fresh.clone(), twin_id).await, Value::from(30.0));
+
+    let original = rotated.get_envelopes(twin_id, 0).await.unwrap();
+    let copied = fresh.get_envelopes(twin_id, 0).await.unwrap();
+    for ((v1, a), (v2, b)) in original.iter().zip(&copied) {
+        assert_eq!(v1, v2);
+        assert_eq!(a.metadata, b.metadata);
+        assert_eq!(a.event.timestamp(), b.event.timestamp());
+    }
+}
@@ -2,11 +2,24 @@
 
 use chrono::{Duration, Utc};
 use std::collections::BTreeMap;
+use std::sync::Arc;
 use twintalk_core::event::{EventStore, SnapshotStore, TwinEvent, TwinSnapshot};
-use twintalk_core::storage::memory_store::MemoryEventStore;
+use twintalk_core::storage::{
+    Durability, FileEventStore, FileStoreConfig, GroupCommitter, MemoryEventStore, SledEventStore,
+};
 use twintalk_core::twin::TwinId;
 use twintalk_core::Value;
 
+mod common;
+
+fn telemetry(twin_id: TwinId, value: f64) -> TwinEvent {
+    TwinEvent::TelemetryReceived {
+        twin_id,
+        data: vec![("value".to_string(), value)],
+        timestamp: Utc::now(),
+    }
+}
+
 #[tokio::test]
 async fn test_memory_event_store() {
     let store = MemoryEventStore::new();
@@ -111,14 +124,7 @@ async fn test_snapshot_store() {
     properties.insert("temperature".to_string(), Value::from(25.0));
     properties.insert("humidity".to_string(), Value::from(60.0));
 
-    let snapshot = TwinSnapshot {
-        twin_id,
-        class_name: "Sensor".to_string(),
-        properties,
-        parent_id: None,
-        event_version: 10,
-        timestamp: Utc::now(),
-    };
+    let snapshot = TwinSnapshot::new(twin_id, "Sensor", 10).with_properties(properties);
 
     // Save snapshot
     store.save_snapshot(snapshot.clone()).await.unwrap();
@@ -141,12 +147,8 @@ async fn test_snapshot_cleanup() {
     // Create old and new snapshots
     for i in 0..5 {
         let snapshot = TwinSnapshot {
-            twin_id: TwinId::new(),
-            class_name: "Sensor".to_string(),
-            properties: BTreeMap::new(),
-            parent_id: None,
-            event_version: i,
             timestamp: now - Duration::days(10_i64.saturating_sub(i64::try_from(i).unwrap_or(0))), // Older snapshots have older timestamps
+            ..TwinSnapshot::new(TwinId::new(), "Sensor", i)
         };
         store.save_snapshot(snapshot).await.unwrap();
     }
@@ -158,3 +160,301 @@ async fn test_snapshot_cleanup() {
     // All snapshots are older than 5 days, so all should be deleted
     assert_eq!(deleted, 5);
 }
+
+#[tokio::test]
+async fn test_sled_append_batch_survives_reopen() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("events");
+    let path = path.to_str().unwrap();
+    let twin_id = TwinId::new();
+
+    {
+        let store = SledEventStore::new(path).unwrap();
+        assert_eq!(store.durability(), Durability::EveryEvent);
+        let versions = store
+            .append_batch((0..5).map(|i| telemetry(twin_id, f64::from(i))).collect())
+            .await
+            .unwrap();
+        assert_eq!(versions, vec![1, 2, 3, 4, 5]);
+        assert!(store.append_batch(Vec::new()).await.unwrap().is_empty());
+    }
+
+    let store = common::sled_store(path).await;
+    assert_eq!(store.get_latest_version().await.unwrap(), 5);
+    let events = store.get_events(twin_id, 2).await.unwrap();
+    assert_eq!(
+        events.iter().map(|(v, _)| *v).collect::<Vec<_>>(),
+        vec![3, 4, 5]
+    );
+}
+
+#[tokio::test]
+async fn test_sled_relaxed_durability_flush() {
+    let dir = tempfile::tempdir().unwrap();
+    let twin_id = TwinId::new();
+
+    for (name, durability) in [
+        (
+            "interval",
+            Durability::Interval(std::time::Duration::from_millis(50)),
+        ),
+        ("os", Durability::OsManaged),
+    ] {
+        let path = dir.path().join(name);
+        let path = path.to_str().unwrap();
+        {
+            let store = SledEventStore::with_durability(path, durability).unwrap();
+            store.append(telemetry(twin_id, 1.0)).await.unwrap();
+            store
+                .append_batch(vec![telemetry(twin_id, 2.0), telemetry(twin_id, 3.0)])
+                .await
+                .unwrap();
+            store.flush().await.unwrap();
+        }
+
+        let store = common::retry_open(|| SledEventStore::with_durability(path, durability)).await;
+        assert_eq!(store.get_events(twin_id, 0).await.unwrap().len(), 3);
+    }
+}
+
+#[cfg(feature = "sqlite")]
+#[tokio::test]
+async fn test_sqlite_reopen_and_plain_sql() {
+    use twintalk_core::storage::SqliteEventStore;
+
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("events.db");
+    let twin_id = TwinId::new();
+
+    {
+        let store = SqliteEventStore::new(&path).unwrap();
+        store
+            .append_batch(vec![telemetry(twin_id, 1.0), telemetry(twin_id, 2.0)])
+            .await
+            .unwrap();
+    }
+
+    let store = SqliteEventStore::new(&path).unwrap();
+    assert_eq!(store.get_latest_version().await.unwrap(), 2);
+    assert_eq!(store.append(telemetry(twin_id, 3.0)).await.unwrap(), 3);
+
+    // The log is queryable without going through the store
+    let conn = rusqlite::Connection::open(&path).unwrap();
+    let count: i64 = conn
+        .query_row(
+            "SELECT COUNT(*) FROM events WHERE twin_id = ?1 AND kind = 'TelemetryReceived'",
+            [twin_id.to_string()],
+            |row| row.get(0),
+        )
+        .unwrap();
+    assert_eq!(count, 3);
+}
+
+#[cfg(feature = "sqlite")]
+#[tokio::test]
+async fn test_sqlite_durability_modes() {
+    use twintalk_core::storage::SqliteEventStore;
+
+    let dir = tempfile::tempdir().unwrap();
+    let twin_id = TwinId::new();
+
+    for (name, durability) in [
+        ("every", Durability::EveryEvent),
+        (
+            "interval",
+            Durability::Interval(std::time::Duration::from_millis(50)),
+        ),
+        ("os", Durability::OsManaged),
+    ] {
+        let path = dir.path().join(format!("{name}.db"));
+        {
+            let store = SqliteEventStore::with_durability(&path, durability).unwrap();
+            assert_eq!(store.durability(), durability);
+            store.append(telemetry(twin_id, 1.0)).await.unwrap();
+            store
+                .append_batch(vec![telemetry(twin_id, 2.0), telemetry(twin_id, 3.0)])
+                .await
+                .unwrap();
+            store.flush().await.unwrap();
+        }
+
+        let store = SqliteEventStore::with_durability(&path, durability).unwrap();
+        assert_eq!(store.get_events(twin_id, 0).await.unwrap().len(), 3);
+    }
+}
+
+/// Small segments and a dense index so a handful of events spans several
+/// segments
+fn small_segments() -> FileStoreConfig {
+    FileStoreConfig {
+        max_segment_bytes: 512,
+        index_interval_bytes: 256,
+        ..FileStoreConfig::default()
+    }
+}
+
+#[tokio::test]
+async fn test_file_store_rotates_and_reads_across_segments() {
+    let dir = tempfile::tempdir().unwrap();
+    let a = TwinId::new();
+    let b = TwinId::new();
+
+    {
+        let store = FileEventStore::with_config(dir.path(), small_segments()).unwrap();
+        for i in 0..30 {
+            let twin_id = if i % 3 == 0 { b } else { a };
+            store
+                .append(telemetry(twin_id, f64::from(i)))
+                .await
+                .unwrap();
+        }
+        assert!(store.segment_count() > 3);
+    }
+
+    let store = FileEventStore::with_config(dir.path(), small_segments()).unwrap();
+    assert_eq!(store.get_latest_version().await.unwrap(), 30);
+
+    let events = store.get_events(a, 10).await.unwrap();
+    let versions: Vec<u64> = events.iter().map(|(v, _)| *v).collect();
+    let expected: Vec<u64> = (11..=30).filter(|v| (v - 1) % 3 != 0).collect();
+    assert_eq!(versions, expected);
+    assert!(events.iter().all(|(_, e)| e.twin_id() == a));
+
+    assert_eq!(store.get_events(b, 0).await.unwrap().len(), 10);
+
+    let page = store.get_envelopes_after(7, 12).await.unwrap();
+    let versions: Vec<u64> = page.iter().map(|(v, _)| *v).collect();
+    assert_eq!(versions, (8..=19).collect::<Vec<_>>());
+}
+
+#[tokio::test]
+async fn test_file_store_recovers_from_torn_record() {
+    use std::io::Write;
+
+    let dir = tempfile::tempdir().unwrap();
+    let twin_id = TwinId::new();
+
+    {
+        let store = FileEventStore::new(dir.path()).unwrap();
+        for i in 0..3 {
+            store
+                .append(telemetry(twin_id, f64::from(i)))
+                .await
+                .unwrap();
+        }
+    }
+
+    // Simulate a crash halfway through writing a fourth record
+    let segment = std::fs::read_dir(dir.path().join("segments"))
+        .unwrap()
+        .map(|e| e.unwrap().path())
+        .find(|p| p.extension().is_some_and(|e| e == "log"))
+        .unwrap();
+    let intact_len = std::fs::metadata(&segment).unwrap().len();
+    let mut file = std::fs::OpenOptions::new()
+        .append(true)
+        .open(&segment)
+        .unwrap();
+    file.write_all(&[200, 0, 0, 0, 1, 2, 3, 4, b'{', b'"'])
+        .unwrap();
+    drop(file);
+
+    let store = FileEventStore::new(dir.path()).unwrap();
+    assert_eq!(std::fs::metadata(&segment).unwrap().len(), intact_len);
+    assert_eq!(store.get_latest_version().await.unwrap(), 3);
+
+    // Appending continues right after the last intact record
+    assert_eq!(store.append(telemetry(twin_id, 3.0)).await.unwrap(), 4);
+    assert_eq!(store.get_events(twin_id, 0).await.unwrap().len(), 4);
+}
+
+#[tokio::test]
+async fn test_file_store_rejects_corruption_mid_segment() {
+    use std::io::Write;
+
+    let dir = tempfile::tempdir().unwrap();
+    let twin_id = TwinId::new();
+    {
+        let store = FileEventStore::new(dir.path()).unwrap();
+        for i in 0..3 {
+            store
+                .append(telemetry(twin_id, f64::from(i)))
+                .await
+                .unwrap();
+        }
+    }
+    let segment = std::fs::read_dir(dir.path().join("segments"))
+        .unwrap()
+        .map(|e| e.unwrap().path())
+        .find(|p| p.extension().is_some_and(|e| e == "log"))
+        .unwrap();
+
+    // A length running past the end is a torn tail, however large
+    let intact = std::fs::read(&segment).unwrap();
+    let mut file = std::fs::OpenOptions::new()
+        .append(true)
+        .open(&segment)
+        .unwrap();
+    file.write_all(&[0xff, 0xff, 0xff, 0xff, 1, 2, 3, 4])
+        .unwrap();
+    drop(file);
+    drop(FileEventStore::new(dir.path()).unwrap());
+    assert_eq!(std::fs::read(&segment).unwrap(), intact);
+
+    // A damaged record followed by intact ones is not truncated away
+    let mut damaged = intact.clone();
+    damaged[20] ^= 0xff;
+    std::fs::write(&segment, &damaged).unwrap();
+    assert!(FileEventStore::new(dir.path()).is_err());
+    assert_eq!(std::fs::read(&segment).unwrap(), damaged);
+}
+
+#[tokio::test]
+async fn test_file_store_segment_retention() {
+    let dir = tempfile::tempdir().unwrap();
+    let twin_id = TwinId::new();
+    let store = FileEventStore::with_config(dir.path(), small_segments()).unwrap();
+
+    for i in 0..30 {
+        store
+            .append(telemetry(twin_id, f64::from(i)))
+            .await
+            .unwrap();
+    }
+    let segments = store.segment_count();
+
+    let removed = store.remove_segments_before(15).unwrap();
+    assert!(removed > 0);
+    assert_eq!(store.segment_count(), segments - removed);
+
+    // Only whole segments below the cutoff are gone
+    let remaining = store.get_events(twin_id, 0).await.unwrap();
+    assert!(remaining.first().unwrap().0 <= 15);
+    assert_eq!(remaining.last().unwrap().0, 30);
+    assert_eq!(store.get_latest_version().await.unwrap(), 30);
+
+    // The active segment is never removed
+    store
+        .remove_segments_older_than(Utc::now() + Duration::days(1))
+        .unwrap();
+    assert_eq!(store.segment_count(), 1);
+    assert_eq!(store.append(telemetry(twin_id, 30.0)).await.unwrap(), 31);
+}
+
+#[tokio::test]
+async fn test_group_commit_outlives_cancelled_callers() {
+    let store = MemoryEventStore::new();
+    let committer = GroupCommitter::new(Arc::new(store.clone()), 8);
+    let twin_id = TwinId::new();
+
+    // Dropped after queueing its event, before learning the version
+    tokio::select! {
+        biased;
+        _ = committer.append(telemetry(twin_id, 1.0)) => panic!("Committed without yielding"),
+        () = std::future::ready(()) => {}
+    }
+
+    let version = committer.append(telemetry(twin_id, 2.0)).await.unwrap();
+    assert_eq!(version, 2);
+    assert_eq!(store.get_events(twin_id, 0).await.unwrap().len(), 2);
+}
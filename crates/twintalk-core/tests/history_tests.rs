@@ -0,0 +1,181 @@
+//! Tests for telemetry history and windowed aggregations
+
+use chrono::{Duration as ChronoDuration, Utc};
+use std::time::Duration;
+use twintalk_core::{HistoryConfig, Message, Runtime, RuntimeConfig, Twin, TwinId, Value};
+
+fn temperature(value: f64) -> Vec<(String, f64)> {
+    vec![("temperature".to_string(), value)]
+}
+
+async fn ask(runtime: &Runtime, twin_id: TwinId, selector: &str, args: Vec<Value>) -> Value {
+    runtime
+        .send(twin_id, &Message::send(selector, args))
+        .await
+        .unwrap()
+}
+
+#[tokio::test]
+async fn test_aggregations_via_send() {
+    let runtime = Runtime::new(RuntimeConfig::default());
+    let twin_id = runtime.create_twin("Sensor").await.unwrap();
+    for value in [20.0, 26.0, 23.0] {
+        runtime
+            .update_telemetry(twin_id, temperature(value))
+            .await
+            .unwrap();
+    }
+
+    let property = || Value::Symbol("temperature".to_string());
+    assert_eq!(
+        ask(&runtime, twin_id, "avg:", vec![property()]).await,
+        Value::from(23.0)
+    );
+    assert_eq!(
+        ask(
+            &runtime,
+            twin_id,
+            "min:over:",
+            vec![property(), Value::from(300)]
+        )
+        .await,
+        Value::from(20.0)
+    );
+    assert_eq!(
+        ask(&runtime, twin_id, "max:", vec![property()]).await,
+        Value::from(26.0)
+    );
+    assert_eq!(
+        ask(
+            &runtime,
+            twin_id,
+            "last:count:",
+            vec![property(), Value::from(2)]
+        )
+        .await,
+        Value::Array(vec![Value::from(26.0), Value::from(23.0)])
+    );
+    assert_eq!(
+        ask(&runtime, twin_id, "avg:", vec![Value::from("pressure")]).await,
+        Value::Nil
+    );
+    assert_eq!(
+        runtime
+            .send(twin_id, &Message::RespondsTo("avg:over:".to_string()))
+            .await
+            .unwrap(),
+        Value::Boolean(true)
+    );
+    assert!(runtime
+        .send(twin_id, &Message::send("avg:", vec![Value::from(1)]))
+        .await
+        .is_err());
+}
+
+#[test]
+fn test_windows_and_rate() {
+    let mut twin = Twin::new("Pump").with_history(HistoryConfig {
+        max_samples: 100,
+        window: Some(Duration::from_secs(3600)),
+    });
+    let now = Utc::now();
+    // Two hours ago falls outside the kept window once newer samples arrive
+    twin.apply_telemetry(
+        &[("rpm".to_string(), 500.0)],
+        now - ChronoDuration::hours(2),
+    )
+    .unwrap();
+    twin.apply_telemetry(
+        &[("rpm".to_string(), 1000.0)],
+        now - ChronoDuration::minutes(30),
+    )
+    .unwrap();
+    twin.apply_telemetry(
+        &[("rpm".to_string(), 1300.0)],
+        now - ChronoDuration::minutes(20),
+    )
+    .unwrap();
+    twin.apply_telemetry(
+        &[("rpm".to_string(), 1500.0)],
+        now - ChronoDuration::minutes(1),
+    )
+    .unwrap();
+    assert_eq!(twin.history("rpm").unwrap().len(), 3);
+
+    let rpm = || Value::from("rpm");
+    let max_recent = twin
+        .send(&Message::send("max:over:", vec![rpm(), Value::from(300)]))
+        .unwrap();
+    assert_eq!(max_recent, Value::from(1500.0));
+    let avg_recent = twin
+        .send(&Message::send(
+            "avg:over:",
+            vec![rpm(), Value::from(25 * 60)],
+        ))
+        .unwrap();
+    assert_eq!(avg_recent, Value::from(1400.0));
+
+    // 500 rpm over 29 minutes
+    let rate = twin
+        .send(&Message::send("rate:", vec![rpm()]))
+        .unwrap()
+        .as_f64()
+        .unwrap();
+    assert!((rate - 500.0 / (29.0 * 60.0)).abs() < 1e-9);
+    assert_eq!(
+        twin.send(&Message::send("rate:over:", vec![rpm(), Value::from(120)]))
+            .unwrap(),
+        Value::Nil
+    );
+
+    let disabled = Twin::new("Pump").with_history(HistoryConfig::disabled());
+    assert!(disabled.history("rpm").is_none());
+}
+
+#[tokio::test]
+async fn test_history_rebuilds_on_lazy_load() {
+    let runtime = Runtime::new(RuntimeConfig {
+        eviction_timeout: Duration::from_millis(10),
+        history: HistoryConfig {
+            max_samples: 4,
+            window: Some(Duration::from_secs(600)),
+        },
+        ..RuntimeConfig::default()
+    });
+    let twin_id = runtime.create_twin("Sensor").await.unwrap();
+    for value in [10.0, 11.0, 12.0] {
+        runtime
+            .update_telemetry(twin_id, temperature(value))
+            .await
+            .unwrap();
+    }
+
+    // Evicting snapshots the state, which carries no history
+    tokio::time::sleep(Duration::from_millis(30)).await;
+    assert_eq!(runtime.evict_inactive().await.unwrap(), 1);
+
+    // Telemetry for inactive twins only reaches the log
+    for value in [13.0, 14.0] {
+        runtime
+            .update_telemetry(twin_id, temperature(value))
+            .await
+            .unwrap();
+    }
+
+    let last = ask(
+        &runtime,
+        twin_id,
+        "last:count:",
+        vec![Value::from("temperature"), Value::from(10)],
+    )
+    .await;
+    assert_eq!(
+        last,
+        Value::Array(
+            [11.0, 12.0, 13.0, 14.0]
+                .into_iter()
+                .map(Value::from)
+                .collect()
+        )
+    );
+}
@@ -0,0 +1,147 @@
+//! Tests for labels and secondary indexes
+
+use std::sync::Arc;
+use std::time::Duration;
+use twintalk_core::index::QueryPage;
+use twintalk_core::storage::MemoryEventStore;
+use twintalk_core::{msg, Condition, Runtime, RuntimeConfig, TwinId, TwinQuery};
+
+async fn sensor(runtime: &Runtime, class: &str, site: &str, firmware: f64) -> TwinId {
+    let twin = runtime.create_twin(class).await.unwrap();
+    runtime.set_label(twin, "site", site).await.unwrap();
+    runtime
+        .update_telemetry(twin, vec![("firmware".to_string(), firmware)])
+        .await
+        .unwrap();
+    twin
+}
+
+fn outdated_in_berlin() -> TwinQuery {
+    TwinQuery::new()
+        .class("TemperatureSensor")
+        .label("site", "berlin")
+        .property("firmware", Condition::lt(2.3))
+}
+
+#[tokio::test]
+async fn test_fleet_query_with_pagination() {
+    let store = MemoryEventStore::new();
+    let runtime = Runtime::with_stores(
+        RuntimeConfig {
+            eviction_timeout: Duration::from_millis(10),
+            ..RuntimeConfig::default()
+        },
+        Arc::new(store.clone()),
+        Arc::new(store.clone()),
+    );
+    runtime.index_property("firmware").await;
+    let mut expected = Vec::new();
+    for firmware in [2.0, 2.1, 2.2, 2.25] {
+        expected.push(sensor(&runtime, "TemperatureSensor", "berlin", firmware).await);
+    }
+    sensor(&runtime, "TemperatureSensor", "berlin", 2.3).await;
+    sensor(&runtime, "TemperatureSensor", "munich", 2.0).await;
+    sensor(&runtime, "Fan", "berlin", 1.0).await;
+    expected.sort();
+
+    // Evicted twins are found without being loaded
+    tokio::time::sleep(Duration::from_millis(30)).await;
+    assert_eq!(runtime.evict_inactive().await.unwrap(), 7);
+    let mut found = Vec::new();
+    let mut query = outdated_in_berlin().limit(3);
+    loop {
+        let QueryPage { twins, next } = runtime.query(&query).await.unwrap();
+        found.extend(twins);
+        match next {
+            Some(cursor) => query = query.after(cursor),
+            None => break,
+        }
+    }
+    assert_eq!(found, expected);
+    assert_eq!(runtime.stats().await.active_twins, 0);
+
+    // Kept current as twins change
+    runtime
+        .update_telemetry(expected[0], vec![("firmware".to_string(), 2.4)])
+        .await
+        .unwrap();
+    runtime.remove_label(expected[1], "site").await.unwrap();
+    assert!(!runtime.remove_label(expected[1], "site").await.unwrap());
+    runtime
+        .set_label(expected[2], "site", "munich")
+        .await
+        .unwrap();
+    let page = runtime.query(&outdated_in_berlin()).await.unwrap();
+    assert_eq!(page.twins, [expected[3]]);
+    assert_eq!(page.next, None);
+
+    assert_eq!(
+        runtime
+            .query(&TwinQuery::new().class("Fan"))
+            .await
+            .unwrap()
+            .twins
+            .len(),
+        1
+    );
+    assert_eq!(
+        runtime
+            .query(&TwinQuery::new().label("site", "munich"))
+            .await
+            .unwrap()
+            .twins
+            .len(),
+        2
+    );
+    assert!(runtime
+        .query(&TwinQuery::new().property("serial", Condition::eq("x")))
+        .await
+        .is_err());
+}
+
+#[tokio::test]
+async fn test_labels_persist() {
+    let store = MemoryEventStore::new();
+    let config = RuntimeConfig {
+        eviction_timeout: Duration::from_millis(10),
+        ..RuntimeConfig::default()
+    };
+    let runtime = Runtime::with_stores(
+        config.clone(),
+        Arc::new(store.clone()),
+        Arc::new(store.clone()),
+    );
+    let prototype = sensor(&runtime, "TemperatureSensor", "berlin", 2.0).await;
+    runtime.set_label(prototype, "floor", "3").await.unwrap();
+    runtime.send(prototype, &msg!(model: "T-1")).await.unwrap();
+    let clone = runtime.clone_twin(prototype).await.unwrap();
+    runtime.remove_label(clone, "floor").await.unwrap();
+
+    tokio::time::sleep(Duration::from_millis(30)).await;
+    assert_eq!(runtime.evict_inactive().await.unwrap(), 2);
+    let compacted = Runtime::with_stores(
+        config,
+        Arc::new(MemoryEventStore::new()),
+        Arc::new(store.clone()),
+    );
+    compacted.index_property("model").await;
+
+    let twin = compacted.get_twin(clone).await.unwrap();
+    let labels = twin.twin.read().await.labels().clone();
+    assert_eq!(labels.len(), 1);
+    assert_eq!(labels["site"], "berlin");
+    let page = compacted
+        .query(
+            &TwinQuery::new()
+                .label("site", "berlin")
+                .property("model", Condition::eq("T-1")),
+        )
+        .await
+        .unwrap();
+    assert_eq!(page.twins.len(), 2);
+    let page = compacted
+        .query(&TwinQuery::new().label("floor", "3"))
+        .await
+        .unwrap();
+    assert_eq!(page.twins, [prototype]);
+}
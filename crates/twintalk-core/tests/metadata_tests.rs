@@ -0,0 +1,144 @@
+//! Tests for property metadata
+
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::Duration;
+use twintalk_core::event::SnapshotStore;
+use twintalk_core::storage::MemoryEventStore;
+use twintalk_core::{msg, Message, Quality, Runtime, RuntimeConfig, Twin, TwinId, Value};
+
+async fn describe(runtime: &Runtime, twin_id: TwinId) {
+    for message in [
+        Message::send("unitOf:put:", vec!["temperature".into(), "°C".into()]),
+        Message::send(
+            "qualityOf:put:",
+            vec!["temperature".into(), Value::Symbol("uncertain".to_string())],
+        ),
+        Message::send(
+            "sourceTimestampOf:put:",
+            vec!["temperature".into(), "2024-05-01T12:00:00Z".into()],
+        ),
+    ] {
+        runtime.send(twin_id, &message).await.unwrap();
+    }
+}
+
+async fn assert_described(runtime: &Runtime, twin_id: TwinId) {
+    let metadata = runtime
+        .send(
+            twin_id,
+            &Message::send("metadataOf:", vec!["temperature".into()]),
+        )
+        .await
+        .unwrap();
+    let Value::Map(metadata) = metadata else {
+        panic!("Expected map, got {metadata}");
+    };
+    assert_eq!(metadata.get("unit"), Some(&Value::from("°C")));
+    assert_eq!(
+        metadata.get("quality"),
+        Some(&Value::Symbol("uncertain".to_string()))
+    );
+    assert_eq!(
+        metadata.get("sourceTimestamp"),
+        Some(&Value::from("2024-05-01T12:00:00+00:00"))
+    );
+    assert!(metadata
+        .get("lastChanged")
+        .and_then(Value::as_str)
+        .is_some());
+}
+
+#[test]
+fn test_last_changed_only_on_change() {
+    let mut twin = Twin::new("Sensor");
+    assert!(twin.metadata("temperature").is_none());
+
+    twin.send(&msg!(temperature: 20.0)).unwrap();
+    let changed = twin.metadata("temperature").unwrap().last_changed;
+    assert!(changed.is_some());
+
+    std::thread::sleep(Duration::from_millis(5));
+    twin.send(&msg!(temperature: 20.0)).unwrap();
+    assert_eq!(twin.metadata("temperature").unwrap().last_changed, changed);
+    twin.update_telemetry([("temperature".to_string(), 21.0)].into())
+        .unwrap();
+    assert!(twin.metadata("temperature").unwrap().last_changed > changed);
+
+    // Unset fields read as their defaults
+    assert_eq!(
+        twin.send(&Message::send("unitOf:", vec!["temperature".into()]))
+            .unwrap(),
+        Value::Nil
+    );
+    assert_eq!(
+        twin.send(&Message::send("qualityOf:", vec!["humidity".into()]))
+            .unwrap(),
+        Value::Symbol("good".to_string())
+    );
+    assert!(twin
+        .send(&Message::send(
+            "qualityOf:put:",
+            vec!["temperature".into(), "excellent".into()]
+        ))
+        .is_err());
+    assert_eq!(twin.metadata("temperature").unwrap().quality, Quality::Good);
+}
+
+#[tokio::test]
+async fn test_metadata_persisted_in_snapshots() {
+    let store = MemoryEventStore::new();
+    let runtime = Runtime::with_stores(
+        RuntimeConfig {
+            eviction_timeout: Duration::from_millis(10),
+            ..RuntimeConfig::default()
+        },
+        Arc::new(store.clone()),
+        Arc::new(store.clone()),
+    );
+    let sensor = runtime.create_twin("Sensor").await.unwrap();
+    runtime
+        .update_telemetry(sensor, vec![("temperature".to_string(), 21.5)])
+        .await
+        .unwrap();
+    describe(&runtime, sensor).await;
+    assert_described(&runtime, sensor).await;
+
+    tokio::time::sleep(Duration::from_millis(30)).await;
+    assert_eq!(runtime.evict_inactive().await.unwrap(), 1);
+    let snapshot = store.get_snapshot(sensor).await.unwrap().unwrap();
+    let metadata = &snapshot.metadata["temperature"];
+    assert_eq!(metadata.unit.as_deref(), Some("°C"));
+    assert_eq!(metadata.quality, Quality::Uncertain);
+
+    assert_described(&runtime, sensor).await;
+}
+
+#[tokio::test]
+async fn test_metadata_replayed_from_log() {
+    let store = MemoryEventStore::new();
+    let runtime = Runtime::with_stores(
+        RuntimeConfig::default(),
+        Arc::new(store.clone()),
+        Arc::new(store.clone()),
+    );
+    let sensor = runtime.create_twin("Sensor").await.unwrap();
+    runtime
+        .update_telemetry(sensor, vec![("temperature".to_string(), 21.5)])
+        .await
+        .unwrap();
+    describe(&runtime, sensor).await;
+    let before = runtime.get_twin(sensor).await.unwrap();
+    let before = before.twin.read().await.state().metadata.clone();
+
+    // A fresh runtime over the same log, without snapshots
+    let restarted = Runtime::with_stores(
+        RuntimeConfig::default(),
+        Arc::new(store.clone()),
+        Arc::new(MemoryEventStore::new()),
+    );
+    let after = restarted.get_twin(sensor).await.unwrap();
+    let after: BTreeMap<_, _> = after.twin.read().await.state().metadata.clone();
+    assert_eq!(after, before);
+    assert_described(&restarted, sensor).await;
+}
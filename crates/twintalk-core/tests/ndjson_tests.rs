@@ -0,0 +1,195 @@
+//! Tests for NDJSON export and import
+
+use std::sync::Arc;
+use twintalk_core::event::{EventContext, EventEnvelope, EventStore, SnapshotStore, TwinEvent};
+use twintalk_core::storage::{
+    export_ndjson, import_ndjson, FileEventStore, ImportOptions, MemoryEventStore, SledEventStore,
+    TransferReport,
+};
+use twintalk_core::{msg, Runtime, RuntimeConfig, TwinId, Value};
+
+/// A store with two sensors and a meter, one sensor snapshotted
+async fn populated() -> (Arc<MemoryEventStore>, [TwinId; 3]) {
+    let store = Arc::new(MemoryEventStore::new());
+    let runtime = Runtime::with_stores(RuntimeConfig::default(), store.clone(), store.clone());
+
+    let sensor = runtime.create_twin("Sensor").await.unwrap();
+    let other = runtime.create_twin("Sensor").await.unwrap();
+    let meter = runtime.create_twin("Meter").await.unwrap();
+
+    let context = EventContext::new().with_principal("import-test");
+    context
+        .scope(async {
+            runtime
+                .update_telemetry(sensor, vec![("temperature".to_string(), 21.5)])
+                .await
+                .unwrap();
+            runtime
+                .update_telemetry(meter, vec![("power".to_string(), 3.0)])
+                .await
+                .unwrap();
+        })
+        .await;
+    runtime.snapshot_twin(sensor).await.unwrap();
+    runtime
+        .update_telemetry(sensor, vec![("temperature".to_string(), 22.0)])
+        .await
+        .unwrap();
+    runtime
+        .update_telemetry(other, vec![("temperature".to_string(), 18.0)])
+        .await
+        .unwrap();
+
+    (store, [sensor, other, meter])
+}
+
+async fn export(store: &MemoryEventStore) -> Vec<u8> {
+    let mut out = Vec::new();
+    export_ndjson(store, &mut out).await.unwrap();
+    out
+}
+
+async fn all_envelopes<S: EventStore>(store: &S) -> Vec<(u64, EventEnvelope)> {
+    let start = chrono::DateTime::UNIX_EPOCH;
+    let end = chrono::Utc::now() + chrono::Duration::days(1);
+    store.get_envelopes_in_range(start, end).await.unwrap()
+}
+
+async fn check_round_trip<S: EventStore + SnapshotStore + 'static>(target: Arc<S>) {
+    let (source, [sensor, ..]) = populated().await;
+    let data = export(&source).await;
+
+    let report = import_ndjson(target.as_ref(), data.as_slice(), &ImportOptions::new())
+        .await
+        .unwrap();
+    let expected = all_envelopes(source.as_ref()).await;
+    assert_eq!(
+        report,
+        TransferReport {
+            events: expected.len() as u64,
+            snapshots: 1,
+            skipped: 0,
+        }
+    );
+
+    let imported = all_envelopes(target.as_ref()).await;
+    assert_eq!(imported.len(), expected.len());
+    for ((version, got), (expected_version, want)) in imported.iter().zip(&expected) {
+        assert_eq!(version, expected_version);
+        assert_eq!(got.metadata, want.metadata);
+        assert_eq!(got.event.timestamp(), want.event.timestamp());
+        assert_eq!(got.event.kind(), want.event.kind());
+    }
+    assert_eq!(
+        target.get_latest_version().await.unwrap(),
+        source.get_latest_version().await.unwrap()
+    );
+    let snapshot = target.get_snapshot(sensor).await.unwrap().unwrap();
+    assert_eq!(snapshot.properties["temperature"], Value::from(21.5));
+
+    // The imported log replays to the same state
+    let runtime = Runtime::with_stores(RuntimeConfig::default(), target.clone(), target);
+    assert_eq!(
+        runtime.send(sensor, &msg!(temperature)).await.unwrap(),
+        Value::from(22.0)
+    );
+}
+
+#[tokio::test]
+async fn test_round_trip_into_memory() {
+    check_round_trip(Arc::new(MemoryEventStore::new())).await;
+}
+
+#[tokio::test]
+async fn test_round_trip_into_sled() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("events");
+    check_round_trip(Arc::new(
+        SledEventStore::new(path.to_str().unwrap()).unwrap(),
+    ))
+    .await;
+}
+
+#[tokio::test]
+async fn test_round_trip_into_file_store() {
+    let dir = tempfile::tempdir().unwrap();
+    check_round_trip(Arc::new(FileEventStore::new(dir.path()).unwrap())).await;
+}
+
+#[tokio::test]
+async fn test_filters() {
+    let (source, [sensor, other, meter]) = populated().await;
+    let data = export(&source).await;
+
+    let target = MemoryEventStore::new();
+    let report = import_ndjson(
+        &target,
+        data.as_slice(),
+        &ImportOptions::new().classes(["Meter"]),
+    )
+    .await
+    .unwrap();
+    assert_eq!(report.events, 2);
+    assert_eq!(report.snapshots, 0);
+    assert!(target.get_events(sensor, 0).await.unwrap().is_empty());
+
+    // Filtered events keep their versions, leaving gaps
+    let versions = |events: Vec<(u64, TwinEvent)>| -> Vec<u64> {
+        events.into_iter().map(|(version, _)| version).collect()
+    };
+    let meter_versions = versions(source.get_events(meter, 0).await.unwrap());
+    assert_eq!(meter_versions.len(), 2);
+    assert_eq!(
+        versions(target.get_events(meter, 0).await.unwrap()),
+        meter_versions
+    );
+    assert_eq!(
+        target.get_latest_version().await.unwrap(),
+        meter_versions[1]
+    );
+
+    let target = MemoryEventStore::new();
+    import_ndjson(
+        &target,
+        data.as_slice(),
+        &ImportOptions::new()
+            .twins([sensor, meter])
+            .classes(["Sensor"]),
+    )
+    .await
+    .unwrap();
+    assert!(target.get_snapshot(sensor).await.unwrap().is_some());
+    assert_eq!(target.get_events(sensor, 0).await.unwrap().len(), 3);
+    assert!(target.get_events(other, 0).await.unwrap().is_empty());
+    assert!(target.get_events(meter, 0).await.unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn test_import_rejects_overlapping_versions_and_bad_lines() {
+    let (source, _) = populated().await;
+    let data = export(&source).await;
+
+    let target = MemoryEventStore::new();
+    target
+        .append(TwinEvent::Created {
+            twin_id: TwinId::new(),
+            class_name: "Sensor".to_string(),
+            timestamp: chrono::Utc::now(),
+        })
+        .await
+        .unwrap();
+    assert!(
+        import_ndjson(&target, data.as_slice(), &ImportOptions::new())
+            .await
+            .is_err()
+    );
+
+    let error = import_ndjson(
+        &MemoryEventStore::new(),
+        "\n{\"record\":\"bogus\"}\n".as_bytes(),
+        &ImportOptions::new(),
+    )
+    .await
+    .unwrap_err();
+    assert!(error.to_string().contains("line 2"), "{error}");
+}
@@ -0,0 +1,371 @@
+//! Tests for the Parquet telemetry export
+#![cfg(feature = "parquet")]
+
+use arrow_array::{Array, Float64Array, RecordBatch, StringArray, UInt64Array};
+use chrono::{DateTime, TimeZone, Utc};
+use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+use std::collections::BTreeSet;
+use std::path::Path;
+use twintalk_core::event::{EventEnvelope, EventStore, SnapshotStore, TwinEvent, TwinSnapshot};
+use twintalk_core::storage::{MemoryEventStore, ParquetExporter};
+use twintalk_core::{TwinId, Value};
+
+fn read(path: &Path) -> RecordBatch {
+    let file = std::fs::File::open(path).unwrap();
+    let mut reader = ParquetRecordBatchReaderBuilder::try_new(file)
+        .unwrap()
+        .build()
+        .unwrap();
+    let batch = reader.next().unwrap().unwrap();
+    assert!(reader.next().is_none());
+    batch
+}
+
+fn strings(batch: &RecordBatch, column: &str) -> Vec<Option<String>> {
+    let array = batch
+        .column_by_name(column)
+        .unwrap()
+        .as_any()
+        .downcast_ref::<StringArray>()
+        .unwrap();
+    (0..array.len())
+        .map(|i| (!array.is_null(i)).then(|| array.value(i).to_string()))
+        .collect()
+}
+
+fn relative(dir: &Path, path: &Path) -> String {
+    path.strip_prefix(dir).unwrap().display().to_string()
+}
+
+#[tokio::test]
+async fn test_incremental_partitioned_export() {
+    let dir = tempfile::tempdir().unwrap();
+    let store = MemoryEventStore::new();
+    let sensor = TwinId::new();
+    let pump = TwinId::new();
+    let day1 = Utc.with_ymd_and_hms(2024, 5, 1, 12, 0, 0).unwrap();
+    let day2 = Utc.with_ymd_and_hms(2024, 5, 2, 8, 30, 0).unwrap();
+
+    store
+        .append(TwinEvent::Created {
+            twin_id: sensor,
+            class_name: "Sensor".to_string(),
+            timestamp: day1,
+        })
+        .await
+        .unwrap();
+    store
+        .append(TwinEvent::TelemetryReceived {
+            twin_id: sensor,
+            data: vec![
+                ("temperature".to_string(), 21.5),
+                ("humidity".to_string(), 40.0),
+            ],
+            timestamp: day1,
+        })
+        .await
+        .unwrap();
+    store
+        .append(TwinEvent::PropertyChanged {
+            twin_id: sensor,
+            property: "mode".to_string(),
+            old_value: None,
+            new_value: Value::from("eco"),
+            timestamp: day2,
+        })
+        .await
+        .unwrap();
+    // The pump's class is only known from its snapshot
+    store
+        .save_snapshot(TwinSnapshot {
+            timestamp: day1,
+            ..TwinSnapshot::new(pump, "Pump", 0)
+        })
+        .await
+        .unwrap();
+    store
+        .append(TwinEvent::PropertyChanged {
+            twin_id: pump,
+            property: "rpm".to_string(),
+            old_value: None,
+            new_value: Value::Integer(1450),
+            timestamp: day2,
+        })
+        .await
+        .unwrap();
+
+    let exporter = ParquetExporter::new(dir.path());
+    let report = exporter.export(&store).await.unwrap();
+    assert_eq!(report.rows, 4);
+    assert_eq!(report.last_version, 4);
+    let files: Vec<String> = report
+        .files
+        .iter()
+        .map(|p| relative(dir.path(), p))
+        .collect();
+    let part = "part-00000000000000000001-00000000000000000004.parquet";
+    assert_eq!(
+        files,
+        vec![
+            format!("class=Pump/date=2024-05-02/{part}"),
+            format!("class=Sensor/date=2024-05-01/{part}"),
+            format!("class=Sensor/date=2024-05-02/{part}"),
+        ]
+    );
+
+    let telemetry = read(&report.files[1]);
+    assert_eq!(telemetry.num_rows(), 2);
+    assert_eq!(
+        strings(&telemetry, "property"),
+        vec![
+            Some("temperature".to_string()),
+            Some("humidity".to_string())
+        ]
+    );
+    assert_eq!(strings(&telemetry, "twin_id")[0], Some(sensor.to_string()));
+    let values = telemetry
+        .column_by_name("value")
+        .unwrap()
+        .as_any()
+        .downcast_ref::<Float64Array>()
+        .unwrap();
+    assert!((values.value(0) - 21.5).abs() < f64::EPSILON);
+
+    let mode = read(&report.files[2]);
+    assert_eq!(strings(&mode, "value_text"), vec![Some("eco".to_string())]);
+    assert_eq!(strings(&mode, "type"), vec![Some("String".to_string())]);
+    assert!(mode.column_by_name("value").unwrap().is_null(0));
+
+    let rpm = read(&report.files[0]);
+    assert_eq!(strings(&rpm, "class"), vec![Some("Pump".to_string())]);
+    assert_eq!(strings(&rpm, "type"), vec![Some("Integer".to_string())]);
+
+    // A second run only exports what was appended since
+    store
+        .append(TwinEvent::TelemetryReceived {
+            twin_id: sensor,
+            data: vec![("temperature".to_string(), 22.0)],
+            timestamp: day2,
+        })
+        .await
+        .unwrap();
+    let exporter = ParquetExporter::new(dir.path());
+    assert_eq!(exporter.checkpoint().unwrap().last_version, 4);
+    let report = exporter.export(&store).await.unwrap();
+    assert_eq!(report.rows, 1);
+    assert_eq!(
+        relative(dir.path(), &report.files[0]),
+        "class=Sensor/date=2024-05-02/part-00000000000000000005-00000000000000000005.parquet"
+    );
+    let batch = read(&report.files[0]);
+    let versions = batch
+        .column_by_name("version")
+        .unwrap()
+        .as_any()
+        .downcast_ref::<UInt64Array>()
+        .unwrap();
+    assert_eq!(versions.value(0), 5);
+
+    let report = exporter.export(&store).await.unwrap();
+    assert_eq!(report.rows, 0);
+    assert!(report.files.is_empty());
+    assert_eq!(report.last_version, 5);
+}
+
+/// A store whose hidden versions are committed but not visible yet, as
+/// when concurrent appends finish out of version order
+#[derive(Default)]
+struct DelayedStore {
+    inner: MemoryEventStore,
+    hidden: std::sync::Mutex<Vec<u64>>,
+}
+
+impl DelayedStore {
+    fn visible(
+        &self,
+        envelopes: Vec<(u64, EventEnvelope)>,
+    ) -> anyhow::Result<Vec<(u64, EventEnvelope)>> {
+        let hidden = self.hidden.lock().unwrap();
+        Ok(envelopes
+            .into_iter()
+            .filter(|(version, _)| !hidden.contains(version))
+            .collect())
+    }
+}
+
+#[async_trait::async_trait]
+impl EventStore for DelayedStore {
+    async fn append_envelope(&self, envelope: EventEnvelope) -> anyhow::Result<u64> {
+        self.inner.append_envelope(envelope).await
+    }
+
+    async fn get_envelopes(
+        &self,
+        twin_id: TwinId,
+        after_version: u64,
+    ) -> anyhow::Result<Vec<(u64, EventEnvelope)>> {
+        self.visible(self.inner.get_envelopes(twin_id, after_version).await?)
+    }
+
+    async fn get_envelopes_in_range(
+        &self,
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+    ) -> anyhow::Result<Vec<(u64, EventEnvelope)>> {
+        self.visible(self.inner.get_envelopes_in_range(start, end).await?)
+    }
+
+    async fn get_latest_version(&self) -> anyhow::Result<u64> {
+        self.inner.get_latest_version().await
+    }
+}
+
+#[async_trait::async_trait]
+impl SnapshotStore for DelayedStore {
+    async fn save_snapshot(&self, snapshot: TwinSnapshot) -> anyhow::Result<()> {
+        self.inner.save_snapshot(snapshot).await
+    }
+
+    async fn get_snapshot(&self, twin_id: TwinId) -> anyhow::Result<Option<TwinSnapshot>> {
+        self.inner.get_snapshot(twin_id).await
+    }
+
+    async fn list_snapshots(&self) -> anyhow::Result<Vec<TwinSnapshot>> {
+        self.inner.list_snapshots().await
+    }
+
+    async fn cleanup_old_snapshots(&self, before: DateTime<Utc>) -> anyhow::Result<u64> {
+        self.inner.cleanup_old_snapshots(before).await
+    }
+}
+
+#[tokio::test]
+async fn test_export_picks_up_versions_committed_out_of_order() {
+    let dir = tempfile::tempdir().unwrap();
+    let store = DelayedStore::default();
+    let sensor = TwinId::new();
+    let reading = |value: f64| TwinEvent::TelemetryReceived {
+        twin_id: sensor,
+        data: vec![("temperature".to_string(), value)],
+        timestamp: Utc::now(),
+    };
+
+    for value in [1.0, 2.0, 3.0] {
+        store.append(reading(value)).await.unwrap();
+    }
+    store.hidden.lock().unwrap().push(2);
+
+    let exporter = ParquetExporter::new(dir.path());
+    let report = exporter.export(&store).await.unwrap();
+    assert_eq!(report.rows, 2);
+    assert_eq!(report.last_version, 3);
+    assert_eq!(exporter.checkpoint().unwrap().missing, BTreeSet::from([2]));
+
+    // Version 2 becomes visible after the checkpoint passed it
+    store.hidden.lock().unwrap().clear();
+    store.append(reading(4.0)).await.unwrap();
+    let report = exporter.export(&store).await.unwrap();
+    assert_eq!(report.rows, 2);
+    assert_eq!(report.last_version, 4);
+    let batch = read(&report.files[0]);
+    let versions = batch
+        .column_by_name("version")
+        .unwrap()
+        .as_any()
+        .downcast_ref::<UInt64Array>()
+        .unwrap();
+    assert_eq!(versions.values().to_vec(), vec![2, 4]);
+    assert!(exporter.checkpoint().unwrap().missing.is_empty());
+}
+
+#[tokio::test]
+async fn test_classes_differing_in_escaped_characters_get_their_own_partitions() {
+    let dir = tempfile::tempdir().unwrap();
+    let store = MemoryEventStore::new();
+    let day = Utc.with_ymd_and_hms(2024, 5, 1, 12, 0, 0).unwrap();
+    for class_name in ["Air Handler", "Air_Handler", "Air/Handler", "Air%20Handler"] {
+        let twin_id = TwinId::new();
+        store
+            .append(TwinEvent::Created {
+                twin_id,
+                class_name: class_name.to_string(),
+                timestamp: day,
+            })
+            .await
+            .unwrap();
+        store
+            .append(TwinEvent::TelemetryReceived {
+                twin_id,
+                data: vec![("temperature".to_string(), 21.5)],
+                timestamp: day,
+            })
+            .await
+            .unwrap();
+    }
+
+    let report = ParquetExporter::new(dir.path())
+        .export(&store)
+        .await
+        .unwrap();
+    let partitions: Vec<String> = report
+        .files
+        .iter()
+        .map(|p| relative(dir.path(), p.parent().unwrap().parent().unwrap()))
+        .collect();
+    assert_eq!(
+        partitions,
+        vec![
+            "class=Air%20Handler",
+            "class=Air%2520Handler",
+            "class=Air%2FHandler",
+            "class=Air_Handler",
+        ]
+    );
+    for file in &report.files {
+        assert_eq!(read(file).num_rows(), 1);
+    }
+}
+
+#[tokio::test]
+async fn test_long_runs_are_written_out_in_parts() {
+    let dir = tempfile::tempdir().unwrap();
+    let store = MemoryEventStore::new();
+    let sensor = TwinId::new();
+    let day = Utc.with_ymd_and_hms(2024, 5, 1, 12, 0, 0).unwrap();
+    for i in 0..5 {
+        store
+            .append(TwinEvent::TelemetryReceived {
+                twin_id: sensor,
+                data: vec![
+                    ("temperature".to_string(), f64::from(i)),
+                    ("humidity".to_string(), 40.0),
+                ],
+                timestamp: day,
+            })
+            .await
+            .unwrap();
+    }
+
+    let report = ParquetExporter::new(dir.path())
+        .with_flush_rows(4)
+        .export(&store)
+        .await
+        .unwrap();
+    assert_eq!(report.rows, 10);
+    assert_eq!(report.last_version, 5);
+    let files: Vec<String> = report
+        .files
+        .iter()
+        .map(|p| relative(dir.path(), p))
+        .collect();
+    assert_eq!(
+        files,
+        vec![
+            "class=unknown/date=2024-05-01/part-00000000000000000001-00000000000000000002.parquet",
+            "class=unknown/date=2024-05-01/part-00000000000000000003-00000000000000000004.parquet",
+            "class=unknown/date=2024-05-01/part-00000000000000000005-00000000000000000005.parquet",
+        ]
+    );
+    let rows: Vec<usize> = report.files.iter().map(|f| read(f).num_rows()).collect();
+    assert_eq!(rows, vec![4, 4, 2]);
+}
@@ -0,0 +1,186 @@
+//! Tests for unit-aware quantities
+
+use std::sync::Arc;
+use twintalk_core::alert::Threshold;
+use twintalk_core::event::{EventStore, TwinEvent};
+use twintalk_core::storage::MemoryEventStore;
+use twintalk_core::{AlertRule, Message, Quantity, Runtime, RuntimeConfig, Twin, TwinId, Value};
+
+fn celsius(value: &Value) -> f64 {
+    let q = value.as_quantity().unwrap();
+    assert_eq!(q.unit(), "°C");
+    q.value()
+}
+
+#[test]
+fn test_quantities_in_messages() {
+    let mut twin = Twin::new("Thermostat");
+    twin.send(&Message::parse("setpoint: 20degC").unwrap())
+        .unwrap();
+
+    // Later writes convert to the unit of the current value
+    twin.send(&Message::parse("setpoint: 77°F").unwrap())
+        .unwrap();
+    let setpoint = twin.send(&Message::parse("setpoint").unwrap()).unwrap();
+    assert!((celsius(&setpoint) - 25.0).abs() < 1e-9);
+    assert!(twin
+        .send(&Message::parse("setpoint: 101.3kPa").unwrap())
+        .is_err());
+
+    let fahrenheit = twin
+        .send(&Message::send(
+            "convert:to:",
+            vec!["setpoint".into(), "°F".into()],
+        ))
+        .unwrap();
+    assert!((fahrenheit.as_quantity().unwrap().value() - 77.0).abs() < 1e-9);
+
+    let raised = twin
+        .send(&Message::send(
+            "increase:by:",
+            vec!["setpoint".into(), Quantity::new(9.0, "°F").into()],
+        ))
+        .unwrap();
+    assert!((celsius(&raised) - 30.0).abs() < 1e-9);
+
+    assert_eq!(
+        twin.send(&Message::send(
+            "compare:with:",
+            vec!["setpoint".into(), Quantity::new(310.0, "K").into()]
+        ))
+        .unwrap(),
+        Value::Integer(-1)
+    );
+    assert!(twin
+        .send(&Message::send(
+            "compare:with:",
+            vec!["setpoint".into(), Quantity::new(1.0, "bar").into()]
+        ))
+        .is_err());
+}
+
+#[test]
+fn test_declared_unit_applies_to_plain_numbers() {
+    let mut twin = Twin::new("Boiler");
+    twin.send(&Message::send(
+        "unitOf:put:",
+        vec!["pressure".into(), "kPa".into()],
+    ))
+    .unwrap();
+    twin.send(&Message::SetProperty(
+        "pressure".to_string(),
+        Value::from(150.0),
+    ))
+    .unwrap();
+
+    let bar = twin
+        .send(&Message::send(
+            "convert:to:",
+            vec!["pressure".into(), "bar".into()],
+        ))
+        .unwrap();
+    assert_eq!(bar, Value::Quantity(Quantity::new(1.5, "bar")));
+
+    // Plain numbers stay plain
+    twin.send(&Message::send(
+        "increase:by:",
+        vec!["pressure".into(), Quantity::new(0.5, "bar").into()],
+    ))
+    .unwrap();
+    assert_eq!(
+        twin.send(&Message::GetProperty("pressure".to_string()))
+            .unwrap(),
+        Value::from(200.0)
+    );
+
+    // Quantities written to a property with a declared unit are converted
+    twin.send(&Message::SetProperty(
+        "pressure".to_string(),
+        Quantity::new(2.0, "bar").into(),
+    ))
+    .unwrap();
+    assert_eq!(twin.quantity("pressure"), Some(Quantity::new(200.0, "kPa")));
+}
+
+async fn alerts(store: &MemoryEventStore, twin_id: TwinId) -> Vec<String> {
+    store
+        .get_events(twin_id, 0)
+        .await
+        .unwrap()
+        .into_iter()
+        .filter_map(|(_, event)| match event {
+            TwinEvent::AlertRaised { rule, value, .. } => Some(format!("{rule} at {value}")),
+            _ => None,
+        })
+        .collect()
+}
+
+#[tokio::test]
+async fn test_telemetry_in_mixed_units() {
+    let store = MemoryEventStore::new();
+    let runtime = Runtime::with_stores(
+        RuntimeConfig::default(),
+        Arc::new(store.clone()),
+        Arc::new(store.clone()),
+    );
+    let oven = runtime.create_twin("Oven").await.unwrap();
+    runtime
+        .add_class_rule(
+            "Oven",
+            AlertRule::above("hot", "temperature", Quantity::new(392.0, "°F")),
+        )
+        .await;
+    // Thresholds read from properties in other dimensions never compare
+    runtime
+        .add_class_rule(
+            "Oven",
+            AlertRule::above("odd", "temperature", Threshold::property("limit", 0.0)),
+        )
+        .await;
+    runtime
+        .send(
+            oven,
+            &Message::SetProperty("limit".to_string(), Quantity::new(1.0, "bar").into()),
+        )
+        .await
+        .unwrap();
+
+    // The first reading declares the unit
+    runtime
+        .update_telemetry_in_units(
+            oven,
+            vec![("temperature".to_string(), Quantity::new(180.0, "°C"))],
+        )
+        .await
+        .unwrap();
+    runtime
+        .update_telemetry_in_units(
+            oven,
+            vec![("temperature".to_string(), Quantity::new(410.0, "°F"))],
+        )
+        .await
+        .unwrap();
+    assert!(runtime
+        .update_telemetry_in_units(
+            oven,
+            vec![("temperature".to_string(), Quantity::new(3.0, "kPa"))],
+        )
+        .await
+        .is_err());
+
+    // Recorded in °C: 410°F is 210°C, past the 200°C threshold
+    let recorded: Vec<f64> = store
+        .get_events(oven, 0)
+        .await
+        .unwrap()
+        .into_iter()
+        .filter_map(|(_, event)| match event {
+            TwinEvent::TelemetryReceived { data, .. } => Some(data[0].1),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(recorded.len(), 2);
+    assert!((recorded[1] - 210.0).abs() < 1e-9);
+    assert_eq!(alerts(&store, oven).await.len(), 1);
+    assert!(alerts(&store, oven).await[0].starts_with("hot at 210"));
+}
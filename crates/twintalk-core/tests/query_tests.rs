@@ -0,0 +1,225 @@
+//! Tests for the twin query language
+
+use chrono::{DateTime, Utc};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use twintalk_core::event::{EventEnvelope, EventStore};
+use twintalk_core::storage::MemoryEventStore;
+use twintalk_core::{msg, Query, Runtime, RuntimeConfig, TwinId, Value};
+
+async fn fleet() -> (Runtime, Vec<TwinId>) {
+    let store = MemoryEventStore::new();
+    let runtime = Runtime::with_stores(
+        RuntimeConfig {
+            eviction_timeout: Duration::from_millis(10),
+            ..RuntimeConfig::default()
+        },
+        Arc::new(store.clone()),
+        Arc::new(store.clone()),
+    );
+    runtime.index_property("temperature").await;
+    let mut twins = Vec::new();
+    for (site, temperature, mode) in [
+        ("berlin", 21.0, "auto"),
+        ("berlin", 24.5, "auto"),
+        ("berlin", 26.0, "manual"),
+        ("munich", 23.0, "auto"),
+        ("munich", 19.5, "manual"),
+    ] {
+        let twin = runtime.create_twin("Thermostat").await.unwrap();
+        runtime.set_label(twin, "site", site).await.unwrap();
+        runtime
+            .update_telemetry(twin, vec![("temperature".to_string(), temperature)])
+            .await
+            .unwrap();
+        runtime.send(twin, &msg!(mode: mode)).await.unwrap();
+        twins.push(twin);
+    }
+    let fan = runtime.create_twin("Fan").await.unwrap();
+    runtime.set_label(fan, "site", "berlin").await.unwrap();
+    twins.push(fan);
+
+    // Half the fleet is only persisted
+    tokio::time::sleep(Duration::from_millis(30)).await;
+    runtime.evict_inactive().await.unwrap();
+    for twin in &twins[..3] {
+        runtime.get_twin(*twin).await.unwrap();
+    }
+    (runtime, twins)
+}
+
+#[tokio::test]
+async fn test_select_filters_orders_and_projects() {
+    let (runtime, twins) = fleet().await;
+    let query = Query::parse(
+        "select id, temperature, label.site where class = Thermostat \
+         and (temperature > 22.0 or mode = manual) and not label.site = 'munich' \
+         order by temperature desc",
+    )
+    .unwrap();
+    let rows = runtime.select(&query).collect().await.unwrap();
+    let found: Vec<Option<TwinId>> = rows.iter().map(|row| row.twin_id).collect();
+    assert_eq!(found, [Some(twins[2]), Some(twins[1])]);
+    assert_eq!(rows[0].get("temperature"), Some(&Value::from(26.0)));
+    assert_eq!(rows[0].get("label.site"), Some(&Value::from("berlin")));
+    assert_eq!(
+        rows[1].get("id"),
+        Some(&Value::String(twins[1].to_string()))
+    );
+
+    // Rows stream one at a time, up to the limit
+    let query = Query::parse("select * where temperature >= 21 limit 3").unwrap();
+    let mut rows = runtime.select(&query);
+    let mut count = 0;
+    while let Some(row) = rows.next().await.unwrap() {
+        assert_eq!(row.get("class"), Some(&Value::from("Thermostat")));
+        assert!(row.get("mode").is_some());
+        count += 1;
+    }
+    assert_eq!(count, 3);
+
+    // Twins without the property match no comparison of it, but `!=` is
+    // `not =`, so matches them
+    let query = Query::parse("select id where temperature < 100").unwrap();
+    assert_eq!(runtime.select(&query).collect().await.unwrap().len(), 5);
+    let query = Query::parse("select id where temperature != 21").unwrap();
+    assert_eq!(runtime.select(&query).collect().await.unwrap().len(), 5);
+}
+
+#[tokio::test]
+async fn test_select_aggregates() {
+    let (runtime, _) = fleet().await;
+    let query = Query::parse(
+        "select label.site, count(*), avg(temperature), max(temperature) \
+         group by label.site order by avg(temperature) desc",
+    )
+    .unwrap();
+    let rows = runtime.select(&query).collect().await.unwrap();
+    assert_eq!(rows.len(), 2);
+    assert_eq!(rows[0].twin_id, None);
+    assert_eq!(
+        rows[0].columns,
+        [
+            ("label.site".to_string(), Value::from("berlin")),
+            ("count(*)".to_string(), Value::Integer(4)),
+            (
+                "avg(temperature)".to_string(),
+                Value::from(23.833_333_333_333_332)
+            ),
+            ("max(temperature)".to_string(), Value::from(26.0)),
+        ]
+    );
+    assert_eq!(rows[1].get("avg(temperature)"), Some(&Value::from(21.25)));
+
+    let query = Query::parse("select count(*), min(temperature) where class = Pump").unwrap();
+    let rows = runtime.select(&query).collect().await.unwrap();
+    assert_eq!(rows[0].get("count(*)"), Some(&Value::Integer(0)));
+    assert_eq!(rows[0].get("min(temperature)"), Some(&Value::Nil));
+}
+
+#[tokio::test]
+async fn test_broadcast_to_matches() {
+    let (runtime, twins) = fleet().await;
+    let query = Query::parse("select id where mode = auto and temperature > 22").unwrap();
+    let results = runtime.broadcast(&query, &msg!(mode: "eco")).await.unwrap();
+    let mut targets: Vec<TwinId> = results.iter().map(|(twin, _)| *twin).collect();
+    targets.sort();
+    let mut expected = vec![twins[1], twins[3]];
+    expected.sort();
+    assert_eq!(targets, expected);
+    assert!(results.iter().all(|(_, result)| result.is_ok()));
+
+    let eco = Query::parse("select id where mode = eco").unwrap();
+    assert_eq!(runtime.select(&eco).collect().await.unwrap().len(), 2);
+    assert_eq!(
+        runtime.send(twins[3], &msg!(mode)).await.unwrap(),
+        Value::from("eco")
+    );
+    let summary = Query::parse("select count(*)").unwrap();
+    assert!(runtime.broadcast(&summary, &msg!(mode)).await.is_err());
+}
+
+/// Counts the reads of single twins' events
+#[derive(Default)]
+struct CountingStore {
+    inner: MemoryEventStore,
+    twin_reads: AtomicUsize,
+}
+
+#[async_trait::async_trait]
+impl EventStore for CountingStore {
+    async fn append_envelope(&self, envelope: EventEnvelope) -> anyhow::Result<u64> {
+        self.inner.append_envelope(envelope).await
+    }
+
+    async fn get_envelopes(
+        &self,
+        twin_id: TwinId,
+        after_version: u64,
+    ) -> anyhow::Result<Vec<(u64, EventEnvelope)>> {
+        self.twin_reads.fetch_add(1, Ordering::SeqCst);
+        self.inner.get_envelopes(twin_id, after_version).await
+    }
+
+    async fn get_envelopes_in_range(
+        &self,
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+    ) -> anyhow::Result<Vec<(u64, EventEnvelope)>> {
+        self.inner.get_envelopes_in_range(start, end).await
+    }
+
+    async fn get_latest_version(&self) -> anyhow::Result<u64> {
+        self.inner.get_latest_version().await
+    }
+}
+
+#[tokio::test]
+async fn test_indexed_queries_do_not_load_twins() {
+    let store = Arc::new(CountingStore::default());
+    let runtime = Runtime::with_stores(
+        RuntimeConfig {
+            eviction_timeout: Duration::from_millis(10),
+            ..RuntimeConfig::default()
+        },
+        store.clone(),
+        Arc::new(store.inner.clone()),
+    );
+    runtime.index_property("temperature").await;
+    for (site, temperature) in [("berlin", 21.0), ("berlin", 24.5), ("munich", 23.0)] {
+        let twin = runtime.create_twin("Thermostat").await.unwrap();
+        runtime.set_label(twin, "site", site).await.unwrap();
+        runtime
+            .update_telemetry(twin, vec![("temperature".to_string(), temperature)])
+            .await
+            .unwrap();
+        runtime.send(twin, &msg!(mode: "auto")).await.unwrap();
+    }
+    tokio::time::sleep(Duration::from_millis(30)).await;
+    runtime.evict_inactive().await.unwrap();
+
+    let reads = store.twin_reads.load(Ordering::SeqCst);
+    let query = Query::parse(
+        "select label.site, max(temperature) where class = Thermostat \
+         and temperature > 20 group by label.site",
+    )
+    .unwrap();
+    let rows = runtime.select(&query).collect().await.unwrap();
+    assert_eq!(rows.len(), 2);
+    assert_eq!(
+        rows[0].get("max(temperature)"),
+        Some(&Value::Float(24.5.into()))
+    );
+    assert_eq!(store.twin_reads.load(Ordering::SeqCst), reads);
+
+    // Unindexed properties are read from the twins
+    let query = Query::parse("select id, mode where label.site = berlin").unwrap();
+    let rows = runtime.select(&query).collect().await.unwrap();
+    assert_eq!(rows.len(), 2);
+    assert_eq!(
+        rows[0].get("mode"),
+        Some(&Value::String("auto".to_string()))
+    );
+    assert!(store.twin_reads.load(Ordering::SeqCst) > reads);
+}
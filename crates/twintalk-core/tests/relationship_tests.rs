@@ -0,0 +1,183 @@
+//! Tests for relationships between twins
+
+use std::sync::Arc;
+use std::time::Duration;
+use twintalk_core::event::{EventStore, TwinEvent};
+use twintalk_core::relationship::Direction;
+use twintalk_core::storage::MemoryEventStore;
+use twintalk_core::{msg, RelationshipKind, Runtime, RuntimeConfig, TwinId, Value};
+
+async fn hierarchy(runtime: &Runtime) -> [TwinId; 5] {
+    let mut twins = Vec::new();
+    for class in ["Site", "Building", "Floor", "Room", "Sensor"] {
+        twins.push(runtime.create_twin(class).await.unwrap());
+    }
+    for pair in twins.windows(2) {
+        assert!(runtime
+            .relate(pair[0], RelationshipKind::Contains, pair[1])
+            .await
+            .unwrap());
+    }
+    twins.try_into().unwrap()
+}
+
+#[tokio::test]
+async fn test_hierarchy_traversal() {
+    let store = MemoryEventStore::new();
+    let runtime = Runtime::with_stores(
+        RuntimeConfig::default(),
+        Arc::new(store.clone()),
+        Arc::new(store.clone()),
+    );
+    let [site, building, floor, room, sensor] = hierarchy(&runtime).await;
+
+    assert_eq!(runtime.children(floor).await.unwrap(), [room]);
+    assert_eq!(
+        runtime.ancestors(sensor).await.unwrap(),
+        [room, floor, building, site]
+    );
+    assert_eq!(runtime.neighbors(floor, 1, None).await.unwrap().len(), 2);
+    assert_eq!(
+        runtime.neighbors(site, 3, None).await.unwrap(),
+        [building, floor, room]
+    );
+
+    // Already related, contained elsewhere, looping, or to itself
+    assert!(!runtime.relate(site, "contains", building).await.unwrap());
+    assert!(runtime.relate(floor, "contains", sensor).await.is_err());
+    assert!(runtime.relate(room, "contains", building).await.is_err());
+    assert!(runtime.relate(room, "feeds", room).await.is_err());
+
+    // Relationships are events on the source twin
+    let kinds: Vec<&str> = store
+        .get_events(site, 0)
+        .await
+        .unwrap()
+        .iter()
+        .map(|(_, event)| event.kind())
+        .collect();
+    assert_eq!(kinds, ["Created", "RelationshipAdded"]);
+
+    assert!(runtime.unrelate(room, "contains", sensor).await.unwrap());
+    assert!(!runtime.unrelate(room, "contains", sensor).await.unwrap());
+    assert!(runtime.ancestors(sensor).await.unwrap().is_empty());
+    assert!(runtime.relate(floor, "contains", sensor).await.unwrap());
+}
+
+#[tokio::test]
+async fn test_send_along_relationship() {
+    let runtime = Runtime::new(RuntimeConfig::default());
+    let pump = runtime.create_twin("Pump").await.unwrap();
+    let tanks = [
+        runtime.create_twin("Tank").await.unwrap(),
+        runtime.create_twin("Tank").await.unwrap(),
+    ];
+    let valve = runtime.create_twin("Valve").await.unwrap();
+    for tank in tanks {
+        runtime
+            .relate(pump, RelationshipKind::Feeds, tank)
+            .await
+            .unwrap();
+    }
+    runtime
+        .relate(pump, RelationshipKind::ConnectedTo, valve)
+        .await
+        .unwrap();
+
+    let results = runtime
+        .send_related(pump, RelationshipKind::Feeds, &msg!(inlet: "open"))
+        .await
+        .unwrap();
+    assert_eq!(results.len(), 2);
+    assert!(results.iter().all(|(_, result)| result.is_ok()));
+    for tank in tanks {
+        assert_eq!(
+            runtime.send(tank, &msg!(inlet)).await.unwrap(),
+            Value::from("open")
+        );
+    }
+    assert_eq!(runtime.send(valve, &msg!(inlet)).await.unwrap(), Value::Nil);
+    assert_eq!(
+        runtime
+            .related(tanks[0], None, Direction::Incoming)
+            .await
+            .unwrap(),
+        [pump]
+    );
+}
+
+#[tokio::test]
+async fn test_relationships_survive_restart() {
+    let store = MemoryEventStore::new();
+    let config = RuntimeConfig {
+        eviction_timeout: Duration::from_millis(10),
+        ..RuntimeConfig::default()
+    };
+    let runtime = Runtime::with_stores(
+        config.clone(),
+        Arc::new(store.clone()),
+        Arc::new(store.clone()),
+    );
+    let [site, building, _, room, sensor] = hierarchy(&runtime).await;
+
+    // Rebuilt from the log
+    let restarted = Runtime::with_stores(
+        config.clone(),
+        Arc::new(store.clone()),
+        Arc::new(MemoryEventStore::new()),
+    );
+    assert_eq!(restarted.ancestors(sensor).await.unwrap().len(), 4);
+
+    // And from snapshots, once the events are gone
+    tokio::time::sleep(Duration::from_millis(30)).await;
+    assert_eq!(runtime.evict_inactive().await.unwrap(), 5);
+    let compacted = Runtime::with_stores(
+        config,
+        Arc::new(MemoryEventStore::new()),
+        Arc::new(store.clone()),
+    );
+    assert_eq!(compacted.children(site).await.unwrap(), [building]);
+    assert_eq!(compacted.ancestors(sensor).await.unwrap()[0], room);
+    let twin = compacted.get_twin(site).await.unwrap();
+    let relationships = twin.twin.read().await.relationships().clone();
+    assert_eq!(relationships.len(), 1);
+    assert!(matches!(
+        store.get_events(site, 0).await.unwrap().last(),
+        Some((_, TwinEvent::RelationshipAdded { .. }))
+    ));
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_concurrent_containment() {
+    let runtime = Arc::new(Runtime::new(RuntimeConfig::default()));
+    let sensor = runtime.create_twin("Sensor").await.unwrap();
+    let mut rooms = Vec::new();
+    for _ in 0..8 {
+        rooms.push(runtime.create_twin("Room").await.unwrap());
+    }
+
+    // Only one room can win the sensor
+    let tasks: Vec<_> = rooms
+        .iter()
+        .map(|&room| {
+            let runtime = runtime.clone();
+            tokio::spawn(async move { runtime.relate(room, "contains", sensor).await })
+        })
+        .collect();
+    let mut won = 0;
+    for task in tasks {
+        if task.await.unwrap().is_ok() {
+            won += 1;
+        }
+    }
+    assert_eq!(won, 1);
+    assert_eq!(runtime.ancestors(sensor).await.unwrap().len(), 1);
+
+    // Nor can two rooms contain each other
+    let (a, b) = (rooms[0], rooms[1]);
+    let (first, second) = tokio::join!(
+        runtime.relate(a, "contains", b),
+        runtime.relate(b, "contains", a)
+    );
+    assert!(first.is_ok() != second.is_ok());
+}
@@ -0,0 +1,337 @@
+//! Tests for retention policies and event log compaction
+
+use chrono::{DateTime, Duration, Utc};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use tokio::sync::Notify;
+use twintalk_core::event::{EventEnvelope, EventStore, SnapshotStore, TwinEvent, TwinSnapshot};
+use twintalk_core::storage::{
+    CompactionReport, HashChainedStore, MemoryEventStore, Retention, RetentionPolicy,
+    SledEventStore,
+};
+use twintalk_core::{msg, Runtime, RuntimeConfig, TwinId, Value};
+
+mod common;
+
+const WEEK: std::time::Duration = std::time::Duration::from_secs(7 * 24 * 3600);
+
+/// The backends with compaction, behind one interface
+trait Compact: EventStore + SnapshotStore + 'static {
+    async fn compact_now(&self, policy: &RetentionPolicy) -> CompactionReport;
+}
+
+impl Compact for MemoryEventStore {
+    async fn compact_now(&self, policy: &RetentionPolicy) -> CompactionReport {
+        self.compact(policy, Utc::now())
+    }
+}
+
+impl Compact for SledEventStore {
+    async fn compact_now(&self, policy: &RetentionPolicy) -> CompactionReport {
+        self.compact(policy, Utc::now()).await.unwrap()
+    }
+}
+
+fn created(twin_id: TwinId, class_name: &str, timestamp: DateTime<Utc>) -> TwinEvent {
+    TwinEvent::Created {
+        twin_id,
+        class_name: class_name.to_string(),
+        timestamp,
+    }
+}
+
+fn telemetry(twin_id: TwinId, temperature: f64, timestamp: DateTime<Utc>) -> TwinEvent {
+    TwinEvent::TelemetryReceived {
+        twin_id,
+        data: vec![("temperature".to_string(), temperature)],
+        timestamp,
+    }
+}
+
+fn snapshot(
+    twin_id: TwinId,
+    class_name: &str,
+    event_version: u64,
+    properties: &[(&str, Value)],
+) -> TwinSnapshot {
+    TwinSnapshot::new(twin_id, class_name, event_version).with_properties(
+        properties
+            .iter()
+            .map(|(k, v)| ((*k).to_string(), v.clone())),
+    )
+}
+
+fn versions(events: &[(u64, TwinEvent)]) -> Vec<u64> {
+    events.iter().map(|(v, _)| *v).collect()
+}
+
+async fn check_compaction<S: Compact>(store: Arc<S>) {
+    let old = Utc::now() - Duration::days(10);
+    let sensor = TwinId::new();
+    let meter = TwinId::new();
+
+    store.append(created(sensor, "Sensor", old)).await.unwrap();
+    store.append(telemetry(sensor, 1.0, old)).await.unwrap();
+    store
+        .append(TwinEvent::PropertyChanged {
+            twin_id: sensor,
+            property: "mode".to_string(),
+            old_value: None,
+            new_value: Value::from("eco"),
+            timestamp: old,
+        })
+        .await
+        .unwrap();
+    store
+        .append(telemetry(sensor, 2.0, Utc::now() - Duration::hours(1)))
+        .await
+        .unwrap();
+    store
+        .save_snapshot(snapshot(
+            sensor,
+            "Sensor",
+            4,
+            &[
+                ("temperature", Value::from(2.0)),
+                ("mode", Value::from("eco")),
+            ],
+        ))
+        .await
+        .unwrap();
+
+    // Expired, but not folded into the snapshot
+    store.append(telemetry(sensor, 3.0, old)).await.unwrap();
+
+    // Meters keep their raw telemetry
+    store.append(created(meter, "Meter", old)).await.unwrap();
+    store.append(telemetry(meter, 7.0, old)).await.unwrap();
+    store
+        .save_snapshot(snapshot(
+            meter,
+            "Meter",
+            7,
+            &[("temperature", Value::from(7.0))],
+        ))
+        .await
+        .unwrap();
+
+    let policy = RetentionPolicy::new()
+        .kind("TelemetryReceived", Retention::MaxAge(WEEK))
+        .class_kind("Meter", "TelemetryReceived", Retention::Forever);
+    let report = store.compact_now(&policy).await;
+    assert_eq!(report.twins, 1);
+    assert_eq!(report.removed, 1);
+    assert_eq!(report.removed_by_kind["TelemetryReceived"], 1);
+
+    assert_eq!(
+        versions(&store.get_events(sensor, 0).await.unwrap()),
+        vec![1, 3, 4, 5]
+    );
+    assert_eq!(
+        versions(&store.get_events(meter, 0).await.unwrap()),
+        vec![6, 7]
+    );
+    assert_eq!(
+        store.compact_now(&policy).await,
+        CompactionReport::default()
+    );
+
+    // The snapshot plus the remaining events still replay to the same state
+    let runtime = Runtime::with_stores(RuntimeConfig::default(), store.clone(), store);
+    assert_eq!(
+        runtime.send(sensor, &msg!(temperature)).await.unwrap(),
+        Value::from(3.0)
+    );
+    assert_eq!(
+        runtime.send(sensor, &msg!(mode)).await.unwrap(),
+        Value::from("eco")
+    );
+}
+
+#[tokio::test]
+async fn test_memory_compaction() {
+    check_compaction(Arc::new(MemoryEventStore::new())).await;
+}
+
+#[tokio::test]
+async fn test_sled_compaction() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("events");
+    check_compaction(Arc::new(
+        SledEventStore::new(path.to_str().unwrap()).unwrap(),
+    ))
+    .await;
+}
+
+async fn check_chained_twins_kept<S: Compact>(store: S) {
+    let old = Utc::now() - Duration::days(10);
+    let sensor = TwinId::new();
+    let store = HashChainedStore::new(store);
+    store.append(created(sensor, "Sensor", old)).await.unwrap();
+    store.append(telemetry(sensor, 1.0, old)).await.unwrap();
+    store
+        .save_snapshot(snapshot(
+            sensor,
+            "Sensor",
+            2,
+            &[("temperature", Value::from(1.0))],
+        ))
+        .await
+        .unwrap();
+
+    let policy = RetentionPolicy::new().kind("TelemetryReceived", Retention::MaxAge(WEEK));
+    let report = store.inner().compact_now(&policy).await;
+    assert_eq!(report.chained, 1);
+    assert_eq!(report.removed, 0);
+    assert_eq!(store.verify(sensor).await.unwrap(), None);
+}
+
+#[tokio::test]
+async fn test_chained_twins_are_not_compacted() {
+    check_chained_twins_kept(MemoryEventStore::new()).await;
+
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("events");
+    check_chained_twins_kept(SledEventStore::new(path.to_str().unwrap()).unwrap()).await;
+}
+
+#[tokio::test]
+async fn test_sled_versions_not_reused_after_compacting_newest_events() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("events");
+    let path = path.to_str().unwrap();
+    let old = Utc::now() - Duration::days(10);
+    let twin_id = TwinId::new();
+
+    {
+        let store = SledEventStore::new(path).unwrap();
+        store.append(created(twin_id, "Sensor", old)).await.unwrap();
+        store.append(telemetry(twin_id, 1.0, old)).await.unwrap();
+        store
+            .save_snapshot(snapshot(
+                twin_id,
+                "Sensor",
+                2,
+                &[("temperature", Value::from(1.0))],
+            ))
+            .await
+            .unwrap();
+
+        let policy = RetentionPolicy::new().class("Sensor", Retention::MaxAge(WEEK));
+        assert_eq!(store.compact(&policy, Utc::now()).await.unwrap().removed, 2);
+        assert!(store.get_events(twin_id, 0).await.unwrap().is_empty());
+    }
+
+    let store = common::sled_store(path).await;
+    assert_eq!(store.get_latest_version().await.unwrap(), 2);
+    assert_eq!(
+        store
+            .append(telemetry(twin_id, 2.0, Utc::now()))
+            .await
+            .unwrap(),
+        3
+    );
+}
+
+/// A store that can hold an append back once it is stored
+#[derive(Default)]
+struct PausingStore {
+    inner: MemoryEventStore,
+    pause: AtomicBool,
+    appended: Notify,
+    resume: Notify,
+}
+
+#[async_trait::async_trait]
+impl EventStore for PausingStore {
+    async fn append_envelope(&self, envelope: EventEnvelope) -> anyhow::Result<u64> {
+        let version = self.inner.append_envelope(envelope).await?;
+        if self.pause.swap(false, Ordering::SeqCst) {
+            self.appended.notify_one();
+            self.resume.notified().await;
+        }
+        Ok(version)
+    }
+
+    async fn get_envelopes(
+        &self,
+        twin_id: TwinId,
+        after_version: u64,
+    ) -> anyhow::Result<Vec<(u64, EventEnvelope)>> {
+        self.inner.get_envelopes(twin_id, after_version).await
+    }
+
+    async fn get_envelopes_in_range(
+        &self,
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+    ) -> anyhow::Result<Vec<(u64, EventEnvelope)>> {
+        self.inner.get_envelopes_in_range(start, end).await
+    }
+
+    async fn get_latest_version(&self) -> anyhow::Result<u64> {
+        self.inner.get_latest_version().await
+    }
+}
+
+#[tokio::test]
+async fn test_snapshot_holds_every_event_up_to_its_version() {
+    let store = Arc::new(PausingStore::default());
+    let snapshots = MemoryEventStore::new();
+    let runtime = Arc::new(Runtime::with_stores(
+        RuntimeConfig::default(),
+        store.clone(),
+        Arc::new(snapshots.clone()),
+    ));
+    let twin = runtime.create_twin("Sensor").await.unwrap();
+
+    // Snapshot while telemetry is stored but not yet applied
+    store.pause.store(true, Ordering::SeqCst);
+    let update = tokio::spawn({
+        let runtime = runtime.clone();
+        async move {
+            runtime
+                .update_telemetry(twin, vec![("temperature".to_string(), 21.0)])
+                .await
+        }
+    });
+    store.appended.notified().await;
+    let snapshot = tokio::spawn({
+        let runtime = runtime.clone();
+        async move { runtime.snapshot_twin(twin).await }
+    });
+    tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+    store.resume.notify_one();
+    update.await.unwrap().unwrap();
+    snapshot.await.unwrap().unwrap();
+
+    let saved = snapshots.get_snapshot(twin).await.unwrap().unwrap();
+    assert_eq!(saved.event_version, 2);
+    assert_eq!(
+        saved.properties.get("temperature"),
+        Some(&Value::from(21.0))
+    );
+}
+
+#[test]
+fn test_most_specific_rule_wins() {
+    let day = std::time::Duration::from_secs(24 * 3600);
+    let policy = RetentionPolicy::new()
+        .kind("TelemetryReceived", Retention::MaxAge(WEEK))
+        .class("Scratch", Retention::MaxAge(day))
+        .class_kind("Scratch", "Created", Retention::Forever);
+
+    assert_eq!(
+        policy.retention("Sensor", "TelemetryReceived"),
+        Retention::MaxAge(WEEK)
+    );
+    assert_eq!(
+        policy.retention("Sensor", "PropertyChanged"),
+        Retention::Forever
+    );
+    assert_eq!(
+        policy.retention("Scratch", "TelemetryReceived"),
+        Retention::MaxAge(day)
+    );
+    assert_eq!(policy.retention("Scratch", "Created"), Retention::Forever);
+}
@@ -2,7 +2,9 @@
 
 use std::sync::Arc;
 use std::time::Duration;
-use twintalk_core::{msg, Message, Runtime, RuntimeConfig, TwinId, Value};
+use twintalk_core::event::EventStore;
+use twintalk_core::storage::MemoryEventStore;
+use twintalk_core::{msg, EventContext, Message, Runtime, RuntimeConfig, TwinId, Value};
 
 #[tokio::test]
 async fn test_twin_lifecycle() {
@@ -40,6 +42,7 @@ async fn test_lazy_loading() {
         eviction_interval: Duration::from_secs(1),
         snapshot_on_eviction: true,
         max_active_twins: None,
+        ..RuntimeConfig::default()
     }));
 
     // Create twin
@@ -228,6 +231,46 @@ async fn test_concurrent_access() {
     }
 }
 
+#[tokio::test]
+async fn test_group_commit_concurrent_telemetry() {
+    let runtime = Arc::new(Runtime::new(RuntimeConfig {
+        group_commit: Some(4),
+        ..RuntimeConfig::default()
+    }));
+    let twin_id = runtime.create_twin("GroupCommitTwin").await.unwrap();
+
+    let handles: Vec<_> = (0..20)
+        .map(|i| {
+            let rt = runtime.clone();
+            tokio::spawn(async move {
+                rt.update_telemetry(twin_id, vec![(format!("value_{i}"), f64::from(i))])
+                    .await
+                    .unwrap();
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.await.unwrap();
+    }
+
+    // Every append was committed exactly once
+    assert_eq!(runtime.stats().await.total_events, 21);
+
+    // And replays into the same state after eviction
+    runtime.evict_inactive().await.unwrap();
+    let active = runtime.get_twin(twin_id).await.unwrap();
+    {
+        let mut twin = active.twin.write().await;
+        for i in 0..20 {
+            let value = twin
+                .send(&Message::GetProperty(format!("value_{i}")))
+                .unwrap();
+            assert_eq!(value, Value::from(f64::from(i)));
+        }
+        drop(twin); // Explicitly drop the lock
+    }
+}
+
 #[tokio::test]
 async fn test_error_handling() {
     let runtime = Runtime::new(RuntimeConfig::default());
@@ -239,3 +282,68 @@ async fn test_error_handling() {
     assert!(result.is_err());
     // Don't check the error message as it requires Debug trait
 }
+
+#[tokio::test]
+async fn test_event_metadata_propagation() {
+    let store = MemoryEventStore::new();
+    let runtime = Runtime::with_stores(
+        RuntimeConfig::default(),
+        Arc::new(store.clone()),
+        Arc::new(store.clone()),
+    );
+    let twin_id = runtime.create_twin("TemperatureSensor").await.unwrap();
+
+    let context = EventContext::new()
+        .with_principal("api:alice")
+        .with_header("request_id", "r-1");
+    let correlation_id = context.correlation_id.unwrap();
+    context
+        .scope(async {
+            runtime
+                .send(twin_id, &msg!(temperature: 35.0))
+                .await
+                .unwrap();
+            let alert = runtime
+                .send(
+                    twin_id,
+                    &Message::Send {
+                        selector: "checkAlert".to_string(),
+                        args: vec![],
+                    },
+                )
+                .await
+                .unwrap();
+            assert_eq!(alert, Value::Boolean(true));
+        })
+        .await;
+
+    let envelopes = store.get_envelopes(twin_id, 1).await.unwrap();
+    let kinds: Vec<_> = envelopes.iter().map(|(_, e)| e.event.kind()).collect();
+    assert_eq!(kinds, ["PropertyChanged", "MessageSent", "PropertyChanged"]);
+    for (_, envelope) in &envelopes {
+        assert_eq!(envelope.metadata.correlation_id, correlation_id);
+        assert_eq!(envelope.metadata.principal.as_deref(), Some("api:alice"));
+        assert_eq!(envelope.metadata.headers["request_id"], "r-1");
+    }
+
+    // The alert flag was changed by the checkAlert message
+    let message = &envelopes[1].1.metadata;
+    assert_eq!(envelopes[0].1.metadata.causation_id, None);
+    assert_eq!(envelopes[2].1.metadata.causation_id, Some(message.event_id));
+
+    // Events outside any context start their own correlation
+    let created = &store.get_envelopes(twin_id, 0).await.unwrap()[0].1.metadata;
+    assert_eq!(created.correlation_id, created.event_id);
+    assert_eq!(created.principal, None);
+
+    // Changes made through the runtime replay from the log
+    let reloaded = Runtime::with_stores(
+        RuntimeConfig::default(),
+        Arc::new(store.clone()),
+        Arc::new(store),
+    );
+    assert_eq!(
+        reloaded.send(twin_id, &msg!(alert)).await.unwrap(),
+        Value::Boolean(true)
+    );
+}
@@ -0,0 +1,259 @@
+//! Tests for event schema versioning and upcasting
+
+use chrono::Utc;
+use serde_json::json;
+use twintalk_core::event::{EventStore, TwinEvent};
+use twintalk_core::storage::schema::{encode_event, CURRENT_SCHEMA_VERSION};
+use twintalk_core::storage::{FileEventStore, FileStoreConfig, UpcasterRegistry};
+use twintalk_core::TwinId;
+
+mod common;
+
+/// A hypothetical schema 0 in which telemetry data was a JSON object
+/// instead of a list of pairs
+fn registry_with_v0() -> UpcasterRegistry {
+    let mut registry = UpcasterRegistry::new();
+    registry.register(0, |mut event| {
+        if event["type"] == "TelemetryReceived" {
+            let pairs: Vec<_> = event["data"]
+                .as_object()
+                .map(|data| data.iter().map(|(k, v)| json!([k, v])).collect())
+                .unwrap_or_default();
+            event["data"] = json!(pairs);
+        }
+        Ok(event)
+    });
+    registry
+}
+
+fn v0_telemetry(twin_id: TwinId) -> serde_json::Value {
+    json!({
+        "schema_version": 0,
+        "event": {
+            "type": "TelemetryReceived",
+            "twin_id": twin_id,
+            "data": {"temperature": 21.5},
+            "timestamp": Utc::now(),
+        }
+    })
+}
+
+#[test]
+fn test_envelope_round_trip() {
+    let event = TwinEvent::Created {
+        twin_id: TwinId::new(),
+        class_name: "Sensor".to_string(),
+        timestamp: Utc::now(),
+    };
+
+    let encoded = encode_event(&event).unwrap();
+    let json: serde_json::Value = serde_json::from_slice(&encoded).unwrap();
+    assert_eq!(json["schema_version"], CURRENT_SCHEMA_VERSION);
+    assert_eq!(json["event"]["type"], "Created");
+
+    let decoded = UpcasterRegistry::new().decode_event(&encoded).unwrap();
+    assert_eq!(decoded.twin_id(), event.twin_id());
+}
+
+#[test]
+fn test_legacy_records_without_envelope() {
+    let twin_id = TwinId::new();
+    let legacy = serde_json::to_vec(&TwinEvent::Destroyed {
+        twin_id,
+        timestamp: Utc::now(),
+    })
+    .unwrap();
+
+    let decoded = UpcasterRegistry::new().decode_event(&legacy).unwrap();
+    assert!(matches!(decoded, TwinEvent::Destroyed { .. }));
+    assert_eq!(decoded.twin_id(), twin_id);
+}
+
+#[test]
+fn test_upcaster_chain() {
+    let twin_id = TwinId::new();
+    let record = serde_json::to_vec(&v0_telemetry(twin_id)).unwrap();
+
+    // Without the upcaster the old record cannot be read
+    let err = UpcasterRegistry::new().decode_event(&record).unwrap_err();
+    assert!(err.to_string().contains("No upcaster"));
+
+    match registry_with_v0().decode_event(&record).unwrap() {
+        TwinEvent::TelemetryReceived { data, .. } => {
+            assert_eq!(data, vec![("temperature".to_string(), 21.5)]);
+        }
+        other => panic!("unexpected event {other:?}"),
+    }
+}
+
+#[test]
+fn test_newer_schema_is_rejected() {
+    let record = json!({
+        "schema_version": CURRENT_SCHEMA_VERSION + 1,
+        "event": {"type": "Destroyed", "twin_id": TwinId::new(), "timestamp": Utc::now()},
+    });
+    let err = UpcasterRegistry::new()
+        .decode_event(&serde_json::to_vec(&record).unwrap())
+        .unwrap_err();
+    assert!(err.to_string().contains("newer"));
+}
+
+#[tokio::test]
+async fn test_file_store_upcasts_old_records() {
+    let dir = tempfile::tempdir().unwrap();
+    let twin_id = TwinId::new();
+
+    // Write a segment by hand containing one schema 0 record
+    let mut body = 1u64.to_le_bytes().to_vec();
+    body.extend(serde_json::to_vec(&v0_telemetry(twin_id)).unwrap());
+    let mut record = u32::try_from(body.len()).unwrap().to_le_bytes().to_vec();
+    record.extend(crc32fast::hash(&body).to_le_bytes());
+    record.extend(body);
+    let segments = dir.path().join("segments");
+    std::fs::create_dir_all(&segments).unwrap();
+    std::fs::write(segments.join(format!("{:020}.log", 1)), record).unwrap();
+
+    let config = FileStoreConfig {
+        upcasters: registry_with_v0(),
+        ..FileStoreConfig::default()
+    };
+    let store = FileEventStore::with_config(dir.path(), config).unwrap();
+
+    // Old and new records read back side by side
+    store
+        .append(TwinEvent::Destroyed {
+            twin_id,
+            timestamp: Utc::now(),
+        })
+        .await
+        .unwrap();
+    let events = store.get_events(twin_id, 0).await.unwrap();
+    assert_eq!(events.len(), 2);
+    assert!(matches!(
+        &events[0].1,
+        TwinEvent::TelemetryReceived { data, .. } if data[0].1 == 21.5
+    ));
+}
+
+#[cfg(feature = "sqlite")]
+#[tokio::test]
+async fn test_sqlite_store_upcasts_old_rows() {
+    use twintalk_core::storage::SqliteEventStore;
+
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("events.db");
+    let twin_id = TwinId::new();
+
+    drop(SqliteEventStore::new(&path).unwrap());
+    let conn = rusqlite::Connection::open(&path).unwrap();
+    conn.execute(
+        "INSERT INTO events (version, twin_id, kind, timestamp, payload, schema_version)
+         VALUES (1, ?1, 'TelemetryReceived', ?2, ?3, 0)",
+        rusqlite::params![
+            twin_id.to_string(),
+            Utc::now().to_rfc3339(),
+            v0_telemetry(twin_id)["event"].to_string(),
+        ],
+    )
+    .unwrap();
+    drop(conn);
+
+    let store = SqliteEventStore::new(&path)
+        .unwrap()
+        .with_upcasters(registry_with_v0());
+    let events = store.get_events(twin_id, 0).await.unwrap();
+    assert_eq!(events.len(), 1);
+    assert!(matches!(events[0].1, TwinEvent::TelemetryReceived { .. }));
+}
+
+/// Records as written by the sled store before records were versioned:
+/// (tree, key, value), hex encoded
+const BINCODE_RECORDS: &[(&str, &str, &str)] = &[
+    ("events", "0000000000000001", "0743726561746564100123456789abcdef0123456789abcdef0653656e736f7214323032342d30352d30365430373a30383a30395a"),
+    ("events", "0000000000000002", "06436c6f6e6564100123456789abcdef0123456789abcdef10fedcba9876543210fedcba987654321014323032342d30352d30365430373a30383a30395a"),
+    ("events", "0000000000000003", "0f50726f70657274794368616e676564100123456789abcdef0123456789abcdef097468726573686f6c640003000000000080354014323032342d30352d30365430373a30383a30395a"),
+    ("events", "0000000000000004", "0f50726f70657274794368616e676564100123456789abcdef0123456789abcdef0474616773010006050503686f7402050101080201020701016b04017614323032342d30352d30365430373a30383a30395a"),
+    ("events", "0000000000000005", "1154656c656d657472795265636569766564100123456789abcdef0123456789abcdef010b74656d7065726174757265000000000040334014323032342d30352d30365430373a30383a30395a"),
+    ("events", "0000000000000006", "0b4d65737361676553656e74100123456789abcdef0123456789abcdef057265736574010202000014323032342d30352d30365430373a30383a30395a"),
+    ("events", "0000000000000007", "0b4d65737361676553656e74100123456789abcdef0123456789abcdef046661696c0001046e6f706514323032342d30352d30365430373a30383a30395a"),
+    ("events", "0000000000000008", "0944657374726f796564100123456789abcdef0123456789abcdef14323032342d30352d30365430373a30383a30395a"),
+    ("twin_events", "0123456789abcdef0123456789abcdef", "080102030405060708"),
+    ("snapshots", "0123456789abcdef0123456789abcdef", "100123456789abcdef0123456789abcdef0653656e736f7201097468726573686f6c640300000000008035400110fedcba9876543210fedcba98765432100314323032342d30352d30365430373a30383a30395a"),
+];
+
+#[tokio::test]
+async fn test_sled_store_reads_bincode_records() {
+    use std::collections::BTreeMap;
+    use twintalk_core::event::SnapshotStore;
+    use twintalk_core::Value;
+
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("events");
+    {
+        let db = sled::open(&path).unwrap();
+        for (tree, key, value) in BINCODE_RECORDS {
+            db.open_tree(tree)
+                .unwrap()
+                .insert(hex::decode(key).unwrap(), hex::decode(value).unwrap())
+                .unwrap();
+        }
+        db.flush().unwrap();
+    }
+
+    let store = common::sled_store(path.to_str().unwrap()).await;
+    let twin_id = TwinId(uuid::Uuid::from_u128(
+        0x0123_4567_89ab_cdef_0123_4567_89ab_cdef,
+    ));
+    let source = TwinId(uuid::Uuid::from_u128(
+        0xfedc_ba98_7654_3210_fedc_ba98_7654_3210,
+    ));
+    let events = store.get_events(twin_id, 0).await.unwrap();
+    let kinds: Vec<&str> = events.iter().map(|(_, event)| event.kind()).collect();
+    assert_eq!(
+        kinds,
+        [
+            "Created",
+            "Cloned",
+            "PropertyChanged",
+            "PropertyChanged",
+            "TelemetryReceived",
+            "MessageSent",
+            "MessageSent",
+            "Destroyed"
+        ]
+    );
+    assert!(matches!(
+        &events[0].1,
+        TwinEvent::Created { class_name, timestamp, .. }
+            if class_name == "Sensor" && timestamp.to_rfc3339() == "2024-05-06T07:08:09+00:00"
+    ));
+    assert!(matches!(
+        &events[1].1,
+        TwinEvent::Cloned { source_id, .. } if *source_id == source
+    ));
+    assert!(matches!(
+        &events[3].1,
+        TwinEvent::PropertyChanged { old_value: Some(Value::Nil), new_value: Value::Array(values), .. }
+            if values == &[
+                Value::Symbol("hot".to_string()),
+                Value::Integer(-3),
+                Value::Boolean(true),
+                Value::Bytes(vec![1, 2]),
+                Value::Map(BTreeMap::from([("k".to_string(), Value::from("v"))])),
+            ]
+    ));
+    assert!(matches!(
+        &events[4].1,
+        TwinEvent::TelemetryReceived { data, .. } if data == &[("temperature".to_string(), 19.25)]
+    ));
+    assert!(matches!(
+        &events[6].1,
+        TwinEvent::MessageSent { result: Err(error), .. } if error == "nope"
+    ));
+    assert_eq!(store.get_latest_version().await.unwrap(), 8);
+
+    let snapshot = store.get_snapshot(twin_id).await.unwrap().unwrap();
+    assert_eq!(snapshot.properties["threshold"], Value::from(21.5));
+    assert_eq!(snapshot.parent_id, Some(source));
+    assert_eq!(snapshot.event_version, 3);
+}
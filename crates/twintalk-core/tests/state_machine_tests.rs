@@ -0,0 +1,200 @@
+//! Tests for twin state machines
+
+use std::sync::Arc;
+use std::time::Duration;
+use twintalk_core::event::{EventStore, TwinEvent};
+use twintalk_core::storage::MemoryEventStore;
+use twintalk_core::{
+    msg, Condition, Message, Query, Runtime, RuntimeConfig, StateMachine, Transition, Value,
+};
+
+fn pump() -> StateMachine {
+    StateMachine::new("state", "Idle")
+        .transition(Transition::on("start", "Idle", "Running").when("pressure", Condition::ge(1.0)))
+        .transition(
+            Transition::on_telemetry("Running", "Fault").when("temperature", Condition::gt(90.0)),
+        )
+        .transition(Transition::on("repair", "Fault", "Maintenance"))
+        .transition(Transition::on("resume", "Maintenance", "Idle"))
+        .on_entry("Fault", msg!(alarm: true))
+        .on_exit("Fault", msg!(alarm: false))
+}
+
+fn state(name: &str) -> Value {
+    Value::Symbol(name.to_string())
+}
+
+#[tokio::test]
+async fn test_transitions_on_messages_and_telemetry() {
+    let store = MemoryEventStore::new();
+    let runtime = Runtime::with_stores(
+        RuntimeConfig::default(),
+        Arc::new(store.clone()),
+        Arc::new(store.clone()),
+    );
+    runtime.define_state_machine("Pump", pump());
+    let twin = runtime.create_twin("Pump").await.unwrap();
+
+    // Guards hold transitions back, and other states do not take them
+    let start = runtime.parse_message(twin, "start").await.unwrap();
+    assert_eq!(start, Message::send("start", vec![]));
+    assert!(runtime.send(twin, &start).await.is_err());
+    runtime
+        .update_telemetry(twin, vec![("pressure".to_string(), 1.2)])
+        .await
+        .unwrap();
+    assert_eq!(runtime.send(twin, &start).await.unwrap(), state("Running"));
+    assert!(runtime.send(twin, &start).await.is_err());
+    assert!(runtime.send(twin, &msg!(state: "Idle")).await.is_err());
+
+    // Reading a property named like a trigger does not fire it
+    assert_eq!(runtime.send(twin, &msg!(repair)).await.unwrap(), Value::Nil);
+    assert_eq!(
+        runtime.send(twin, &msg!(state)).await.unwrap(),
+        state("Running")
+    );
+
+    runtime
+        .update_telemetry(twin, vec![("temperature".to_string(), 85.0)])
+        .await
+        .unwrap();
+    assert_eq!(
+        runtime.send(twin, &msg!(state)).await.unwrap(),
+        state("Running")
+    );
+    runtime
+        .update_telemetry(twin, vec![("temperature".to_string(), 95.0)])
+        .await
+        .unwrap();
+    assert_eq!(
+        runtime.send(twin, &msg!(state)).await.unwrap(),
+        state("Fault")
+    );
+    assert_eq!(
+        runtime.send(twin, &msg!(alarm)).await.unwrap(),
+        Value::from(true)
+    );
+
+    // The state is queryable like any property
+    let faulty = Query::parse("select id where state = Fault").unwrap();
+    assert_eq!(runtime.select(&faulty).collect().await.unwrap().len(), 1);
+
+    let repair = Message::Send {
+        selector: "repair".to_string(),
+        args: vec![],
+    };
+    runtime.send(twin, &repair).await.unwrap();
+    assert_eq!(
+        runtime.send(twin, &msg!(alarm)).await.unwrap(),
+        Value::from(false)
+    );
+
+    let changes: Vec<(String, String, String)> = runtime
+        .state_changes(twin)
+        .await
+        .unwrap()
+        .into_iter()
+        .filter_map(|event| match event {
+            TwinEvent::StateChanged {
+                from, to, trigger, ..
+            } => Some((from, to, trigger)),
+            _ => None,
+        })
+        .collect();
+    let expected = [
+        ("Idle", "Running", "start"),
+        ("Running", "Fault", "telemetry"),
+        ("Fault", "Maintenance", "repair"),
+    ]
+    .map(|(from, to, trigger)| (from.to_string(), to.to_string(), trigger.to_string()));
+    assert_eq!(changes, expected);
+
+    // Actions follow the transition that caused them
+    let events = store.get_events(twin, 0).await.unwrap();
+    let kinds: Vec<&str> = events.iter().map(|(_, event)| event.kind()).collect();
+    let fault = kinds
+        .iter()
+        .rposition(|kind| *kind == "TelemetryReceived")
+        .unwrap();
+    assert_eq!(
+        kinds[fault..],
+        [
+            "TelemetryReceived",
+            "StateChanged",
+            "PropertyChanged",
+            "StateChanged",
+            "PropertyChanged"
+        ]
+    );
+}
+
+#[tokio::test]
+async fn test_state_survives_restart() {
+    let store = MemoryEventStore::new();
+    let config = RuntimeConfig {
+        eviction_timeout: Duration::from_millis(10),
+        ..RuntimeConfig::default()
+    };
+    let runtime = Runtime::with_stores(
+        config.clone(),
+        Arc::new(store.clone()),
+        Arc::new(store.clone()),
+    );
+    runtime.define_state_machine("Pump", pump());
+    let twin = runtime.create_twin("Pump").await.unwrap();
+    runtime
+        .update_telemetry(twin, vec![("pressure".to_string(), 2.0)])
+        .await
+        .unwrap();
+    runtime
+        .send(twin, &Message::send("start", vec![]))
+        .await
+        .unwrap();
+
+    let restarted = Runtime::with_stores(
+        config.clone(),
+        Arc::new(store.clone()),
+        Arc::new(MemoryEventStore::new()),
+    );
+    restarted.define_state_machine("Pump", pump());
+    assert_eq!(
+        restarted.send(twin, &msg!(state)).await.unwrap(),
+        state("Running")
+    );
+
+    tokio::time::sleep(Duration::from_millis(30)).await;
+    assert_eq!(runtime.evict_inactive().await.unwrap(), 1);
+    let compacted = Runtime::with_stores(
+        config,
+        Arc::new(MemoryEventStore::new()),
+        Arc::new(store.clone()),
+    );
+    compacted.define_state_machine("Pump", pump());
+    compacted
+        .update_telemetry(twin, vec![("temperature".to_string(), 99.0)])
+        .await
+        .unwrap();
+    assert_eq!(
+        compacted.send(twin, &msg!(state)).await.unwrap(),
+        state("Fault")
+    );
+}
+
+#[tokio::test]
+async fn test_cascading_actions_are_bounded() {
+    let runtime = Runtime::new(RuntimeConfig::default());
+    // Entering either state fires the transition back to the other
+    runtime.define_state_machine(
+        "Relay",
+        StateMachine::new("state", "Open")
+            .transition(Transition::on("close", "Open", "Closed"))
+            .transition(Transition::on("open", "Closed", "Open"))
+            .on_entry("Closed", Message::send("open", vec![]))
+            .on_entry("Open", Message::send("close", vec![])),
+    );
+    let relay = runtime.create_twin("Relay").await.unwrap();
+
+    let close = Message::send("close", vec![]);
+    let error = runtime.send(relay, &close).await.unwrap_err();
+    assert!(error.to_string().contains("cascades"), "{error}");
+}
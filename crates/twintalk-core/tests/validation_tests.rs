@@ -0,0 +1,194 @@
+//! Tests for per-class property schemas
+
+use std::sync::Arc;
+use std::time::Duration;
+use twintalk_core::event::{EventStore, TwinEvent};
+use twintalk_core::storage::MemoryEventStore;
+use twintalk_core::validation::{PropertyType, ViolationKind};
+use twintalk_core::{
+    msg, ClassSchema, Message, PropertySchema, Quantity, Runtime, RuntimeConfig, Twin,
+    ValidationError, Value,
+};
+
+fn thermostat() -> ClassSchema {
+    ClassSchema::new()
+        .property(
+            "temperature",
+            PropertySchema::number().range(-40.0, 125.0).unit("°C"),
+        )
+        .property("threshold", PropertySchema::number().required())
+        .property(
+            "mode",
+            PropertySchema::symbol().one_of(["heating", "cooling"]),
+        )
+}
+
+fn violations(error: &anyhow::Error) -> Vec<(String, ViolationKind)> {
+    error
+        .downcast_ref::<ValidationError>()
+        .unwrap_or_else(|| panic!("Expected a validation error, got {error}"))
+        .violations
+        .iter()
+        .map(|v| (v.property.clone(), v.kind.clone()))
+        .collect()
+}
+
+#[test]
+fn test_invalid_writes_rejected() {
+    let mut twin = Twin::new("Thermostat").with_schema(thermostat());
+
+    let error = twin
+        .send(&Message::SetProperty(
+            "threshold".to_string(),
+            Value::from("hot"),
+        ))
+        .unwrap_err();
+    assert_eq!(
+        violations(&error),
+        [(
+            "threshold".to_string(),
+            ViolationKind::WrongType {
+                expected: PropertyType::Number,
+                actual: "String"
+            }
+        )]
+    );
+    assert_eq!(
+        error.to_string(),
+        "Invalid Thermostat: threshold: expected Number, got String"
+    );
+
+    // Every violation of a batch is reported, and nothing is written
+    let error = twin
+        .send(&Message::UpdateProperties(vec![
+            ("temperature".to_string(), Value::from(30.0)),
+            ("mode".to_string(), Value::Symbol("defrost".to_string())),
+            ("threshold".to_string(), Value::Nil),
+        ]))
+        .unwrap_err();
+    assert_eq!(violations(&error).len(), 2);
+    assert_eq!(twin.send(&msg!(temperature)).unwrap(), Value::Nil);
+
+    // Quantities are checked in, and converted to, the schema's unit
+    twin.send(&Message::SetProperty(
+        "temperature".to_string(),
+        Quantity::new(212.0, "°F").into(),
+    ))
+    .unwrap();
+    assert_eq!(
+        twin.send(&msg!(temperature)).unwrap(),
+        Value::Quantity(Quantity::new(100.0, "°C"))
+    );
+    let error = twin
+        .send(&Message::SetProperty(
+            "temperature".to_string(),
+            Quantity::new(300.0, "°F").into(),
+        ))
+        .unwrap_err();
+    assert!(matches!(
+        violations(&error)[0].1,
+        ViolationKind::OutOfRange {
+            max: Some(125.0),
+            ..
+        }
+    ));
+
+    // Required properties must be set
+    assert_eq!(
+        violations(&twin.validate().unwrap_err().into()),
+        [("threshold".to_string(), ViolationKind::Missing)]
+    );
+    twin.send(&msg!(threshold: 30.0)).unwrap();
+    twin.send(&Message::SetProperty(
+        "mode".to_string(),
+        Value::Symbol("cooling".to_string()),
+    ))
+    .unwrap();
+    twin.validate().unwrap();
+
+    // Undeclared properties are only rejected by strict schemas
+    twin.send(&msg!(humidity: 40.0)).unwrap();
+    twin.set_schema(thermostat().strict());
+    assert!(twin.send(&msg!(humidity: 41.0)).is_err());
+}
+
+#[tokio::test]
+async fn test_telemetry_validated_before_recording() {
+    let store = MemoryEventStore::new();
+    let runtime = Runtime::with_stores(
+        RuntimeConfig {
+            eviction_timeout: Duration::from_millis(10),
+            ..RuntimeConfig::default()
+        },
+        Arc::new(store.clone()),
+        Arc::new(store.clone()),
+    );
+    let sensor = runtime.create_twin("Thermostat").await.unwrap();
+    let fan = runtime.create_twin("Fan").await.unwrap();
+    runtime
+        .update_telemetry(sensor, vec![("temperature".to_string(), 200.0)])
+        .await
+        .unwrap();
+    runtime.define_schema("Thermostat", thermostat()).await;
+
+    // Also for twins that are not loaded, but only of classes with a schema
+    tokio::time::sleep(Duration::from_millis(30)).await;
+    assert_eq!(runtime.evict_inactive().await.unwrap(), 2);
+    runtime
+        .update_telemetry(fan, vec![("mode".to_string(), 1.0)])
+        .await
+        .unwrap();
+    assert_eq!(runtime.stats().await.active_twins, 0);
+    let error = runtime
+        .update_telemetry(
+            sensor,
+            vec![("temperature".to_string(), 21.0), ("mode".to_string(), 1.0)],
+        )
+        .await
+        .unwrap_err();
+    assert_eq!(violations(&error)[0].0, "mode");
+    runtime
+        .update_telemetry(sensor, vec![("temperature".to_string(), 21.0)])
+        .await
+        .unwrap();
+
+    // Events recorded before the schema still replay
+    let telemetry = store
+        .get_events(sensor, 0)
+        .await
+        .unwrap()
+        .into_iter()
+        .filter(|(_, event)| matches!(event, TwinEvent::TelemetryReceived { .. }))
+        .count();
+    assert_eq!(telemetry, 2);
+    assert_eq!(
+        runtime.send(sensor, &msg!(temperature)).await.unwrap(),
+        Value::from(21.0)
+    );
+    assert!(runtime.send(sensor, &msg!(threshold: "hot")).await.is_err());
+}
+
+#[test]
+fn test_json_schema_export() {
+    let schema = thermostat().strict().to_json_schema("Thermostat");
+    assert_eq!(
+        schema,
+        serde_json::json!({
+            "$schema": "https://json-schema.org/draft/2020-12/schema",
+            "title": "Thermostat",
+            "type": "object",
+            "properties": {
+                "mode": {"type": "string", "enum": ["heating", "cooling"]},
+                "temperature": {
+                    "type": "number",
+                    "minimum": -40.0,
+                    "maximum": 125.0,
+                    "x-unit": "°C"
+                },
+                "threshold": {"type": "number"}
+            },
+            "required": ["threshold"],
+            "additionalProperties": false
+        })
+    );
+}